@@ -3,15 +3,26 @@ use futures::{
     stream::{FusedStream, Stream},
 };
 use std::{
+    env,
+    path::Path,
     pin::Pin,
     process,
     sync::Arc,
     task::{Context, Poll},
+    thread,
     time::Duration,
 };
+#[cfg(target_os = "linux")]
+use std::{io, path::PathBuf};
 
 use crate::daemon::*;
-use crate::{Board, BoardEvent, Bootloaded};
+use crate::{
+    flash_bootloaded, Board, BoardEvent, BootloadedDevice, Error, Firmware, FlashProgress,
+};
+
+/// Environment variable naming a file to record daemon commands to, to replay
+/// with `Backend::new_replay`
+pub const RECORD_ENV: &str = "KEYBOARD_CONFIGURATOR_RECORD";
 
 #[derive(Clone, Debug)]
 pub enum Event {
@@ -21,8 +32,13 @@ pub enum Event {
     Board(BoardId, BoardEvent),
     BoardAdded(Board),
     BoardRemoved(BoardId),
-    BootloadedAdded(Bootloaded),
-    BootloadedRemoved,
+    BootloadedAdded(BootloadedDevice),
+    BootloadedRemoved(BootloadedDevice),
+    Flash(BootloadedDevice, FlashProgress),
+    /// The daemon exited, so all boards were removed without `BoardRemoved`.
+    /// Boards found by the relaunched daemon are added again, with the same
+    /// `BoardId` if they are the same boards.
+    DaemonExited,
 }
 
 #[derive(Debug)]
@@ -50,6 +66,8 @@ impl Unpin for Events {}
 struct BackendInner {
     thread_client: Arc<ThreadClient>,
     executor: futures::executor::ThreadPool,
+    has_hotplug: bool,
+    event_sender: async_mpsc::UnboundedSender<Event>,
 }
 
 #[derive(Clone, Debug)]
@@ -58,7 +76,7 @@ pub struct Backend(Arc<BackendInner>);
 unsafe impl Send for Backend {}
 
 impl Backend {
-    fn new_internal<T: Daemon + 'static>(daemon: T) -> Result<(Self, Events), String> {
+    fn new_internal<T: Daemon + 'static>(daemon: T) -> Result<(Self, Events), Error> {
         let (sender, receiver) = async_mpsc::unbounded();
 
         let executor = futures::executor::ThreadPool::builder()
@@ -66,32 +84,81 @@ impl Backend {
             .create()
             .unwrap();
 
-        let thread_client = ThreadClient::new(Box::new(daemon), sender);
+        let is_fake = daemon.is_fake();
+        let daemon: Box<dyn Daemon> = match env::var_os(RECORD_ENV) {
+            // Fake boards aren't worth recording
+            Some(path) if !is_fake => {
+                info!("Recording daemon commands to {:?}", path);
+                Box::new(DaemonRecorder::create(Box::new(daemon), Path::new(&path))?)
+            }
+            _ => Box::new(daemon),
+        };
+        let thread_client = ThreadClient::new(daemon, sender.clone());
+
+        #[cfg(target_os = "linux")]
+        let has_hotplug = !is_fake
+            && match NetlinkUevents::new() {
+                Ok(uevents) => {
+                    watch_hotplug(
+                        uevents,
+                        PathBuf::from("/sys"),
+                        Arc::downgrade(&thread_client),
+                    );
+                    true
+                }
+                Err(err) => {
+                    error!("Failed to watch for hotplug: {}", err);
+                    false
+                }
+            };
+        #[cfg(not(target_os = "linux"))]
+        let has_hotplug = {
+            let _ = is_fake;
+            false
+        };
 
         Ok((
             Self(Arc::new(BackendInner {
                 thread_client,
                 executor,
+                has_hotplug,
+                event_sender: sender,
             })),
             Events(receiver),
         ))
     }
 
-    pub fn new_dummy(board_names: Vec<String>) -> Result<(Self, Events), String> {
+    pub fn new_dummy(board_names: Vec<String>) -> Result<(Self, Events), Error> {
         let dummy_daemon = DaemonDummy::new(board_names)?;
         Self::new_internal(dummy_daemon)
     }
 
+    /// Serve boards from a recording of daemon commands, made by setting
+    /// `RECORD_ENV`
+    pub fn new_replay(path: &Path) -> Result<(Self, Events), Error> {
+        Self::new_internal(DaemonReplay::open(path)?)
+    }
+
     #[cfg(target_os = "linux")]
-    pub fn new_s76power() -> Result<(Self, Events), String> {
+    pub fn new_s76power() -> Result<(Self, Events), Error> {
         Self::new_internal(DaemonS76Power::new()?)
     }
 
-    pub fn new_pkexec() -> Result<(Self, Events), String> {
-        Self::new_internal(DaemonClient::new_pkexec())
+    pub fn new_pkexec() -> Result<(Self, Events), Error> {
+        let client = DaemonClient::new_pkexec()?;
+        Self::new_internal(DaemonSupervisor::new(client, relaunch_daemon))
+    }
+
+    /// Connect to a daemon shared with other clients of the current user,
+    /// started with `run_daemon_socket`
+    #[cfg(target_os = "linux")]
+    pub fn new_socket() -> Result<(Self, Events), Error> {
+        let uid = unsafe { libc::getuid() };
+        let client = DaemonClient::new_socket(&daemon_socket_path(uid))?;
+        Self::new_internal(DaemonSupervisor::new(client, relaunch_daemon))
     }
 
-    pub fn new() -> Result<(Self, Events), String> {
+    pub fn new() -> Result<(Self, Events), Error> {
         Self::new_internal(DaemonServer::new_stdio()?)
     }
 
@@ -107,6 +174,19 @@ impl Backend {
         });
     }
 
+    /// Like `refresh`, but returns a future that resolves once the refresh is done
+    ///
+    /// Any `BoardAdded`/`BoardRemoved` events have been sent by the time it resolves.
+    pub async fn refresh_wait(&self) -> Result<(), Error> {
+        self.0.thread_client.refresh().await
+    }
+
+    /// Whether added and removed boards and boards in bootloader mode are
+    /// found without calling `refresh` and `check_for_bootloader`
+    pub fn has_hotplug(&self) -> bool {
+        self.0.has_hotplug
+    }
+
     pub fn check_for_bootloader(&self) {
         let self_ = self.clone();
         self.0.executor.spawn_ok(async move {
@@ -116,12 +196,35 @@ impl Backend {
         });
     }
 
+    /// Flash `firmware` to `device` in the background, sending `Flash` events
+    /// with the progress until it is `Done` or `Failed`
+    pub fn flash(&self, device: BootloadedDevice, firmware: Firmware) {
+        let sender = self.0.event_sender.clone();
+        thread::spawn(move || {
+            let send = |progress| {
+                let _ = sender.unbounded_send(Event::Flash(device.clone(), progress));
+            };
+            if let Err(err) = flash_bootloaded(&device, &firmware, send) {
+                error!("Failed to flash {}: {}", device.bus_path, err);
+                send(FlashProgress::Failed(err));
+            }
+        });
+    }
+
     pub fn set_matrix_get_rate(&self, rate: Option<Duration>) {
         let self_ = self.clone();
         self.0.executor.spawn_ok(async move {
             let _ = self_.0.thread_client.set_matrix_get_rate(rate).await;
         });
     }
+
+    /// Periodically resync boards with the device (see `Board::resync`); `None` to disable
+    pub fn set_resync_rate(&self, rate: Option<Duration>) {
+        let self_ = self.clone();
+        self.0.executor.spawn_ok(async move {
+            let _ = self_.0.thread_client.set_resync_rate(rate).await;
+        });
+    }
 }
 
 impl Drop for BackendInner {
@@ -130,8 +233,39 @@ impl Drop for BackendInner {
     }
 }
 
+/// Connect to the shared daemon if it is running, which doesn't need
+/// authentication, or else start another with pkexec
+fn relaunch_daemon() -> Result<DaemonClient, Error> {
+    #[cfg(target_os = "linux")]
+    match DaemonClient::new_socket(&daemon_socket_path(unsafe { libc::getuid() })) {
+        Ok(client) => return Ok(client),
+        Err(err) => info!("No shared daemon: {}", err),
+    }
+    DaemonClient::new_pkexec()
+}
+
 pub fn run_daemon() -> ! {
     let server = DaemonServer::new_stdio().expect("Failed to create server");
     server.run().expect("Failed to run server");
     process::exit(0)
 }
+
+/// Run a daemon for the user that started it with pkexec, listening on a
+/// socket so the GUI, CLI and other clients can share it
+#[cfg(target_os = "linux")]
+pub fn run_daemon_socket() -> ! {
+    // Set by pkexec to the user that ran it
+    let uid = env::var("PKEXEC_UID")
+        .ok()
+        .and_then(|uid| uid.parse().ok())
+        .unwrap_or_else(|| unsafe { libc::getuid() });
+    let path = daemon_socket_path(uid);
+    let listener = bind_daemon_socket(&path, uid).expect("Failed to create daemon socket");
+    info!("Listening on {}", path.display());
+
+    let server = DaemonServer::new(io::empty(), io::sink()).expect("Failed to create server");
+    server
+        .run_socket(listener, vec![0, uid])
+        .expect("Failed to run server");
+    process::exit(0)
+}
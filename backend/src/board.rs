@@ -4,17 +4,19 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeMap,
-    process::Command,
+    fmt,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex, MutexGuard, Weak,
     },
 };
 
-use crate::daemon::ThreadClient;
+use crate::daemon::{KeyMapWrite, ThreadClient};
+use crate::key::KeyPreload;
 use crate::{
-    Benchmark, BoardId, Daemon, Event, Key, KeyMap, KeyMapLayer, Layer, Layout, Matrix, Nelson,
-    NelsonKind,
+    Benchmark, BoardId, Daemon, Edit, Error, Event, FirmwareManifest, FirmwareStatus, History,
+    HistoryGroup, Hs, Key, KeyMap, KeyMapError, KeyMapLayer, Layer, Layout, Matrix, Nelson,
+    NelsonKind, Rgb,
 };
 
 #[derive(Clone, Debug)]
@@ -24,12 +26,83 @@ pub enum BoardEvent {
     MatrixChanged,
 }
 
+/// A scancode changed by `Board::apply_keymap`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyMapChange {
+    /// Logical name of the key
+    pub key: String,
+    pub layer: usize,
+    /// Scancode name before the change
+    pub old: String,
+    /// Scancode name after the change
+    pub new: String,
+}
+
+/// Result of a successful `Board::apply_keymap`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyMapApplyReport {
+    pub changed: Vec<KeyMapChange>,
+    /// Number of scancodes that already matched the keymap
+    pub unchanged: usize,
+}
+
+#[derive(Clone, Debug)]
+pub enum KeyMapApplyError {
+    /// Keymap is for a different model
+    WrongModel(String),
+    /// Keymap failed validation; nothing was written
+    Invalid(Vec<KeyMapError>),
+    /// Writing or verifying a scancode failed. Writes before it were rolled
+    /// back, unless `rollback_error` is set.
+    Write {
+        key: String,
+        layer: usize,
+        error: Error,
+        rollback_error: Option<Error>,
+    },
+    /// Daemon failed before reporting the result, so it is unknown which
+    /// scancodes were written
+    Daemon(Error),
+}
+
+impl fmt::Display for KeyMapApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::WrongModel(model) => write!(f, "keymap is for board '{}'", model),
+            Self::Invalid(errors) => {
+                write!(f, "invalid keymap")?;
+                for error in errors {
+                    write!(f, "\n{}", error)?;
+                }
+                Ok(())
+            }
+            Self::Write {
+                key,
+                layer,
+                error,
+                rollback_error,
+            } => {
+                write!(f, "failed to set {} on layer {}: {}", key, layer, error)?;
+                match rollback_error {
+                    Some(err) => write!(f, "; rollback failed: {}", err),
+                    None => write!(f, "; changes were rolled back"),
+                }
+            }
+            Self::Daemon(err) => write!(f, "failed to apply keymap: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for KeyMapApplyError {}
+
 #[derive(Debug)]
 struct BoardInner {
     thread_client: Arc<ThreadClient>,
     board: BoardId,
     model: String,
     version: String,
+    serial: Option<String>,
+    stable_id: Option<String>,
     layout: Layout,
     keys: OnceCell<Vec<Key>>,
     layers: OnceCell<Vec<Layer>>,
@@ -41,7 +114,8 @@ struct BoardInner {
     is_fake: bool,
     has_keymap: bool,
     matrix: Arc<Mutex<Matrix>>,
-    updated: bool,
+    history: Mutex<History>,
+    firmware_status: FirmwareStatus,
     event_sender: async_mpsc::UnboundedSender<Event>,
 }
 
@@ -76,34 +150,42 @@ impl Board {
         board: BoardId,
         matrix: Arc<Mutex<Matrix>>,
         event_sender: async_mpsc::UnboundedSender<Event>,
-    ) -> Result<Self, String> {
-        let model = match daemon.model(board) {
-            Ok(model) => model,
-            Err(err) => {
-                return Err(format!("Failed to get board model: {}", err));
-            }
-        };
+    ) -> Result<Self, Error> {
+        let model = daemon
+            .model(board)
+            .map_err(|err| err.context("Failed to get board model"))?;
         let version = daemon.version(board).unwrap_or_else(|err| {
             error!("Error getting firmware version: {}", err);
             String::new()
         });
+        let serial = daemon.serial(board).unwrap_or_else(|err| {
+            error!("Error getting serial number: {}", err);
+            None
+        });
+        let stable_id = daemon
+            .stable_id(board)
+            .map_err(|err| error!("Error getting stable ID: {}", err))
+            .ok();
         let layout = Layout::from_board(&model, &version)
-            .ok_or_else(|| format!("Failed to locate layout for '{}'", model))?;
+            .ok_or_else(|| Error::NotFound(format!("Failed to locate layout for '{}'", model)))?;
+        let firmware_status = FirmwareStatus::new(
+            &model,
+            &version,
+            layout.meta.is_qmk,
+            FirmwareManifest::installed(),
+        );
 
         let max_brightness = daemon.max_brightness(board).unwrap_or_else(|err| {
             error!("Error getting max brightness: {}", err);
             100
         });
 
-        let num_layers = if layout.meta.has_per_layer {
-            layout.meta.num_layers
-        } else {
-            1
-        };
+        let num_layers = layout.num_led_layers();
 
         let has_led_save = daemon.led_save(board).is_ok();
         let has_matrix = daemon.matrix_get(board).is_ok();
-        let logical = layout.layout.values().next().unwrap();
+        // The same key every time, so recorded sessions replay
+        let logical = layout.layout.values().min().unwrap();
         let has_keymap = daemon.keymap_get(board, 0, logical.0, logical.1).is_ok();
 
         let self_ = Board(Arc::new(BoardInner {
@@ -111,6 +193,8 @@ impl Board {
             board,
             model,
             version,
+            serial,
+            stable_id,
             layout,
             max_brightness,
             has_led_save,
@@ -122,16 +206,19 @@ impl Board {
             leds_changed: AtomicBool::new(false),
             led_save_blocked: AtomicBool::new(false),
             matrix,
+            history: Mutex::new(History::default()),
             event_sender,
-            updated: is_launch_updated().unwrap_or(false),
+            firmware_status,
         }));
 
+        let preload = KeyPreload::new(daemon, board, self_.layout(), true);
         let keys = self_
             .layout()
             .physical
             .keys
             .iter()
-            .map(|i| Key::new(daemon, &self_, i))
+            .enumerate()
+            .map(|(index, i)| Key::new(daemon, &self_, index, i, &preload))
             .collect();
         self_.0.keys.set(keys).unwrap();
 
@@ -150,7 +237,8 @@ impl Board {
             .unbounded_send(Event::Board(self.0.board, event));
     }
 
-    pub(crate) fn set_leds_changed(&self) {
+    /// Mark LED settings as changed, so the next `led_save` will persist them
+    pub fn set_leds_changed(&self) {
         self.0.leds_changed.store(true, Ordering::SeqCst);
         self.send_event(BoardEvent::LedsChanged);
     }
@@ -171,6 +259,19 @@ impl Board {
         &self.0.version
     }
 
+    /// USB serial number, if the board has one
+    pub fn serial(&self) -> Option<&str> {
+        self.0.serial.as_deref()
+    }
+
+    /// Identity of the physical board, which stays the same when it is
+    /// reconnected or the daemon restarts, to attach settings to. Based on
+    /// the serial number or USB port of the board. `None` if the daemon is
+    /// too old to report it.
+    pub fn stable_id(&self) -> Option<&str> {
+        self.0.stable_id.as_deref()
+    }
+
     pub fn has_matrix(&self) -> bool {
         self.0.has_matrix
     }
@@ -179,15 +280,15 @@ impl Board {
         self.0.max_brightness
     }
 
-    pub async fn benchmark(&self) -> Result<Benchmark, String> {
+    pub async fn benchmark(&self) -> Result<Benchmark, Error> {
         self.thread_client().benchmark(self.board()).await
     }
 
-    pub async fn nelson(&self, kind: NelsonKind) -> Result<Nelson, String> {
+    pub async fn nelson(&self, kind: NelsonKind) -> Result<Nelson, Error> {
         self.thread_client().nelson(self.board(), kind).await
     }
 
-    pub async fn led_save(&self) -> Result<(), String> {
+    pub async fn led_save(&self) -> Result<(), Error> {
         if self.0.led_save_blocked.load(Ordering::SeqCst) {
             return Ok(());
         }
@@ -216,8 +317,14 @@ impl Board {
         RE.is_match(self.model())
     }
 
+    /// Firmware version, compared to the newest in the installed manifest
+    pub fn firmware_status(&self) -> &FirmwareStatus {
+        &self.0.firmware_status
+    }
+
+    /// Whether no newer firmware is known
     pub fn is_updated(&self) -> bool {
-        self.0.updated
+        !self.0.firmware_status.update_available()
     }
 
     pub fn has_led_save(&self) -> bool {
@@ -244,6 +351,137 @@ impl Board {
         self.0.matrix.lock().unwrap()
     }
 
+    /// Re-read keymap, LEDs and modes from the device, in case they were
+    /// changed by another program or by the firmware. Sends
+    /// `BoardEvent::KeymapChanged` and `BoardEvent::LedsChanged` if they differ
+    /// from the cached values.
+    pub async fn resync(&self) -> Result<(), Error> {
+        self.thread_client().resync(self.board()).await
+    }
+
+    pub(crate) fn resync_from(&self, daemon: &dyn Daemon) -> Result<(), Error> {
+        let mut keymap_changed = false;
+        let mut leds_changed = false;
+        let mut res = Ok(());
+        let preload = KeyPreload::new(daemon, self.board(), self.layout(), self.has_keymap());
+        for key in self.keys() {
+            match key.resync(daemon, self, &preload) {
+                Ok((keymap, leds)) => {
+                    keymap_changed |= keymap;
+                    leds_changed |= leds;
+                }
+                Err(err) => res = res.and(Err(err)),
+            }
+        }
+        for layer in self.layers() {
+            match layer.resync(daemon, self) {
+                Ok(changed) => leds_changed |= changed,
+                Err(err) => res = res.and(Err(err)),
+            }
+        }
+
+        // Send events even on error, for the values that were updated
+        if keymap_changed {
+            self.send_event(BoardEvent::KeymapChanged);
+        }
+        if leds_changed {
+            self.send_event(BoardEvent::LedsChanged);
+        }
+        res
+    }
+
+    pub(crate) fn history(&self) -> MutexGuard<History> {
+        self.0.history.lock().unwrap()
+    }
+
+    pub(crate) fn record_edit(&self, edit: Edit, group: Option<&HistoryGroup>) {
+        self.history().record(edit, group);
+    }
+
+    /// New group, to record edits through as one undo step
+    pub fn history_group(&self) -> HistoryGroup {
+        HistoryGroup::new()
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.history().can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.history().can_redo()
+    }
+
+    async fn apply_edit(&self, edit: &Edit, undo: bool) -> Result<(), Error> {
+        match *edit {
+            Edit::Scancode {
+                key,
+                layer,
+                old,
+                new,
+            } => {
+                self.keys()[key]
+                    .write_scancode(layer, if undo { old } else { new })
+                    .await
+            }
+            Edit::KeyColor { key, old, new } => {
+                self.keys()[key]
+                    .write_color(if undo { old } else { new })
+                    .await
+            }
+            Edit::LayerMode { layer, old, new } => {
+                let (mode, speed) = if undo { old } else { new };
+                self.layers()[layer].write_mode(mode, speed).await
+            }
+            Edit::LayerBrightness { layer, old, new } => {
+                self.layers()[layer]
+                    .write_brightness(if undo { old } else { new })
+                    .await
+            }
+            Edit::LayerColor { layer, old, new } => {
+                self.layers()[layer]
+                    .write_color(if undo { old } else { new })
+                    .await
+            }
+        }
+    }
+
+    /// Revert the last step in the undo history. Returns `false` if there was
+    /// nothing to undo.
+    ///
+    /// If a write fails, the step stays in the undo history and may be
+    /// partially reverted.
+    pub async fn undo(&self) -> Result<bool, Error> {
+        let step = match self.history().pop_undo() {
+            Some(step) => step,
+            None => return Ok(false),
+        };
+        for edit in step.edits.iter().rev() {
+            if let Err(err) = self.apply_edit(edit, true).await {
+                self.history().push_undo(step);
+                return Err(err);
+            }
+        }
+        self.history().push_redo(step);
+        Ok(true)
+    }
+
+    /// Reapply the last undone step. Returns `false` if there was nothing to
+    /// redo.
+    pub async fn redo(&self) -> Result<bool, Error> {
+        let step = match self.history().pop_redo() {
+            Some(step) => step,
+            None => return Ok(false),
+        };
+        for edit in &step.edits {
+            if let Err(err) = self.apply_edit(edit, false).await {
+                self.history().push_redo(step);
+                return Err(err);
+            }
+        }
+        self.history().push_undo(step);
+        Ok(true)
+    }
+
     pub fn export_keymap(&self) -> KeyMap {
         let mut map = BTreeMap::new();
         let mut key_leds = BTreeMap::new();
@@ -267,14 +505,181 @@ impl Board {
             .collect();
         KeyMap {
             model: self.model().to_string(),
-            version: 1,
+            version: KeyMap::VERSION,
             map,
             key_leds,
             layers,
         }
     }
 
-    pub async fn set_no_input(&self, no_input: bool) -> Result<(), String> {
+    /// Set the scancodes of `keymap`, writing only those that differ from the
+    /// cached scancodes. Every write is verified by reading it back, and if any
+    /// fails the previous scancodes are restored.
+    ///
+    /// LED settings in `keymap` are not applied. The scancodes are recorded as
+    /// one undo step.
+    pub async fn apply_keymap(
+        &self,
+        keymap: &KeyMap,
+    ) -> Result<KeyMapApplyReport, KeyMapApplyError> {
+        self.apply_keymap_in(&self.history_group(), keymap).await
+    }
+
+    /// Like `apply_keymap`, recording the edits in `group`
+    pub async fn apply_keymap_in(
+        &self,
+        group: &HistoryGroup,
+        keymap: &KeyMap,
+    ) -> Result<KeyMapApplyReport, KeyMapApplyError> {
+        if keymap.model != self.model() {
+            return Err(KeyMapApplyError::WrongModel(keymap.model.clone()));
+        }
+        keymap
+            .validate(self.layout())
+            .map_err(KeyMapApplyError::Invalid)?;
+
+        let mut report = KeyMapApplyReport::default();
+        let mut writes = Vec::new();
+        let mut write_keys = Vec::new();
+        for key in self.keys() {
+            let scancodes = match keymap.map.get(&key.logical_name) {
+                Some(scancodes) => scancodes,
+                None => continue,
+            };
+            for (layer, scancode_name) in scancodes.iter().enumerate() {
+                // `validate` has checked that the scancode exists
+                let new = self.layout().scancode_from_name(scancode_name).unwrap();
+                let (old, old_name) = key.get_scancode(layer).unwrap();
+                if old == new {
+                    report.unchanged += 1;
+                    continue;
+                }
+                writes.push(KeyMapWrite {
+                    layer: layer as u8,
+                    output: key.electrical.0,
+                    input: key.electrical.1,
+                    old,
+                    new,
+                });
+                write_keys.push(key);
+                report.changed.push(KeyMapChange {
+                    key: key.logical_name.clone(),
+                    layer,
+                    old: old_name,
+                    new: scancode_name.clone(),
+                });
+            }
+        }
+
+        if writes.is_empty() {
+            return Ok(report);
+        }
+
+        let write_error = |index: usize, error, rollback_error| {
+            let change = &report.changed[index];
+            KeyMapApplyError::Write {
+                key: change.key.clone(),
+                layer: change.layer,
+                error,
+                rollback_error,
+            }
+        };
+        let err = match self
+            .thread_client()
+            .keymap_apply(self.board(), writes.clone())
+            .await
+        {
+            Ok(Ok(())) => None,
+            Ok(Err(failure)) => Some(write_error(
+                failure.index,
+                failure.error,
+                failure.rollback_error,
+            )),
+            Err(err) => Some(KeyMapApplyError::Daemon(err)),
+        };
+        if let Some(err) = err {
+            // Unless the writes were rolled back, the cached scancodes may not
+            // match the board anymore
+            let rolled_back = matches!(
+                err,
+                KeyMapApplyError::Write {
+                    rollback_error: None,
+                    ..
+                }
+            );
+            if !rolled_back {
+                if let Err(err) = self.resync().await {
+                    error!("Error resyncing after failing to apply keymap: {}", err);
+                }
+            }
+            return Err(err);
+        }
+
+        for (key, write) in write_keys.iter().zip(&writes) {
+            key.set_cached_scancode(write.layer as usize, write.new);
+            self.record_edit(
+                Edit::Scancode {
+                    key: key.index(),
+                    layer: write.layer as usize,
+                    old: write.old,
+                    new: write.new,
+                },
+                Some(group),
+            );
+        }
+        self.send_event(BoardEvent::KeymapChanged);
+
+        Ok(report)
+    }
+
+    /// Set the LED color of keys by logical name, such as the `key_leds` of a
+    /// `KeyMap`, with one batch command. Recorded as one undo step.
+    pub async fn set_key_colors(&self, colors: &BTreeMap<String, Option<Hs>>) -> Result<(), Error> {
+        self.set_key_colors_in(&self.history_group(), colors).await
+    }
+
+    /// Like `set_key_colors`, recording the edits in `group`
+    pub async fn set_key_colors_in(
+        &self,
+        group: &HistoryGroup,
+        colors: &BTreeMap<String, Option<Hs>>,
+    ) -> Result<(), Error> {
+        let keys = self
+            .keys()
+            .iter()
+            .filter_map(|key| Some((key, *colors.get(&key.logical_name)?)))
+            .collect::<Vec<_>>();
+        let leds = keys
+            .iter()
+            .flat_map(|(key, color)| {
+                let Rgb { r, g, b } = color.map_or(Rgb::new(0, 0, 0), Hs::to_rgb);
+                key.leds.iter().map(move |index| (*index, (r, g, b)))
+            })
+            .collect::<Vec<_>>();
+        if leds.is_empty() {
+            return Ok(());
+        }
+        self.thread_client()
+            .set_color_batch(self.board(), leds)
+            .await?;
+
+        for (key, color) in keys {
+            let old = key.color();
+            key.set_cached_color(color);
+            self.record_edit(
+                Edit::KeyColor {
+                    key: key.index(),
+                    old,
+                    new: color,
+                },
+                Some(group),
+            );
+        }
+        self.set_leds_changed();
+        Ok(())
+    }
+
+    pub async fn set_no_input(&self, no_input: bool) -> Result<(), Error> {
         self.thread_client()
             .set_no_input(self.board(), no_input)
             .await
@@ -291,14 +696,439 @@ impl WeakBoard {
     }
 }
 
-pub fn is_launch_updated() -> Result<bool, String> {
-    use regex::bytes::Regex;
-    let stdout = Command::new("fwupdmgr")
-        .args(["get-updates", "--json"])
-        .output()
-        .map_err(|e| format!("Failed to use fwupdmgr: {}", e))?
-        .stdout;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{
+        DaemonClientTrait, DaemonCommand, DaemonDummy, DaemonRecorder, DaemonReplay, DaemonResponse,
+    };
+    use futures::{executor::block_on, FutureExt, StreamExt};
+    use std::{collections::HashMap, env, fs, sync::atomic::AtomicUsize};
+
+    type SharedKeyMap = Arc<Mutex<HashMap<(u8, u8, u8), u16>>>;
+
+    /// Dummy daemon with a keymap visible to the test, that fails the
+    /// `fail_at`th call to `keymap_set`
+    struct FailingDaemon {
+        dummy: DaemonDummy,
+        keymap: SharedKeyMap,
+        writes: AtomicUsize,
+        fail_at: Option<usize>,
+        /// Fail every write after `fail_at` too, so rolling back fails
+        fail_after: bool,
+    }
+
+    impl DaemonClientTrait for FailingDaemon {
+        fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, Error> {
+            match command {
+                DaemonCommand::keymap_get {
+                    layer,
+                    output,
+                    input,
+                    ..
+                } => {
+                    let keymap = self.keymap.lock().unwrap();
+                    let value = keymap.get(&(layer, output, input)).copied().unwrap_or(0);
+                    Ok(DaemonResponse::keymap_get(value))
+                }
+                DaemonCommand::keymap_set {
+                    layer,
+                    output,
+                    input,
+                    value,
+                    ..
+                } => {
+                    let write = self.writes.fetch_add(1, Ordering::SeqCst);
+                    let fail = match self.fail_at {
+                        Some(fail_at) if self.fail_after => write >= fail_at,
+                        fail_at => Some(write) == fail_at,
+                    };
+                    if fail {
+                        return Err(Error::Io("write failed".to_string()));
+                    }
+                    let mut keymap = self.keymap.lock().unwrap();
+                    keymap.insert((layer, output, input), value);
+                    Ok(DaemonResponse::keymap_set(()))
+                }
+                DaemonCommand::keymap_get_batch { board, keys } => {
+                    let values = keys
+                        .into_iter()
+                        .map(|(layer, output, input)| self.keymap_get(board, layer, output, input))
+                        .collect::<Result<_, _>>()?;
+                    Ok(DaemonResponse::keymap_get_batch(values))
+                }
+                DaemonCommand::keymap_set_batch { board, keys } => {
+                    for (layer, output, input, value) in keys {
+                        self.keymap_set(board, layer, output, input, value)?;
+                    }
+                    Ok(DaemonResponse::keymap_set_batch(()))
+                }
+                command => self.dummy.dispatch_command_to_method(command),
+            }
+        }
+    }
+
+    type SharedColors = Arc<Mutex<HashMap<u8, (u8, u8, u8)>>>;
+
+    /// Dummy daemon with LED colors visible to the test
+    struct ColorDaemon {
+        dummy: DaemonDummy,
+        colors: SharedColors,
+    }
+
+    impl DaemonClientTrait for ColorDaemon {
+        fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, Error> {
+            let mut colors = self.colors.lock().unwrap();
+            match command {
+                DaemonCommand::color { index, .. } => Ok(DaemonResponse::color(
+                    colors.get(&index).copied().unwrap_or_default(),
+                )),
+                DaemonCommand::color_batch { indices, .. } => Ok(DaemonResponse::color_batch(
+                    indices
+                        .iter()
+                        .map(|index| colors.get(index).copied().unwrap_or_default())
+                        .collect(),
+                )),
+                DaemonCommand::set_color { index, color, .. } => {
+                    colors.insert(index, color);
+                    Ok(DaemonResponse::set_color(()))
+                }
+                command => {
+                    drop(colors);
+                    self.dummy.dispatch_command_to_method(command)
+                }
+            }
+        }
+    }
+
+    /// Dummy daemon that counts commands, as round trips to a real daemon,
+    /// and optionally doesn't support batch commands
+    struct CountingDaemon {
+        dummy: DaemonDummy,
+        commands: Arc<Mutex<Counts>>,
+        batch: bool,
+    }
+
+    /// Number of commands sent, by name
+    #[derive(Debug, Default)]
+    struct Counts(HashMap<&'static str, usize>);
+
+    impl Counts {
+        fn get(&self, names: &[&str]) -> usize {
+            names.iter().filter_map(|name| self.0.get(name)).sum()
+        }
+    }
+
+    impl DaemonClientTrait for CountingDaemon {
+        fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, Error> {
+            *self
+                .commands
+                .lock()
+                .unwrap()
+                .0
+                .entry(command.name())
+                .or_default() += 1;
+            if !self.batch && command.name().ends_with("_batch") {
+                return Err(Error::Unsupported(command.name().to_string()));
+            }
+            self.dummy.dispatch_command_to_method(command)
+        }
+    }
+
+    fn load_board(daemon: Box<dyn Daemon>) -> Board {
+        load_board_events(daemon).0
+    }
+
+    /// Load a board, returning it with the receiver of later events
+    fn load_board_events(daemon: Box<dyn Daemon>) -> (Board, async_mpsc::UnboundedReceiver<Event>) {
+        let (sender, mut receiver) = async_mpsc::unbounded();
+        let client = ThreadClient::new(daemon, sender);
+        block_on(client.refresh()).unwrap();
+        while let Some(Some(event)) = receiver.next().now_or_never() {
+            if let Event::BoardAdded(board) = event {
+                return (board, receiver);
+            }
+        }
+        panic!("board not added");
+    }
+
+    fn board(fail_at: Option<usize>) -> (Board, SharedKeyMap) {
+        failing_board(fail_at, false)
+    }
+
+    fn failing_board(fail_at: Option<usize>, fail_after: bool) -> (Board, SharedKeyMap) {
+        let keymap = SharedKeyMap::default();
+        let daemon = FailingDaemon {
+            dummy: DaemonDummy::new(vec!["system76/launch_1".to_string()]).unwrap(),
+            keymap: keymap.clone(),
+            writes: AtomicUsize::new(0),
+            fail_at,
+            fail_after,
+        };
+        (load_board(Box::new(daemon)), keymap)
+    }
+
+    /// Load and import a keymap on a board, returning the commands sent by
+    /// each
+    fn count_commands(batch: bool) -> (Counts, Counts) {
+        let commands = Arc::new(Mutex::new(Counts::default()));
+        let daemon = CountingDaemon {
+            dummy: DaemonDummy::new(vec!["system76/launch_1".to_string()]).unwrap(),
+            commands: commands.clone(),
+            batch,
+        };
+        let board = load_board(Box::new(daemon));
+        let loaded = std::mem::take(&mut *commands.lock().unwrap());
+
+        let mut keymap = board.layout().default.clone();
+        for scancodes in keymap.map.values_mut() {
+            scancodes.iter_mut().for_each(|x| *x = "A".to_string());
+        }
+        for color in keymap.key_leds.values_mut() {
+            *color = Some(Hs::new(0.5, 1.));
+        }
+        block_on(board.apply_keymap(&keymap)).unwrap();
+        block_on(board.set_key_colors(&keymap.key_leds)).unwrap();
+        assert_eq!(board.export_keymap().map, keymap.map);
+        assert_eq!(board.export_keymap().key_leds, keymap.key_leds);
+        let imported = std::mem::take(&mut *commands.lock().unwrap());
+        (loaded, imported)
+    }
+
+    #[test]
+    fn batch_round_trips() {
+        let layout = Layout::from_board("system76/launch_1", "dummy").unwrap();
+        let keys = layout.layout.len();
+        let layers = usize::from(layout.meta.num_layers);
+        let leds = layout.default.key_leds.len();
+
+        let (batch_load, batch_import) = count_commands(true);
+        let (each_load, each_import) = count_commands(false);
+
+        // With batches, the number of keymap and key color commands doesn't
+        // depend on the number of keys. The only colors read one at a time
+        // are those of the layers.
+        let keymap = [
+            "keymap_get",
+            "keymap_get_batch",
+            "keymap_set",
+            "keymap_set_batch",
+        ];
+        assert!(batch_load.get(&keymap) <= 2);
+        assert_eq!(batch_load.get(&["color_batch"]), 1);
+        assert!(batch_load.get(&["color"]) <= layers);
+        assert!(batch_import.get(&keymap) <= 2);
+        assert_eq!(batch_import.get(&["set_color", "set_color_batch"]), 1);
+
+        // Without, each key is read on each layer and each LED's color read
+        assert_eq!(
+            each_load.get(&["keymap_get"]) - batch_load.get(&["keymap_get"]),
+            keys * layers
+        );
+        assert_eq!(each_load.get(&["color"]) - batch_load.get(&["color"]), leds);
+        // Then each key is read and written on each layer, and each LED's
+        // color written
+        assert_eq!(
+            each_import.get(&["keymap_get", "keymap_set"]),
+            2 * keys * layers
+        );
+        assert_eq!(each_import.get(&["set_color"]), leds);
+    }
+
+    #[test]
+    fn apply_keymap() {
+        let (board, keymap) = board(None);
+        let default = &board.layout().default;
+
+        let report = block_on(board.apply_keymap(default)).unwrap();
+        assert!(!report.changed.is_empty());
+        assert_eq!(board.export_keymap().map, default.map);
+        assert_eq!(keymap.lock().unwrap().len(), report.changed.len());
+
+        // Only differences are written
+        let mut keymap2 = default.clone();
+        keymap2.map.get_mut("K00").unwrap()[0] = "A".to_string();
+        let report2 = block_on(board.apply_keymap(&keymap2)).unwrap();
+        assert_eq!(
+            report2.changed,
+            vec![KeyMapChange {
+                key: "K00".to_string(),
+                layer: 0,
+                old: default.map["K00"][0].clone(),
+                new: "A".to_string(),
+            }]
+        );
+        assert_eq!(
+            report2.unchanged,
+            report.changed.len() + report.unchanged - 1
+        );
+
+        keymap2.model = "system76/launch_2".to_string();
+        assert!(matches!(
+            block_on(board.apply_keymap(&keymap2)),
+            Err(KeyMapApplyError::WrongModel(_))
+        ));
+    }
+
+    #[test]
+    fn apply_keymap_rollback() {
+        let (board, keymap) = board(Some(5));
+        let before = board.export_keymap();
+
+        let err = block_on(board.apply_keymap(&board.layout().default)).unwrap_err();
+        assert!(matches!(
+            err,
+            KeyMapApplyError::Write {
+                rollback_error: None,
+                ..
+            }
+        ));
+        assert_eq!(board.export_keymap().map, before.map);
+        assert!(keymap.lock().unwrap().values().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn apply_keymap_rollback_failed() {
+        let (board, keymap) = failing_board(Some(5), true);
+
+        let err = block_on(board.apply_keymap(&board.layout().default)).unwrap_err();
+        assert!(matches!(
+            err,
+            KeyMapApplyError::Write {
+                rollback_error: Some(_),
+                ..
+            }
+        ));
+        // Resynced with the writes that weren't rolled back
+        let keymap = keymap.lock().unwrap();
+        assert_eq!(keymap.values().filter(|x| **x != 0).count(), 5);
+        for key in board.keys() {
+            for layer in 0..usize::from(board.layout().meta.num_layers) {
+                let device = keymap
+                    .get(&(layer as u8, key.electrical.0, key.electrical.1))
+                    .copied()
+                    .unwrap_or(0);
+                assert_eq!(key.get_scancode(layer).unwrap().0, device);
+            }
+        }
+    }
 
-    static RE: Lazy<Regex> = Lazy::new(|| Regex::new("Launch.* Configurable Keyboard").unwrap());
-    Ok(!RE.is_match(&stdout))
+    #[test]
+    fn undo_redo() {
+        let (board, _keymap) = board(None);
+        let scancode = |i: usize| board.keys()[i].get_scancode(0).unwrap().1;
+        let (old0, old1) = (scancode(0), scancode(1));
+
+        block_on(board.keys()[0].set_scancode(0, "A")).unwrap();
+        assert!(matches!(
+            block_on(board.keys()[0].set_scancode(0, "NOT_A_SCANCODE")),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            block_on(board.keys()[0].set_scancode(100, "A")),
+            Err(Error::InvalidArgument(_))
+        ));
+        // Not recorded, since it doesn't change anything
+        block_on(board.keys()[0].set_scancode(0, "A")).unwrap();
+        let group = board.history_group();
+        block_on(board.keys()[0].set_scancode_in(&group, 0, "B")).unwrap();
+        block_on(board.keys()[1].set_scancode_in(&group, 0, "C")).unwrap();
+        assert_eq!(
+            (scancode(0), scancode(1)),
+            ("B".to_string(), "C".to_string())
+        );
+
+        assert_eq!(block_on(board.undo()), Ok(true));
+        assert_eq!((scancode(0), scancode(1)), ("A".to_string(), old1.clone()));
+        assert_eq!(block_on(board.undo()), Ok(true));
+        assert_eq!((scancode(0), scancode(1)), (old0, old1));
+        assert_eq!(block_on(board.undo()), Ok(false));
+
+        assert_eq!(block_on(board.redo()), Ok(true));
+        assert_eq!(block_on(board.redo()), Ok(true));
+        assert_eq!(
+            (scancode(0), scancode(1)),
+            ("B".to_string(), "C".to_string())
+        );
+        assert!(!board.can_redo());
+
+        // Undoing an applied keymap reverts all of it
+        let before = board.export_keymap();
+        block_on(board.apply_keymap(&board.layout().default)).unwrap();
+        assert_eq!(block_on(board.undo()), Ok(true));
+        assert_eq!(board.export_keymap().map, before.map);
+    }
+
+    #[test]
+    fn replay() {
+        let path = env::temp_dir().join(format!("s76-recording-{}.jsonl", uuid::Uuid::new_v4()));
+        let dummy = DaemonDummy::new(vec!["system76/launch_1".to_string()]).unwrap();
+        let recorder = DaemonRecorder::create(Box::new(dummy), &path).unwrap();
+        let board = load_board(Box::new(recorder));
+        let loaded = board.export_keymap();
+        let mut keymap = board.layout().default.clone();
+        for scancodes in keymap.map.values_mut() {
+            scancodes.iter_mut().for_each(|x| *x = "A".to_string());
+        }
+        block_on(board.apply_keymap(&keymap)).unwrap();
+        block_on(board.resync()).unwrap();
+
+        // Loads as it was recorded, then resyncs to the applied keymap
+        let replay = DaemonReplay::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let board = load_board(Box::new(replay));
+        assert!(board.is_fake());
+        assert_eq!(board.export_keymap().map, loaded.map);
+        block_on(board.resync()).unwrap();
+        assert_eq!(board.export_keymap().map, keymap.map);
+    }
+
+    #[test]
+    fn stable_id() {
+        let (board, _) = board(None);
+        assert_eq!(board.serial(), None);
+        assert_eq!(board.stable_id(), Some("dummy:system76/launch_1:0"));
+    }
+
+    #[test]
+    fn resync() {
+        let (board, keymap) = board(None);
+        let key = &board.keys()[0];
+        let scancode = board.layout().scancode_from_name("A").unwrap();
+
+        // Changed by another program
+        keymap
+            .lock()
+            .unwrap()
+            .insert((1, key.electrical.0, key.electrical.1), scancode);
+        assert_ne!(key.get_scancode(1).unwrap().0, scancode);
+
+        block_on(board.resync()).unwrap();
+        assert_eq!(key.get_scancode(1).unwrap(), (scancode, "A".to_string()));
+    }
+
+    #[test]
+    fn resync_lossy_color() {
+        let colors = SharedColors::default();
+        let daemon = ColorDaemon {
+            dummy: DaemonDummy::new(vec!["system76/launch_1".to_string()]).unwrap(),
+            colors: colors.clone(),
+        };
+        let (board, mut receiver) = load_board_events(Box::new(daemon));
+        let key = &board.keys()[0];
+
+        // Not representable exactly as `Hs`, so only the first resync changes it
+        colors.lock().unwrap().insert(key.leds[0], (128, 64, 0));
+        block_on(board.resync()).unwrap();
+        assert!(key.color().is_some());
+        block_on(board.resync()).unwrap();
+
+        let mut leds_changed = 0;
+        while let Some(Some(event)) = receiver.next().now_or_never() {
+            if let Event::Board(_, BoardEvent::LedsChanged) = event {
+                leds_changed += 1;
+            }
+        }
+        assert_eq!(leds_changed, 1);
+    }
 }
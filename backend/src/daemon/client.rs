@@ -1,21 +1,36 @@
+use futures::channel::mpsc as async_mpsc;
 use std::{
     cell::RefCell,
     env,
-    io::{BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, Read, Write},
     path::PathBuf,
-    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
 };
 
-use super::{err_str, Daemon, DaemonClientTrait, DaemonCommand, DaemonResponse};
+use super::{
+    Daemon, DaemonClientTrait, DaemonCommand, DaemonMessage, DaemonNotification, DaemonResponse,
+    Hello, PROTOCOL_VERSION,
+};
+use crate::Error;
 
 pub struct DaemonClient {
-    child: Child,
-    read: RefCell<BufReader<ChildStdout>>,
-    write: RefCell<ChildStdin>,
+    child: Option<Child>,
+    responses: mpsc::Receiver<Result<DaemonResponse, Error>>,
+    notifications: RefCell<Option<async_mpsc::UnboundedReceiver<DaemonNotification>>>,
+    write: RefCell<Box<dyn Write + Send>>,
+    /// Commands supported by the daemon; `None` until the handshake succeeds
+    capabilities: Option<Vec<String>>,
+    /// Set when the daemon closes its output, usually by exiting
+    exited: Arc<AtomicBool>,
 }
 
 impl DaemonClient {
-    pub fn new_pkexec() -> Self {
+    pub fn new_pkexec() -> Result<Self, Error> {
         // Use canonicalized command name
         let command_path = if cfg!(feature = "appimage") {
             PathBuf::from(env::var("APPIMAGE").expect("Failed to get executable path"))
@@ -29,53 +44,317 @@ impl DaemonClient {
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()
-            .expect("Failed to spawn daemon");
+            .map_err(|err| Error::Io(format!("Failed to spawn daemon: {}", err)))?;
 
         let stdin = child.stdin.take().unwrap();
-        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Self::connect(Some(child), Box::new(stdout), Box::new(stdin))
+    }
+
+    /// Connect to a daemon reading commands from `write` and writing responses
+    /// to `read`, such as a `DaemonServer` in another thread
+    pub fn new<R: Read + Send + 'static, W: Write + Send + 'static>(
+        read: R,
+        write: W,
+    ) -> Result<Self, Error> {
+        Self::connect(None, Box::new(BufReader::new(read)), Box::new(write))
+    }
 
+    fn connect(
+        child: Option<Child>,
+        mut read: Box<dyn BufRead + Send>,
+        write: Box<dyn Write + Send>,
+    ) -> Result<Self, Error> {
         // Check if daemon has started
         let mut line = String::new();
-        if let Ok(count) = stdout.read_line(&mut line) {
+        if read.read_line(&mut line)? == 0 {
             // pkexec terminated returning EOF
-            if count == 0 {
-                panic!("Failed to start daemon with pkexec");
-            }
+            return Err(Error::Io("Failed to start daemon".to_string()));
         }
 
-        Self {
+        let (response_sender, responses) = mpsc::channel();
+        let (notification_sender, notifications) = async_mpsc::unbounded();
+        let exited = Arc::new(AtomicBool::new(false));
+        let exited_clone = exited.clone();
+        thread::spawn(move || {
+            read_messages(read, &response_sender, &notification_sender);
+            // Before the channels close, so `has_exited` is already set when
+            // their receivers see it
+            exited_clone.store(true, Ordering::SeqCst);
+        });
+
+        let mut client = Self {
             child,
-            read: RefCell::new(stdout),
-            write: RefCell::new(stdin),
+            responses,
+            notifications: RefCell::new(Some(notifications)),
+            write: RefCell::new(write),
+            capabilities: None,
+            exited,
+        };
+
+        // A daemon from before the handshake exits on the unknown command
+        let hello = client
+            .hello(PROTOCOL_VERSION, Hello::current().capabilities)
+            .map_err(|err| err.context("Handshake with daemon failed"))?;
+        if hello.version != PROTOCOL_VERSION {
+            return Err(Error::Unsupported(format!(
+                "Daemon uses protocol version {}, expected version {}",
+                hello.version, PROTOCOL_VERSION
+            )));
+        }
+        client.capabilities = Some(hello.capabilities);
+
+        Ok(client)
+    }
+
+    /// Whether the daemon supports the command `name`
+    pub fn has_capability(&self, name: &str) -> bool {
+        match &self.capabilities {
+            Some(capabilities) => capabilities.iter().any(|x| x == name),
+            None => false,
+        }
+    }
+
+    /// Whether the daemon exited, so every command fails
+    pub fn has_exited(&self) -> bool {
+        self.exited.load(Ordering::SeqCst)
+    }
+}
+
+/// Read lines from the daemon until EOF, sending responses and notifications
+/// to their channels
+fn read_messages(
+    mut read: Box<dyn BufRead + Send>,
+    responses: &mpsc::Sender<Result<DaemonResponse, Error>>,
+    notifications: &async_mpsc::UnboundedSender<DaemonNotification>,
+) {
+    loop {
+        let mut message_json = String::new();
+        let response = match read.read_line(&mut message_json) {
+            Ok(0) => return,
+            Ok(_) => match serde_json::from_str(&message_json) {
+                Ok(DaemonMessage::Ok(response)) => Ok(response),
+                Ok(DaemonMessage::Err(err)) => Err(err),
+                Ok(DaemonMessage::Notification(notification)) => {
+                    let _ = notifications.unbounded_send(notification);
+                    continue;
+                }
+                Err(err) => Err(Error::from(err)),
+            },
+            Err(err) => {
+                let _ = responses.send(Err(Error::from(err)));
+                return;
+            }
+        };
+        if responses.send(response).is_err() {
+            return;
         }
     }
 }
 
 impl DaemonClientTrait for DaemonClient {
-    fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, String> {
-        let mut command_json = serde_json::to_string(&command).map_err(err_str)?;
+    fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, Error> {
+        if self.capabilities.is_some() && !self.has_capability(command.name()) {
+            return Err(Error::Unsupported(format!(
+                "Daemon does not support '{}'",
+                command.name()
+            )));
+        }
+
+        let mut command_json = serde_json::to_string(&command)?;
         command_json.push('\n');
-        self.write
-            .borrow_mut()
-            .write_all(command_json.as_bytes())
-            .map_err(err_str)?;
-
-        let mut response_json = String::new();
-        self.read
-            .borrow_mut()
-            .read_line(&mut response_json)
-            .map_err(err_str)?;
-        serde_json::from_str(&response_json).map_err(err_str)?
+        let mut write = self.write.borrow_mut();
+        write.write_all(command_json.as_bytes())?;
+        write.flush()?;
+
+        match self.responses.recv() {
+            Ok(response) => response,
+            Err(_) => Err(Error::Io("Daemon exited".to_string())),
+        }
+    }
+
+    fn notification_receiver(&self) -> Option<async_mpsc::UnboundedReceiver<DaemonNotification>> {
+        self.notifications.borrow_mut().take()
     }
 }
 
 impl Drop for DaemonClient {
     fn drop(&mut self) {
         let _ = self.exit();
+        // Close the daemon's input, in case it didn't understand `exit`
+        *self.write.get_mut() = Box::new(io::sink());
 
-        let status = self.child.wait().expect("Failed to wait for daemon");
-        if !status.success() {
-            panic!("Failed to run daemon with exit status {:?}", status);
+        if let Some(mut child) = self.child.take() {
+            match child.wait() {
+                // Only an error if the daemon was known to be compatible
+                Ok(status) if !status.success() && self.capabilities.is_some() => {
+                    error!("Daemon failed with exit status {:?}", status);
+                }
+                Ok(_) => {}
+                Err(err) => error!("Failed to wait for daemon: {}", err),
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        daemon::{BoardId, DaemonServer},
+        Matrix,
+    };
+    use futures::StreamExt;
+    use std::{os::unix::net::UnixStream, thread};
+
+    /// Run a daemon in another thread, which replies to each command with
+    /// `reply`, or stops if it returns `None` or after `exit`
+    fn fake_daemon<F>(reply: F) -> (UnixStream, thread::JoinHandle<()>)
+    where
+        F: Fn(DaemonCommand) -> Option<Result<DaemonResponse, Error>> + Send + 'static,
+    {
+        fake_daemon_messages(move |command| reply(command).map(|x| vec![x.into()]))
+    }
+
+    /// Like `fake_daemon`, but `reply` returns all lines to write, which may
+    /// include notifications
+    fn fake_daemon_messages<F>(reply: F) -> (UnixStream, thread::JoinHandle<()>)
+    where
+        F: Fn(DaemonCommand) -> Option<Vec<DaemonMessage>> + Send + 'static,
+    {
+        let (client, server) = UnixStream::pair().unwrap();
+        let join_handle = thread::spawn(move || {
+            let mut write = server.try_clone().unwrap();
+            writeln!(write, "Daemon started").unwrap();
+            for line in BufReader::new(server).lines() {
+                let command = serde_json::from_str(&line.unwrap()).unwrap();
+                let exit = matches!(command, DaemonCommand::exit {});
+                match reply(command) {
+                    Some(messages) => {
+                        for message in messages {
+                            let json = serde_json::to_string(&message).unwrap();
+                            writeln!(write, "{}", json).unwrap();
+                        }
+                    }
+                    None => break,
+                }
+                if exit {
+                    break;
+                }
+            }
+        });
+        (client, join_handle)
+    }
+
+    fn connect(stream: UnixStream) -> Result<DaemonClient, Error> {
+        DaemonClient::new(stream.try_clone().unwrap(), stream)
+    }
+
+    #[test]
+    fn handshake() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server_thread = thread::spawn(move || {
+            let read = server.try_clone().unwrap();
+            DaemonServer::new_without_hardware(read, server)
+                .run()
+                .unwrap();
+        });
+
+        let client = connect(client).unwrap();
+        for name in DaemonCommand::NAMES {
+            assert!(client.has_capability(name));
+        }
+        client.refresh().unwrap();
+        client.boards().unwrap();
+        client.subscribe(Some(10)).unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        client.boards().unwrap();
+
+        drop(client);
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn message_compat() {
+        // Clients from before notifications parse a `Result`
+        let response = Err(Error::NotFound("failed to find board".to_string()));
+        let json = serde_json::to_string(&DaemonMessage::from(response)).unwrap();
+        assert!(matches!(
+            serde_json::from_str::<Result<DaemonResponse, Error>>(&json),
+            Ok(Err(Error::NotFound(_)))
+        ));
+    }
+
+    #[test]
+    fn notifications() {
+        let (stream, join_handle) = fake_daemon_messages(|command| match command {
+            DaemonCommand::hello { .. } => {
+                Some(vec![Ok(DaemonResponse::hello(Hello::current())).into()])
+            }
+            // Notifications may arrive before the response to a command
+            DaemonCommand::subscribe { .. } => Some(vec![
+                DaemonMessage::Notification(DaemonNotification::MatrixChanged(
+                    BoardId(1),
+                    Matrix::default(),
+                )),
+                Ok(DaemonResponse::subscribe(())).into(),
+            ]),
+            DaemonCommand::boards {} => Some(vec![Ok(DaemonResponse::boards(Vec::new())).into()]),
+            _ => Some(vec![Ok(DaemonResponse::exit(())).into()]),
+        });
+        let client = connect(stream).unwrap();
+        let mut notifications = client.take_notifications().unwrap();
+        assert!(client.take_notifications().is_none());
+
+        assert_eq!(client.subscribe(None), Ok(()));
+        assert_eq!(client.boards(), Ok(Vec::new()));
+        let notification = futures::executor::block_on(notifications.next());
+        assert!(matches!(
+            notification,
+            Some(DaemonNotification::MatrixChanged(_, _))
+        ));
+
+        drop(client);
+        join_handle.join().unwrap();
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let (stream, join_handle) = fake_daemon(|command| match command {
+            DaemonCommand::hello { .. } => Some(Ok(DaemonResponse::hello(Hello {
+                version: PROTOCOL_VERSION + 1,
+                capabilities: Vec::new(),
+            }))),
+            _ => Some(Ok(DaemonResponse::exit(()))),
+        });
+        assert!(matches!(connect(stream), Err(Error::Unsupported(_))));
+        join_handle.join().unwrap();
+    }
+
+    #[test]
+    fn no_handshake() {
+        // Daemon from before the handshake, which exits on unknown commands
+        let (stream, join_handle) = fake_daemon(|_| None);
+        assert!(matches!(connect(stream), Err(Error::Io(_))));
+        join_handle.join().unwrap();
+    }
+
+    #[test]
+    fn missing_capability() {
+        let (stream, join_handle) = fake_daemon(|command| match command {
+            DaemonCommand::hello { .. } => {
+                let mut hello = Hello::current();
+                hello.capabilities.retain(|x| x != "nelson");
+                Some(Ok(DaemonResponse::hello(hello)))
+            }
+            DaemonCommand::boards {} => Some(Ok(DaemonResponse::boards(Vec::new()))),
+            DaemonCommand::nelson { .. } => panic!("unsupported command sent"),
+            _ => Some(Ok(DaemonResponse::exit(()))),
+        });
+        let client = connect(stream).unwrap();
+        assert!(!client.has_capability("nelson"));
+        assert_eq!(client.boards(), Ok(Vec::new()));
+        drop(client);
+        join_handle.join().unwrap();
+    }
+}
@@ -6,7 +6,6 @@ use futures::{
     task::LocalSpawnExt,
 };
 use futures_timer::Delay;
-use once_cell::sync::Lazy;
 use std::{
     cell::{Cell, RefCell},
     cmp::PartialEq,
@@ -18,8 +17,8 @@ use std::{
     time::Duration,
 };
 
-use super::{Benchmark, BoardId, Daemon, Matrix, Nelson, NelsonKind};
-use crate::{Board, BoardEvent, Bootloaded, Event};
+use super::{Benchmark, BoardId, Daemon, DaemonNotification, Matrix, Nelson, NelsonKind};
+use crate::{bootloaded_devices, Board, BoardEvent, BootloadedDevice, Error, Event, WeakBoard};
 
 #[derive(Clone, Debug)]
 struct Item<K: Hash + Eq, V> {
@@ -47,32 +46,62 @@ impl<K: Hash + Eq, V> PartialEq for Item<K, V> {
 
 impl<K: Hash + Eq, V> Eq for Item<K, V> {}
 
+/// A single scancode write, with the value to restore if the batch fails
+#[derive(Clone, Copy, Hash, Eq, PartialEq, Debug)]
+pub(crate) struct KeyMapWrite {
+    pub layer: u8,
+    pub output: u8,
+    pub input: u8,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// Failure of `ThreadClient::keymap_apply`, after previous writes were rolled back
+#[derive(Clone, Debug)]
+pub(crate) struct KeyMapWriteFailure {
+    /// Index of the write that failed
+    pub index: usize,
+    pub error: Error,
+    /// First error encountered while rolling back, if any
+    pub rollback_error: Option<Error>,
+}
+
 #[derive(Clone, Hash, Eq, PartialEq, Debug)]
 enum SetEnum {
     KeyMap(Item<(BoardId, u8, u8, u8), u16>),
+    KeyMapApply(BoardId, Vec<KeyMapWrite>),
     Color(Item<(BoardId, u8), (u8, u8, u8)>),
+    ColorBatch(BoardId, Vec<(u8, (u8, u8, u8))>),
     Brightness(Item<(BoardId, u8), i32>),
     Mode(Item<(BoardId, u8), (u8, u8)>),
     Benchmark(BoardId),
     Nelson(BoardId, NelsonKind),
     LedSave(BoardId),
     MatrixGetRate(Item<(), Option<Duration>>),
+    ResyncRate(Item<(), Option<Duration>>),
+    Resync(BoardId),
     Refresh,
-    BootLoaderUpdate(Option<Bootloaded>),
+    BootLoaderUpdate(Vec<BootloadedDevice>),
     NoInput(BoardId, bool),
     Exit,
 }
 
 impl SetEnum {
     fn is_cancelable(&self) -> bool {
-        !matches!(self, Self::Nelson(_, _) | Self::Benchmark(_))
+        !matches!(
+            self,
+            Self::Nelson(_, _)
+                | Self::Benchmark(_)
+                | Self::KeyMapApply(_, _)
+                | Self::ColorBatch(_, _)
+        )
     }
 }
 
 #[derive(Debug)]
 struct Set {
     inner: SetEnum,
-    oneshot: oneshot::Sender<Result<Response, String>>,
+    oneshot: oneshot::Sender<Result<Response, Error>>,
 }
 
 #[derive(Debug)]
@@ -80,6 +109,7 @@ enum Response {
     Benchmark(Benchmark),
     Canceled,
     Empty,
+    KeyMapApply(Result<(), KeyMapWriteFailure>),
     Nelson(Box<Nelson>),
 }
 
@@ -95,6 +125,12 @@ impl From<()> for Response {
     }
 }
 
+impl From<Result<(), KeyMapWriteFailure>> for Response {
+    fn from(res: Result<(), KeyMapWriteFailure>) -> Self {
+        Response::KeyMapApply(res)
+    }
+}
+
 impl From<Nelson> for Response {
     fn from(nelson: Nelson) -> Self {
         Response::Nelson(Box::new(nelson))
@@ -102,7 +138,7 @@ impl From<Nelson> for Response {
 }
 
 impl Set {
-    fn reply<T: Into<Response>>(self, resp: Result<T, String>) {
+    fn reply<T: Into<Response>>(self, resp: Result<T, Error>) {
         let _ = self.oneshot.send(resp.map(|x| x.into()));
     }
 }
@@ -132,7 +168,7 @@ impl ThreadClient {
     }
 
     #[allow(clippy::await_holding_lock)]
-    async fn send(&self, set_enum: SetEnum) -> Result<Response, String> {
+    async fn send(&self, set_enum: SetEnum) -> Result<Response, Error> {
         let (sender, receiver) = oneshot::channel();
         let (receiver, cancel) = abortable(receiver);
         {
@@ -157,43 +193,25 @@ impl ThreadClient {
         }
     }
 
-    async fn send_noresp(&self, set_enum: SetEnum) -> Result<(), String> {
+    async fn send_noresp(&self, set_enum: SetEnum) -> Result<(), Error> {
         self.send(set_enum).await.and(Ok(()))
     }
 
-    pub async fn refresh(&self) -> Result<(), String> {
+    pub async fn refresh(&self) -> Result<(), Error> {
         self.send_noresp(SetEnum::Refresh).await
     }
 
-    pub async fn check_for_bootloader(&self) -> Result<(), String> {
-        use regex::bytes::Regex;
-        static HAS_USB_HUB: Lazy<Regex> =
-            Lazy::new(|| Regex::new("3384:000.*System76 USB").unwrap());
-        static ATMEGA32U4: Lazy<Regex> =
-            Lazy::new(|| Regex::new("03eb:2ff4.*atmega32u4.*bootloader").unwrap());
-        static AT90USB646: Lazy<Regex> =
-            Lazy::new(|| Regex::new("03eb:2ff9.*at90usb646.*bootloader").unwrap());
-
-        let lsusb = async_process::Command::new("lsusb")
-            .arg("--verbose")
-            .output()
-            .await
-            .map_err(|_| "Failed to run lsusb".to_string())?
-            .stdout;
-
-        let update = if AT90USB646.is_match(&lsusb) {
-            if HAS_USB_HUB.is_match(&lsusb) {
-                Some(Bootloaded::At90usb646)
-            } else {
-                Some(Bootloaded::At90usb646Lite)
-            }
-        } else if ATMEGA32U4.is_match(&lsusb) {
-            Some(Bootloaded::AtMega32u4)
-        } else {
-            None
-        };
+    pub async fn check_for_bootloader(&self) -> Result<(), Error> {
+        self.bootloader_update(bootloaded_devices()?).await
+    }
 
-        self.send_noresp(SetEnum::BootLoaderUpdate(update)).await
+    /// Set the boards in bootloader mode, emitting `BootloadedAdded` and
+    /// `BootloadedRemoved` for the changes
+    pub(crate) async fn bootloader_update(
+        &self,
+        devices: Vec<BootloadedDevice>,
+    ) -> Result<(), Error> {
+        self.send_noresp(SetEnum::BootLoaderUpdate(devices)).await
     }
 
     pub async fn keymap_set(
@@ -203,7 +221,7 @@ impl ThreadClient {
         output: u8,
         input: u8,
         value: u16,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         self.send_noresp(SetEnum::KeyMap(Item::new(
             (board, layer, output, input),
             value,
@@ -211,22 +229,46 @@ impl ThreadClient {
         .await
     }
 
+    /// Write all of `writes`, verifying each by reading it back. If a write
+    /// fails, all writes up to it are restored to their old value.
+    pub(crate) async fn keymap_apply(
+        &self,
+        board: BoardId,
+        writes: Vec<KeyMapWrite>,
+    ) -> Result<Result<(), KeyMapWriteFailure>, Error> {
+        let resp = self.send(SetEnum::KeyMapApply(board, writes)).await?;
+        match resp {
+            Response::KeyMapApply(res) => Ok(res),
+            Response::Canceled => Err(Error::Io("keymap apply canceled".to_string())),
+            _ => panic!("{}", format!("'{:?}' unexpected", resp)),
+        }
+    }
+
     pub async fn set_color(
         &self,
         board: BoardId,
         index: u8,
         color: (u8, u8, u8),
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         self.send_noresp(SetEnum::Color(Item::new((board, index), color)))
             .await
     }
 
+    /// Set the color of many LEDs at once
+    pub(crate) async fn set_color_batch(
+        &self,
+        board: BoardId,
+        colors: Vec<(u8, (u8, u8, u8))>,
+    ) -> Result<(), Error> {
+        self.send_noresp(SetEnum::ColorBatch(board, colors)).await
+    }
+
     pub async fn set_brightness(
         &self,
         board: BoardId,
         index: u8,
         brightness: i32,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         self.send_noresp(SetEnum::Brightness(Item::new((board, index), brightness)))
             .await
     }
@@ -237,17 +279,27 @@ impl ThreadClient {
         layer: u8,
         mode: u8,
         speed: u8,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         self.send_noresp(SetEnum::Mode(Item::new((board, layer), (mode, speed))))
             .await
     }
 
-    pub async fn set_matrix_get_rate(&self, rate: Option<Duration>) -> Result<(), String> {
+    pub async fn set_matrix_get_rate(&self, rate: Option<Duration>) -> Result<(), Error> {
         self.send_noresp(SetEnum::MatrixGetRate(Item::new((), rate)))
             .await
     }
 
-    pub async fn benchmark(&self, board: BoardId) -> Result<Benchmark, String> {
+    /// Set how often boards are resynced with the device; `None` to disable
+    pub async fn set_resync_rate(&self, rate: Option<Duration>) -> Result<(), Error> {
+        self.send_noresp(SetEnum::ResyncRate(Item::new((), rate)))
+            .await
+    }
+
+    pub async fn resync(&self, board: BoardId) -> Result<(), Error> {
+        self.send_noresp(SetEnum::Resync(board)).await
+    }
+
+    pub async fn benchmark(&self, board: BoardId) -> Result<Benchmark, Error> {
         let resp = self.send(SetEnum::Benchmark(board)).await?;
         if let Response::Benchmark(benchmark) = resp {
             Ok(benchmark)
@@ -256,7 +308,7 @@ impl ThreadClient {
         }
     }
 
-    pub async fn nelson(&self, board: BoardId, kind: NelsonKind) -> Result<Nelson, String> {
+    pub async fn nelson(&self, board: BoardId, kind: NelsonKind) -> Result<Nelson, Error> {
         let resp = self.send(SetEnum::Nelson(board, kind)).await?;
         if let Response::Nelson(nelson) = resp {
             Ok(*nelson)
@@ -265,11 +317,11 @@ impl ThreadClient {
         }
     }
 
-    pub async fn led_save(&self, board: BoardId) -> Result<(), String> {
+    pub async fn led_save(&self, board: BoardId) -> Result<(), Error> {
         self.send_noresp(SetEnum::LedSave(board)).await
     }
 
-    pub async fn set_no_input(&self, board: BoardId, no_input: bool) -> Result<(), String> {
+    pub async fn set_no_input(&self, board: BoardId, no_input: bool) -> Result<(), Error> {
         self.send_noresp(SetEnum::NoInput(board, no_input)).await
     }
 
@@ -293,42 +345,59 @@ impl ThreadClient {
     }
 }
 
-/* TODO
-    BootloadedAdded(Bootloaded),
-    BootloadedRemoved
-*/
-
 struct ThreadBoard {
     matrix: Arc<Mutex<Matrix>>,
     board: BoardId,
+    /// Weak, since `Board` holds a reference to the `ThreadClient`
+    handle: WeakBoard,
     event_sender: async_mpsc::UnboundedSender<Event>,
     has_matrix: bool,
 }
 
 impl ThreadBoard {
     fn new(
-        board: BoardId,
+        board: &Board,
         event_sender: async_mpsc::UnboundedSender<Event>,
-        has_matrix: bool,
         matrix: Arc<Mutex<Matrix>>,
     ) -> Self {
         Self {
             matrix,
-            board,
+            board: board.board(),
+            handle: board.downgrade(),
             event_sender,
-            has_matrix,
+            has_matrix: board.has_matrix(),
+        }
+    }
+
+    /// Update the matrix, sending `MatrixChanged` if it differs
+    fn set_matrix(&self, matrix: Matrix) {
+        let mut matrix_lock = self.matrix.lock().unwrap();
+        if *matrix_lock != matrix {
+            *matrix_lock = matrix;
+            let _ = self
+                .event_sender
+                .unbounded_send(Event::Board(self.board, BoardEvent::MatrixChanged));
         }
     }
 }
 
+fn keymap_mismatch(write: &KeyMapWrite, value: u16) -> Error {
+    Error::Protocol(format!(
+        "read back {:04X} from layer {} ({}, {}), expected {:04X}",
+        value, write.layer, write.output, write.input, write.new
+    ))
+}
+
 struct Thread {
     daemon: Box<dyn Daemon>,
     boards: RefCell<HashMap<BoardId, ThreadBoard>>,
     client: Weak<ThreadClient>,
     event_sender: async_mpsc::UnboundedSender<Event>,
     matrix_get_rate: Cell<Option<Duration>>,
-    previous_bootloaded: RefCell<Option<Bootloaded>>,
-    current_bootloaded: RefCell<Option<Bootloaded>>,
+    /// Whether the daemon pushes changes, so boards don't need to be polled
+    subscribed: Cell<bool>,
+    resync_rate: Cell<Option<Duration>>,
+    bootloaded: RefCell<Vec<BootloadedDevice>>,
 }
 
 impl Thread {
@@ -343,8 +412,9 @@ impl Thread {
             event_sender,
             boards: RefCell::new(HashMap::new()),
             matrix_get_rate: Cell::new(None),
-            previous_bootloaded: RefCell::new(None),
-            current_bootloaded: RefCell::new(None),
+            subscribed: Cell::new(false),
+            resync_rate: Cell::new(None),
+            bootloaded: RefCell::new(Vec::new()),
         }
     }
 
@@ -355,13 +425,37 @@ impl Thread {
 
             let self_ = Rc::new(self);
 
+            let self_clone = self_.clone();
+            if let Some(mut notifications) = self_.subscribe() {
+                spawner
+                    .spawn_local(async move {
+                        while let Some(notification) = notifications.next().await {
+                            self_clone.handle_notification(notification);
+                        }
+                    })
+                    .unwrap();
+            } else {
+                spawner
+                    .spawn_local(async move {
+                        loop {
+                            if let Some(rate) = self_clone.matrix_get_rate.get() {
+                                Delay::new(rate).await;
+                                self_clone.matrix_refresh_all();
+                            } else {
+                                Delay::new(Duration::from_millis(100)).await;
+                            }
+                        }
+                    })
+                    .unwrap();
+            }
+
             let self_clone = self_.clone();
             spawner
                 .spawn_local(async move {
                     loop {
-                        if let Some(rate) = self_clone.matrix_get_rate.get() {
+                        if let Some(rate) = self_clone.resync_rate.get() {
                             Delay::new(rate).await;
-                            self_clone.matrix_refresh_all();
+                            self_clone.resync_all();
                         } else {
                             Delay::new(Duration::from_millis(100)).await;
                         }
@@ -388,9 +482,17 @@ impl Thread {
             SetEnum::KeyMap(Item { key, value }) => {
                 set.reply(self.daemon.keymap_set(key.0, key.1, key.2, key.3, value))
             }
+            SetEnum::KeyMapApply(board, ref writes) => {
+                let res = self.keymap_apply(board, writes);
+                set.reply(Ok(res))
+            }
             SetEnum::Color(Item { key, value }) => {
                 set.reply(self.daemon.set_color(key.0, key.1, value))
             }
+            SetEnum::ColorBatch(board, ref colors) => {
+                let res = self.set_color_batch(board, colors);
+                set.reply(res)
+            }
             SetEnum::Brightness(Item { key, value }) => {
                 set.reply(self.daemon.set_brightness(key.0, key.1, value))
             }
@@ -402,10 +504,23 @@ impl Thread {
             SetEnum::LedSave(board) => set.reply(self.daemon.led_save(board)),
             SetEnum::MatrixGetRate(Item { value, .. }) => {
                 self.matrix_get_rate.set(value);
+                if self.subscribed.get() {
+                    let interval_ms = value.map(|rate| rate.as_millis() as u64);
+                    set.reply(self.daemon.subscribe(interval_ms))
+                } else {
+                    set.reply(Ok(()))
+                }
+            }
+            SetEnum::ResyncRate(Item { value, .. }) => {
+                self.resync_rate.set(value);
                 set.reply(Ok(()))
             }
+            SetEnum::Resync(board) => set.reply(self.resync(board)),
             SetEnum::Refresh => set.reply(self.refresh()),
-            SetEnum::BootLoaderUpdate(update) => set.reply(self.bootloader_update(update)),
+            SetEnum::BootLoaderUpdate(ref devices) => {
+                let res = self.bootloader_update(devices);
+                set.reply(res)
+            }
             SetEnum::NoInput(board, no_input) => {
                 set.reply(self.daemon.set_no_input(board, no_input))
             }
@@ -415,52 +530,247 @@ impl Thread {
         true
     }
 
+    fn set_color_batch(&self, board: BoardId, colors: &[(u8, (u8, u8, u8))]) -> Result<(), Error> {
+        match self.daemon.set_color_batch(board, colors.to_vec()) {
+            Err(Error::Unsupported(_)) => {
+                for (index, color) in colors {
+                    self.daemon.set_color(board, *index, *color)?;
+                }
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    /// Write `writes` with one batch command and verify them with another,
+    /// falling back to one write at a time if the daemon doesn't support that
+    fn keymap_apply(
+        &self,
+        board: BoardId,
+        writes: &[KeyMapWrite],
+    ) -> Result<(), KeyMapWriteFailure> {
+        let new = writes
+            .iter()
+            .map(|write| (write.layer, write.output, write.input, write.new))
+            .collect();
+        match self.daemon.keymap_set_batch(board, new) {
+            Ok(()) => {}
+            Err(Error::Unsupported(_)) => return self.keymap_apply_each(board, writes),
+            Err(error) => {
+                // The batch stops at the failed write, the first that doesn't
+                // read back
+                let index = match self.keymap_verify(board, writes) {
+                    Ok(Some((index, _))) => index,
+                    _ => writes.len() - 1,
+                };
+                return Err(KeyMapWriteFailure {
+                    index,
+                    error,
+                    rollback_error: self.keymap_rollback(board, &writes[..=index]),
+                });
+            }
+        }
+
+        match self.keymap_verify(board, writes) {
+            Ok(None) => Ok(()),
+            // Everything was written, so restore all of it
+            Ok(Some((index, error))) => Err(KeyMapWriteFailure {
+                index,
+                error,
+                rollback_error: self.keymap_rollback(board, writes),
+            }),
+            // Writing again is harmless, so verify one write at a time
+            Err(err) => {
+                debug!("Verifying keymap per key: {}", err);
+                self.keymap_apply_each(board, writes)
+            }
+        }
+    }
+
+    /// Read back `writes` in one batch, returning the index of the first that
+    /// doesn't match
+    fn keymap_verify(
+        &self,
+        board: BoardId,
+        writes: &[KeyMapWrite],
+    ) -> Result<Option<(usize, Error)>, Error> {
+        let keys = writes
+            .iter()
+            .map(|write| (write.layer, write.output, write.input))
+            .collect();
+        let values = self.daemon.keymap_get_batch(board, keys)?;
+        if values.len() != writes.len() {
+            return Err(Error::Protocol(format!(
+                "read back {} scancodes, expected {}",
+                values.len(),
+                writes.len()
+            )));
+        }
+        Ok(writes
+            .iter()
+            .zip(values)
+            .enumerate()
+            .find(|(_, (write, value))| *value != write.new)
+            .map(|(index, (write, value))| (index, keymap_mismatch(write, value))))
+    }
+
+    /// Restore the old value of `writes` in reverse order, returning the first
+    /// error
+    fn keymap_rollback(&self, board: BoardId, writes: &[KeyMapWrite]) -> Option<Error> {
+        let old = writes
+            .iter()
+            .rev()
+            .map(|write| (write.layer, write.output, write.input, write.old))
+            .collect();
+        if self.daemon.keymap_set_batch(board, old).is_ok() {
+            return None;
+        }
+        let mut rollback_error = None;
+        for write in writes.iter().rev() {
+            if let Err(err) =
+                self.daemon
+                    .keymap_set(board, write.layer, write.output, write.input, write.old)
+            {
+                rollback_error.get_or_insert(err);
+            }
+        }
+        rollback_error
+    }
+
+    /// Write and verify `writes` one at a time, restoring all writes up to a
+    /// failed one
+    fn keymap_apply_each(
+        &self,
+        board: BoardId,
+        writes: &[KeyMapWrite],
+    ) -> Result<(), KeyMapWriteFailure> {
+        for (index, write) in writes.iter().enumerate() {
+            let res = self
+                .daemon
+                .keymap_set(board, write.layer, write.output, write.input, write.new)
+                .and_then(|()| {
+                    self.daemon
+                        .keymap_get(board, write.layer, write.output, write.input)
+                })
+                .and_then(|value| {
+                    if value == write.new {
+                        Ok(())
+                    } else {
+                        Err(keymap_mismatch(write, value))
+                    }
+                });
+
+            if let Err(error) = res {
+                // Restore in reverse order, including the failed write
+                return Err(KeyMapWriteFailure {
+                    index,
+                    error,
+                    rollback_error: self.keymap_rollback(board, &writes[..=index]),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the daemon to push changes, returning the receiver for them if
+    /// it supports that
+    fn subscribe(&self) -> Option<async_mpsc::UnboundedReceiver<DaemonNotification>> {
+        let notifications = self.daemon.take_notifications()?;
+        match self.daemon.subscribe(None) {
+            Ok(()) => {
+                self.subscribed.set(true);
+                Some(notifications)
+            }
+            Err(err) => {
+                info!("Polling boards, since daemon can't push changes: {}", err);
+                None
+            }
+        }
+    }
+
+    fn handle_notification(&self, notification: DaemonNotification) {
+        match notification {
+            DaemonNotification::BoardAdded(_) | DaemonNotification::BoardRemoved(_) => {
+                if let Err(err) = self.refresh() {
+                    error!("failed to refresh boards: {}", err);
+                }
+            }
+            DaemonNotification::MatrixChanged(id, matrix) => {
+                if let Some(board) = self.boards.borrow().get(&id) {
+                    board.set_matrix(matrix);
+                }
+            }
+            DaemonNotification::LedsChanged(id) => {
+                if let Err(err) = self.resync(id) {
+                    error!("failed to resync board: {}", err);
+                }
+            }
+            DaemonNotification::Exited => self.reload(),
+        }
+    }
+
     fn matrix_refresh_all(&self) {
-        for (k, v) in self.boards.borrow_mut().iter_mut() {
+        for (k, v) in self.boards.borrow().iter() {
             if !v.has_matrix {
                 continue;
             }
-            let matrix = match self.daemon.matrix_get(*k) {
-                Ok(ok) => ok,
-                Err(err) => {
-                    error!("failed to get matrix: {}", err);
-                    continue;
-                }
-            };
-            let mut matrix_lock = v.matrix.lock().unwrap();
-            if *matrix_lock != matrix {
-                *matrix_lock = matrix;
-                let _ = v
-                    .event_sender
-                    .unbounded_send(Event::Board(v.board, BoardEvent::MatrixChanged));
+            match self.daemon.matrix_get(*k) {
+                Ok(matrix) => v.set_matrix(matrix),
+                Err(err) => error!("failed to get matrix: {}", err),
             }
         }
     }
 
-    fn bootloader_update(&self, update: Option<Bootloaded>) -> Result<(), String> {
-        *self.previous_bootloaded.borrow_mut() = *self.current_bootloaded.borrow();
-        *self.current_bootloaded.borrow_mut() = update;
+    fn resync(&self, id: BoardId) -> Result<(), Error> {
+        let board = self
+            .boards
+            .borrow()
+            .get(&id)
+            .and_then(|board| board.handle.upgrade())
+            .ok_or_else(|| Error::NotFound(format!("Board {:?} not found", id)))?;
+        board.resync_from(self.daemon.as_ref())
+    }
 
-        // If a new board is plugged in and is in bootloader mode, update the gui
-        // only check if we are in launch test mode for production.
-        match (
-            *self.previous_bootloaded.borrow(),
-            *self.current_bootloaded.borrow(),
-        ) {
-            (None, Some(board)) => {
+    /// Remove all boards, then add them again from the relaunched daemon
+    fn reload(&self) {
+        info!("Daemon exited, reloading boards");
+        self.boards.borrow_mut().clear();
+        let _ = self.event_sender.unbounded_send(Event::DaemonExited);
+        if let Err(err) = self.refresh() {
+            error!("failed to reload boards: {}", err);
+        }
+    }
+
+    fn resync_all(&self) {
+        let ids = self.boards.borrow().keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            if let Err(err) = self.resync(id) {
+                error!("failed to resync board: {}", err);
+            }
+        }
+    }
+
+    fn bootloader_update(&self, devices: &[BootloadedDevice]) -> Result<(), Error> {
+        let mut bootloaded = self.bootloaded.borrow_mut();
+        for device in bootloaded.iter() {
+            if !devices.contains(device) {
                 let _ = self
                     .event_sender
-                    .unbounded_send(Event::BootloadedAdded(board));
+                    .unbounded_send(Event::BootloadedRemoved(device.clone()));
             }
-            (Some(_), None) => {
-                let _ = self.event_sender.unbounded_send(Event::BootloadedRemoved);
+        }
+        for device in devices {
+            if !bootloaded.contains(device) {
+                let _ = self
+                    .event_sender
+                    .unbounded_send(Event::BootloadedAdded(device.clone()));
             }
-            _ => {}
         }
+        *bootloaded = devices.to_vec();
         Ok(())
     }
 
-    fn refresh(&self) -> Result<(), String> {
+    fn refresh(&self) -> Result<(), Error> {
         self.daemon.refresh()?;
 
         let mut boards = self.boards.borrow_mut();
@@ -498,10 +808,7 @@ impl Thread {
                 self.event_sender.clone(),
             ) {
                 Ok(board) => {
-                    boards.insert(
-                        *i,
-                        ThreadBoard::new(*i, event_sender.clone(), board.has_matrix(), matrix),
-                    );
+                    boards.insert(*i, ThreadBoard::new(&board, event_sender.clone(), matrix));
                     let _ = self.event_sender.unbounded_send(Event::BoardAdded(board));
                 }
                 Err(err) => error!("Failed to add board: {}", err),
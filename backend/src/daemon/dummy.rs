@@ -1,7 +1,7 @@
 use std::{cell::RefCell, collections::HashMap};
 
-use super::{BoardId, Daemon};
-use crate::{fl, Benchmark, Layout, Matrix, Nelson, NelsonKind};
+use super::{BoardId, Daemon, Hello};
+use crate::{fl, Benchmark, Error, Layout, Matrix, Nelson, NelsonKind};
 
 struct BoardDummy {
     name: String,
@@ -29,7 +29,7 @@ pub struct DaemonDummy {
 }
 
 impl DaemonDummy {
-    pub fn new(board_names: Vec<String>) -> Result<Self, String> {
+    pub fn new(board_names: Vec<String>) -> Result<Self, Error> {
         let mut boards = Vec::with_capacity(board_names.len());
         for name in board_names {
             if let Some(layout) = Layout::from_board(&name, "dummy") {
@@ -42,39 +42,59 @@ impl DaemonDummy {
                     modes: Default::default(),
                 })
             } else {
-                return Err(format!(
+                return Err(Error::NotFound(format!(
                     "'{name}' is an invalid board name. Might need a prefix, 'system76/{name}'?"
-                ));
+                )));
             }
         }
         Ok(Self { boards })
     }
 
-    fn board(&self, board: BoardId) -> Result<&BoardDummy, String> {
+    fn board(&self, board: BoardId) -> Result<&BoardDummy, Error> {
         self.boards
             .get(board.0 as usize)
-            .ok_or_else(|| fl!("no-board"))
+            .ok_or_else(|| Error::NotFound(fl!("no-board")))
     }
 }
 
 impl Daemon for DaemonDummy {
-    fn boards(&self) -> Result<Vec<BoardId>, String> {
+    fn hello(&self, _version: u32, _capabilities: Vec<String>) -> Result<Hello, Error> {
+        Ok(Hello::current())
+    }
+
+    fn boards(&self) -> Result<Vec<BoardId>, Error> {
         Ok((0..self.boards.len() as u128).map(BoardId).collect())
     }
 
-    fn model(&self, board: BoardId) -> Result<String, String> {
+    fn model(&self, board: BoardId) -> Result<String, Error> {
         Ok(self.board(board)?.name.clone())
     }
 
-    fn version(&self, _board: BoardId) -> Result<String, String> {
+    fn version(&self, _board: BoardId) -> Result<String, Error> {
         Ok("1970-01-01-deadbee".to_string())
     }
 
+    fn serial(&self, board: BoardId) -> Result<Option<String>, Error> {
+        self.board(board)?;
+        Ok(None)
+    }
+
+    fn stable_id(&self, board: BoardId) -> Result<String, Error> {
+        // Numbered among boards of the same model, so it doesn't depend on
+        // the order they're listed in
+        let name = &self.board(board)?.name;
+        let index = self.boards[..board.0 as usize]
+            .iter()
+            .filter(|x| x.name == *name)
+            .count();
+        Ok(format!("dummy:{}:{}", name, index))
+    }
+
     fn is_fake(&self) -> bool {
         true
     }
 
-    fn keymap_get(&self, board: BoardId, layer: u8, output: u8, input: u8) -> Result<u16, String> {
+    fn keymap_get(&self, board: BoardId, layer: u8, output: u8, input: u8) -> Result<u16, Error> {
         let keymap = self.board(board)?.keymap.borrow();
         Ok(keymap.get(&(layer, output, input)).copied().unwrap_or(0))
     }
@@ -86,95 +106,148 @@ impl Daemon for DaemonDummy {
         output: u8,
         input: u8,
         value: u16,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         let mut keymap = self.board(board)?.keymap.borrow_mut();
         keymap.insert((layer, output, input), value);
         Ok(())
     }
 
-    fn matrix_get(&self, _board: BoardId) -> Result<Matrix, String> {
+    fn matrix_get(&self, _board: BoardId) -> Result<Matrix, Error> {
         Ok(Matrix::new(0, 0, Vec::new().into_boxed_slice()))
     }
 
-    fn benchmark(&self, _board: BoardId) -> Result<Benchmark, String> {
-        Err("Unimplemented".to_string())
+    fn benchmark(&self, _board: BoardId) -> Result<Benchmark, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn nelson(&self, _board: BoardId, _kind: NelsonKind) -> Result<Nelson, String> {
-        Err("Unimplemented".to_string())
+    fn nelson(&self, _board: BoardId, _kind: NelsonKind) -> Result<Nelson, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn color(&self, board: BoardId, index: u8) -> Result<(u8, u8, u8), String> {
+    fn color(&self, board: BoardId, index: u8) -> Result<(u8, u8, u8), Error> {
         let board = self.board(board)?;
         if !board.valid_index(index, true) {
-            return Err(format!("Can't get color index {} {}", index, board.name));
+            return Err(Error::InvalidArgument(format!(
+                "Can't get color index {} {}",
+                index, board.name
+            )));
         }
         Ok(*board.colors.borrow_mut().entry(index).or_default())
     }
 
-    fn set_color(&self, board: BoardId, index: u8, color: (u8, u8, u8)) -> Result<(), String> {
+    fn set_color(&self, board: BoardId, index: u8, color: (u8, u8, u8)) -> Result<(), Error> {
         let board = self.board(board)?;
         if !board.valid_index(index, true) {
-            return Err(format!("Can't set color index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't set color index {}",
+                index
+            )));
         }
         board.colors.borrow_mut().insert(index, color);
         Ok(())
     }
 
-    fn max_brightness(&self, _board: BoardId) -> Result<i32, String> {
+    fn keymap_get_batch(&self, board: BoardId, keys: Vec<(u8, u8, u8)>) -> Result<Vec<u16>, Error> {
+        keys.iter()
+            .map(|&(layer, output, input)| self.keymap_get(board, layer, output, input))
+            .collect()
+    }
+
+    fn keymap_set_batch(&self, board: BoardId, keys: Vec<(u8, u8, u8, u16)>) -> Result<(), Error> {
+        for (layer, output, input, value) in keys {
+            self.keymap_set(board, layer, output, input, value)?;
+        }
+        Ok(())
+    }
+
+    fn color_batch(&self, board: BoardId, indices: Vec<u8>) -> Result<Vec<(u8, u8, u8)>, Error> {
+        indices
+            .iter()
+            .map(|&index| self.color(board, index))
+            .collect()
+    }
+
+    fn set_color_batch(
+        &self,
+        board: BoardId,
+        colors: Vec<(u8, (u8, u8, u8))>,
+    ) -> Result<(), Error> {
+        for (index, color) in colors {
+            self.set_color(board, index, color)?;
+        }
+        Ok(())
+    }
+
+    fn max_brightness(&self, _board: BoardId) -> Result<i32, Error> {
         Ok(100)
     }
 
-    fn brightness(&self, board: BoardId, index: u8) -> Result<i32, String> {
+    fn brightness(&self, board: BoardId, index: u8) -> Result<i32, Error> {
         let board = self.board(board)?;
         if !board.valid_index(index, false) {
-            return Err(format!("Can't get brightness index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't get brightness index {}",
+                index
+            )));
         }
         Ok(*board.brightnesses.borrow_mut().entry(index).or_default())
     }
 
-    fn set_brightness(&self, board: BoardId, index: u8, brightness: i32) -> Result<(), String> {
+    fn set_brightness(&self, board: BoardId, index: u8, brightness: i32) -> Result<(), Error> {
         let board = self.board(board)?;
         if !board.valid_index(index, false) {
-            return Err(format!("Can't set brightness index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't set brightness index {}",
+                index
+            )));
         }
         board.brightnesses.borrow_mut().insert(index, brightness);
         Ok(())
     }
 
-    fn mode(&self, board: BoardId, layer: u8) -> Result<(u8, u8), String> {
+    fn mode(&self, board: BoardId, layer: u8) -> Result<(u8, u8), Error> {
         let index = layer + 0xf0;
         let board = self.board(board)?;
         if !board.valid_index(index, false) {
-            return Err(format!("Can't get mode index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't get mode index {}",
+                index
+            )));
         }
         Ok(*board.modes.borrow_mut().entry(index).or_default())
     }
 
-    fn set_mode(&self, board: BoardId, layer: u8, mode: u8, speed: u8) -> Result<(), String> {
+    fn set_mode(&self, board: BoardId, layer: u8, mode: u8, speed: u8) -> Result<(), Error> {
         let index = layer + 0xf0;
         let board = self.board(board)?;
         if !board.valid_index(index, false) {
-            return Err(format!("Can't get mode index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't get mode index {}",
+                index
+            )));
         }
         board.modes.borrow_mut().insert(index, (mode, speed));
         Ok(())
     }
 
-    fn led_save(&self, board: BoardId) -> Result<(), String> {
+    fn led_save(&self, board: BoardId) -> Result<(), Error> {
         self.board(board)?;
         Ok(())
     }
 
-    fn refresh(&self) -> Result<(), String> {
+    fn refresh(&self) -> Result<(), Error> {
         Ok(())
     }
 
-    fn set_no_input(&self, _board: BoardId, _no_input: bool) -> Result<(), String> {
+    fn set_no_input(&self, _board: BoardId, _no_input: bool) -> Result<(), Error> {
         Ok(())
     }
 
-    fn exit(&self) -> Result<(), String> {
+    fn subscribe(&self, _matrix_interval_ms: Option<u64>) -> Result<(), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
+    }
+
+    fn exit(&self) -> Result<(), Error> {
         Ok(())
     }
 }
@@ -0,0 +1,418 @@
+// Hotplug monitoring with kernel uevents, so added and removed boards and
+// bootloaders are found without polling
+
+use futures::executor::block_on;
+use std::{
+    collections::HashMap,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    sync::{mpsc, Weak},
+    thread,
+};
+
+use super::{server::ClientMessage, ThreadClient};
+use crate::{
+    usb::{bootloaded_devices_in, is_bootloader},
+    BootloadedDevice, Error,
+};
+
+const SYSTEM76_VID: u16 = 0x3384;
+
+/// Source of raw kernel uevent messages
+pub trait UeventSource: Send + 'static {
+    /// Wait for the next message; `None` once there are no more
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Fake source, for messages sent through a channel
+impl UeventSource for mpsc::Receiver<Vec<u8>> {
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(mpsc::Receiver::recv(self).ok())
+    }
+}
+
+/// Uevents broadcast by the kernel on a netlink socket
+pub struct NetlinkUevents(OwnedFd);
+
+impl NetlinkUevents {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // Group of uevents sent by the kernel, rather than by udev
+        addr.nl_groups = 1;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self(fd))
+    }
+}
+
+impl UeventSource for NetlinkUevents {
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let mut buf = vec![0; 8192];
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            let mut addr_len = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+            let len = unsafe {
+                libc::recvfrom(
+                    self.0.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                    &mut addr as *mut libc::sockaddr_nl as *mut libc::sockaddr,
+                    &mut addr_len,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            // Ignore messages not sent by the kernel
+            if addr.nl_pid != 0 {
+                continue;
+            }
+            buf.truncate(len as usize);
+            return Ok(Some(buf));
+        }
+    }
+}
+
+/// A uevent, such as `add@/devices/...` followed by `KEY=value` lines
+#[derive(Debug)]
+struct Uevent {
+    action: String,
+    devpath: String,
+    vars: HashMap<String, String>,
+}
+
+impl Uevent {
+    fn parse(message: &[u8]) -> Option<Self> {
+        let mut lines = message
+            .split(|b| *b == 0)
+            .filter_map(|line| std::str::from_utf8(line).ok());
+        // Skip the `action@devpath` header
+        lines.next()?.split_once('@')?;
+
+        let vars = lines
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        Some(Self {
+            action: vars.get("ACTION")?.clone(),
+            devpath: vars.get("DEVPATH")?.clone(),
+            vars,
+        })
+    }
+
+    fn var(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(|x| x.as_str())
+    }
+
+    /// Name of the device, such as `1-2` for a USB device
+    fn name(&self) -> &str {
+        self.devpath.rsplit('/').next().unwrap_or_default()
+    }
+
+    /// Vendor and product ID of a USB device, from `PRODUCT=vid/pid/bcd`
+    fn usb_device(&self) -> Option<(u16, u16)> {
+        if self.var("SUBSYSTEM")? != "usb" || self.var("DEVTYPE")? != "usb_device" {
+            return None;
+        }
+        let mut product = self.var("PRODUCT")?.split('/');
+        let vid = u16::from_str_radix(product.next()?, 16).ok()?;
+        let pid = u16::from_str_radix(product.next()?, 16).ok()?;
+        Some((vid, pid))
+    }
+
+    /// Vendor and product ID of a hidraw device, from the name of its parent
+    /// HID device, such as `0003:3384:0001.0005`
+    fn hidraw_device(&self) -> Option<(u16, u16)> {
+        if self.var("SUBSYSTEM")? != "hidraw" {
+            return None;
+        }
+        self.devpath.split('/').find_map(|component| {
+            let (ids, _) = component.split_once('.')?;
+            match ids.split(':').collect::<Vec<_>>()[..] {
+                [_bus, vid, pid] if vid.len() == 4 && pid.len() == 4 => Some((
+                    u16::from_str_radix(vid, 16).ok()?,
+                    u16::from_str_radix(pid, 16).ok()?,
+                )),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Change found in a uevent
+#[derive(Debug, PartialEq)]
+enum HotplugChange {
+    /// A System76 HID device was added or removed, so boards need a refresh
+    Boards,
+    /// A board entered or left bootloader mode
+    Bootloaded,
+}
+
+impl HotplugChange {
+    fn from_uevent(uevent: &Uevent) -> Option<Self> {
+        if !matches!(uevent.action.as_str(), "add" | "remove") {
+            return None;
+        }
+        if let Some((SYSTEM76_VID, _)) = uevent.hidraw_device() {
+            return Some(Self::Boards);
+        }
+        match uevent.usb_device() {
+            Some((vid, pid)) if is_bootloader(vid, pid) => Some(Self::Bootloaded),
+            _ => None,
+        }
+    }
+}
+
+/// Boards in bootloader mode after `uevent`
+fn bootloaded_after(sysfs: &Path, uevent: &Uevent) -> Result<Vec<BootloadedDevice>, Error> {
+    let mut devices = bootloaded_devices_in(sysfs)?;
+    // The device may still be in sysfs while its removal is announced
+    if uevent.action == "remove" {
+        devices.retain(|device| device.bus_path != uevent.name());
+    }
+    Ok(devices)
+}
+
+/// Watch for uevents from `source` in a new thread, refreshing boards and
+/// updating the bootloaded boards of `client`, which emits the `Event`s.
+/// `sysfs` is normally `/sys`. The thread stops at the first uevent after
+/// `client` is dropped.
+pub(crate) fn watch_hotplug<S: UeventSource>(
+    mut source: S,
+    sysfs: PathBuf,
+    client: Weak<ThreadClient>,
+) {
+    thread::spawn(move || {
+        // Boards already in bootloader mode
+        match (client.upgrade(), bootloaded_devices_in(&sysfs)) {
+            (Some(client), Ok(devices)) => {
+                let _ = block_on(client.bootloader_update(devices));
+            }
+            (_, Err(err)) => error!("{}", err),
+            (None, _) => return,
+        }
+
+        loop {
+            let message = match source.recv() {
+                Ok(Some(message)) => message,
+                Ok(None) => return,
+                Err(err) => {
+                    error!("Failed to receive uevent: {}", err);
+                    return;
+                }
+            };
+            let uevent = match Uevent::parse(&message) {
+                Some(uevent) => uevent,
+                None => continue,
+            };
+            let change = match HotplugChange::from_uevent(&uevent) {
+                Some(change) => change,
+                None => continue,
+            };
+            let client = match client.upgrade() {
+                Some(client) => client,
+                None => return,
+            };
+            let res = match change {
+                HotplugChange::Boards => block_on(client.refresh()),
+                HotplugChange::Bootloaded => bootloaded_after(&sysfs, &uevent)
+                    .and_then(|devices| block_on(client.bootloader_update(devices))),
+            };
+            if let Err(err) = res {
+                error!("Failed to handle hotplug: {}", err);
+            }
+        }
+    });
+}
+
+/// Watch for uevents from `source` in a new thread, sending `Hotplug` to the
+/// main loop of the daemon when boards are added or removed. The thread stops
+/// once `sender` is closed.
+pub(super) fn watch_board_hotplug<S: UeventSource>(
+    mut source: S,
+    sender: mpsc::Sender<ClientMessage>,
+) {
+    thread::spawn(move || loop {
+        let message = match source.recv() {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Failed to receive uevent: {}", err);
+                return;
+            }
+        };
+        let change = Uevent::parse(&message).and_then(|x| HotplugChange::from_uevent(&x));
+        if change == Some(HotplugChange::Boards) && sender.send(ClientMessage::Hotplug).is_err() {
+            return;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{daemon::DaemonDummy, usb::TestSysfs, Bootloaded, Event};
+    use futures::{channel::mpsc as async_mpsc, StreamExt};
+    use std::{env, fs};
+
+    fn uevent(action: &str, devpath: &str, vars: &[&str]) -> Vec<u8> {
+        let mut message = format!(
+            "{}@{}\0ACTION={}\0DEVPATH={}\0",
+            action, devpath, action, devpath
+        );
+        for var in vars {
+            message.push_str(var);
+            message.push('\0');
+        }
+        message.into_bytes()
+    }
+
+    fn hidraw(action: &str, vid: &str) -> Vec<u8> {
+        let devpath = format!(
+            "/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.1/0003:{}:0001.0005/hidraw/hidraw3",
+            vid
+        );
+        uevent(action, &devpath, &["SUBSYSTEM=hidraw"])
+    }
+
+    fn bootloader(action: &str, name: &str, pid: &str) -> Vec<u8> {
+        let devpath = format!("/devices/pci0000:00/0000:00:14.0/usb1/{}", name);
+        let product = format!("PRODUCT=3eb/{}/0", pid);
+        uevent(
+            action,
+            &devpath,
+            &["SUBSYSTEM=usb", "DEVTYPE=usb_device", &product],
+        )
+    }
+
+    fn change(message: &[u8]) -> Option<HotplugChange> {
+        HotplugChange::from_uevent(&Uevent::parse(message).unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        let uevent = Uevent::parse(&hidraw("add", "3384")).unwrap();
+        assert_eq!(uevent.action, "add");
+        assert_eq!(uevent.name(), "hidraw3");
+        assert_eq!(uevent.hidraw_device(), Some((0x3384, 0x0001)));
+        assert_eq!(uevent.usb_device(), None);
+
+        let uevent = Uevent::parse(&bootloader("remove", "1-4", "2ff9")).unwrap();
+        assert_eq!(uevent.name(), "1-4");
+        assert_eq!(uevent.usb_device(), Some((0x03eb, 0x2ff9)));
+
+        assert!(Uevent::parse(b"libudev\0\xfe\xed").is_none());
+    }
+
+    #[test]
+    fn changes() {
+        assert_eq!(change(&hidraw("add", "3384")), Some(HotplugChange::Boards));
+        assert_eq!(
+            change(&hidraw("remove", "3384")),
+            Some(HotplugChange::Boards)
+        );
+        assert_eq!(change(&hidraw("add", "046D")), None);
+        assert_eq!(
+            change(&bootloader("add", "1-4", "2ff9")),
+            Some(HotplugChange::Bootloaded)
+        );
+        assert_eq!(change(&bootloader("change", "1-4", "2ff9")), None);
+        assert_eq!(change(&bootloader("add", "1-4", "2ff3")), None);
+
+        // Still listed in sysfs while removed
+        let sysfs = TestSysfs::new();
+        let uevent = Uevent::parse(&bootloader("remove", "1-4", "2ff9")).unwrap();
+        let devices = bootloaded_after(sysfs.path(), &uevent).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert!(devices.iter().all(|device| device.bus_path != "1-4"));
+    }
+
+    #[test]
+    fn daemon_events() {
+        let (sender, receiver) = mpsc::channel();
+        let (messages, server) = mpsc::channel();
+        watch_board_hotplug(receiver, messages);
+
+        sender.send(bootloader("add", "1-4", "2ff4")).unwrap();
+        sender.send(hidraw("add", "046D")).unwrap();
+        sender.send(hidraw("add", "3384")).unwrap();
+        sender.send(hidraw("remove", "3384")).unwrap();
+        drop(sender);
+
+        // Only for System76 boards, which the daemon opens
+        let messages = server.iter().collect::<Vec<_>>();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|x| matches!(x, ClientMessage::Hotplug)));
+    }
+
+    #[test]
+    fn events() {
+        let daemon = DaemonDummy::new(vec!["system76/launch_1".to_string()]).unwrap();
+        let (event_sender, mut events) = async_mpsc::unbounded();
+        let client = ThreadClient::new(Box::new(daemon), event_sender);
+
+        let sysfs = env::temp_dir().join(format!("s76-sysfs-{}", uuid::Uuid::new_v4()));
+        let device_dir = sysfs.join("bus/usb/devices/1-4");
+        fs::create_dir_all(&device_dir).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        watch_hotplug(receiver, sysfs.clone(), std::sync::Arc::downgrade(&client));
+
+        sender.send(hidraw("add", "3384")).unwrap();
+        fs::write(device_dir.join("idVendor"), "03eb\n").unwrap();
+        fs::write(device_dir.join("idProduct"), "2ff4\n").unwrap();
+        sender.send(bootloader("add", "1-4", "2ff4")).unwrap();
+        sender.send(bootloader("remove", "1-4", "2ff4")).unwrap();
+
+        let mut board_added = false;
+        let mut bootloaded_added = false;
+        while let Some(event) = block_on(events.next()) {
+            match event {
+                Event::BoardAdded(_) => board_added = true,
+                Event::BootloadedAdded(device) => {
+                    assert_eq!(device.kind, Bootloaded::AtMega32u4);
+                    assert_eq!(device.bus_path, "1-4");
+                    bootloaded_added = true;
+                }
+                Event::BootloadedRemoved(device) => {
+                    assert_eq!(device.bus_path, "1-4");
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert!(board_added && bootloaded_added);
+
+        drop(sender);
+        client.close();
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+}
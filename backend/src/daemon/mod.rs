@@ -1,39 +1,147 @@
+use futures::channel::mpsc as async_mpsc;
 use serde::{Deserialize, Serialize};
 
-use crate::{Benchmark, Matrix, Nelson, NelsonKind};
+use crate::{Benchmark, Error, Matrix, Nelson, NelsonKind};
 
 mod client;
 mod daemon_thread;
 mod dummy;
+mod record;
 mod server;
+mod supervisor;
+#[cfg(test)]
+mod test_output;
 
+#[cfg(target_os = "linux")]
+mod hotplug;
+#[cfg(target_os = "linux")]
+pub(crate) use self::hotplug::{watch_hotplug, NetlinkUevents};
 #[cfg(target_os = "linux")]
 mod s76power;
 #[cfg(target_os = "linux")]
 pub use self::s76power::*;
+#[cfg(target_os = "linux")]
+mod socket;
+#[cfg(target_os = "linux")]
+pub use self::socket::{bind_daemon_socket, daemon_socket_path};
 
-pub use self::{client::*, daemon_thread::*, dummy::*, server::*};
+pub use self::{client::*, daemon_thread::*, dummy::*, record::*, server::*, supervisor::*};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
 pub struct BoardId(u128);
 
+/// Version of the daemon protocol, compared in the `hello` handshake. Only
+/// changed for incompatible changes to existing commands; new commands are
+/// advertised in `Hello::capabilities` instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Reply to the `hello` handshake
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Hello {
+    pub version: u32,
+    /// Names of the supported commands
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// Current protocol version, supporting every command
+    pub fn current() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: DaemonCommand::NAMES.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+/// Change pushed by the daemon to clients that sent `subscribe`
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "t", content = "c")]
+pub enum DaemonNotification {
+    /// Found by another client's `refresh`
+    BoardAdded(BoardId),
+    /// Found by another client's `refresh`
+    BoardRemoved(BoardId),
+    MatrixChanged(BoardId, Matrix),
+    /// LEDs were changed by another client
+    LedsChanged(BoardId),
+    /// Sent by `DaemonSupervisor`, not the daemon, when the daemon exits.
+    /// It is relaunched by the next command.
+    Exited,
+}
+
+impl DaemonNotification {
+    fn board_mut(&mut self) -> Option<&mut BoardId> {
+        match self {
+            Self::BoardAdded(board)
+            | Self::BoardRemoved(board)
+            | Self::MatrixChanged(board, _)
+            | Self::LedsChanged(board) => Some(board),
+            Self::Exited => None,
+        }
+    }
+}
+
+/// Line written by the daemon. Responses are serialized the same as
+/// `Result<DaemonResponse, Error>`, which is all a client from before
+/// notifications expects.
+#[derive(Deserialize, Serialize)]
+pub enum DaemonMessage {
+    Ok(DaemonResponse),
+    Err(Error),
+    Notification(DaemonNotification),
+}
+
+impl From<Result<DaemonResponse, Error>> for DaemonMessage {
+    fn from(response: Result<DaemonResponse, Error>) -> Self {
+        match response {
+            Ok(response) => Self::Ok(response),
+            Err(err) => Self::Err(err),
+        }
+    }
+}
+
 pub trait DaemonClientTrait: Send + 'static {
-    fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, String>;
+    fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, Error>;
+
+    /// Implements `Daemon::take_notifications`
+    fn notification_receiver(&self) -> Option<async_mpsc::UnboundedReceiver<DaemonNotification>> {
+        None
+    }
+
+    /// Implements `Daemon::is_fake`
+    fn is_fake(&self) -> bool {
+        false
+    }
+}
+
+// Return the argument of a command if it is `board`
+macro_rules! board_arg {
+    (board, $arg:ident) => {
+        return Some($arg)
+    };
+    ($other:ident, $arg:ident) => {};
 }
 
 // Define Daemon trait, DaemonCommand enum, and DaemonResponse enum
 macro_rules! commands {
-    ( $( fn $func:ident(&self $(,)? $( $arg:ident: $type:ty ),*) -> Result<$ret:ty, String>; )* ) => {
+    ( $( fn $func:ident(&self $(,)? $( $arg:ident: $type:ty ),*) -> Result<$ret:ty, Error>; )* ) => {
         pub trait Daemon: Send + 'static {
         $(
-            fn $func(&self, $( $arg: $type ),*) -> Result<$ret, String>;
+            fn $func(&self, $( $arg: $type ),*) -> Result<$ret, Error>;
         )*
 
             fn is_fake(&self) -> bool {
                 false
             }
 
-            fn dispatch_command_to_method(&self, command: DaemonCommand) -> Result<DaemonResponse, String> {
+            /// Receiver for notifications pushed by the daemon after
+            /// `subscribe`. Returns `None` if the daemon can't push
+            /// notifications, or if it was already taken.
+            fn take_notifications(&self) -> Option<async_mpsc::UnboundedReceiver<DaemonNotification>> {
+                None
+            }
+
+            fn dispatch_command_to_method(&self, command: DaemonCommand) -> Result<DaemonResponse, Error> {
                 match command {
                 $(
                     DaemonCommand::$func{$( $arg ),*} => {
@@ -45,7 +153,7 @@ macro_rules! commands {
         }
 
         #[allow(non_camel_case_types)]
-        #[derive(Deserialize, Serialize)]
+        #[derive(Clone, Deserialize, Serialize)]
         #[serde(tag = "t", content = "c")]
         pub enum DaemonCommand {
         $(
@@ -53,6 +161,31 @@ macro_rules! commands {
         ),*
         }
 
+        impl DaemonCommand {
+            pub const NAMES: &'static [&'static str] = &[$( stringify!($func) ),*];
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                $(
+                    DaemonCommand::$func{..} => stringify!($func)
+                ),*
+                }
+            }
+
+            /// The board the command is for, if any
+            #[allow(unreachable_code, unused_variables)]
+            pub fn board_mut(&mut self) -> Option<&mut BoardId> {
+                match self {
+                $(
+                    DaemonCommand::$func{$( $arg ),*} => {
+                        $( board_arg!($arg, $arg); )*
+                        None
+                    }
+                )*
+                }
+            }
+        }
+
         #[allow(non_camel_case_types)]
         #[derive(Deserialize, Serialize)]
         #[serde(tag = "t", content = "c")]
@@ -63,12 +196,24 @@ macro_rules! commands {
         }
 
         impl<T: DaemonClientTrait> Daemon for T {
+            fn is_fake(&self) -> bool {
+                DaemonClientTrait::is_fake(self)
+            }
+
+            fn take_notifications(&self) -> Option<async_mpsc::UnboundedReceiver<DaemonNotification>> {
+                DaemonClientTrait::notification_receiver(self)
+            }
+
         $(
-            fn $func(&self, $( $arg: $type ),*) -> Result<$ret, String> {
+            fn $func(&self, $( $arg: $type ),*) -> Result<$ret, Error> {
                 let res = self.send_command(DaemonCommand::$func{$( $arg ),*});
                 match res {
                     Ok(DaemonResponse::$func(ret)) => Ok(ret),
-                    Ok(_) => unreachable!(),
+                    // Such as from an edited recording
+                    Ok(_) => Err(Error::Protocol(format!(
+                        "Response to '{}' is for another command",
+                        stringify!($func)
+                    ))),
                     Err(err) => Err(err),
                 }
             }
@@ -78,27 +223,35 @@ macro_rules! commands {
 }
 
 commands! {
-    fn boards(&self) -> Result<Vec<BoardId>, String>;
-    fn model(&self, board: BoardId) -> Result<String, String>;
-    fn version(&self, board: BoardId) -> Result<String, String>;
-    fn refresh(&self) -> Result<(), String>;
-    fn keymap_get(&self, board: BoardId, layer: u8, output: u8, input: u8) -> Result<u16, String>;
-    fn keymap_set(&self, board: BoardId, layer: u8, output: u8, input: u8, value: u16) -> Result<(), String>;
-    fn matrix_get(&self, board: BoardId) -> Result<Matrix, String>;
-    fn benchmark(&self, board: BoardId) -> Result<Benchmark, String>;
-    fn nelson(&self, board: BoardId, kind: NelsonKind) -> Result<Nelson, String>;
-    fn color(&self, board: BoardId, index: u8) -> Result<(u8, u8, u8), String>;
-    fn set_color(&self, board: BoardId, index: u8, color: (u8, u8, u8)) -> Result<(), String>;
-    fn max_brightness(&self, board: BoardId) -> Result<i32, String>;
-    fn brightness(&self, board: BoardId, index: u8) -> Result<i32, String>;
-    fn set_brightness(&self, board: BoardId, index: u8, brightness: i32) -> Result<(), String>;
-    fn mode(&self, board: BoardId, layer: u8) -> Result<(u8, u8), String>;
-    fn set_mode(&self, board: BoardId, layer: u8, mode: u8, speed: u8) -> Result<(), String>;
-    fn led_save(&self, board: BoardId) -> Result<(), String>;
-    fn set_no_input(&self, board: BoardId, no_input: bool) -> Result<(), String>;
-    fn exit(&self) -> Result<(), String>;
-}
-
-fn err_str<E: std::fmt::Debug>(err: E) -> String {
-    format!("{:?}", err)
+    fn hello(&self, version: u32, capabilities: Vec<String>) -> Result<Hello, Error>;
+    fn boards(&self) -> Result<Vec<BoardId>, Error>;
+    fn model(&self, board: BoardId) -> Result<String, Error>;
+    fn version(&self, board: BoardId) -> Result<String, Error>;
+    fn serial(&self, board: BoardId) -> Result<Option<String>, Error>;
+    fn stable_id(&self, board: BoardId) -> Result<String, Error>;
+    fn refresh(&self) -> Result<(), Error>;
+    fn keymap_get(&self, board: BoardId, layer: u8, output: u8, input: u8) -> Result<u16, Error>;
+    fn keymap_set(&self, board: BoardId, layer: u8, output: u8, input: u8, value: u16) -> Result<(), Error>;
+    fn matrix_get(&self, board: BoardId) -> Result<Matrix, Error>;
+    fn benchmark(&self, board: BoardId) -> Result<Benchmark, Error>;
+    fn nelson(&self, board: BoardId, kind: NelsonKind) -> Result<Nelson, Error>;
+    fn color(&self, board: BoardId, index: u8) -> Result<(u8, u8, u8), Error>;
+    fn set_color(&self, board: BoardId, index: u8, color: (u8, u8, u8)) -> Result<(), Error>;
+    fn max_brightness(&self, board: BoardId) -> Result<i32, Error>;
+    fn brightness(&self, board: BoardId, index: u8) -> Result<i32, Error>;
+    fn set_brightness(&self, board: BoardId, index: u8, brightness: i32) -> Result<(), Error>;
+    fn mode(&self, board: BoardId, layer: u8) -> Result<(u8, u8), Error>;
+    fn set_mode(&self, board: BoardId, layer: u8, mode: u8, speed: u8) -> Result<(), Error>;
+    fn led_save(&self, board: BoardId) -> Result<(), Error>;
+    fn set_no_input(&self, board: BoardId, no_input: bool) -> Result<(), Error>;
+    // Like `keymap_get`, `keymap_set`, `color` and `set_color` for many keys
+    // or LEDs, in one round trip. Stop at the first error.
+    fn keymap_get_batch(&self, board: BoardId, keys: Vec<(u8, u8, u8)>) -> Result<Vec<u16>, Error>;
+    fn keymap_set_batch(&self, board: BoardId, keys: Vec<(u8, u8, u8, u16)>) -> Result<(), Error>;
+    fn color_batch(&self, board: BoardId, indices: Vec<u8>) -> Result<Vec<(u8, u8, u8)>, Error>;
+    fn set_color_batch(&self, board: BoardId, colors: Vec<(u8, (u8, u8, u8))>) -> Result<(), Error>;
+    // Push changes to the client as `DaemonNotification`s, including the
+    // matrix every `matrix_interval_ms` if set
+    fn subscribe(&self, matrix_interval_ms: Option<u64>) -> Result<(), Error>;
+    fn exit(&self) -> Result<(), Error>;
 }
@@ -0,0 +1,213 @@
+// Recording of the commands sent to a daemon, to replay a session from a bug
+// report or from real hardware in tests
+
+use futures::channel::mpsc as async_mpsc;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    time::Instant,
+};
+
+use super::{Daemon, DaemonClientTrait, DaemonCommand, DaemonNotification, DaemonResponse};
+use crate::Error;
+
+/// Line of a recording: a command and the daemon's response to it
+#[derive(Deserialize, Serialize)]
+struct Record<R> {
+    /// Milliseconds since recording started, when the command was sent
+    time_ms: u64,
+    command: DaemonCommand,
+    response: R,
+}
+
+/// Daemon that passes commands to another, writing each with its response as
+/// a line of JSON to `write`. Notifications are passed on, but not recorded.
+pub struct DaemonRecorder<W: Write + Send + 'static> {
+    daemon: Box<dyn Daemon>,
+    write: RefCell<W>,
+    start: Instant,
+}
+
+impl<W: Write + Send + 'static> DaemonRecorder<W> {
+    pub fn new(daemon: Box<dyn Daemon>, write: W) -> Self {
+        Self {
+            daemon,
+            write: RefCell::new(write),
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&self, record: &Record<&Result<DaemonResponse, Error>>) -> Result<(), Error> {
+        let json = serde_json::to_string(record)?;
+        let mut write = self.write.borrow_mut();
+        writeln!(write, "{}", json)?;
+        // Keep what was recorded before a crash
+        write.flush()?;
+        Ok(())
+    }
+}
+
+impl DaemonRecorder<File> {
+    /// Record to the file at `path`, replacing it if it exists
+    pub fn create(daemon: Box<dyn Daemon>, path: &Path) -> Result<Self, Error> {
+        let file = File::create(path)
+            .map_err(|err| Error::from(err).context("Failed to create recording"))?;
+        Ok(Self::new(daemon, file))
+    }
+}
+
+impl<W: Write + Send + 'static> DaemonClientTrait for DaemonRecorder<W> {
+    fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, Error> {
+        let time_ms = self.start.elapsed().as_millis() as u64;
+        let response = self.daemon.dispatch_command_to_method(command.clone());
+        let record = Record {
+            time_ms,
+            command,
+            response: &response,
+        };
+        if let Err(err) = self.record(&record) {
+            error!("Failed to record daemon command: {}", err);
+        }
+        response
+    }
+
+    fn notification_receiver(&self) -> Option<async_mpsc::UnboundedReceiver<DaemonNotification>> {
+        self.daemon.take_notifications()
+    }
+
+    fn is_fake(&self) -> bool {
+        self.daemon.is_fake()
+    }
+}
+
+/// Daemon that answers commands with the responses in a recording from
+/// `DaemonRecorder`, without waiting for their timestamps.
+///
+/// Each command gets the response of the first unanswered record of the same
+/// command, so commands may be sent in a different order than recorded.
+/// Commands sent more often than recorded get the last response again, and
+/// commands that weren't recorded fail with `Error::NotFound`.
+pub struct DaemonReplay {
+    /// JSON of each recorded command, to compare commands with
+    commands: Vec<String>,
+    /// JSON of each recorded response, deserialized again for each command
+    /// since responses can't be cloned
+    responses: Vec<String>,
+    answered: RefCell<Vec<bool>>,
+}
+
+impl DaemonReplay {
+    pub fn new<R: BufRead>(read: R) -> Result<Self, Error> {
+        let mut commands = Vec::new();
+        let mut responses = Vec::new();
+        for (i, line) in read.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record<Result<DaemonResponse, Error>> = serde_json::from_str(&line)
+                .map_err(|err| {
+                    Error::from(err).context(&format!("Invalid record on line {}", i + 1))
+                })?;
+            commands.push(serde_json::to_string(&record.command)?);
+            responses.push(serde_json::to_string(&record.response)?);
+        }
+        Ok(Self {
+            answered: RefCell::new(vec![false; commands.len()]),
+            commands,
+            responses,
+        })
+    }
+
+    /// Replay the recording in the file at `path`
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file =
+            File::open(path).map_err(|err| Error::from(err).context("Failed to open recording"))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl DaemonClientTrait for DaemonReplay {
+    fn send_command(&self, command: DaemonCommand) -> Result<DaemonResponse, Error> {
+        let name = command.name();
+        let command = serde_json::to_string(&command)?;
+        let mut answered = self.answered.borrow_mut();
+        let matches = || {
+            self.commands
+                .iter()
+                .enumerate()
+                .filter(|(_, recorded)| **recorded == command)
+        };
+        let index = match matches().find(|(i, _)| !answered[*i]) {
+            Some((i, _)) => i,
+            None => matches()
+                .last()
+                .map(|(i, _)| i)
+                .ok_or_else(|| Error::NotFound(format!("'{}' isn't in the recording", name)))?,
+        };
+        answered[index] = true;
+        serde_json::from_str(&self.responses[index])
+            .map_err(|err| Error::from(err).context("Invalid recorded response"))?
+    }
+
+    fn is_fake(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{test_output::Output, BoardId, DaemonDummy};
+
+    #[test]
+    fn record_replay() {
+        let dummy = DaemonDummy::new(vec!["system76/launch_1".to_string()]).unwrap();
+        let output = Output::default();
+        let recorder = DaemonRecorder::new(Box::new(dummy), output.clone());
+        let board = recorder.boards().unwrap()[0];
+        recorder.keymap_set(board, 0, 1, 2, 4).unwrap();
+        assert_eq!(recorder.keymap_get(board, 0, 1, 2).unwrap(), 4);
+        recorder.keymap_set(board, 0, 1, 2, 5).unwrap();
+        assert_eq!(recorder.keymap_get(board, 0, 1, 2).unwrap(), 5);
+        let err = recorder.model(BoardId(1)).unwrap_err();
+
+        let recording = output.contents();
+        assert_eq!(recording.iter().filter(|x| **x == b'\n').count(), 6);
+        let replay = DaemonReplay::new(&recording[..]).unwrap();
+        assert_eq!(replay.boards().unwrap(), vec![board]);
+        // Answered in order, then the last response is repeated
+        assert_eq!(replay.keymap_get(board, 0, 1, 2).unwrap(), 4);
+        assert_eq!(replay.keymap_get(board, 0, 1, 2).unwrap(), 5);
+        assert_eq!(replay.keymap_get(board, 0, 1, 2).unwrap(), 5);
+        // Errors are replayed too
+        assert_eq!(replay.model(BoardId(1)).unwrap_err(), err);
+        // Not recorded
+        assert!(matches!(
+            replay.keymap_get(board, 1, 1, 2),
+            Err(Error::NotFound(_))
+        ));
+        assert!(Daemon::is_fake(&replay));
+    }
+
+    #[test]
+    fn invalid_recording() {
+        assert!(matches!(
+            DaemonReplay::new(&b"{\"time_ms\": 0}\n"[..]),
+            Err(Error::Protocol(message)) if message.starts_with("Invalid record on line 1")
+        ));
+
+        // Valid, but answering with the response to another command
+        let record = Record {
+            time_ms: 0,
+            command: DaemonCommand::boards {},
+            response: Ok::<_, Error>(DaemonResponse::model("system76/launch_1".to_string())),
+        };
+        let recording = serde_json::to_string(&record).unwrap();
+        let replay = DaemonReplay::new(recording.as_bytes()).unwrap();
+        assert!(matches!(replay.boards(), Err(Error::Protocol(_))));
+    }
+}
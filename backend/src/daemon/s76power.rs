@@ -2,14 +2,18 @@
 // Need to watch properties of each object?
 // TODO: Hotplug detection support
 
-use std::iter::Iterator;
+use std::{fmt, iter::Iterator};
 use zbus::{blocking::fdo::ObjectManagerProxy, blocking::Connection, dbus_proxy};
 
-use super::{err_str, BoardId, Daemon, Matrix};
-use crate::{fl, Benchmark, Nelson, NelsonKind, Rgb};
+use super::{BoardId, Daemon, Hello, Matrix};
+use crate::{fl, Benchmark, Error, Nelson, NelsonKind, Rgb};
 
 const DBUS_NAME: &str = "com.system76.PowerDaemon";
 
+fn dbus_err<E: fmt::Debug>(err: E) -> Error {
+    Error::Io(format!("{:?}", err))
+}
+
 #[dbus_proxy(
     interface = "com.system76.PowerDaemon.Keyboard",
     assume_defaults = true
@@ -37,15 +41,15 @@ struct Keyboard {
 }
 
 impl Keyboard {
-    fn new(path: &str) -> Result<Self, String> {
-        let connection = Connection::system().map_err(err_str)?;
+    fn new(path: &str) -> Result<Self, Error> {
+        let connection = Connection::system().map_err(dbus_err)?;
         let proxy = KeyboardProxyBlocking::builder(&connection)
             .destination(DBUS_NAME.to_owned())
-            .map_err(err_str)?
+            .map_err(dbus_err)?
             .path(path.to_owned())
-            .map_err(err_str)?
+            .map_err(dbus_err)?
             .build()
-            .map_err(err_str)?;
+            .map_err(dbus_err)?;
         Ok(Self { proxy })
     }
 }
@@ -55,26 +59,26 @@ pub struct DaemonS76Power {
 }
 
 impl DaemonS76Power {
-    fn board(&self, board: BoardId) -> Result<&Keyboard, String> {
+    fn board(&self, board: BoardId) -> Result<&Keyboard, Error> {
         self.boards
             .get(board.0 as usize)
-            .ok_or_else(|| fl!("no-board"))
+            .ok_or_else(|| Error::NotFound(fl!("no-board")))
     }
 }
 
 impl DaemonS76Power {
-    pub fn new() -> Result<Self, String> {
+    pub fn new() -> Result<Self, Error> {
         let mut boards = Vec::new();
 
-        let connection = Connection::system().map_err(err_str)?;
+        let connection = Connection::system().map_err(dbus_err)?;
         let proxy = ObjectManagerProxy::builder(&connection)
             .destination(DBUS_NAME)
-            .map_err(err_str)?
+            .map_err(dbus_err)?
             .path("/com/system76/PowerDaemon")
-            .map_err(err_str)?
+            .map_err(dbus_err)?
             .build()
-            .map_err(err_str)?;
-        let objects = proxy.get_managed_objects().map_err(err_str)?;
+            .map_err(dbus_err)?;
+        let objects = proxy.get_managed_objects().map_err(dbus_err)?;
 
         for path in objects.keys() {
             if path.starts_with("/com/system76/PowerDaemon/keyboard") {
@@ -87,17 +91,32 @@ impl DaemonS76Power {
 }
 
 impl Daemon for DaemonS76Power {
-    fn boards(&self) -> Result<Vec<BoardId>, String> {
+    fn hello(&self, _version: u32, _capabilities: Vec<String>) -> Result<Hello, Error> {
+        Ok(Hello::current())
+    }
+
+    fn boards(&self) -> Result<Vec<BoardId>, Error> {
         Ok((0..self.boards.len() as u128).map(BoardId).collect())
     }
 
-    fn model(&self, _board: BoardId) -> Result<String, String> {
+    fn model(&self, _board: BoardId) -> Result<String, Error> {
         // XXX
         Ok("system76/darp6".to_string())
     }
 
-    fn version(&self, _board: BoardId) -> Result<String, String> {
-        Err("Unimplemented".to_string())
+    fn version(&self, _board: BoardId) -> Result<String, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
+    }
+
+    fn serial(&self, board: BoardId) -> Result<Option<String>, Error> {
+        self.board(board)?;
+        Ok(None)
+    }
+
+    fn stable_id(&self, board: BoardId) -> Result<String, Error> {
+        // Built in keyboards, listed in the same order by the power daemon
+        self.board(board)?;
+        Ok(format!("s76power:{}", board.0))
     }
 
     fn keymap_get(
@@ -106,8 +125,8 @@ impl Daemon for DaemonS76Power {
         _layer: u8,
         _output: u8,
         _input: u8,
-    ) -> Result<u16, String> {
-        Err("Unimplemented".to_string())
+    ) -> Result<u16, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
     fn keymap_set(
@@ -117,82 +136,126 @@ impl Daemon for DaemonS76Power {
         _output: u8,
         _input: u8,
         _value: u16,
-    ) -> Result<(), String> {
-        Err("Unimplemented".to_string())
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn matrix_get(&self, _board: BoardId) -> Result<Matrix, String> {
-        Err("Unimplemented".to_string())
+    fn matrix_get(&self, _board: BoardId) -> Result<Matrix, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn benchmark(&self, _board: BoardId) -> Result<Benchmark, String> {
-        Err("Unimplemented".to_string())
+    fn benchmark(&self, _board: BoardId) -> Result<Benchmark, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn nelson(&self, _board: BoardId, _kind: NelsonKind) -> Result<Nelson, String> {
-        Err("Unimplemented".to_string())
+    fn nelson(&self, _board: BoardId, _kind: NelsonKind) -> Result<Nelson, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn color(&self, board: BoardId, index: u8) -> Result<(u8, u8, u8), String> {
+    fn color(&self, board: BoardId, index: u8) -> Result<(u8, u8, u8), Error> {
         if index != 0xFF {
-            return Err(format!("Can't set color index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't set color index {}",
+                index
+            )));
         }
-        let color = self.board(board)?.proxy.color().map_err(err_str)?;
+        let color = self.board(board)?.proxy.color().map_err(dbus_err)?;
         Ok(Rgb::parse(&color).map_or((0, 0, 0), |rgb| (rgb.r, rgb.g, rgb.b)))
     }
 
-    fn set_color(&self, board: BoardId, index: u8, color: (u8, u8, u8)) -> Result<(), String> {
+    fn set_color(&self, board: BoardId, index: u8, color: (u8, u8, u8)) -> Result<(), Error> {
         if index != 0xFF {
-            return Err(format!("Can't set color index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't set color index {}",
+                index
+            )));
         }
         self.board(board)?
             .proxy
             .set_color(&Rgb::new(color.0, color.1, color.2).to_string())
-            .map_err(err_str)
+            .map_err(dbus_err)
     }
 
-    fn max_brightness(&self, board: BoardId) -> Result<i32, String> {
-        self.board(board)?.proxy.max_brightness().map_err(err_str)
+    fn max_brightness(&self, board: BoardId) -> Result<i32, Error> {
+        self.board(board)?.proxy.max_brightness().map_err(dbus_err)
     }
 
-    fn brightness(&self, board: BoardId, index: u8) -> Result<i32, String> {
+    fn brightness(&self, board: BoardId, index: u8) -> Result<i32, Error> {
         if index != 0xFF {
-            return Err(format!("Can't set brightness index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't set brightness index {}",
+                index
+            )));
         }
-        self.board(board)?.proxy.brightness().map_err(err_str)
+        self.board(board)?.proxy.brightness().map_err(dbus_err)
     }
 
-    fn set_brightness(&self, board: BoardId, index: u8, brightness: i32) -> Result<(), String> {
+    fn set_brightness(&self, board: BoardId, index: u8, brightness: i32) -> Result<(), Error> {
         if index != 0xFF {
-            return Err(format!("Can't set brightness index {}", index));
+            return Err(Error::InvalidArgument(format!(
+                "Can't set brightness index {}",
+                index
+            )));
         }
         self.board(board)?
             .proxy
             .set_brightness(brightness)
-            .map_err(err_str)
+            .map_err(dbus_err)
     }
 
-    fn mode(&self, _board: BoardId, _layer: u8) -> Result<(u8, u8), String> {
-        Err("Unimplemented".to_string())
+    fn mode(&self, _board: BoardId, _layer: u8) -> Result<(u8, u8), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn set_mode(&self, _board: BoardId, _layer: u8, _mode: u8, _speed: u8) -> Result<(), String> {
-        Err("Unimplemented".to_string())
+    fn set_mode(&self, _board: BoardId, _layer: u8, _mode: u8, _speed: u8) -> Result<(), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn led_save(&self, _board: BoardId) -> Result<(), String> {
-        Err("Unimplemented".to_string())
+    fn led_save(&self, _board: BoardId) -> Result<(), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn refresh(&self) -> Result<(), String> {
+    fn refresh(&self) -> Result<(), Error> {
         Ok(())
     }
 
-    fn set_no_input(&self, _board: BoardId, _no_input: bool) -> Result<(), String> {
-        Err("Unimplemented".to_string())
+    fn set_no_input(&self, _board: BoardId, _no_input: bool) -> Result<(), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
+    }
+
+    fn keymap_get_batch(
+        &self,
+        _board: BoardId,
+        _keys: Vec<(u8, u8, u8)>,
+    ) -> Result<Vec<u16>, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
+    }
+
+    fn keymap_set_batch(
+        &self,
+        _board: BoardId,
+        _keys: Vec<(u8, u8, u8, u16)>,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
+    }
+
+    fn color_batch(&self, _board: BoardId, _indices: Vec<u8>) -> Result<Vec<(u8, u8, u8)>, Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
+    }
+
+    fn set_color_batch(
+        &self,
+        _board: BoardId,
+        _colors: Vec<(u8, (u8, u8, u8))>,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
+    }
+
+    fn subscribe(&self, _matrix_interval_ms: Option<u64>) -> Result<(), Error> {
+        Err(Error::Unsupported("Unimplemented".to_string()))
     }
 
-    fn exit(&self) -> Result<(), String> {
+    fn exit(&self) -> Result<(), Error> {
         Ok(())
     }
 }
@@ -6,50 +6,224 @@ use std::{
     cell::{Cell, RefCell, RefMut},
     collections::HashMap,
     io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
     str,
-    thread::sleep,
-    time::Duration,
+    sync::mpsc,
+    thread::{self, sleep},
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
-use super::{err_str, BoardId, Daemon, DaemonCommand};
-use crate::{Benchmark, Matrix, Nelson, NelsonKind};
+#[cfg(target_os = "linux")]
+use super::{hotplug::watch_board_hotplug, socket, NetlinkUevents};
+#[cfg(target_os = "linux")]
+use std::os::unix::net::UnixListener;
+
+use super::{
+    BoardId, Daemon, DaemonCommand, DaemonMessage, DaemonNotification, DaemonResponse, Hello,
+};
+use crate::{hidraw_bus_path, Benchmark, Error, Matrix, Nelson, NelsonKind};
 
 const QMK_RAW_USAGE_PAGE: u16 = 0xFF60;
 const QMK_RAW_USAGE_ID: u16 = 0x61;
 
-#[allow(clippy::type_complexity)]
+/// Stable ID of the EC on the LPC bus, of which there is only one
+const LPC_STABLE_ID: &str = "lpc";
+
+/// Longest command accepted from a client, in bytes. Longer lines are
+/// answered with an error and skipped.
+pub(super) const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+pub(super) type ClientId = usize;
+
+/// Input to the main loop of the daemon from a client connection
+pub(super) enum ClientMessage {
+    /// Lines sent to the channel are written to the client
+    Connected(ClientId, mpsc::Sender<String>),
+    Command(ClientId, String),
+    /// A line that isn't a command, answered with the error
+    Invalid(ClientId, Error),
+    Disconnected(ClientId),
+    /// A System76 HID device was added or removed, found by the daemon's own
+    /// hotplug watch, so boards need a refresh
+    Hotplug,
+}
+
+/// Forward each line from `read` as a command of client `id`, until EOF
+pub(super) fn read_client<R: BufRead>(
+    id: ClientId,
+    mut read: R,
+    sender: &mpsc::Sender<ClientMessage>,
+) {
+    loop {
+        let message = match read_line(id, &mut read) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(err) => {
+                error!("Failed to read from daemon client: {}", err);
+                break;
+            }
+        };
+        if sender.send(message).is_err() {
+            return;
+        }
+    }
+    let _ = sender.send(ClientMessage::Disconnected(id));
+}
+
+/// Read a line of at most `MAX_LINE_LENGTH` bytes, returning `None` at EOF
+fn read_line<R: BufRead>(id: ClientId, read: &mut R) -> io::Result<Option<ClientMessage>> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE_LENGTH as u64 + 1;
+    if read.by_ref().take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.len() > MAX_LINE_LENGTH && line.last() != Some(&b'\n') {
+        skip_line(read)?;
+        return Ok(Some(ClientMessage::Invalid(
+            id,
+            Error::Protocol(format!("Command longer than {} bytes", MAX_LINE_LENGTH)),
+        )));
+    }
+    Ok(Some(match String::from_utf8(line) {
+        Ok(command_json) => ClientMessage::Command(id, command_json),
+        Err(err) => ClientMessage::Invalid(id, Error::from(err.utf8_error())),
+    }))
+}
+
+/// Discard input up to and including the next newline
+fn skip_line<R: BufRead>(read: &mut R) -> io::Result<()> {
+    loop {
+        let buf = read.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(i) => {
+                read.consume(i + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                read.consume(len);
+            }
+        }
+    }
+}
+
+/// Write the greeting, then each line from `receiver` until it is closed
+pub(super) fn write_client<W: Write>(
+    mut write: W,
+    receiver: mpsc::Receiver<String>,
+) -> io::Result<()> {
+    // Clients wait for this line before sending commands
+    writeln!(write, "Daemon started")?;
+    write.flush()?;
+    for line in receiver {
+        writeln!(write, "{}", line)?;
+        write.flush()?;
+    }
+    Ok(())
+}
+
+struct Client {
+    sender: mpsc::Sender<String>,
+    /// Set by `subscribe`
+    subscribed: bool,
+    matrix_interval: Option<Duration>,
+}
+
+impl Client {
+    fn send(&self, message: &DaemonMessage) {
+        let json = match serde_json::to_string(message) {
+            Ok(json) => json,
+            // Still answer the command, so the client doesn't wait forever
+            Err(err) if matches!(message, DaemonMessage::Ok(_)) => {
+                error!("Failed to serialize daemon response: {}", err);
+                return self.send(&DaemonMessage::Err(Error::from(err)));
+            }
+            Err(err) => {
+                error!("Failed to serialize daemon message: {}", err);
+                return;
+            }
+        };
+        let _ = self.sender.send(json);
+    }
+}
+
+/// State for finding changes to notify subscribed clients of
+struct Watch {
+    /// Boards subscribed clients were last told of
+    board_ids: Vec<BoardId>,
+    matrices: HashMap<BoardId, Matrix>,
+    next_matrix: Instant,
+}
+
+impl Watch {
+    /// Shortest matrix interval of subscribed clients
+    fn matrix_interval(clients: &HashMap<ClientId, Client>) -> Option<Duration> {
+        clients
+            .values()
+            .filter(|client| client.subscribed)
+            .filter_map(|client| client.matrix_interval)
+            .min()
+    }
+
+    /// When to next check for matrix changes, if any subscribed client
+    /// wants them. Boards are only added and removed by `refresh`, on hotplug
+    /// or when a client asks, so they aren't polled.
+    fn deadline(&self, clients: &HashMap<ClientId, Client>) -> Option<Instant> {
+        Self::matrix_interval(clients).map(|_| self.next_matrix)
+    }
+}
+
+/// An EC found by the daemon
+struct ServerBoard {
+    ec: Ec<Box<dyn Access>>,
+    /// Set for USB HID boards
+    info: Option<DeviceInfo>,
+    /// Identity of the physical board, which stays the same when it is
+    /// reconnected
+    stable_id: String,
+}
+
 pub struct DaemonServer<R: Read + Send + 'static, W: Write + Send + 'static> {
     hidapi: RefCell<Option<HidApi>>,
     running: Cell<bool>,
-    read: BufReader<R>,
-    write: W,
-    boards: RefCell<HashMap<BoardId, (Ec<Box<dyn Access>>, Option<DeviceInfo>)>>,
+    read: Option<BufReader<R>>,
+    write: Option<W>,
+    boards: RefCell<HashMap<BoardId, ServerBoard>>,
     board_ids: RefCell<Vec<BoardId>>,
+    /// ID given to each stable ID, so boards keep theirs when reconnected
+    known_ids: RefCell<HashMap<String, BoardId>>,
     nelson: RefCell<Option<Ec<AccessHid>>>,
 }
 
 impl DaemonServer<io::Stdin, io::Stdout> {
-    pub fn new_stdio() -> Result<Self, String> {
+    pub fn new_stdio() -> Result<Self, Error> {
         Self::new(io::stdin(), io::stdout())
     }
 }
 
 impl<R: Read + Send + 'static, W: Write + Send + 'static> DaemonServer<R, W> {
-    pub fn new(read: R, write: W) -> Result<Self, String> {
-        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
-        let mut boards = HashMap::new();
-        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
-        let mut board_ids = Vec::new();
+    pub fn new(read: R, write: W) -> Result<Self, Error> {
+        //TODO: should we continue through HID errors?
+        let hidapi = match HidApi::new() {
+            Ok(api) => Some(api),
+            Err(err) => {
+                error!("Failed to list USB HID ECs: {:?}", err);
+                None
+            }
+        };
+
+        let server = Self::with_hidapi(read, write, hidapi);
 
         #[cfg(target_os = "linux")]
         match unsafe { AccessLpcLinux::new(Duration::new(1, 0)) } {
             Ok(access) => match unsafe { Ec::new(access) } {
                 Ok(ec) => {
                     info!("Adding LPC EC");
-                    let id = BoardId(Uuid::new_v4().as_u128());
-                    boards.insert(id, (ec.into_dyn(), None));
-                    board_ids.push(id);
+                    server.add_board(ec.into_dyn(), None, LPC_STABLE_ID.to_string());
                 }
                 Err(err) => {
                     error!("Failed to probe LPC EC: {:?}", err);
@@ -60,29 +234,72 @@ impl<R: Read + Send + 'static, W: Write + Send + 'static> DaemonServer<R, W> {
             }
         }
 
-        //TODO: should we continue through HID errors?
-        let hidapi = match HidApi::new() {
-            Ok(api) => Some(api),
-            Err(err) => {
-                error!("Failed to list USB HID ECs: {:?}", err);
-                None
-            }
-        };
+        Ok(server)
+    }
+
+    /// Server without any boards, that doesn't access the hardware
+    #[cfg(test)]
+    pub(crate) fn new_without_hardware(read: R, write: W) -> Self {
+        Self::with_hidapi(read, write, None)
+    }
 
-        Ok(Self {
+    fn with_hidapi(read: R, write: W, hidapi: Option<HidApi>) -> Self {
+        Self {
             hidapi: RefCell::new(hidapi),
             running: Cell::new(true),
-            read: BufReader::new(read),
-            write,
-            boards: RefCell::new(boards),
-            board_ids: RefCell::new(board_ids),
+            read: Some(BufReader::new(read)),
+            write: Some(write),
+            boards: RefCell::new(HashMap::new()),
+            board_ids: RefCell::new(Vec::new()),
+            known_ids: RefCell::new(HashMap::new()),
             nelson: RefCell::new(None),
-        })
+        }
+    }
+
+    /// Add a board, with the ID it had before if it was attached earlier
+    fn add_board(&self, ec: Ec<Box<dyn Access>>, info: Option<DeviceInfo>, stable_id: String) {
+        let mut boards = self.boards.borrow_mut();
+        let id = *self
+            .known_ids
+            .borrow_mut()
+            .entry(stable_id.clone())
+            .or_insert_with(|| BoardId(Uuid::new_v4().as_u128()));
+        boards.insert(
+            id,
+            ServerBoard {
+                ec,
+                info,
+                stable_id,
+            },
+        );
+        self.board_ids.borrow_mut().push(id);
+    }
+
+    /// Stable ID of a USB HID board: its serial number if it has one that no
+    /// other attached board has, otherwise the USB port it is plugged into
+    fn hid_stable_id(&self, info: &DeviceInfo) -> String {
+        let prefix = format!("usb:{:04x}:{:04x}", info.vendor_id(), info.product_id());
+        if let Some(serial) = info.serial_number().filter(|x| !x.is_empty()) {
+            let stable_id = format!("{}:{}", prefix, serial);
+            if !self
+                .boards
+                .borrow()
+                .values()
+                .any(|x| x.stable_id == stable_id)
+            {
+                return stable_id;
+            }
+        }
+        let path = info.path().to_string_lossy();
+        match hidraw_bus_path(Path::new("/sys"), Path::new(&*path)) {
+            Some(bus_path) => format!("{}:port:{}", prefix, bus_path),
+            None => format!("{}:path:{}", prefix, path),
+        }
     }
 
     fn have_device(&self, info: &DeviceInfo) -> bool {
-        for (_, i) in self.boards.borrow().values() {
-            if let Some(i) = i {
+        for board in self.boards.borrow().values() {
+            if let Some(i) = &board.info {
                 if (i.vendor_id(), i.product_id(), i.path())
                     == (info.vendor_id(), info.product_id(), info.path())
                 {
@@ -93,63 +310,298 @@ impl<R: Read + Send + 'static, W: Write + Send + 'static> DaemonServer<R, W> {
         false
     }
 
+    /// Serve the client on `read` and `write` until it sends `exit` or
+    /// closes its end
     pub fn run(mut self) -> io::Result<()> {
-        println!("Daemon started");
+        let read = self.read.take().unwrap();
+        let write = self.write.take().unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let (line_sender, line_receiver) = mpsc::channel();
+        let _ = sender.send(ClientMessage::Connected(0, line_sender));
+        thread::spawn(move || read_client(0, read, &sender));
+        let writer = thread::spawn(move || write_client(write, line_receiver));
+
+        self.serve(receiver, true);
+        writer.join().unwrap()
+    }
+
+    /// Serve clients connecting to `listener` until it fails, refusing users
+    /// not in `allowed_uids`. Unlike with `run`, `exit` only closes the
+    /// connection of the client that sent it.
+    ///
+    /// Boards are refreshed on hotplug, notifying every subscribed client,
+    /// since no single client is responsible for that.
+    #[cfg(target_os = "linux")]
+    pub fn run_socket(self, listener: UnixListener, allowed_uids: Vec<u32>) -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        match NetlinkUevents::new() {
+            Ok(uevents) => watch_board_hotplug(uevents, sender.clone()),
+            Err(err) => error!("Failed to watch for hotplug: {}", err),
+        }
+        thread::spawn(move || socket::accept_clients(listener, allowed_uids, sender));
+        self.serve(receiver, false);
+        Ok(())
+    }
+
+    /// Handle messages from clients, and notify subscribed clients of
+    /// changes. If `single_client`, stops when the client exits.
+    fn serve(&self, receiver: mpsc::Receiver<ClientMessage>, single_client: bool) {
+        let mut clients = HashMap::new();
+        let mut watch = Watch {
+            board_ids: self.board_ids.borrow().clone(),
+            matrices: HashMap::new(),
+            next_matrix: Instant::now(),
+        };
 
         while self.running.get() {
-            let mut command_json = String::new();
-            self.read.read_line(&mut command_json)?;
-
-            let command = serde_json::from_str::<DaemonCommand>(&command_json)
-                .expect("failed to deserialize command");
-            let response = self.dispatch_command_to_method(command);
-
-            //TODO: what to do if we fail to serialize result?
-            let mut result_json =
-                serde_json::to_string(&response).expect("failed to serialize result");
-            result_json.push('\n');
-            self.write.write_all(result_json.as_bytes())?;
+            let message = match watch.deadline(&clients) {
+                Some(deadline) => {
+                    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(message) => Some(message),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match receiver.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => break,
+                },
+            };
+
+            match message {
+                Some(ClientMessage::Connected(id, sender)) => {
+                    clients.insert(
+                        id,
+                        Client {
+                            sender,
+                            subscribed: false,
+                            matrix_interval: None,
+                        },
+                    );
+                }
+                Some(ClientMessage::Command(id, command_json)) => {
+                    self.handle_command(&mut clients, &mut watch, id, &command_json, single_client);
+                }
+                Some(ClientMessage::Invalid(id, err)) => {
+                    if let Some(client) = clients.get(&id) {
+                        client.send(&DaemonMessage::Err(err));
+                    }
+                }
+                Some(ClientMessage::Disconnected(id)) => {
+                    clients.remove(&id);
+                    if single_client {
+                        break;
+                    }
+                }
+                Some(ClientMessage::Hotplug) => match self.refresh() {
+                    Ok(()) => self.notify_boards(&clients, &mut watch, None),
+                    Err(err) => error!("Failed to refresh boards on hotplug: {}", err),
+                },
+                None => {}
+            }
+
+            if watch
+                .deadline(&clients)
+                .map_or(false, |deadline| deadline <= Instant::now())
+            {
+                self.notify_matrices(&clients, &mut watch);
+            }
         }
+    }
 
-        Ok(())
+    fn handle_command(
+        &self,
+        clients: &mut HashMap<ClientId, Client>,
+        watch: &mut Watch,
+        id: ClientId,
+        command_json: &str,
+        single_client: bool,
+    ) {
+        let command = serde_json::from_str::<DaemonCommand>(command_json);
+        let is_refresh = matches!(command, Ok(DaemonCommand::refresh {}));
+        let leds_changed = match &command {
+            Ok(DaemonCommand::set_color { board, .. })
+            | Ok(DaemonCommand::set_color_batch { board, .. })
+            | Ok(DaemonCommand::set_brightness { board, .. })
+            | Ok(DaemonCommand::set_mode { board, .. }) => Some(*board),
+            _ => None,
+        };
+        let mut close = false;
+
+        let response = match command {
+            Ok(DaemonCommand::exit {}) if !single_client => {
+                close = true;
+                Ok(DaemonResponse::exit(()))
+            }
+            Ok(DaemonCommand::subscribe { matrix_interval_ms }) => {
+                if let Some(client) = clients.get_mut(&id) {
+                    client.subscribed = true;
+                    client.matrix_interval = matrix_interval_ms.map(Duration::from_millis);
+                }
+                // Only notify of boards added after this
+                watch.board_ids = self.board_ids.borrow().clone();
+                Ok(DaemonResponse::subscribe(()))
+            }
+            Ok(command) => self.dispatch_command_to_method(command),
+            Err(err) => Err(Error::from(err)),
+        };
+
+        if let (Some(board), Ok(_)) = (leds_changed, &response) {
+            let notification = DaemonMessage::Notification(DaemonNotification::LedsChanged(board));
+            for (_, client) in clients
+                .iter()
+                .filter(|(i, client)| **i != id && client.subscribed)
+            {
+                client.send(&notification);
+            }
+        }
+
+        if is_refresh && response.is_ok() {
+            self.notify_boards(clients, watch, Some(id));
+        }
+
+        if let Some(client) = clients.get(&id) {
+            client.send(&DaemonMessage::from(response));
+        }
+        if close {
+            clients.remove(&id);
+        }
     }
 
-    fn board(&self, board: BoardId) -> Result<RefMut<Ec<Box<dyn Access>>>, String> {
+    /// Notify subscribed clients of boards added or removed by a refresh,
+    /// other than `refreshed_by`, the client that sent the `refresh` and
+    /// already knows
+    fn notify_boards(
+        &self,
+        clients: &HashMap<ClientId, Client>,
+        watch: &mut Watch,
+        refreshed_by: Option<ClientId>,
+    ) {
+        let notify = |notification: DaemonNotification| {
+            let message = DaemonMessage::Notification(notification);
+            for (_, client) in clients
+                .iter()
+                .filter(|(i, client)| Some(**i) != refreshed_by && client.subscribed)
+            {
+                client.send(&message);
+            }
+        };
+
+        let board_ids = self.board_ids.borrow().clone();
+        for id in &watch.board_ids {
+            if !board_ids.contains(id) {
+                watch.matrices.remove(id);
+                notify(DaemonNotification::BoardRemoved(*id));
+            }
+        }
+        for id in &board_ids {
+            if !watch.board_ids.contains(id) {
+                notify(DaemonNotification::BoardAdded(*id));
+            }
+        }
+        watch.board_ids = board_ids;
+    }
+
+    /// Check for matrix changes, notifying subscribed clients that asked for
+    /// them
+    fn notify_matrices(&self, clients: &HashMap<ClientId, Client>, watch: &mut Watch) {
+        let interval = match Watch::matrix_interval(clients) {
+            Some(interval) => interval,
+            None => return,
+        };
+        let now = Instant::now();
+        if watch.next_matrix > now {
+            return;
+        }
+        watch.next_matrix = now + interval;
+
+        for id in &watch.board_ids {
+            // Boards without a matrix fail here
+            let matrix = match self.matrix_get(*id) {
+                Ok(matrix) => matrix,
+                Err(_) => continue,
+            };
+            if watch.matrices.get(id) != Some(&matrix) {
+                watch.matrices.insert(*id, matrix.clone());
+                let message =
+                    DaemonMessage::Notification(DaemonNotification::MatrixChanged(*id, matrix));
+                for client in clients
+                    .values()
+                    .filter(|client| client.subscribed && client.matrix_interval.is_some())
+                {
+                    client.send(&message);
+                }
+            }
+        }
+    }
+
+    fn board(&self, board: BoardId) -> Result<RefMut<Ec<Box<dyn Access>>>, Error> {
         let mut boards = self.boards.borrow_mut();
         if boards.get_mut(&board).is_some() {
-            Ok(RefMut::map(boards, |x| &mut x.get_mut(&board).unwrap().0))
+            Ok(RefMut::map(boards, |x| &mut x.get_mut(&board).unwrap().ec))
         } else {
-            Err("failed to find board".to_string())
+            Err(Error::NotFound("failed to find board".to_string()))
         }
     }
 }
 
 impl<R: Read + Send + 'static, W: Write + Send + 'static> Daemon for DaemonServer<R, W> {
-    fn boards(&self) -> Result<Vec<BoardId>, String> {
+    fn hello(&self, version: u32, capabilities: Vec<String>) -> Result<Hello, Error> {
+        info!(
+            "Client protocol version {}, capabilities: {:?}",
+            version, capabilities
+        );
+        Ok(Hello::current())
+    }
+
+    fn boards(&self) -> Result<Vec<BoardId>, Error> {
         Ok(self.board_ids.borrow().clone())
     }
 
-    fn model(&self, board: BoardId) -> Result<String, String> {
+    fn model(&self, board: BoardId) -> Result<String, Error> {
         let mut ec = self.board(board)?;
         let data_size = unsafe { ec.access().data_size() };
         let mut data = vec![0; data_size];
-        let len = unsafe { ec.board(&mut data).map_err(err_str)? };
-        let board = str::from_utf8(&data[..len]).map_err(err_str)?;
+        let len = unsafe { ec.board(&mut data)? };
+        let board = str::from_utf8(&data[..len])?;
         Ok(board.to_string())
     }
 
-    fn version(&self, board: BoardId) -> Result<String, String> {
+    fn version(&self, board: BoardId) -> Result<String, Error> {
         let mut ec = self.board(board)?;
         let data_size = unsafe { ec.access().data_size() };
         let mut data = vec![0; data_size];
-        let len = unsafe { ec.version(&mut data).map_err(err_str)? };
-        let version = str::from_utf8(&data[..len]).map_err(err_str)?;
+        let len = unsafe { ec.version(&mut data)? };
+        let version = str::from_utf8(&data[..len])?;
         Ok(version.to_string())
     }
 
-    fn keymap_get(&self, board: BoardId, layer: u8, output: u8, input: u8) -> Result<u16, String> {
+    fn serial(&self, board: BoardId) -> Result<Option<String>, Error> {
+        let boards = self.boards.borrow();
+        let board = boards
+            .get(&board)
+            .ok_or_else(|| Error::NotFound("failed to find board".to_string()))?;
+        Ok(board
+            .info
+            .as_ref()
+            .and_then(|info| info.serial_number())
+            .filter(|x| !x.is_empty())
+            .map(str::to_string))
+    }
+
+    fn stable_id(&self, board: BoardId) -> Result<String, Error> {
+        let boards = self.boards.borrow();
+        let board = boards
+            .get(&board)
+            .ok_or_else(|| Error::NotFound("failed to find board".to_string()))?;
+        Ok(board.stable_id.clone())
+    }
+
+    fn keymap_get(&self, board: BoardId, layer: u8, output: u8, input: u8) -> Result<u16, Error> {
         let mut ec = self.board(board)?;
-        unsafe { ec.keymap_get(layer, output, input).map_err(err_str) }
+        unsafe { ec.keymap_get(layer, output, input).map_err(Error::from) }
     }
 
     fn keymap_set(
@@ -159,44 +611,47 @@ impl<R: Read + Send + 'static, W: Write + Send + 'static> Daemon for DaemonServe
         output: u8,
         input: u8,
         value: u16,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         let mut ec = self.board(board)?;
-        unsafe { ec.keymap_set(layer, output, input, value).map_err(err_str) }
+        unsafe {
+            ec.keymap_set(layer, output, input, value)
+                .map_err(Error::from)
+        }
     }
 
-    fn matrix_get(&self, board: BoardId) -> Result<Matrix, String> {
+    fn matrix_get(&self, board: BoardId) -> Result<Matrix, Error> {
         let mut ec = self.board(board)?;
 
         let data_size = unsafe { ec.access().data_size() };
         let mut data = vec![0; data_size];
-        unsafe { ec.matrix_get(&mut data).map_err(err_str)? };
+        unsafe { ec.matrix_get(&mut data)? };
 
         let rows = data.remove(0) as usize;
         let cols = data.remove(0) as usize;
         Ok(Matrix::new(rows, cols, data.into_boxed_slice()))
     }
 
-    fn benchmark(&self, _board: BoardId) -> Result<Benchmark, String> {
-        Benchmark::new().map_err(err_str)
+    fn benchmark(&self, _board: BoardId) -> Result<Benchmark, Error> {
+        Benchmark::new().map_err(Error::from)
     }
 
-    fn nelson(&self, board: BoardId, kind: NelsonKind) -> Result<Nelson, String> {
+    fn nelson(&self, board: BoardId, kind: NelsonKind) -> Result<Nelson, Error> {
         if let Some(nelson) = &mut *self.nelson.borrow_mut() {
             const DELAY_MS: u64 = 200;
             info!("Nelson delay is {} ms", DELAY_MS);
             let delay = Duration::from_millis(DELAY_MS);
 
             // Check if Nelson is already closed
-            if unsafe { nelson.led_get_value(0).map_err(err_str)?.0 > 0 } {
+            if unsafe { nelson.led_get_value(0)?.0 > 0 } {
                 info!("Open Nelson");
-                unsafe { nelson.led_set_value(0, 0).map_err(err_str)? };
+                unsafe { nelson.led_set_value(0, 0)? };
 
                 info!("Sleep");
                 sleep(delay);
             }
 
             info!("Close Nelson");
-            unsafe { nelson.led_set_value(0, 1).map_err(err_str)? };
+            unsafe { nelson.led_set_value(0, 1)? };
 
             info!("Sleep");
             sleep(delay);
@@ -219,7 +674,7 @@ impl<R: Read + Send + 'static, W: Write + Send + 'static> Daemon for DaemonServe
             }
 
             info!("Open Nelson");
-            unsafe { nelson.led_set_value(0, 0).map_err(err_str)? };
+            unsafe { nelson.led_set_value(0, 0)? };
 
             info!("Sleep");
             sleep(delay);
@@ -233,24 +688,61 @@ impl<R: Read + Send + 'static, W: Write + Send + 'static> Daemon for DaemonServe
                 sticking,
             })
         } else {
-            Err("failed to find Nelson".to_string())
+            Err(Error::NotFound("failed to find Nelson".to_string()))
         }
     }
 
-    fn color(&self, board: BoardId, index: u8) -> Result<(u8, u8, u8), String> {
+    fn color(&self, board: BoardId, index: u8) -> Result<(u8, u8, u8), Error> {
         let mut ec = self.board(board)?;
-        unsafe { ec.led_get_color(index) }.map_err(err_str)
+        unsafe { ec.led_get_color(index) }.map_err(Error::from)
     }
 
-    fn set_color(&self, board: BoardId, index: u8, color: (u8, u8, u8)) -> Result<(), String> {
+    fn set_color(&self, board: BoardId, index: u8, color: (u8, u8, u8)) -> Result<(), Error> {
         let mut ec = self.board(board)?;
         unsafe {
             ec.led_set_color(index, color.0, color.1, color.2)
-                .map_err(err_str)
+                .map_err(Error::from)
+        }
+    }
+
+    fn keymap_get_batch(&self, board: BoardId, keys: Vec<(u8, u8, u8)>) -> Result<Vec<u16>, Error> {
+        let mut ec = self.board(board)?;
+        keys.iter()
+            .map(|&(layer, output, input)| unsafe {
+                ec.keymap_get(layer, output, input).map_err(Error::from)
+            })
+            .collect()
+    }
+
+    fn keymap_set_batch(&self, board: BoardId, keys: Vec<(u8, u8, u8, u16)>) -> Result<(), Error> {
+        let mut ec = self.board(board)?;
+        for (layer, output, input, value) in keys {
+            unsafe { ec.keymap_set(layer, output, input, value)? };
+        }
+        Ok(())
+    }
+
+    fn color_batch(&self, board: BoardId, indices: Vec<u8>) -> Result<Vec<(u8, u8, u8)>, Error> {
+        let mut ec = self.board(board)?;
+        indices
+            .iter()
+            .map(|&index| unsafe { ec.led_get_color(index).map_err(Error::from) })
+            .collect()
+    }
+
+    fn set_color_batch(
+        &self,
+        board: BoardId,
+        colors: Vec<(u8, (u8, u8, u8))>,
+    ) -> Result<(), Error> {
+        let mut ec = self.board(board)?;
+        for (index, (r, g, b)) in colors {
+            unsafe { ec.led_set_color(index, r, g, b)? };
         }
+        Ok(())
     }
 
-    fn max_brightness(&self, board: BoardId) -> Result<i32, String> {
+    fn max_brightness(&self, board: BoardId) -> Result<i32, Error> {
         let mut ec = self.board(board)?;
         let index = if unsafe { ec.access().is::<AccessHid>() } {
             0xf0
@@ -259,47 +751,50 @@ impl<R: Read + Send + 'static, W: Write + Send + 'static> Daemon for DaemonServe
         };
         unsafe { ec.led_get_value(index) }
             .map(|x| i32::from(x.1))
-            .map_err(err_str)
+            .map_err(Error::from)
     }
 
-    fn brightness(&self, board: BoardId, index: u8) -> Result<i32, String> {
+    fn brightness(&self, board: BoardId, index: u8) -> Result<i32, Error> {
         let mut ec = self.board(board)?;
         unsafe {
             ec.led_get_value(index)
                 .map(|x| i32::from(x.0))
-                .map_err(err_str)
+                .map_err(Error::from)
         }
     }
 
-    fn set_brightness(&self, board: BoardId, index: u8, brightness: i32) -> Result<(), String> {
+    fn set_brightness(&self, board: BoardId, index: u8, brightness: i32) -> Result<(), Error> {
         let mut ec = self.board(board)?;
-        unsafe { ec.led_set_value(index, brightness as u8).map_err(err_str) }
+        unsafe {
+            ec.led_set_value(index, brightness as u8)
+                .map_err(Error::from)
+        }
     }
 
-    fn mode(&self, board: BoardId, layer: u8) -> Result<(u8, u8), String> {
+    fn mode(&self, board: BoardId, layer: u8) -> Result<(u8, u8), Error> {
         let mut ec = self.board(board)?;
-        unsafe { ec.led_get_mode(layer).map_err(err_str) }
+        unsafe { ec.led_get_mode(layer).map_err(Error::from) }
     }
 
-    fn set_mode(&self, board: BoardId, layer: u8, mode: u8, speed: u8) -> Result<(), String> {
+    fn set_mode(&self, board: BoardId, layer: u8, mode: u8, speed: u8) -> Result<(), Error> {
         let mut ec = self.board(board)?;
-        unsafe { ec.led_set_mode(layer, mode, speed).map_err(err_str) }
+        unsafe { ec.led_set_mode(layer, mode, speed).map_err(Error::from) }
     }
 
-    fn led_save(&self, board: BoardId) -> Result<(), String> {
+    fn led_save(&self, board: BoardId) -> Result<(), Error> {
         let mut ec = self.board(board)?;
-        unsafe { ec.led_save().map_err(err_str) }
+        unsafe { ec.led_save().map_err(Error::from) }
     }
 
-    fn refresh(&self) -> Result<(), String> {
+    fn refresh(&self) -> Result<(), Error> {
         if let Some(api) = &mut *self.hidapi.borrow_mut() {
             // Remove USB boards that are no longer attached
             {
                 let mut boards = self.boards.borrow_mut();
                 let mut board_ids = self.board_ids.borrow_mut();
 
-                boards.retain(|_, (ec, _)| unsafe {
-                    !(ec.access().is::<AccessHid>() && ec.probe().is_err())
+                boards.retain(|_, board| unsafe {
+                    !(board.ec.access().is::<AccessHid>() && board.ec.probe().is_err())
                 });
                 board_ids.retain(|i| boards.contains_key(i));
             }
@@ -343,11 +838,12 @@ impl<R: Read + Send + 'static, W: Write + Send + 'static> Daemon for DaemonServe
                                 Ok(access) => match unsafe { Ec::new(access) } {
                                     Ok(ec) => {
                                         info!("Adding USB HID EC at {:?}", info.path());
-                                        let id = BoardId(Uuid::new_v4().as_u128());
-                                        self.boards
-                                            .borrow_mut()
-                                            .insert(id, (ec.into_dyn(), Some(info.clone())));
-                                        self.board_ids.borrow_mut().push(id);
+                                        let stable_id = self.hid_stable_id(info);
+                                        self.add_board(
+                                            ec.into_dyn(),
+                                            Some(info.clone()),
+                                            stable_id,
+                                        );
                                     }
                                     Err(err) => error!(
                                         "Failed to probe USB HID EC at {:?}: {:?}",
@@ -404,12 +900,17 @@ impl<R: Read + Send + 'static, W: Write + Send + 'static> Daemon for DaemonServe
         Ok(())
     }
 
-    fn set_no_input(&self, board: BoardId, no_input: bool) -> Result<(), String> {
+    fn set_no_input(&self, board: BoardId, no_input: bool) -> Result<(), Error> {
         let mut ec = self.board(board)?;
-        unsafe { ec.set_no_input(no_input) }.map_err(err_str)
+        unsafe { ec.set_no_input(no_input) }.map_err(Error::from)
     }
 
-    fn exit(&self) -> Result<(), String> {
+    fn subscribe(&self, _matrix_interval_ms: Option<u64>) -> Result<(), Error> {
+        // Subscriptions are per client, so handled by `handle_command`
+        Ok(())
+    }
+
+    fn exit(&self) -> Result<(), Error> {
         self.running.set(false);
         Ok(())
     }
@@ -425,3 +926,152 @@ fn is_qmk_raw_interface(interface: i32, usage_page: u16, usage: u16) -> bool {
         interface == 1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::test_output::Output;
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    /// Run a server with `input` from its client, returning the messages it
+    /// wrote after the greeting
+    fn serve(input: Vec<u8>) -> Vec<DaemonMessage> {
+        let output = Output::default();
+        DaemonServer::new_without_hardware(io::Cursor::new(input), output.clone())
+            .run()
+            .unwrap();
+        let output = String::from_utf8(output.contents()).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("Daemon started"));
+        lines
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    /// Number of commands in `input`, which don't need to end in a newline
+    fn count_lines(input: &[u8]) -> usize {
+        let newlines = input.iter().filter(|b| **b == b'\n').count();
+        newlines + usize::from(!input.is_empty() && input.last() != Some(&b'\n'))
+    }
+
+    fn command_json(command: DaemonCommand) -> String {
+        serde_json::to_string(&command).unwrap()
+    }
+
+    fn is_protocol_error(message: &DaemonMessage) -> bool {
+        matches!(message, DaemonMessage::Err(Error::Protocol(_)))
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let mut input = Vec::new();
+        for line in [
+            "",
+            "{",
+            "not json",
+            r#"{"t":"not_a_command","c":{}}"#,
+            r#"{"t":"model","c":{"board":"not a board"}}"#,
+        ] {
+            input.extend_from_slice(line.as_bytes());
+            input.push(b'\n');
+        }
+        input.extend_from_slice(b"\xff\xfe\n");
+        input.extend(vec![b' '; MAX_LINE_LENGTH + 10]);
+        input.push(b'\n');
+        // Still answered after all that, without a newline before EOF
+        input.extend_from_slice(command_json(DaemonCommand::boards {}).as_bytes());
+
+        let messages = serve(input);
+        assert_eq!(messages.len(), 8);
+        assert!(messages[..7].iter().all(is_protocol_error));
+        assert!(matches!(
+            messages[7],
+            DaemonMessage::Ok(DaemonResponse::boards(_))
+        ));
+    }
+
+    #[test]
+    fn max_line_length() {
+        // A command padded to exactly the limit is accepted
+        let mut line = command_json(DaemonCommand::boards {}).into_bytes();
+        line.resize(MAX_LINE_LENGTH, b' ');
+        line.push(b'\n');
+        let messages = serve(line.clone());
+        assert!(matches!(
+            messages[..],
+            [DaemonMessage::Ok(DaemonResponse::boards(_))]
+        ));
+
+        line.insert(0, b' ');
+        let messages = serve(line);
+        assert!(matches!(
+            messages[..],
+            [DaemonMessage::Err(Error::Protocol(_))]
+        ));
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(serve(Vec::new()).is_empty());
+    }
+
+    // Randomized input of bytes biased towards JSON syntax, from a fixed
+    // seed. Every line must get exactly one response, without the server
+    // panicking.
+    #[test]
+    fn random_bytes() {
+        const ALPHABET: &[u8] = b"{}[]\":,tc0123456789 \n\\";
+        let mut rng = StdRng::seed_from_u64(0x5376);
+        for _ in 0..100 {
+            let len = rng.gen_range(0..256);
+            let input = (0..len)
+                .map(|_| {
+                    if rng.gen_bool(0.5) {
+                        *ALPHABET.choose(&mut rng).unwrap()
+                    } else {
+                        rng.gen()
+                    }
+                })
+                .collect::<Vec<u8>>();
+            let messages = serve(input.clone());
+            assert_eq!(messages.len(), count_lines(&input), "{:?}", input);
+        }
+    }
+
+    // Valid commands randomly mixed with truncated ones get responses in
+    // order, with protocol errors for exactly the truncated ones
+    #[test]
+    fn truncated_commands() {
+        let mut rng = StdRng::seed_from_u64(0x5377);
+        for _ in 0..50 {
+            let mut input = String::new();
+            let mut truncated = Vec::new();
+            for _ in 0..rng.gen_range(0..20) {
+                let board = BoardId(rng.gen());
+                let mut line = match rng.gen_range(0..3) {
+                    0 => command_json(DaemonCommand::boards {}),
+                    1 => command_json(DaemonCommand::model { board }),
+                    _ => command_json(DaemonCommand::keymap_get {
+                        board,
+                        layer: rng.gen(),
+                        output: rng.gen(),
+                        input: rng.gen(),
+                    }),
+                };
+                let is_truncated = rng.gen_bool(0.5);
+                if is_truncated {
+                    line.truncate(rng.gen_range(0..line.len()));
+                }
+                truncated.push(is_truncated);
+                input.push_str(&line);
+                input.push('\n');
+            }
+
+            let messages = serve(input.clone().into_bytes());
+            assert_eq!(messages.len(), truncated.len(), "{}", input);
+            for (message, is_truncated) in messages.iter().zip(truncated) {
+                assert_eq!(is_protocol_error(message), is_truncated, "{}", input);
+            }
+        }
+    }
+}
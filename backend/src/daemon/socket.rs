@@ -0,0 +1,257 @@
+// Unix socket transport, so a single privileged daemon can be shared by the
+// GUI, the CLI and other tools run by the same user
+
+use std::{
+    fs,
+    io::{self, BufReader},
+    mem,
+    net::Shutdown,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::FileTypeExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+};
+
+use super::{
+    server::{read_client, write_client, ClientId, ClientMessage},
+    DaemonClient,
+};
+use crate::Error;
+
+/// Socket used by the daemon shared by user `uid`
+pub fn daemon_socket_path(uid: u32) -> PathBuf {
+    PathBuf::from(format!(
+        "/run/user/{}/system76-keyboard-configurator.sock",
+        uid
+    ))
+}
+
+/// User ID of the process on the other end of `stream`
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 {
+        Ok(cred.uid)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Create the daemon socket at `path`, accessible only by `uid`. Fails if
+/// another daemon is already listening on it.
+///
+/// The directory of `path` is writable by the user, so nothing here follows
+/// symlinks a user could swap in. Clients are still checked by `peer_uid`.
+pub fn bind_daemon_socket(path: &Path, uid: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ));
+        }
+        Ok(_) => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("daemon already running at {}", path.display()),
+                ));
+            }
+            // Remove socket left by a daemon that didn't exit cleanly
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    // Created with mode 0600, so there's no time it's accessible by others
+    let old_umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old_umask) };
+    let listener = listener?;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::fchownat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            uid,
+            u32::MAX,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+/// Accept connections from users in `allowed_uids`, forwarding their
+/// commands to `sender`
+pub(super) fn accept_clients(
+    listener: UnixListener,
+    allowed_uids: Vec<u32>,
+    sender: mpsc::Sender<ClientMessage>,
+) {
+    for (id, stream) in (1..).zip(listener.incoming()) {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Failed to accept daemon client: {}", err);
+                continue;
+            }
+        };
+        match peer_uid(&stream) {
+            Ok(uid) if allowed_uids.contains(&uid) => {
+                info!("Daemon client connected with uid {}", uid);
+            }
+            Ok(uid) => {
+                warn!("Refused daemon client with uid {}", uid);
+                continue;
+            }
+            Err(err) => {
+                error!("Failed to get credentials of daemon client: {}", err);
+                continue;
+            }
+        }
+        let sender = sender.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve_client(id, stream, sender) {
+                error!("Daemon client failed: {}", err);
+            }
+        });
+    }
+}
+
+fn serve_client(
+    id: ClientId,
+    stream: UnixStream,
+    sender: mpsc::Sender<ClientMessage>,
+) -> io::Result<()> {
+    let read = BufReader::new(stream.try_clone()?);
+    let (line_sender, line_receiver) = mpsc::channel();
+    if sender
+        .send(ClientMessage::Connected(id, line_sender))
+        .is_err()
+    {
+        return Ok(());
+    }
+    thread::spawn(move || read_client(id, read, &sender));
+
+    // Until the daemon drops the client, after `exit` or EOF
+    write_client(&stream, line_receiver)?;
+    // Also ends `read_client`
+    let _ = stream.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+impl DaemonClient {
+    /// Connect to a daemon listening on `path`, which must be run by root or
+    /// the current user
+    pub fn new_socket(path: &Path) -> Result<Self, Error> {
+        let stream = UnixStream::connect(path).map_err(|err| {
+            Error::Io(format!("Failed to connect to {}: {}", path.display(), err))
+        })?;
+        let uid = peer_uid(&stream)?;
+        if uid != 0 && uid != unsafe { libc::getuid() } {
+            return Err(Error::Io(format!(
+                "Daemon at {} is run by uid {}",
+                path.display(),
+                uid
+            )));
+        }
+        Self::new(stream.try_clone()?, stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{Daemon, DaemonServer};
+    use std::{
+        env,
+        os::unix::fs::{symlink, PermissionsExt},
+    };
+
+    fn listen(allowed_uids: Vec<u32>) -> PathBuf {
+        let path = env::temp_dir().join(format!("s76-daemon-{}.sock", uuid::Uuid::new_v4()));
+        let listener = bind_daemon_socket(&path, unsafe { libc::getuid() }).unwrap();
+        // Runs until the test process exits
+        thread::spawn(move || {
+            let server = DaemonServer::new_without_hardware(io::empty(), io::sink());
+            server.run_socket(listener, allowed_uids).unwrap();
+        });
+        path
+    }
+
+    #[test]
+    fn shared_daemon() {
+        let path = listen(vec![unsafe { libc::getuid() }]);
+        assert!(bind_daemon_socket(&path, 0).is_err());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let client1 = DaemonClient::new_socket(&path).unwrap();
+        let client2 = DaemonClient::new_socket(&path).unwrap();
+        assert_eq!(client1.boards(), client2.boards());
+
+        // `exit` only closes the connection of that client
+        drop(client1);
+        client2.refresh().unwrap();
+        drop(client2);
+        DaemonClient::new_socket(&path).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn symlink_not_followed() {
+        let id = uuid::Uuid::new_v4();
+        let target = env::temp_dir().join(format!("s76-daemon-{}", id));
+        let path = env::temp_dir().join(format!("s76-daemon-{}.sock", id));
+        fs::write(&target, "").unwrap();
+        symlink(&target, &path).unwrap();
+
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(
+            bind_daemon_socket(&path, uid).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert!(fs::symlink_metadata(&path)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(target.is_file());
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(target).unwrap();
+    }
+
+    #[test]
+    fn refused_uid() {
+        let path = listen(Vec::new());
+        assert!(DaemonClient::new_socket(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
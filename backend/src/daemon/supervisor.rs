@@ -0,0 +1,338 @@
+// Client that relaunches the daemon if it exits, such as when the pkexec
+// child is killed, so the GUI can reload instead of failing forever
+
+use futures::{channel::mpsc as async_mpsc, executor::block_on, StreamExt};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use super::{
+    BoardId, Daemon, DaemonClient, DaemonClientTrait, DaemonCommand, DaemonNotification,
+    DaemonResponse,
+};
+use crate::Error;
+
+type Relaunch = Box<dyn Fn() -> Result<DaemonClient, Error> + Send>;
+
+/// Boards get new `BoardId`s from a relaunched daemon, so callers keep using
+/// the ones they already know
+#[derive(Default)]
+struct BoardIds {
+    /// Caller's ID for boards of the current daemon, if it differs
+    caller_ids: HashMap<BoardId, BoardId>,
+    /// Identity of each board by caller's ID, to find it after a relaunch
+    identities: BTreeMap<BoardId, String>,
+}
+
+impl BoardIds {
+    fn caller_id(&self, id: BoardId) -> BoardId {
+        self.caller_ids.get(&id).copied().unwrap_or(id)
+    }
+
+    fn daemon_id(&self, id: BoardId) -> BoardId {
+        self.caller_ids
+            .iter()
+            .find(|(_, caller_id)| **caller_id == id)
+            .map_or(id, |(daemon_id, _)| *daemon_id)
+    }
+}
+
+pub struct DaemonSupervisor {
+    client: RefCell<DaemonClient>,
+    relaunch: Relaunch,
+    /// Set if relaunching failed, so the user isn't asked to authenticate
+    /// again for every command
+    relaunch_failed: Cell<bool>,
+    ids: Arc<Mutex<BoardIds>>,
+    /// Argument of the last `subscribe`, sent again after a relaunch
+    subscription: Cell<Option<Option<u64>>>,
+    notification_sender: async_mpsc::UnboundedSender<DaemonNotification>,
+    notifications: RefCell<Option<async_mpsc::UnboundedReceiver<DaemonNotification>>>,
+}
+
+impl DaemonSupervisor {
+    /// Supervise `client`, calling `relaunch` to connect to a new daemon if
+    /// it exits. The daemon thread is sent `DaemonNotification::Exited` when
+    /// it does, and relaunching is left to the next command.
+    pub fn new<F>(client: DaemonClient, relaunch: F) -> Self
+    where
+        F: Fn() -> Result<DaemonClient, Error> + Send + 'static,
+    {
+        let (notification_sender, notifications) = async_mpsc::unbounded();
+        let ids = Arc::new(Mutex::new(BoardIds::default()));
+        forward_notifications(&client, ids.clone(), notification_sender.clone());
+        Self {
+            client: RefCell::new(client),
+            relaunch: Box::new(relaunch),
+            relaunch_failed: Cell::new(false),
+            ids,
+            subscription: Cell::new(None),
+            notification_sender,
+            notifications: RefCell::new(Some(notifications)),
+        }
+    }
+
+    fn relaunch(&self) -> Result<(), Error> {
+        if self.relaunch_failed.get() {
+            return Err(Error::Io("Daemon exited".to_string()));
+        }
+        info!("Daemon exited, relaunching it");
+        let client = (self.relaunch)().map_err(|err| {
+            self.relaunch_failed.set(true);
+            err.context("Failed to relaunch daemon")
+        })?;
+
+        // Match boards of the new daemon to the old ones by identity, in
+        // order for boards that look the same
+        client.refresh()?;
+        let daemon_ids = client.boards()?;
+        {
+            let mut ids = self.ids.lock().unwrap();
+            let mut unmatched = ids.identities.clone();
+            ids.caller_ids.clear();
+            ids.identities.clear();
+            for daemon_id in daemon_ids {
+                let identity = match identity(&client, daemon_id) {
+                    Some(identity) => identity,
+                    None => continue,
+                };
+                let caller_id = unmatched
+                    .iter()
+                    .find(|(_, x)| **x == identity)
+                    .map(|(caller_id, _)| *caller_id);
+                let id = match caller_id {
+                    Some(caller_id) => {
+                        unmatched.remove(&caller_id);
+                        ids.caller_ids.insert(daemon_id, caller_id);
+                        caller_id
+                    }
+                    None => daemon_id,
+                };
+                ids.identities.insert(id, identity);
+            }
+        }
+
+        if let Some(matrix_interval_ms) = self.subscription.get() {
+            if let Err(err) = client.subscribe(matrix_interval_ms) {
+                error!("Failed to subscribe to relaunched daemon: {}", err);
+            }
+        }
+        forward_notifications(&client, self.ids.clone(), self.notification_sender.clone());
+        *self.client.borrow_mut() = client;
+        Ok(())
+    }
+
+    /// Remember what boards returned by `boards` are, to find them after a
+    /// relaunch
+    fn update_identities(&self, boards: &[BoardId]) {
+        let client = self.client.borrow();
+        let mut ids = self.ids.lock().unwrap();
+        ids.identities.retain(|id, _| boards.contains(id));
+        for id in boards {
+            if ids.identities.contains_key(id) {
+                continue;
+            }
+            if let Some(identity) = identity(&client, ids.daemon_id(*id)) {
+                ids.identities.insert(*id, identity);
+            }
+        }
+    }
+}
+
+/// What board `id` is: its stable ID, or its model and version if the daemon
+/// doesn't report one
+fn identity(client: &DaemonClient, id: BoardId) -> Option<String> {
+    if let Ok(stable_id) = client.stable_id(id) {
+        return Some(stable_id);
+    }
+    let model = client.model(id).ok()?;
+    let version = client.version(id).ok()?;
+    Some(format!("{} {}", model, version))
+}
+
+/// Forward notifications of `client`, with IDs mapped to the caller's, then
+/// `Exited` once it has no more
+fn forward_notifications(
+    client: &DaemonClient,
+    ids: Arc<Mutex<BoardIds>>,
+    sender: async_mpsc::UnboundedSender<DaemonNotification>,
+) {
+    let mut receiver = match client.take_notifications() {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    thread::spawn(move || {
+        block_on(async {
+            while let Some(mut notification) = receiver.next().await {
+                if let Some(board) = notification.board_mut() {
+                    *board = ids.lock().unwrap().caller_id(*board);
+                }
+                if sender.unbounded_send(notification).is_err() {
+                    return;
+                }
+            }
+            let _ = sender.unbounded_send(DaemonNotification::Exited);
+        })
+    });
+}
+
+impl DaemonClientTrait for DaemonSupervisor {
+    fn send_command(&self, mut command: DaemonCommand) -> Result<DaemonResponse, Error> {
+        if self.client.borrow().has_exited() {
+            // Don't start a daemon just to stop it
+            if let DaemonCommand::exit {} = command {
+                return Ok(DaemonResponse::exit(()));
+            }
+            self.relaunch()?;
+        }
+
+        if let Some(board) = command.board_mut() {
+            *board = self.ids.lock().unwrap().daemon_id(*board);
+        }
+        let subscription = match command {
+            DaemonCommand::subscribe { matrix_interval_ms } => Some(matrix_interval_ms),
+            _ => None,
+        };
+
+        let mut response = self.client.borrow().send_command(command)?;
+        if subscription.is_some() {
+            self.subscription.set(subscription);
+        }
+        if let DaemonResponse::boards(boards) = &mut response {
+            {
+                let ids = self.ids.lock().unwrap();
+                for board in boards.iter_mut() {
+                    *board = ids.caller_id(*board);
+                }
+            }
+            self.update_identities(boards);
+        }
+        Ok(response)
+    }
+
+    fn notification_receiver(&self) -> Option<async_mpsc::UnboundedReceiver<DaemonNotification>> {
+        self.notifications.borrow_mut().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{DaemonDummy, DaemonMessage};
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::Shutdown,
+        os::unix::net::UnixStream,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// Connect to a dummy daemon with `boards` in another thread, returning
+    /// its end of the connection to kill it with
+    fn spawn_dummy(boards: &[&str]) -> (DaemonClient, UnixStream) {
+        let daemon = DaemonDummy::new(boards.iter().map(|x| x.to_string()).collect()).unwrap();
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut write = server.try_clone().unwrap();
+        let server_clone = server.try_clone().unwrap();
+        thread::spawn(move || {
+            writeln!(write, "Daemon started").unwrap();
+            for line in BufReader::new(server).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let command = serde_json::from_str(&line).unwrap();
+                let message = DaemonMessage::from(daemon.dispatch_command_to_method(command));
+                let json = serde_json::to_string(&message).unwrap();
+                if writeln!(write, "{}", json).is_err() {
+                    break;
+                }
+            }
+        });
+        let client = DaemonClient::new(client.try_clone().unwrap(), client).unwrap();
+        (client, server_clone)
+    }
+
+    #[test]
+    fn relaunch() {
+        let (client, server) = spawn_dummy(&["system76/launch_1", "system76/launch_2"]);
+        let launches = Arc::new(AtomicUsize::new(0));
+        let supervisor = {
+            let launches = launches.clone();
+            DaemonSupervisor::new(client, move || {
+                launches.fetch_add(1, Ordering::SeqCst);
+                // Found in the opposite order, so their IDs are swapped
+                let (client, server) = spawn_dummy(&["system76/launch_2", "system76/launch_1"]);
+                // Leak the connection, to keep the daemon running
+                std::mem::forget(server);
+                Ok(client)
+            })
+        };
+        let mut notifications = supervisor.take_notifications().unwrap();
+        let mut boards = supervisor.boards().unwrap();
+        boards.sort();
+        let models = |supervisor: &DaemonSupervisor| {
+            boards
+                .iter()
+                .map(|id| supervisor.model(*id).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let before = models(&supervisor);
+
+        // Kill the daemon
+        server.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(
+            block_on(notifications.next()),
+            Some(DaemonNotification::Exited)
+        );
+        assert_eq!(launches.load(Ordering::SeqCst), 0);
+
+        // Relaunched by the next command, with the same IDs for each board
+        let mut relaunched_boards = supervisor.boards().unwrap();
+        relaunched_boards.sort();
+        assert_eq!(relaunched_boards, boards);
+        assert_eq!(launches.load(Ordering::SeqCst), 1);
+        assert_eq!(models(&supervisor), before);
+    }
+
+    #[test]
+    fn relaunch_failed() {
+        let (client, server) = spawn_dummy(&["system76/launch_1"]);
+        let launches = Arc::new(AtomicUsize::new(0));
+        let supervisor = {
+            let launches = launches.clone();
+            DaemonSupervisor::new(client, move || {
+                launches.fetch_add(1, Ordering::SeqCst);
+                Err(Error::Io("Authentication cancelled".to_string()))
+            })
+        };
+        let mut notifications = supervisor.take_notifications().unwrap();
+        server.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(
+            block_on(notifications.next()),
+            Some(DaemonNotification::Exited)
+        );
+
+        // Only asks once
+        assert!(supervisor.boards().is_err());
+        assert!(supervisor.boards().is_err());
+        assert_eq!(launches.load(Ordering::SeqCst), 1);
+        // Dropping doesn't launch a daemon to exit
+        drop(supervisor);
+        assert_eq!(launches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn board_ids() {
+        let mut ids = BoardIds::default();
+        let (old, new, other) = (BoardId(1), BoardId(2), BoardId(3));
+        ids.caller_ids.insert(new, old);
+        assert_eq!(ids.caller_id(new), old);
+        assert_eq!(ids.daemon_id(old), new);
+        // Boards found after the relaunch keep the daemon's ID
+        assert_eq!(ids.caller_id(other), other);
+        assert_eq!(ids.daemon_id(other), other);
+    }
+}
@@ -0,0 +1,29 @@
+// Writer for tests to check what a daemon or recorder wrote
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// Buffer shared by its clones, so output written by one can be read through
+/// another
+#[derive(Clone, Default)]
+pub(super) struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    /// Everything written so far
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, str};
+
+/// Error from the daemon, a board, or a key or layer of a board.
+///
+/// Serialized as part of the daemon protocol, so errors from a daemon running
+/// in another process keep their variant.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "t", content = "c")]
+pub enum Error {
+    /// Communication with the device or daemon failed, for instance because
+    /// the board was unplugged or timed out
+    Io(String),
+    /// The device or daemon sent an invalid or unexpected response
+    Protocol(String),
+    /// The board or daemon doesn't support the operation
+    Unsupported(String),
+    /// An argument, such as a scancode name or LED index, is invalid
+    InvalidArgument(String),
+    /// The board, layout or device doesn't exist
+    NotFound(String),
+}
+
+impl Error {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Io(message)
+            | Self::Protocol(message)
+            | Self::Unsupported(message)
+            | Self::InvalidArgument(message)
+            | Self::NotFound(message) => message,
+        }
+    }
+
+    /// Prefix the message with `context`, keeping the variant
+    pub(crate) fn context(self, context: &str) -> Self {
+        let add = |message: String| format!("{}: {}", context, message);
+        match self {
+            Self::Io(message) => Self::Io(add(message)),
+            Self::Protocol(message) => Self::Protocol(add(message)),
+            Self::Unsupported(message) => Self::Unsupported(add(message)),
+            Self::InvalidArgument(message) => Self::InvalidArgument(add(message)),
+            Self::NotFound(message) => Self::NotFound(add(message)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for String {
+    fn from(err: Error) -> Self {
+        err.to_string()
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::NotFound(err.to_string()),
+            _ => Self::Io(err.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        match err.classify() {
+            serde_json::error::Category::Io => Self::Io(err.to_string()),
+            _ => Self::Protocol(err.to_string()),
+        }
+    }
+}
+
+impl From<str::Utf8Error> for Error {
+    fn from(err: str::Utf8Error) -> Self {
+        Self::Protocol(err.to_string())
+    }
+}
+
+impl From<ectool::Error> for Error {
+    fn from(err: ectool::Error) -> Self {
+        use ectool::Error::*;
+        let message = format!("{:?}", err);
+        match err {
+            DataLength(_) | Parameter => Self::InvalidArgument(message),
+            NotSupported => Self::Unsupported(message),
+            Protocol(_) | Signature(_) | SuperIoId(_) | Verify | Version(_) => {
+                Self::Protocol(message)
+            }
+            Timeout | WouldBlock | Io(_) | Hid(_) => Self::Io(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{Daemon, DaemonCommand, DaemonDummy, DaemonResponse};
+
+    #[test]
+    fn serialize() {
+        let err = Error::NotFound("failed to find board".to_string());
+        let json = serde_json::to_string(&err).unwrap();
+        assert_eq!(json, r#"{"t":"NotFound","c":"failed to find board"}"#);
+        assert_eq!(serde_json::from_str::<Error>(&json).unwrap(), err);
+    }
+
+    #[test]
+    fn daemon_protocol() {
+        let daemon = DaemonDummy::new(vec!["system76/launch_1".to_string()]).unwrap();
+        let board = daemon.boards().unwrap()[0];
+        let response = daemon.dispatch_command_to_method(DaemonCommand::benchmark { board });
+        let json = serde_json::to_string(&response).unwrap();
+        match serde_json::from_str::<Result<DaemonResponse, Error>>(&json).unwrap() {
+            Err(Error::Unsupported(_)) => {}
+            _ => panic!("unexpected response: {}", json),
+        }
+    }
+
+    #[test]
+    fn from_ectool() {
+        assert!(matches!(
+            Error::from(ectool::Error::Timeout),
+            Error::Io(message) if message == "Timeout"
+        ));
+        assert!(matches!(
+            Error::from(ectool::Error::NotSupported),
+            Error::Unsupported(_)
+        ));
+        assert!(matches!(
+            Error::from(ectool::Error::Protocol(1)),
+            Error::Protocol(_)
+        ));
+    }
+
+    #[test]
+    fn context() {
+        let err = Error::Io("Timeout".to_string()).context("failed to get board model");
+        assert_eq!(
+            err,
+            Error::Io("failed to get board model: Timeout".to_string())
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::BTreeMap, fmt, fs, path::Path};
+
+use crate::Error;
+
+// Merge date of https://github.com/system76/ec/pull/229
+// Before this, `PAUSE` will not work.
+const EC_PAUSE_DATE: (u16, u16, u16) = (2022, 5, 23);
+// https://github.com/system76/ec/pull/263
+const EC_FNLOCK_DATE: (u16, u16, u16) = (2023, 8, 1);
+/// QMK releases with the mod-tap and layer-tap keycodes of `qmk_legacy.json`
+const QMK_LEGACY_VERSIONS: [(u16, u16, u16); 3] = [(0, 7, 103), (0, 7, 104), (0, 12, 20)];
+
+/// Manifest installed with the default firmware of each model
+pub const FIRMWARE_MANIFEST_PATH: &str = "/var/lib/system76-keyboard-configurator/firmware.json";
+
+/// Firmware version reported by a board
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FirmwareVersion {
+    /// System76 EC, such as `2022-05-23_7e1a2b1`
+    Ec {
+        date: (u16, u16, u16),
+        revision: String,
+    },
+    /// System76 QMK, such as `0.7.104` or `0.7.104-188-g7a655e530b` from
+    /// `git describe`. Suffixes after that, like `-dirty`, are ignored.
+    Qmk {
+        version: (u16, u16, u16),
+        /// Commits since the release
+        commits: u32,
+        revision: Option<String>,
+    },
+}
+
+impl FirmwareVersion {
+    /// Parse the version reported by the board, or `None` if it isn't in a
+    /// known format
+    pub fn parse(version: &str) -> Option<Self> {
+        static EC_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(\d+)-(\d+)-(\d+)_(\S+)$").unwrap());
+        static QMK_RE: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^(\d+)\.(\d+)\.(\d+)(?:-(\d+)-g([0-9a-f]+))?(?:-\S+)?$").unwrap()
+        });
+
+        let version = version.trim();
+        let number = |x: Option<regex::Match>| x?.as_str().parse().ok();
+        if let Some(captures) = EC_RE.captures(version) {
+            Some(Self::Ec {
+                date: (
+                    number(captures.get(1))?,
+                    number(captures.get(2))?,
+                    number(captures.get(3))?,
+                ),
+                revision: captures[4].to_string(),
+            })
+        } else if let Some(captures) = QMK_RE.captures(version) {
+            Some(Self::Qmk {
+                version: (
+                    number(captures.get(1))?,
+                    number(captures.get(2))?,
+                    number(captures.get(3))?,
+                ),
+                commits: captures
+                    .get(4)
+                    .map_or(Some(0), |x| x.as_str().parse().ok())?,
+                revision: captures.get(5).map(|x| x.as_str().to_string()),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the `PAUSE` scancode works
+    pub fn supports_pause(&self) -> bool {
+        match self {
+            Self::Ec { date, .. } => *date >= EC_PAUSE_DATE,
+            Self::Qmk { .. } => true,
+        }
+    }
+
+    /// Whether the `FNLOCK` scancode works
+    pub fn supports_fnlock(&self) -> bool {
+        match self {
+            Self::Ec { date, .. } => *date >= EC_FNLOCK_DATE,
+            Self::Qmk { .. } => false,
+        }
+    }
+
+    /// Whether mod-tap and layer-tap use the scancodes of older QMK releases
+    pub fn legacy_mod_tap(&self) -> bool {
+        match self {
+            Self::Ec { .. } => false,
+            Self::Qmk { version, .. } => QMK_LEGACY_VERSIONS.contains(version),
+        }
+    }
+
+    /// Whether the `PAUSE` scancode works on a board with the firmware
+    /// `version`, which is assumed to be new if unknown
+    pub(crate) fn has_pause(version: Option<&Self>, is_qmk: bool) -> bool {
+        is_qmk || version.map_or(true, Self::supports_pause)
+    }
+
+    /// Like `has_pause`, for the `FNLOCK` scancode
+    pub(crate) fn has_fnlock(version: Option<&Self>, is_qmk: bool) -> bool {
+        !is_qmk && version.map_or(true, Self::supports_fnlock)
+    }
+}
+
+impl PartialOrd for FirmwareVersion {
+    /// Versions of EC and QMK firmware, or builds with different revisions
+    /// from the same date or commit count, can't be compared
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let ordering = match (self, other) {
+            (Self::Ec { date: a, .. }, Self::Ec { date: b, .. }) => a.cmp(b),
+            (
+                Self::Qmk {
+                    version: a,
+                    commits: a_commits,
+                    ..
+                },
+                Self::Qmk {
+                    version: b,
+                    commits: b_commits,
+                    ..
+                },
+            ) => (a, a_commits).cmp(&(b, b_commits)),
+            _ => return None,
+        };
+        match ordering {
+            Ordering::Equal if self != other => None,
+            ordering => Some(ordering),
+        }
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ec {
+                date: (year, month, day),
+                revision,
+            } => write!(f, "{:04}-{:02}-{:02}_{}", year, month, day, revision),
+            Self::Qmk {
+                version: (major, minor, patch),
+                commits,
+                revision,
+            } => {
+                write!(f, "{}.{}.{}", major, minor, patch)?;
+                if let Some(revision) = revision {
+                    write!(f, "-{}-g{}", commits, revision)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Newest known firmware of a model
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FirmwareManifestEntry {
+    pub version: String,
+    /// Intel HEX file in the same directory as the manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// Newest known firmware of each model, by board name such as
+/// `system76/launch_1`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FirmwareManifest(pub BTreeMap<String, FirmwareManifestEntry>);
+
+impl FirmwareManifest {
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        serde_json::from_str(json).map_err(|err| {
+            Error::InvalidArgument(format!("Failed to parse firmware manifest: {}", err))
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).map_err(|err| {
+            Error::from(err).context(&format!("Failed to read {}", path.display()))
+        })?;
+        Self::from_json(&json).map_err(|err| err.context(&path.display().to_string()))
+    }
+
+    /// Manifest at `FIRMWARE_MANIFEST_PATH`, loaded once; empty if it isn't
+    /// installed
+    pub fn installed() -> &'static Self {
+        static MANIFEST: Lazy<FirmwareManifest> =
+            Lazy::new(|| match FirmwareManifest::load(FIRMWARE_MANIFEST_PATH) {
+                Ok(manifest) => manifest,
+                Err(Error::NotFound(_)) => FirmwareManifest::default(),
+                Err(err) => {
+                    error!("{}", err);
+                    FirmwareManifest::default()
+                }
+            });
+        &MANIFEST
+    }
+
+    pub fn latest(&self, board: &str) -> Option<FirmwareVersion> {
+        let entry = self.0.get(board)?;
+        let version = FirmwareVersion::parse(&entry.version);
+        if version.is_none() {
+            warn!(
+                "Invalid version '{}' for '{}' in firmware manifest",
+                entry.version, board
+            );
+        }
+        version
+    }
+}
+
+/// Firmware of a board, compared to the newest in the manifest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirmwareStatus {
+    /// `None` if the version isn't in a known format
+    pub current: Option<FirmwareVersion>,
+    /// `None` if the model isn't in the manifest
+    pub latest: Option<FirmwareVersion>,
+    /// Whether the `PAUSE` scancode works
+    pub has_pause: bool,
+    /// Whether the `FNLOCK` scancode works
+    pub has_fnlock: bool,
+}
+
+impl FirmwareStatus {
+    pub fn new(board: &str, version: &str, is_qmk: bool, manifest: &FirmwareManifest) -> Self {
+        let current = FirmwareVersion::parse(version);
+        Self {
+            latest: manifest.latest(board),
+            has_pause: FirmwareVersion::has_pause(current.as_ref(), is_qmk),
+            has_fnlock: FirmwareVersion::has_fnlock(current.as_ref(), is_qmk),
+            current,
+        }
+    }
+
+    /// Whether the manifest has newer firmware than the board. `false` if
+    /// either version is unknown.
+    pub fn update_available(&self) -> bool {
+        match (&self.current, &self.latest) {
+            (Some(current), Some(latest)) => current < latest,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qmk(version: (u16, u16, u16), commits: u32, revision: &str) -> FirmwareVersion {
+        FirmwareVersion::Qmk {
+            version,
+            commits,
+            revision: Some(revision.to_string()),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            FirmwareVersion::parse("2022-05-23_7e1a2b1"),
+            Some(FirmwareVersion::Ec {
+                date: (2022, 5, 23),
+                revision: "7e1a2b1".to_string(),
+            })
+        );
+        assert_eq!(
+            FirmwareVersion::parse("0.7.104-188-g7a655e530b"),
+            Some(qmk((0, 7, 104), 188, "7a655e530b"))
+        );
+        assert_eq!(
+            FirmwareVersion::parse("0.12.20"),
+            Some(FirmwareVersion::Qmk {
+                version: (0, 12, 20),
+                commits: 0,
+                revision: None,
+            })
+        );
+        assert_eq!(
+            FirmwareVersion::parse("0.7.103-4011-g708ed5-dirty"),
+            Some(qmk((0, 7, 103), 4011, "708ed5"))
+        );
+        assert_eq!(
+            FirmwareVersion::parse("0.12.20-dirty"),
+            FirmwareVersion::parse("0.12.20")
+        );
+        for version in ["", "1970-01-01-deadbee", "0.7", "v0.7.104", "2022-05-23"] {
+            assert_eq!(FirmwareVersion::parse(version), None, "{}", version);
+        }
+        for version in ["2022-05-23_7e1a2b1", "0.7.104-188-g7a655e530b", "0.12.20"] {
+            let parsed = FirmwareVersion::parse(version).unwrap();
+            assert_eq!(parsed.to_string(), version);
+        }
+    }
+
+    #[test]
+    fn features() {
+        // (version, supports_pause, supports_fnlock, legacy_mod_tap)
+        for (version, features) in [
+            // EC
+            ("2021-03-02_ad7ef8b", Some((false, false, false))),
+            ("2022-05-22_93cbb3c", Some((false, false, false))),
+            ("2022-05-23_7e1a2b1", Some((true, false, false))),
+            ("2023-07-31_f0b0d6f", Some((true, false, false))),
+            ("2023-08-01_8c5f7d6", Some((true, true, false))),
+            ("2024-02-14_5c0f4b2", Some((true, true, false))),
+            // QMK releases
+            ("0.7.103", Some((true, false, true))),
+            ("0.7.104", Some((true, false, true))),
+            ("0.12.20", Some((true, false, true))),
+            ("0.19.12", Some((true, false, false))),
+            // QMK from `git describe`, as in `data/*.hex`
+            ("0.7.103-4011-g708ed5", Some((true, false, true))),
+            ("0.7.103-4026-g7fd2f5", Some((true, false, true))),
+            ("0.7.104-188-g7a655e530b", Some((true, false, true))),
+            // Not a known format, such as from the dummy daemon
+            ("1970-01-01-deadbee", None),
+            ("dummy", None),
+            ("", None),
+        ] {
+            let parsed = FirmwareVersion::parse(version);
+            let parsed_features = parsed
+                .as_ref()
+                .map(|x| (x.supports_pause(), x.supports_fnlock(), x.legacy_mod_tap()));
+            assert_eq!(parsed_features, features, "{}", version);
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        let sorted = [
+            "0.7.103",
+            "0.7.103-4011-g708ed5",
+            "0.7.103-4026-g7fd2f5",
+            "0.7.104",
+            "0.7.104-188-g7a655e530b",
+            "0.12.20",
+            "0.19.12",
+        ];
+        for (i, a) in sorted.iter().enumerate() {
+            for (j, b) in sorted.iter().enumerate() {
+                let a = FirmwareVersion::parse(a).unwrap();
+                let b = FirmwareVersion::parse(b).unwrap();
+                assert_eq!(a.partial_cmp(&b), Some(i.cmp(&j)), "{} {}", a, b);
+            }
+        }
+
+        let ec = |x| FirmwareVersion::parse(x).unwrap();
+        assert!(ec("2022-05-22_93cbb3c") < ec("2022-05-23_7e1a2b1"));
+        assert!(ec("2023-08-01_8c5f7d6") > ec("2022-05-23_7e1a2b1"));
+        // Different builds from the same day, or EC and QMK
+        assert_eq!(
+            ec("2022-05-23_7e1a2b1").partial_cmp(&ec("2022-05-23_93cbb3c")),
+            None
+        );
+        assert_eq!(ec("2022-05-23_7e1a2b1").partial_cmp(&ec("0.19.12")), None);
+    }
+
+    #[test]
+    fn manifest() {
+        let manifest = FirmwareManifest::from_json(
+            r#"{
+                "system76/launch_1": { "version": "0.7.103-4011-g708ed5" },
+                "system76/darp6": { "version": "2023-08-01_0123456" }
+            }"#,
+        )
+        .unwrap();
+
+        let status =
+            FirmwareStatus::new("system76/launch_1", "0.7.103-10-gabcdef", true, &manifest);
+        assert!(status.update_available());
+        assert!(status.has_pause && !status.has_fnlock);
+        let status = FirmwareStatus::new("system76/launch_1", "0.19.12", true, &manifest);
+        assert!(!status.update_available());
+
+        let status = FirmwareStatus::new("system76/darp6", "2022-01-01_7e1a2b1", false, &manifest);
+        assert!(status.update_available());
+        assert!(!status.has_pause && !status.has_fnlock);
+
+        // Unknown model or version
+        let status = FirmwareStatus::new("system76/launch_2", "0.7.104", true, &manifest);
+        assert_eq!(status.latest, None);
+        assert!(!status.update_available());
+        let status = FirmwareStatus::new("system76/darp6", "1970-01-01-deadbee", false, &manifest);
+        assert_eq!(status.current, None);
+        assert!(!status.update_available());
+        assert!(status.has_pause && status.has_fnlock);
+    }
+
+    #[test]
+    fn manifest_errors() {
+        assert!(matches!(
+            FirmwareManifest::from_json("{"),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            FirmwareManifest::load("../data/not-a-manifest.json"),
+            Err(Error::NotFound(message)) if message.starts_with("Failed to read ../data/not-a-manifest.json")
+        ));
+    }
+
+    #[test]
+    fn installed_manifest() {
+        let manifest = FirmwareManifest::load("../data/firmware.json").unwrap();
+        for (board, entry) in &manifest.0 {
+            assert!(manifest.latest(board).is_some(), "{}", board);
+            let file = entry.file.as_ref().unwrap();
+            assert!(Path::new("../data").join(file).exists(), "{}", file);
+        }
+    }
+}
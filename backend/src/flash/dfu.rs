@@ -0,0 +1,277 @@
+// Atmel's DFU bootloader protocol, as used by dfu-programmer
+
+use std::{thread, time::Duration};
+
+use super::{Firmware, FlashProgress};
+use crate::{Bootloaded, Error};
+
+// DFU class requests
+pub(crate) const DFU_DNLOAD: u8 = 1;
+pub(crate) const DFU_UPLOAD: u8 = 2;
+pub(crate) const DFU_GETSTATUS: u8 = 3;
+pub(crate) const DFU_CLRSTATUS: u8 = 4;
+
+// DFU status and states
+pub(crate) const STATUS_OK: u8 = 0x00;
+pub(crate) const STATE_DFU_DNBUSY: u8 = 4;
+pub(crate) const STATE_DFU_ERROR: u8 = 10;
+
+// Atmel commands, sent as the first bytes of a download
+pub(crate) const CMD_PROGRAM: u8 = 0x01;
+pub(crate) const CMD_READ: u8 = 0x03;
+pub(crate) const CMD_WRITE: u8 = 0x04;
+
+/// Size of the header before the data of a program command
+pub(crate) const HEADER_SIZE: usize = 32;
+/// Size of the DFU suffix after the data of a program command
+pub(crate) const FOOTER_SIZE: usize = 16;
+/// Largest number of bytes programmed or read by one command
+pub(crate) const MAX_TRANSFER_SIZE: usize = 0x400;
+
+/// Response to `DFU_GETSTATUS`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DfuStatus {
+    pub status: u8,
+    pub poll_timeout: Duration,
+    pub state: u8,
+}
+
+impl DfuStatus {
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != 6 {
+            return Err(Error::Protocol(format!(
+                "DFU status has {} bytes, expected 6",
+                bytes.len()
+            )));
+        }
+        let poll_timeout = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], 0]);
+        Ok(Self {
+            status: bytes[0],
+            poll_timeout: Duration::from_millis(poll_timeout.into()),
+            state: bytes[4],
+        })
+    }
+}
+
+/// Class requests to the interface of a device in DFU mode, implemented by
+/// USB devices and by the simulated device used in tests
+pub trait DfuTransport {
+    fn download(&mut self, block: u16, data: &[u8]) -> Result<(), Error>;
+    fn upload(&mut self, block: u16, length: usize) -> Result<Vec<u8>, Error>;
+    fn get_status(&mut self) -> Result<DfuStatus, Error>;
+    fn clear_status(&mut self) -> Result<(), Error>;
+}
+
+impl<T: DfuTransport + ?Sized> DfuTransport for &mut T {
+    fn download(&mut self, block: u16, data: &[u8]) -> Result<(), Error> {
+        (**self).download(block, data)
+    }
+
+    fn upload(&mut self, block: u16, length: usize) -> Result<Vec<u8>, Error> {
+        (**self).upload(block, length)
+    }
+
+    fn get_status(&mut self) -> Result<DfuStatus, Error> {
+        (**self).get_status()
+    }
+
+    fn clear_status(&mut self) -> Result<(), Error> {
+        (**self).clear_status()
+    }
+}
+
+/// Flash memory of a microcontroller
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Chip {
+    pub flash_size: u32,
+    /// The bootloader at the top of flash can't be written
+    pub bootloader_size: u32,
+}
+
+impl Chip {
+    pub fn for_bootloaded(kind: Bootloaded) -> Self {
+        match kind {
+            Bootloaded::At90usb646 | Bootloaded::At90usb646Lite => Self {
+                flash_size: 0x10000,
+                bootloader_size: 0x2000,
+            },
+            Bootloaded::AtMega32u4 => Self {
+                flash_size: 0x8000,
+                bootloader_size: 0x1000,
+            },
+        }
+    }
+
+    pub fn bootloader_start(&self) -> u32 {
+        self.flash_size - self.bootloader_size
+    }
+}
+
+pub(crate) struct AtmelDfu<T: DfuTransport> {
+    transport: T,
+    block: u16,
+}
+
+impl<T: DfuTransport> AtmelDfu<T> {
+    pub fn new(mut transport: T) -> Result<Self, Error> {
+        // Left in the error state by an earlier failure
+        if transport.get_status()?.state == STATE_DFU_ERROR {
+            transport.clear_status()?;
+        }
+        Ok(Self {
+            transport,
+            block: 0,
+        })
+    }
+
+    fn download(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.transport.download(self.block, data)?;
+        self.block = self.block.wrapping_add(1);
+        self.wait()
+    }
+
+    /// Wait until the device is done with the last download
+    fn wait(&mut self) -> Result<(), Error> {
+        loop {
+            let status = self.transport.get_status()?;
+            if status.status != STATUS_OK {
+                let _ = self.transport.clear_status();
+                return Err(Error::Protocol(format!(
+                    "DFU device failed with status {:#04x}",
+                    status.status
+                )));
+            }
+            if status.state != STATE_DFU_DNBUSY {
+                return Ok(());
+            }
+            thread::sleep(status.poll_timeout);
+        }
+    }
+
+    pub fn erase(&mut self) -> Result<(), Error> {
+        self.download(&[CMD_WRITE, 0x00, 0xFF])
+    }
+
+    /// Program at most `MAX_TRANSFER_SIZE` bytes
+    pub fn program(&mut self, address: u16, data: &[u8]) -> Result<(), Error> {
+        let end = address + data.len() as u16 - 1;
+        let mut message = vec![0; HEADER_SIZE + data.len() + FOOTER_SIZE];
+        message[..6].copy_from_slice(&[
+            CMD_PROGRAM,
+            0x00,
+            (address >> 8) as u8,
+            address as u8,
+            (end >> 8) as u8,
+            end as u8,
+        ]);
+        message[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
+        // DFU suffix, with IDs that match any device; the bootloader ignores
+        // the CRC
+        message[HEADER_SIZE + data.len()..].copy_from_slice(&[
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x10, 0x01, b'U', b'F', b'D', 16, 0, 0, 0, 0,
+        ]);
+        self.download(&message)
+    }
+
+    /// Read at most `MAX_TRANSFER_SIZE` bytes
+    pub fn read(&mut self, address: u16, length: usize) -> Result<Vec<u8>, Error> {
+        let end = address + length as u16 - 1;
+        self.download(&[
+            CMD_READ,
+            0x00,
+            (address >> 8) as u8,
+            address as u8,
+            (end >> 8) as u8,
+            end as u8,
+        ])?;
+        let data = self.transport.upload(self.block, length)?;
+        self.block = self.block.wrapping_add(1);
+        if data.len() != length {
+            return Err(Error::Protocol(format!(
+                "DFU device sent {} bytes, expected {}",
+                data.len(),
+                length
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Reset into the application; the device disconnects afterwards
+    pub fn start_app(&mut self) -> Result<(), Error> {
+        self.download(&[CMD_WRITE, 0x03, 0x00])?;
+        // Zero length download to trigger the reset. The device may
+        // disconnect before acknowledging it.
+        if let Err(err) = self.transport.download(self.block, &[]) {
+            debug!("Reset of DFU device not acknowledged: {}", err);
+        }
+        Ok(())
+    }
+}
+
+/// Erase, write and verify `firmware`, then start it. Data for the bootloader
+/// section is skipped, like `--suppress-bootloader-mem` of dfu-programmer.
+pub(crate) fn flash<T: DfuTransport>(
+    transport: T,
+    kind: Bootloaded,
+    firmware: &Firmware,
+    progress: &mut dyn FnMut(FlashProgress),
+) -> Result<(), Error> {
+    let chip = Chip::for_bootloaded(kind);
+    let firmware_end = firmware.segments().last().map_or(0, |x| x.end());
+    if firmware_end > chip.flash_size {
+        return Err(Error::InvalidArgument(format!(
+            "Firmware ends at {:#x}, past the end of flash at {:#x}",
+            firmware_end, chip.flash_size
+        )));
+    }
+    let firmware = firmware.clip(0, chip.bootloader_start());
+    if firmware.is_empty() {
+        return Err(Error::InvalidArgument("Firmware is empty".to_string()));
+    }
+
+    // Split into transfers, in the address space of the 64 KiB page
+    let mut chunks = Vec::new();
+    for segment in firmware.segments() {
+        for (i, data) in segment.data.chunks(MAX_TRANSFER_SIZE).enumerate() {
+            let address = segment.address as usize + i * MAX_TRANSFER_SIZE;
+            chunks.push((address as u16, data));
+        }
+    }
+    let total = firmware.len();
+
+    let mut dfu = AtmelDfu::new(transport)?;
+
+    progress(FlashProgress::Erasing);
+    dfu.erase()
+        .map_err(|err| err.context("Failed to erase flash"))?;
+
+    let mut done = 0;
+    progress(FlashProgress::Writing { done, total });
+    for (address, data) in &chunks {
+        dfu.program(*address, data)
+            .map_err(|err| err.context(&format!("Failed to write at {:#06x}", address)))?;
+        done += data.len();
+        progress(FlashProgress::Writing { done, total });
+    }
+
+    let mut done = 0;
+    progress(FlashProgress::Verifying { done, total });
+    for (address, data) in &chunks {
+        let read = dfu
+            .read(*address, data.len())
+            .map_err(|err| err.context(&format!("Failed to read at {:#06x}", address)))?;
+        if let Some(i) = read.iter().zip(data.iter()).position(|(a, b)| a != b) {
+            return Err(Error::Protocol(format!(
+                "Verification failed at {:#06x}",
+                *address as usize + i
+            )));
+        }
+        done += data.len();
+        progress(FlashProgress::Verifying { done, total });
+    }
+
+    dfu.start_app()
+        .map_err(|err| err.context("Failed to start firmware"))?;
+    progress(FlashProgress::Done);
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use std::{fs, path::Path};
+
+use crate::Error;
+
+/// Contiguous bytes of firmware, starting at `address`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+}
+
+/// Firmware image loaded from an Intel HEX file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Firmware {
+    /// Sorted by address, not overlapping or adjacent
+    segments: Vec<Segment>,
+}
+
+impl Firmware {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let hex = fs::read_to_string(path).map_err(|err| {
+            Error::from(err).context(&format!("Failed to read {}", path.display()))
+        })?;
+        Self::parse(&hex).map_err(|err| err.context(&format!("Failed to parse {}", path.display())))
+    }
+
+    /// Parse data, end of file and address records, ignoring the start
+    /// address, which the bootloader doesn't need
+    pub fn parse(hex: &str) -> Result<Self, Error> {
+        let mut firmware = Self::default();
+        let mut base = 0;
+        for (i, line) in hex.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = Record::parse(line)
+                .map_err(|err| Error::InvalidArgument(format!("line {}: {}", i + 1, err)))?;
+            let address_data = |len| {
+                if record.data.len() == len {
+                    Ok(u32::from(u16::from_be_bytes([
+                        record.data[0],
+                        record.data[1],
+                    ])))
+                } else {
+                    Err(Error::InvalidArgument(format!(
+                        "line {}: address record with {} bytes",
+                        i + 1,
+                        record.data.len()
+                    )))
+                }
+            };
+            match record.kind {
+                0x00 => firmware.insert(base + u32::from(record.address), &record.data),
+                0x01 => return Ok(firmware),
+                0x02 => base = address_data(2)? << 4,
+                0x04 => base = address_data(2)? << 16,
+                0x03 | 0x05 => {}
+                kind => {
+                    return Err(Error::InvalidArgument(format!(
+                        "line {}: unknown record type {:02X}",
+                        i + 1,
+                        kind
+                    )))
+                }
+            }
+        }
+        Err(Error::InvalidArgument(
+            "missing end of file record".to_string(),
+        ))
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Firmware with only the bytes in `start..end`
+    pub fn clip(&self, start: u32, end: u32) -> Self {
+        let mut firmware = Self::default();
+        for segment in &self.segments {
+            let clip_start = segment.address.clamp(start, end);
+            let clip_end = segment.end().clamp(start, end);
+            if clip_start < clip_end {
+                let offset = (clip_start - segment.address) as usize;
+                let len = (clip_end - clip_start) as usize;
+                firmware.insert(clip_start, &segment.data[offset..offset + len]);
+            }
+        }
+        firmware
+    }
+
+    /// Total number of bytes
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|x| x.data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Add `data` at `address`, replacing any bytes already there
+    fn insert(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = address + data.len() as u32;
+
+        // Segments overlapping or touching the new bytes become one segment
+        let (touching, mut segments): (Vec<_>, Vec<_>) = self
+            .segments
+            .drain(..)
+            .partition(|x| x.address <= end && x.end() >= address);
+        let start = touching.iter().map(|x| x.address).fold(address, u32::min);
+        let merged_end = touching.iter().map(Segment::end).fold(end, u32::max);
+        let mut merged = vec![0xFF; (merged_end - start) as usize];
+        for segment in &touching {
+            let offset = (segment.address - start) as usize;
+            merged[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        let offset = (address - start) as usize;
+        merged[offset..offset + data.len()].copy_from_slice(data);
+
+        segments.push(Segment {
+            address: start,
+            data: merged,
+        });
+        segments.sort_by_key(|x| x.address);
+        self.segments = segments;
+    }
+}
+
+struct Record {
+    kind: u8,
+    address: u16,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn parse(line: &str) -> Result<Self, String> {
+        let hex = line
+            .strip_prefix(':')
+            .ok_or_else(|| "missing ':'".to_string())?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err("invalid hex digits".to_string());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| "invalid hex digits".to_string())?;
+        if bytes.len() < 5 || bytes.len() != 5 + usize::from(bytes[0]) {
+            return Err("invalid record length".to_string());
+        }
+        if bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) != 0 {
+            return Err("invalid checksum".to_string());
+        }
+        Ok(Self {
+            kind: bytes[3],
+            address: u16::from_be_bytes([bytes[1], bytes[2]]),
+            data: bytes[4..bytes.len() - 1].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let firmware = Firmware::parse(
+            ":0400000001020304F2\n\
+             :02000004000AF0\n\
+             :02000000AABB99\n\
+             :00000001FF\n",
+        )
+        .unwrap();
+        assert_eq!(
+            firmware.segments(),
+            &[
+                Segment {
+                    address: 0,
+                    data: vec![1, 2, 3, 4],
+                },
+                Segment {
+                    address: 0xA0000,
+                    data: vec![0xAA, 0xBB],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for hex in [
+            // Bad checksum
+            ":0400000001020304F3\n:00000001FF\n",
+            // Length doesn't match
+            ":0500000001020304F2\n:00000001FF\n",
+            ":0400000001020304F2\n:00000001F\n",
+            "0400000001020304F2\n:00000001FF\n",
+            ":0400000001020304F2\n",
+            ":0400000601020304EC\n:00000001FF\n",
+        ] {
+            assert!(
+                matches!(Firmware::parse(hex), Err(Error::InvalidArgument(_))),
+                "{}",
+                hex
+            );
+        }
+    }
+
+    #[test]
+    fn insert() {
+        let mut firmware = Firmware::default();
+        firmware.insert(4, &[4, 5]);
+        firmware.insert(10, &[10]);
+        firmware.insert(0, &[0, 1]);
+        firmware.insert(2, &[2, 3]);
+        firmware.insert(5, &[0xAA, 6]);
+        assert_eq!(
+            firmware.segments(),
+            &[
+                Segment {
+                    address: 0,
+                    data: vec![0, 1, 2, 3, 4, 0xAA, 6],
+                },
+                Segment {
+                    address: 10,
+                    data: vec![10],
+                },
+            ]
+        );
+        assert_eq!(firmware.len(), 8);
+
+        let clipped = firmware.clip(3, 10);
+        assert_eq!(
+            clipped.segments(),
+            &[Segment {
+                address: 3,
+                data: vec![3, 4, 0xAA, 6],
+            }]
+        );
+    }
+
+    #[test]
+    fn default_firmware() {
+        for name in ["launch_1", "launch_2", "launch_heavy_1", "launch_lite_1"] {
+            let path = format!("../data/system76_{}_default.hex", name);
+            let firmware = Firmware::from_file(path).unwrap();
+            assert_eq!(firmware.segments()[0].address, 0);
+            assert!(firmware.len() > 0x4000);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// Flashing firmware to boards in bootloader mode, without dfu-programmer
+
+#[cfg(target_os = "linux")]
+use std::path::Path;
+
+use crate::{BootloadedDevice, Error};
+
+mod dfu;
+mod ihex;
+#[cfg(test)]
+mod simulated;
+#[cfg(target_os = "linux")]
+mod usbfs;
+
+pub use self::ihex::{Firmware, Segment};
+
+/// Progress of flashing a board, with `done` of `total` bytes written or
+/// verified
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlashProgress {
+    Erasing,
+    Writing {
+        done: usize,
+        total: usize,
+    },
+    Verifying {
+        done: usize,
+        total: usize,
+    },
+    /// The board was reset into the new firmware
+    Done,
+    Failed(Error),
+}
+
+/// Erase, write and verify `firmware` on `device`, then start it, calling
+/// `progress` after each step. Blocks until done.
+#[cfg(target_os = "linux")]
+pub fn flash_bootloaded(
+    device: &BootloadedDevice,
+    firmware: &Firmware,
+    mut progress: impl FnMut(FlashProgress),
+) -> Result<(), Error> {
+    let transport = usbfs::UsbfsDfu::open(Path::new("/sys"), device)?;
+    dfu::flash(transport, device.kind, firmware, &mut progress)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn flash_bootloaded(
+    _device: &BootloadedDevice,
+    _firmware: &Firmware,
+    _progress: impl FnMut(FlashProgress),
+) -> Result<(), Error> {
+    Err(Error::Unsupported(
+        "Flashing is only supported on Linux".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dfu::*, simulated::SimulatedDfu, *};
+    use crate::Bootloaded;
+
+    fn flash_simulated(
+        device: &mut SimulatedDfu,
+        kind: Bootloaded,
+        firmware: &Firmware,
+    ) -> (Result<(), Error>, Vec<FlashProgress>) {
+        let mut events = Vec::new();
+        let res = flash(device, kind, firmware, &mut |x| events.push(x));
+        (res, events)
+    }
+
+    #[test]
+    fn default_firmware() {
+        for (kind, name) in [
+            (Bootloaded::AtMega32u4, "launch_1"),
+            (Bootloaded::At90usb646, "launch_2"),
+            (Bootloaded::At90usb646, "launch_heavy_1"),
+            (Bootloaded::At90usb646Lite, "launch_lite_1"),
+        ] {
+            let path = format!("../data/system76_{}_default.hex", name);
+            let firmware = Firmware::from_file(path).unwrap();
+            let chip = Chip::for_bootloaded(kind);
+            let mut device = SimulatedDfu::new(chip);
+            let (res, events) = flash_simulated(&mut device, kind, &firmware);
+            assert_eq!(res, Ok(()), "{}", name);
+            assert!(device.erased && device.started);
+
+            // Everything but the bootloader section was written
+            let app = firmware.clip(0, chip.bootloader_start());
+            for segment in app.segments() {
+                let start = segment.address as usize;
+                assert_eq!(
+                    &device.flash[start..start + segment.data.len()],
+                    &segment.data
+                );
+            }
+            assert!(device.flash[chip.bootloader_start() as usize..]
+                .iter()
+                .all(|x| *x == 0x5A));
+
+            let total = app.len();
+            assert_eq!(events[0], FlashProgress::Erasing);
+            assert_eq!(events[1], FlashProgress::Writing { done: 0, total });
+            assert!(events.contains(&FlashProgress::Writing { done: total, total }));
+            assert!(events.contains(&FlashProgress::Verifying { done: total, total }));
+            assert_eq!(events.last(), Some(&FlashProgress::Done));
+        }
+    }
+
+    #[test]
+    fn verify_failed() {
+        let firmware = Firmware::parse(":0400100001020304E2\n:00000001FF\n").unwrap();
+        let mut device = SimulatedDfu::new(Chip::for_bootloaded(Bootloaded::AtMega32u4));
+        device.corrupt = Some(0x12);
+        let (res, events) = flash_simulated(&mut device, Bootloaded::AtMega32u4, &firmware);
+        assert_eq!(
+            res,
+            Err(Error::Protocol("Verification failed at 0x0012".to_string()))
+        );
+        assert!(!device.started);
+        assert!(!events.contains(&FlashProgress::Done));
+    }
+
+    #[test]
+    fn too_large() {
+        // Past the end of the flash of an ATmega32U4
+        let firmware = Firmware::parse(":01800000017E\n:00000001FF\n").unwrap();
+        let mut device = SimulatedDfu::new(Chip::for_bootloaded(Bootloaded::AtMega32u4));
+        let (res, events) = flash_simulated(&mut device, Bootloaded::AtMega32u4, &firmware);
+        assert!(matches!(res, Err(Error::InvalidArgument(_))));
+        assert!(events.is_empty());
+        assert!(!device.erased);
+    }
+
+    #[test]
+    fn device_error() {
+        let mut device = SimulatedDfu::new(Chip::for_bootloaded(Bootloaded::AtMega32u4));
+        let mut dfu = AtmelDfu::new(&mut device).unwrap();
+        dfu.erase().unwrap();
+        // Writes to the bootloader are refused, leaving the device in the
+        // error state until the status is cleared
+        assert!(matches!(
+            dfu.program(0x7000, &[0; 4]),
+            Err(Error::Protocol(_))
+        ));
+        dfu.program(0, &[0; 4]).unwrap();
+        assert_eq!(dfu.read(0, 4), Ok(vec![0; 4]));
+    }
+}
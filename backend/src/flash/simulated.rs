@@ -0,0 +1,135 @@
+// Simulated Atmel DFU bootloader, for testing flashing without a board
+
+use std::time::Duration;
+
+use super::dfu::*;
+use crate::Error;
+
+const STATUS_ERR_WRITE: u8 = 0x03;
+const STATUS_ERR_STALLED_PKT: u8 = 0x0F;
+const STATE_DFU_IDLE: u8 = 2;
+const STATE_DFU_DNLOAD_IDLE: u8 = 5;
+
+pub(crate) struct SimulatedDfu {
+    pub flash: Vec<u8>,
+    bootloader_start: usize,
+    status: u8,
+    state: u8,
+    /// Range requested by the last read command
+    upload: Option<(usize, usize)>,
+    /// Reported busy by the next status request, after an erase
+    busy: bool,
+    /// Flash byte that reads back wrong, to test verification
+    pub corrupt: Option<usize>,
+    pub started: bool,
+    pub erased: bool,
+}
+
+impl SimulatedDfu {
+    pub fn new(chip: Chip) -> Self {
+        Self {
+            // Left over from older firmware
+            flash: vec![0x5A; chip.flash_size as usize],
+            bootloader_start: chip.bootloader_start() as usize,
+            status: STATUS_OK,
+            state: STATE_DFU_IDLE,
+            upload: None,
+            busy: false,
+            corrupt: None,
+            started: false,
+            erased: false,
+        }
+    }
+
+    fn fail(&mut self, status: u8) -> Result<(), Error> {
+        self.status = status;
+        self.state = STATE_DFU_ERROR;
+        Ok(())
+    }
+
+    fn range(data: &[u8]) -> (usize, usize) {
+        let start = u16::from_be_bytes([data[2], data[3]]);
+        let end = u16::from_be_bytes([data[4], data[5]]);
+        (usize::from(start), usize::from(end) + 1)
+    }
+}
+
+impl DfuTransport for SimulatedDfu {
+    fn download(&mut self, _block: u16, data: &[u8]) -> Result<(), Error> {
+        if self.state == STATE_DFU_ERROR {
+            return Err(Error::Io("Pipe stalled".to_string()));
+        }
+        self.state = STATE_DFU_DNLOAD_IDLE;
+        match data {
+            [] => {
+                if self.started {
+                    return Err(Error::Io("No such device".to_string()));
+                }
+            }
+            [CMD_WRITE, 0x00, 0xFF] => {
+                // Erase the application section
+                self.flash[..self.bootloader_start].fill(0xFF);
+                self.erased = true;
+                self.busy = true;
+            }
+            [CMD_WRITE, 0x03, 0x00] => self.started = true,
+            [CMD_READ, 0x00, _, _, _, _] => {
+                let (start, end) = Self::range(data);
+                if start >= end || end > self.flash.len() {
+                    return self.fail(STATUS_ERR_STALLED_PKT);
+                }
+                self.upload = Some((start, end));
+            }
+            [CMD_PROGRAM, 0x00, ..] if data.len() > HEADER_SIZE + FOOTER_SIZE => {
+                let (start, end) = Self::range(data);
+                let payload = &data[HEADER_SIZE..data.len() - FOOTER_SIZE];
+                if start >= end || end - start != payload.len() {
+                    return self.fail(STATUS_ERR_STALLED_PKT);
+                }
+                if end > self.bootloader_start {
+                    return self.fail(STATUS_ERR_WRITE);
+                }
+                // Like flash memory, writing only clears bits
+                for (byte, new) in self.flash[start..end].iter_mut().zip(payload) {
+                    *byte &= new;
+                }
+            }
+            _ => return self.fail(STATUS_ERR_STALLED_PKT),
+        }
+        Ok(())
+    }
+
+    fn upload(&mut self, _block: u16, length: usize) -> Result<Vec<u8>, Error> {
+        let (start, end) = match self.upload.take() {
+            Some(range) if self.state != STATE_DFU_ERROR => range,
+            _ => return Err(Error::Io("Pipe stalled".to_string())),
+        };
+        let mut data = self.flash[start..end.min(start + length)].to_vec();
+        if let Some(i) = self.corrupt {
+            if (start..end).contains(&i) {
+                data[i - start] ^= 0x01;
+            }
+        }
+        Ok(data)
+    }
+
+    fn get_status(&mut self) -> Result<DfuStatus, Error> {
+        let state = if self.busy {
+            self.busy = false;
+            STATE_DFU_DNBUSY
+        } else {
+            self.state
+        };
+        Ok(DfuStatus {
+            status: self.status,
+            poll_timeout: Duration::from_millis(1),
+            state,
+        })
+    }
+
+    fn clear_status(&mut self) -> Result<(), Error> {
+        self.status = STATUS_OK;
+        self.state = STATE_DFU_IDLE;
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+// DFU transport using control transfers through Linux's usbfs, in
+// `/dev/bus/usb`
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io, mem,
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+use super::dfu::{DfuStatus, DfuTransport, DFU_CLRSTATUS, DFU_DNLOAD, DFU_GETSTATUS, DFU_UPLOAD};
+use crate::{BootloadedDevice, Error};
+
+const INTERFACE: u32 = 0;
+const TIMEOUT_MS: u32 = 5000;
+// Class request to an interface, host to device or device to host
+const REQUEST_TYPE_OUT: u8 = 0x21;
+const REQUEST_TYPE_IN: u8 = 0xA1;
+
+#[repr(C)]
+struct CtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut libc::c_void,
+}
+
+const fn ioc(dir: u64, nr: u64, size: usize) -> u64 {
+    (dir << 30) | ((size as u64) << 16) | ((b'U' as u64) << 8) | nr
+}
+
+const USBDEVFS_CONTROL: u64 = ioc(3, 0, mem::size_of::<CtrlTransfer>());
+const USBDEVFS_CLAIMINTERFACE: u64 = ioc(2, 15, mem::size_of::<u32>());
+const USBDEVFS_RELEASEINTERFACE: u64 = ioc(2, 16, mem::size_of::<u32>());
+
+pub(crate) struct UsbfsDfu(File);
+
+impl UsbfsDfu {
+    /// Open `device`, found in sysfs mounted at `sysfs`
+    pub fn open(sysfs: &Path, device: &BootloadedDevice) -> Result<Self, Error> {
+        let dir = sysfs.join("bus/usb/devices").join(&device.bus_path);
+        let read = |name| -> Result<u32, Error> {
+            let value = fs::read_to_string(dir.join(name))?;
+            value
+                .trim()
+                .parse()
+                .map_err(|_| Error::Protocol(format!("Invalid {} '{}'", name, value.trim())))
+        };
+        let path = format!("/dev/bus/usb/{:03}/{:03}", read("busnum")?, read("devnum")?);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|err| {
+                // Access is given by a udev rule installed with the configurator
+                let context = if err.kind() == io::ErrorKind::PermissionDenied {
+                    format!(
+                        "Failed to open {}; is the configurator's udev rule installed?",
+                        path
+                    )
+                } else {
+                    format!("Failed to open {}", path)
+                };
+                Error::from(err).context(&context)
+            })?;
+        let interface = INTERFACE;
+        if unsafe { libc::ioctl(file.as_raw_fd(), USBDEVFS_CLAIMINTERFACE as _, &interface) } < 0 {
+            return Err(
+                Error::from(io::Error::last_os_error()).context("Failed to claim DFU interface")
+            );
+        }
+        Ok(Self(file))
+    }
+
+    fn control(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        data: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut transfer = CtrlTransfer {
+            request_type,
+            request,
+            value,
+            index: INTERFACE as u16,
+            length: data.len() as u16,
+            timeout: TIMEOUT_MS,
+            data: data.as_mut_ptr() as *mut libc::c_void,
+        };
+        let ret = unsafe { libc::ioctl(self.0.as_raw_fd(), USBDEVFS_CONTROL as _, &mut transfer) };
+        if ret < 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+impl DfuTransport for UsbfsDfu {
+    fn download(&mut self, block: u16, data: &[u8]) -> Result<(), Error> {
+        let mut data = data.to_vec();
+        self.control(REQUEST_TYPE_OUT, DFU_DNLOAD, block, &mut data)?;
+        Ok(())
+    }
+
+    fn upload(&mut self, block: u16, length: usize) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; length];
+        let len = self.control(REQUEST_TYPE_IN, DFU_UPLOAD, block, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    fn get_status(&mut self) -> Result<DfuStatus, Error> {
+        let mut data = [0; 6];
+        let len = self.control(REQUEST_TYPE_IN, DFU_GETSTATUS, 0, &mut data)?;
+        DfuStatus::from_bytes(&data[..len])
+    }
+
+    fn clear_status(&mut self) -> Result<(), Error> {
+        self.control(REQUEST_TYPE_OUT, DFU_CLRSTATUS, 0, &mut [])?;
+        Ok(())
+    }
+}
+
+impl Drop for UsbfsDfu {
+    fn drop(&mut self) {
+        let interface = INTERFACE;
+        unsafe {
+            libc::ioctl(
+                self.0.as_raw_fd(),
+                USBDEVFS_RELEASEINTERFACE as _,
+                &interface,
+            )
+        };
+    }
+}
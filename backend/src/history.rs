@@ -0,0 +1,261 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::Hs;
+
+/// Maximum number of undo steps kept
+const HISTORY_LIMIT: usize = 100;
+
+/// Edits to the same setting closer together than this are merged into one
+/// step, so dragging a slider can be undone at once
+const MERGE_TIME: Duration = Duration::from_secs(1);
+
+/// A change to a setting, recording the old and new value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Edit {
+    Scancode {
+        key: usize,
+        layer: usize,
+        old: u16,
+        new: u16,
+    },
+    KeyColor {
+        key: usize,
+        old: Option<Hs>,
+        new: Option<Hs>,
+    },
+    LayerMode {
+        layer: usize,
+        old: (u8, u8),
+        new: (u8, u8),
+    },
+    LayerBrightness {
+        layer: usize,
+        old: i32,
+        new: i32,
+    },
+    LayerColor {
+        layer: usize,
+        old: Hs,
+        new: Hs,
+    },
+}
+
+impl Edit {
+    fn same_target(&self, other: &Self) -> bool {
+        use Edit::*;
+        match (self, other) {
+            (
+                Scancode { key, layer, .. },
+                Scancode {
+                    key: k, layer: l, ..
+                },
+            ) => key == k && layer == l,
+            (KeyColor { key, .. }, KeyColor { key: k, .. }) => key == k,
+            (LayerMode { layer, .. }, LayerMode { layer: l, .. })
+            | (LayerBrightness { layer, .. }, LayerBrightness { layer: l, .. })
+            | (LayerColor { layer, .. }, LayerColor { layer: l, .. }) => layer == l,
+            _ => false,
+        }
+    }
+
+    /// Replace the new value with the new value of `other`, which has the same target
+    fn merge(&mut self, other: Self) {
+        use Edit::*;
+        match (self, other) {
+            (Scancode { new, .. }, Scancode { new: n, .. }) => *new = n,
+            (KeyColor { new, .. }, KeyColor { new: n, .. }) => *new = n,
+            (LayerMode { new, .. }, LayerMode { new: n, .. }) => *new = n,
+            (LayerBrightness { new, .. }, LayerBrightness { new: n, .. }) => *new = n,
+            (LayerColor { new, .. }, LayerColor { new: n, .. }) => *new = n,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Scancodes are discrete choices, so they are not merged over time
+    fn is_continuous(&self) -> bool {
+        !matches!(self, Edit::Scancode { .. })
+    }
+}
+
+/// Edits that are undone and redone together
+#[derive(Clone, Debug)]
+pub(crate) struct Step {
+    pub edits: Vec<Edit>,
+    time: Instant,
+    /// Id of the `HistoryGroup` the edits were recorded through
+    group: Option<u64>,
+}
+
+impl Step {
+    fn record(&mut self, edit: Edit) {
+        self.time = Instant::now();
+        match self.edits.iter_mut().find(|x| x.same_target(&edit)) {
+            Some(existing) => existing.merge(edit),
+            None => self.edits.push(edit),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+}
+
+impl History {
+    /// Record `edit`, in the step of `group` if it is given. Edits of
+    /// concurrent groups, or without one, stay in separate steps.
+    pub fn record(&mut self, edit: Edit, group: Option<&HistoryGroup>) {
+        self.redo.clear();
+
+        let group = group.map(|x| x.0);
+        let step = match group {
+            Some(_) => self.undo.iter_mut().rev().find(|step| step.group == group),
+            None => self.undo.last_mut().filter(|step| {
+                step.group.is_none()
+                    && edit.is_continuous()
+                    && step.time.elapsed() < MERGE_TIME
+                    && step.edits.iter().any(|x| x.same_target(&edit))
+            }),
+        };
+
+        if let Some(step) = step {
+            step.record(edit);
+        } else {
+            self.undo.push(Step {
+                edits: vec![edit],
+                time: Instant::now(),
+                group,
+            });
+            if self.undo.len() > HISTORY_LIMIT {
+                self.undo.remove(0);
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Step> {
+        self.undo.pop()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Step> {
+        self.redo.pop()
+    }
+
+    pub fn push_undo(&mut self, step: Step) {
+        self.undo.push(step);
+    }
+
+    pub fn push_redo(&mut self, step: Step) {
+        self.redo.push(step);
+    }
+}
+
+/// Edits recorded through the same `HistoryGroup`, with the `_in` variants
+/// of setters, are undone as a single step. Other edits, even while the group
+/// exists, are recorded separately. Returned by `Board::history_group`.
+#[derive(Clone, Debug)]
+pub struct HistoryGroup(u64);
+
+impl HistoryGroup {
+    pub(crate) fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brightness(layer: usize, old: i32, new: i32) -> Edit {
+        Edit::LayerBrightness { layer, old, new }
+    }
+
+    fn scancode(key: usize, old: u16, new: u16) -> Edit {
+        Edit::Scancode {
+            key,
+            layer: 0,
+            old,
+            new,
+        }
+    }
+
+    #[test]
+    fn merge_continuous() {
+        let mut history = History::default();
+        history.record(brightness(0, 0, 10), None);
+        history.record(brightness(0, 10, 20), None);
+        history.record(brightness(1, 0, 5), None);
+        assert_eq!(history.pop_undo().unwrap().edits, vec![brightness(1, 0, 5)]);
+        assert_eq!(
+            history.pop_undo().unwrap().edits,
+            vec![brightness(0, 0, 20)]
+        );
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn scancodes_not_merged() {
+        let mut history = History::default();
+        history.record(scancode(0, 0, 1), None);
+        history.record(scancode(0, 1, 2), None);
+        assert_eq!(history.pop_undo().unwrap().edits, vec![scancode(0, 1, 2)]);
+        assert_eq!(history.pop_undo().unwrap().edits, vec![scancode(0, 0, 1)]);
+    }
+
+    #[test]
+    fn record_clears_redo() {
+        let mut history = History::default();
+        history.record(scancode(0, 0, 1), None);
+        let step = history.pop_undo().unwrap();
+        history.push_redo(step);
+        assert!(history.can_redo());
+        history.record(scancode(1, 0, 1), None);
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn concurrent_groups() {
+        let mut history = History::default();
+        let (a, b) = (HistoryGroup::new(), HistoryGroup::new());
+        history.record(scancode(0, 0, 1), Some(&a));
+        history.record(scancode(1, 0, 1), Some(&b));
+        history.record(brightness(0, 0, 10), None);
+        history.record(scancode(2, 0, 1), Some(&a));
+        history.record(scancode(3, 0, 1), Some(&b));
+        assert_eq!(
+            history.pop_undo().unwrap().edits,
+            vec![brightness(0, 0, 10)]
+        );
+        assert_eq!(
+            history.pop_undo().unwrap().edits,
+            vec![scancode(1, 0, 1), scancode(3, 0, 1)]
+        );
+        assert_eq!(
+            history.pop_undo().unwrap().edits,
+            vec![scancode(0, 0, 1), scancode(2, 0, 1)]
+        );
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn limit() {
+        let mut history = History::default();
+        for i in 0..HISTORY_LIMIT + 10 {
+            history.record(scancode(i, 0, 1), None);
+        }
+        assert_eq!(history.undo.len(), HISTORY_LIMIT);
+        assert_eq!(history.undo[0].edits, vec![scancode(10, 0, 1)]);
+    }
+}
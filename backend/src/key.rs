@@ -1,13 +1,99 @@
-use std::sync::{
-    atomic::{AtomicU16, Ordering},
-    Mutex,
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Mutex,
+    },
 };
 
-use crate::{Board, BoardEvent, Daemon, Hs, PhysicalLayoutKey, Rect, Rgb, WeakBoard};
+use crate::{
+    Board, BoardEvent, BoardId, Daemon, Edit, Error, HistoryGroup, Hs, Layout, PhysicalLayoutKey,
+    Rect, Rgb, WeakBoard,
+};
+
+/// Scancodes and LED colors of every key of a board, read with one batched
+/// command each. Values that aren't preloaded, because the daemon doesn't
+/// support batches or the batch failed, are read per key.
+#[derive(Debug, Default)]
+pub(crate) struct KeyPreload {
+    scancodes: HashMap<(u8, u8, u8), u16>,
+    colors: HashMap<u8, (u8, u8, u8)>,
+}
+
+impl KeyPreload {
+    pub fn new(daemon: &dyn Daemon, board: BoardId, layout: &Layout, read_keymap: bool) -> Self {
+        let mut keys = Vec::new();
+        let mut leds = Vec::new();
+        for physical_key in &layout.physical.keys {
+            let logical_name = physical_key.logical_name();
+            if read_keymap {
+                let electrical = layout.layout.get(&logical_name).unwrap_or(&(0, 0));
+                for layer in 0..layout.meta.num_layers {
+                    keys.push((layer, electrical.0, electrical.1));
+                }
+            }
+            if layout.meta.has_mode {
+                leds.extend(layout.leds.get(&logical_name).and_then(|x| x.first()));
+            }
+        }
+
+        let mut preload = Self::default();
+        if !keys.is_empty() {
+            match daemon.keymap_get_batch(board, keys.clone()) {
+                Ok(values) if values.len() == keys.len() => {
+                    preload.scancodes = keys.into_iter().zip(values).collect();
+                }
+                Ok(values) => error!(
+                    "Read {} scancodes in batch, expected {}",
+                    values.len(),
+                    keys.len()
+                ),
+                Err(err) => debug!("Reading scancodes per key: {}", err),
+            }
+        }
+        if !leds.is_empty() {
+            match daemon.color_batch(board, leds.clone()) {
+                Ok(colors) if colors.len() == leds.len() => {
+                    preload.colors = leds.into_iter().zip(colors).collect();
+                }
+                Ok(colors) => error!(
+                    "Read {} LED colors in batch, expected {}",
+                    colors.len(),
+                    leds.len()
+                ),
+                Err(err) => debug!("Reading LED colors per key: {}", err),
+            }
+        }
+        preload
+    }
+
+    fn keymap_get(
+        &self,
+        daemon: &dyn Daemon,
+        board: BoardId,
+        layer: u8,
+        output: u8,
+        input: u8,
+    ) -> Result<u16, Error> {
+        match self.scancodes.get(&(layer, output, input)) {
+            Some(scancode) => Ok(*scancode),
+            None => daemon.keymap_get(board, layer, output, input),
+        }
+    }
+
+    fn color(&self, daemon: &dyn Daemon, board: BoardId, index: u8) -> Result<(u8, u8, u8), Error> {
+        match self.colors.get(&index) {
+            Some(color) => Ok(*color),
+            None => daemon.color(board, index),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Key {
     pub(crate) board: WeakBoard,
+    /// Index in `Board::keys`
+    index: usize,
     /// Logical position (row, column)
     pub logical: (u8, u8),
     /// Logical name (something like K01, where 0 is the row and 1 is the column)
@@ -24,7 +110,9 @@ pub struct Key {
     pub leds: Vec<u8>,
     /// LED name
     pub led_name: String,
-    led_color: Mutex<Option<Hs>>,
+    /// Cached LED color, and the RGB value last read from or written to the
+    /// device, to compare with since converting between them is lossy
+    led_color: Mutex<(Option<Hs>, (u8, u8, u8))>,
     /// Currently loaded scancodes and their names
     scancodes: Vec<AtomicU16>,
     /// Background color
@@ -35,7 +123,9 @@ impl Key {
     pub(crate) fn new(
         daemon: &dyn Daemon,
         board: &Board,
+        index: usize,
         physical_key: &PhysicalLayoutKey,
+        preload: &KeyPreload,
     ) -> Self {
         let logical = physical_key.logical;
         let logical_name = physical_key.logical_name();
@@ -74,8 +164,13 @@ impl Key {
         let mut scancodes = Vec::new();
         for layer in 0..board.layout().meta.num_layers {
             debug!("  Layer {}", layer);
-            let scancode = match daemon.keymap_get(board.board(), layer, electrical.0, electrical.1)
-            {
+            let scancode = match preload.keymap_get(
+                daemon,
+                board.board(),
+                layer,
+                electrical.0,
+                electrical.1,
+            ) {
                 Ok(value) => value,
                 Err(err) => {
                     error!("Failed to read scancode: {:?}", err);
@@ -91,17 +186,17 @@ impl Key {
             scancodes.push(AtomicU16::new(scancode));
         }
 
-        let mut led_color = None;
+        let mut led_color = (None, (0, 0, 0));
         if board.layout().meta.has_mode && !leds.is_empty() {
-            match daemon.color(board.board(), leds[0]) {
-                Ok((0, 0, 0)) => {}
-                Ok((r, g, b)) => led_color = Some(Rgb::new(r, g, b).to_hs_lossy()),
+            match preload.color(daemon, board.board(), leds[0]) {
+                Ok(rgb) => led_color = (Self::color_from_device(rgb), rgb),
                 Err(err) => error!("error getting key color: {}", err),
             }
         }
 
         Self {
             board: board.downgrade(),
+            index,
             logical,
             logical_name,
             physical,
@@ -120,6 +215,10 @@ impl Key {
         self.board.upgrade().unwrap()
     }
 
+    pub(crate) fn index(&self) -> usize {
+        self.index
+    }
+
     pub fn pressed(&self) -> bool {
         self.board()
             .matrix()
@@ -128,23 +227,69 @@ impl Key {
     }
 
     pub fn color(&self) -> Option<Hs> {
-        *self.led_color.lock().unwrap()
+        self.led_color.lock().unwrap().0
     }
 
-    pub async fn set_color(&self, color: Option<Hs>) -> Result<(), String> {
-        let board = self.board();
+    fn color_from_device((r, g, b): (u8, u8, u8)) -> Option<Hs> {
+        if (r, g, b) == (0, 0, 0) {
+            None
+        } else {
+            Some(Rgb::new(r, g, b).to_hs_lossy())
+        }
+    }
+
+    fn color_to_device(color: Option<Hs>) -> (u8, u8, u8) {
         let Rgb { r, g, b } = color.map_or(Rgb::new(0, 0, 0), Hs::to_rgb);
+        (r, g, b)
+    }
+
+    pub async fn set_color(&self, color: Option<Hs>) -> Result<(), Error> {
+        self.set_color_grouped(color, None).await
+    }
+
+    /// Like `set_color`, recording the edit in `group`
+    pub async fn set_color_in(&self, group: &HistoryGroup, color: Option<Hs>) -> Result<(), Error> {
+        self.set_color_grouped(color, Some(group)).await
+    }
+
+    async fn set_color_grouped(
+        &self,
+        color: Option<Hs>,
+        group: Option<&HistoryGroup>,
+    ) -> Result<(), Error> {
+        let old = self.color();
+        self.write_color(color).await?;
+        self.board().record_edit(
+            Edit::KeyColor {
+                key: self.index,
+                old,
+                new: color,
+            },
+            group,
+        );
+        Ok(())
+    }
+
+    /// Set color, without recording it in the undo history
+    pub(crate) async fn write_color(&self, color: Option<Hs>) -> Result<(), Error> {
+        let board = self.board();
+        let rgb = Self::color_to_device(color);
         for index in &self.leds {
             board
                 .thread_client()
-                .set_color(board.board(), *index, (r, g, b))
+                .set_color(board.board(), *index, rgb)
                 .await?;
         }
-        *self.led_color.lock().unwrap() = color;
+        self.set_cached_color(color);
         board.set_leds_changed();
         Ok(())
     }
 
+    /// Update the cached color, after it has been written to the board
+    pub(crate) fn set_cached_color(&self, color: Option<Hs>) {
+        *self.led_color.lock().unwrap() = (color, Self::color_to_device(color));
+    }
+
     pub fn get_scancode(&self, layer: usize) -> Option<(u16, String)> {
         let board = self.board();
         let scancode = self.scancodes.get(layer)?.load(Ordering::SeqCst);
@@ -155,12 +300,111 @@ impl Key {
         Some((scancode, scancode_name))
     }
 
-    pub async fn set_scancode(&self, layer: usize, scancode_name: &str) -> Result<(), String> {
+    /// Re-read scancodes and LED color from the device, returning whether the
+    /// keymap and LEDs differed from the cached values
+    pub(crate) fn resync(
+        &self,
+        daemon: &dyn Daemon,
+        board: &Board,
+        preload: &KeyPreload,
+    ) -> Result<(bool, bool), Error> {
+        let mut keymap_changed = false;
+        let scancodes = if board.has_keymap() {
+            &self.scancodes[..]
+        } else {
+            &[]
+        };
+        for (layer, cached) in scancodes.iter().enumerate() {
+            let scancode = preload.keymap_get(
+                daemon,
+                board.board(),
+                layer as u8,
+                self.electrical.0,
+                self.electrical.1,
+            )?;
+            if cached.swap(scancode, Ordering::SeqCst) != scancode {
+                debug!(
+                    "{} layer {} changed to {:04X}",
+                    self.logical_name, layer, scancode
+                );
+                keymap_changed = true;
+            }
+        }
+
+        let mut leds_changed = false;
+        if board.layout().meta.has_mode && !self.leds.is_empty() {
+            let rgb = preload.color(daemon, board.board(), self.leds[0])?;
+            let mut led_color = self.led_color.lock().unwrap();
+            if rgb != led_color.1 {
+                debug!("{} LED changed to {:?}", self.logical_name, rgb);
+                *led_color = (Self::color_from_device(rgb), rgb);
+                leds_changed = true;
+            }
+        }
+
+        Ok((keymap_changed, leds_changed))
+    }
+
+    /// Update the cached scancode, after it has been written to the board
+    pub(crate) fn set_cached_scancode(&self, layer: usize, scancode: u16) {
+        self.scancodes[layer].store(scancode, Ordering::SeqCst);
+    }
+
+    pub async fn set_scancode(&self, layer: usize, scancode_name: &str) -> Result<(), Error> {
+        self.set_scancode_grouped(layer, scancode_name, None).await
+    }
+
+    /// Like `set_scancode`, recording the edit in `group`
+    pub async fn set_scancode_in(
+        &self,
+        group: &HistoryGroup,
+        layer: usize,
+        scancode_name: &str,
+    ) -> Result<(), Error> {
+        self.set_scancode_grouped(layer, scancode_name, Some(group))
+            .await
+    }
+
+    async fn set_scancode_grouped(
+        &self,
+        layer: usize,
+        scancode_name: &str,
+        group: Option<&HistoryGroup>,
+    ) -> Result<(), Error> {
         let board = self.board();
         let scancode = board
             .layout()
             .scancode_from_name(scancode_name)
-            .ok_or_else(|| format!("Unable to find scancode '{}'", scancode_name))?;
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("Unable to find scancode '{}'", scancode_name))
+            })?;
+        let old = self.cached_scancode(layer)?.load(Ordering::SeqCst);
+        self.write_scancode(layer, scancode).await?;
+        // Setting the same scancode again isn't worth undoing
+        if old != scancode {
+            board.record_edit(
+                Edit::Scancode {
+                    key: self.index,
+                    layer,
+                    old,
+                    new: scancode,
+                },
+                group,
+            );
+        }
+        Ok(())
+    }
+
+    fn cached_scancode(&self, layer: usize) -> Result<&AtomicU16, Error> {
+        self.scancodes
+            .get(layer)
+            .ok_or_else(|| Error::InvalidArgument(format!("Invalid layer {}", layer)))
+    }
+
+    /// Set scancode, without recording it in the undo history
+    pub(crate) async fn write_scancode(&self, layer: usize, scancode: u16) -> Result<(), Error> {
+        let cached = self.cached_scancode(layer)?;
+        let board = self.board();
         board
             .thread_client()
             .keymap_set(
@@ -171,7 +415,7 @@ impl Key {
                 scancode,
             )
             .await?;
-        self.scancodes[layer].store(scancode, Ordering::SeqCst);
+        cached.store(scancode, Ordering::SeqCst);
         board.send_event(BoardEvent::KeymapChanged);
         Ok(())
     }
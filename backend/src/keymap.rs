@@ -1,11 +1,13 @@
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap, HashSet},
     convert::TryFrom,
+    error::Error,
+    fmt,
     io::{Read, Write},
 };
 
-use crate::Hs;
+use crate::{Hs, Layout, Mode, PhysicalLayoutKey, Rect};
 
 mod hs_serde {
     use super::*;
@@ -53,17 +55,259 @@ pub struct KeyMapLayer {
     pub color: Hs,
 }
 
+/// Keymap file, as imported and exported by the configurator
+///
+/// Version 2 is a superset of version 1: `key_leds` and `layers` may be
+/// omitted, and a key may list fewer layers than the board has. Anything that
+/// is missing is left unchanged when the keymap is applied.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KeyMap {
     pub model: String,
     pub version: u8,
     pub map: BTreeMap<String, Vec<String>>,
-    #[serde(with = "hs_map_serde")]
+    #[serde(default, with = "hs_map_serde")]
     pub key_leds: BTreeMap<String, Option<Hs>>,
+    #[serde(default)]
     pub layers: Vec<KeyMapLayer>,
 }
 
+/// Binding that `KeyMap::translate` could not place on the target board
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnplacedBinding {
+    /// Logical name of the key in the source layout
+    pub key: String,
+    /// Name printed on the keycap in the source layout
+    pub physical_name: String,
+    pub layer: usize,
+    pub scancode: String,
+}
+
+/// Result of `KeyMap::translate`
+#[derive(Clone, Debug)]
+pub struct KeyMapTranslation {
+    /// Keymap for the target board
+    pub keymap: KeyMap,
+    /// Bindings with no matching key, layer, or scancode on the target board
+    pub unplaced: Vec<UnplacedBinding>,
+}
+
+/// Problem found by `KeyMap::migrate` or `KeyMap::validate`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyMapError {
+    /// File is from a newer (or invalid) version of the format
+    UnsupportedVersion(u8),
+    /// Logical key name does not exist in layout
+    UnknownKey(String),
+    /// Scancode name is not valid for this board
+    UnknownScancode {
+        key: String,
+        layer: usize,
+        scancode: String,
+    },
+    /// Key has bindings for more layers than the board has
+    TooManyLayers {
+        key: String,
+        layers: usize,
+        max: usize,
+    },
+    /// Mode index does not correspond to any `Mode`
+    UnknownMode { layer: usize, mode: u8 },
+}
+
+impl fmt::Display for KeyMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported keymap version {}, expected at most {}",
+                version,
+                KeyMap::VERSION
+            ),
+            Self::UnknownKey(key) => write!(f, "unknown key '{}'", key),
+            Self::UnknownScancode {
+                key,
+                layer,
+                scancode,
+            } => write!(
+                f,
+                "unknown scancode '{}' for key '{}' on layer {}",
+                scancode, key, layer
+            ),
+            Self::TooManyLayers { key, layers, max } => write!(
+                f,
+                "key '{}' has {} layers, but board only has {}",
+                key, layers, max
+            ),
+            Self::UnknownMode { layer, mode } => {
+                write!(f, "unknown LED mode {} on layer {}", mode, layer)
+            }
+        }
+    }
+}
+
+impl Error for KeyMapError {}
+
 impl KeyMap {
+    /// Version of the format written by `Board::export_keymap`
+    pub const VERSION: u8 = 2;
+
+    /// Upgrade a keymap from an older version of the format to `KeyMap::VERSION`
+    pub fn migrate(mut self) -> Result<Self, KeyMapError> {
+        match self.version {
+            // Every v1 file is also a valid v2 file
+            1 => {
+                self.version = 2;
+                Ok(self)
+            }
+            Self::VERSION => Ok(self),
+            version => Err(KeyMapError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// Check that every key, scancode, and LED setting is valid for `layout`
+    ///
+    /// Expects a keymap that has already been passed through `migrate`.
+    pub fn validate(&self, layout: &Layout) -> Result<(), Vec<KeyMapError>> {
+        let mut errors = Vec::new();
+
+        if self.version != Self::VERSION {
+            errors.push(KeyMapError::UnsupportedVersion(self.version));
+        }
+
+        let num_layers = usize::from(layout.meta.num_layers);
+        for (key, scancodes) in &self.map {
+            if !layout.layout.contains_key(key) {
+                errors.push(KeyMapError::UnknownKey(key.clone()));
+                continue;
+            }
+            if scancodes.len() > num_layers {
+                errors.push(KeyMapError::TooManyLayers {
+                    key: key.clone(),
+                    layers: scancodes.len(),
+                    max: num_layers,
+                });
+            }
+            for (layer, scancode) in scancodes.iter().enumerate() {
+                if layout.scancode_from_name(scancode).is_none() {
+                    errors.push(KeyMapError::UnknownScancode {
+                        key: key.clone(),
+                        layer,
+                        scancode: scancode.clone(),
+                    });
+                }
+            }
+        }
+
+        for key in self.key_leds.keys() {
+            if !layout.layout.contains_key(key) {
+                errors.push(KeyMapError::UnknownKey(key.clone()));
+            }
+        }
+
+        // LED layers past those of the board are ignored, since keymaps for
+        // boards without per-layer LEDs may still have one per firmware layer
+        for (layer, keymap_layer) in self.layers.iter().enumerate() {
+            if let Some((mode, _speed)) = keymap_layer.mode {
+                if Mode::from_index(mode).is_none() {
+                    errors.push(KeyMapError::UnknownMode { layer, mode });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Translate a keymap for a board with layout `from` to the board `model`, with layout `to`
+    ///
+    /// Keys are matched by the name on their keycap, and keys sharing a name
+    /// (like the two shift keys) by their physical position. Layers, or
+    /// scancodes, the target board doesn't have are reported as unplaced, and
+    /// are left at the target's default binding.
+    pub fn translate(&self, from: &Layout, to: &Layout, model: &str) -> KeyMapTranslation {
+        let key_matches = match_physical_keys(from, to);
+        let physical_names = from
+            .physical
+            .keys
+            .iter()
+            .map(|k| (k.logical_name(), k.physical_name.as_str()))
+            .collect::<HashMap<_, _>>();
+        let num_layers = usize::from(to.meta.num_layers);
+
+        let mut map = BTreeMap::new();
+        let mut unplaced = Vec::new();
+        for (key, scancodes) in &self.map {
+            let target = key_matches.get(key);
+            let mut target_scancodes = Vec::new();
+            // Set once a layer can't be filled, so the layers after it can't
+            // be placed either
+            let mut truncated = false;
+            for (layer, scancode) in scancodes.iter().enumerate() {
+                let in_target = target.is_some() && layer < num_layers && !truncated;
+                let placed = in_target && to.scancode_from_name(scancode).is_some();
+                // `NONE` and `ROLL_OVER` (transparent) aren't really bindings
+                if !placed && scancode != "NONE" && scancode != "ROLL_OVER" {
+                    unplaced.push(UnplacedBinding {
+                        key: key.clone(),
+                        physical_name: physical_names.get(key).unwrap_or(&"").to_string(),
+                        layer,
+                        scancode: scancode.clone(),
+                    });
+                }
+                if let Some(target) = target.filter(|_| in_target) {
+                    if placed {
+                        target_scancodes.push(scancode.clone());
+                    } else if let Some(default) =
+                        to.default.map.get(target).and_then(|x| x.get(layer))
+                    {
+                        target_scancodes.push(default.clone());
+                    } else {
+                        truncated = true;
+                    }
+                }
+            }
+            if let Some(target) = target {
+                map.insert(target.clone(), target_scancodes);
+            }
+        }
+
+        let key_leds = self
+            .key_leds
+            .iter()
+            .filter_map(|(key, hs)| {
+                let target = key_matches.get(key)?;
+                if to.leds.get(target).map_or(true, Vec::is_empty) {
+                    return None;
+                }
+                Some((target.clone(), *hs))
+            })
+            .collect();
+
+        let layers = self
+            .layers
+            .iter()
+            .take(usize::from(to.num_led_layers()))
+            .map(|layer| KeyMapLayer {
+                mode: layer.mode.filter(|_| to.meta.has_mode),
+                ..layer.clone()
+            })
+            .collect();
+
+        KeyMapTranslation {
+            keymap: KeyMap {
+                model: model.to_string(),
+                version: Self::VERSION,
+                map,
+                key_leds,
+                layers,
+            },
+            unplaced,
+        }
+    }
+
     /// Parse layout from json file
     pub fn from_reader<R: Read>(rdr: R) -> serde_json::Result<Self> {
         serde_json::from_reader(rdr)
@@ -80,9 +324,195 @@ impl KeyMap {
     }
 }
 
+/// Map logical names in `from` to logical names in `to`, for keys with the same keycap
+fn match_physical_keys(from: &Layout, to: &Layout) -> HashMap<String, String> {
+    let center = |rect: &Rect| (rect.x + rect.w / 2., rect.y + rect.h / 2.);
+
+    let mut matches = HashMap::new();
+    let mut matched_to = HashSet::new();
+
+    // Base layer binding in the layout's default keymap
+    let default_binding = |layout: &Layout, key: &PhysicalLayoutKey| {
+        let scancodes = layout.default.map.get(&key.logical_name())?;
+        Some(scancodes.get(0)?.clone()).filter(|x| x != "NONE" && x != "ROLL_OVER")
+    };
+
+    // First match identical keycaps, then keys with the same default binding
+    // but a different keycap (like `PgUp` and `PgUp\nPause`). Closest keys
+    // are matched first.
+    type IsMatch<'a> = &'a dyn Fn(&PhysicalLayoutKey, &PhysicalLayoutKey) -> bool;
+    let passes: [IsMatch; 2] = [&|a, b| a.physical_name == b.physical_name, &|a, b| {
+        let binding = default_binding(from, a);
+        binding.is_some() && binding == default_binding(to, b)
+    }];
+    for is_match in passes {
+        let mut pairs = Vec::new();
+        for a in &from.physical.keys {
+            for b in &to.physical.keys {
+                if is_match(a, b) {
+                    let (ax, ay) = center(&a.physical);
+                    let (bx, by) = center(&b.physical);
+                    let distance = (ax - bx).hypot(ay - by);
+                    pairs.push((distance, a.logical_name(), b.logical_name()));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        for (_, a, b) in pairs {
+            if !matches.contains_key(&a) && !matched_to.contains(&b) {
+                matched_to.insert(b.clone());
+                matches.insert(a, b);
+            }
+        }
+    }
+
+    matches
+}
+
 impl TryFrom<&str> for KeyMap {
     type Error = serde_json::Error;
     fn try_from(s: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYMAP_V1: &str = r#"{
+        "model": "system76/launch_1",
+        "version": 1,
+        "map": { "K00": ["ESC", "ESC", "ESC", "ESC"] },
+        "key_leds": { "K00": null },
+        "layers": [{ "mode": [0, 128], "brightness": 100, "color": [0, 0] }]
+    }"#;
+
+    fn layout() -> Layout {
+        Layout::from_board("system76/launch_1", "0.19.12").unwrap()
+    }
+
+    #[test]
+    fn migrate_v1() {
+        let keymap = KeyMap::try_from(KEYMAP_V1).unwrap().migrate().unwrap();
+        assert_eq!(keymap.version, KeyMap::VERSION);
+        assert_eq!(keymap.validate(&layout()), Ok(()));
+    }
+
+    #[test]
+    fn migrate_unsupported() {
+        let mut keymap = KeyMap::try_from(KEYMAP_V1).unwrap();
+        keymap.version = KeyMap::VERSION + 1;
+        assert_eq!(
+            keymap.migrate().unwrap_err(),
+            KeyMapError::UnsupportedVersion(KeyMap::VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn v2_optional_fields() {
+        let keymap = KeyMap::try_from(
+            r#"{ "model": "system76/launch_1", "version": 2, "map": { "K00": ["A"] } }"#,
+        )
+        .unwrap();
+        assert!(keymap.key_leds.is_empty() && keymap.layers.is_empty());
+        assert_eq!(keymap.validate(&layout()), Ok(()));
+    }
+
+    #[test]
+    fn validate_errors() {
+        let mut keymap = KeyMap::try_from(KEYMAP_V1).unwrap().migrate().unwrap();
+        keymap.map.insert("K999".to_string(), vec!["A".to_string()]);
+        keymap
+            .map
+            .insert("K01".to_string(), vec!["NOT_A_SCANCODE".to_string(); 5]);
+        keymap.key_leds.insert("K998".to_string(), None);
+        keymap.layers[0].mode = Some((200, 0));
+
+        let errors = keymap.validate(&layout()).unwrap_err();
+        assert!(errors.contains(&KeyMapError::UnknownKey("K999".to_string())));
+        assert!(errors.contains(&KeyMapError::UnknownKey("K998".to_string())));
+        assert!(errors.contains(&KeyMapError::TooManyLayers {
+            key: "K01".to_string(),
+            layers: 5,
+            max: 4
+        }));
+        assert!(errors.contains(&KeyMapError::UnknownScancode {
+            key: "K01".to_string(),
+            layer: 4,
+            scancode: "NOT_A_SCANCODE".to_string()
+        }));
+        assert!(errors.contains(&KeyMapError::UnknownMode {
+            layer: 0,
+            mode: 200
+        }));
+    }
+
+    fn launch(name: &str) -> Layout {
+        Layout::from_board(&format!("system76/{}", name), "0.19.12").unwrap()
+    }
+
+    #[test]
+    fn translate_same_layout() {
+        let layout = layout();
+        let translation = layout
+            .default
+            .translate(&layout, &layout, "system76/launch_1");
+        assert_eq!(translation.unplaced, Vec::new());
+        assert_eq!(translation.keymap.map, layout.default.map);
+        assert_eq!(translation.keymap.key_leds, layout.default.key_leds);
+    }
+
+    #[test]
+    fn translate_fewer_layers() {
+        let from = launch("launch_1");
+        let mut to = launch("launch_1");
+        to.meta.num_layers = 2;
+        for scancodes in to.default.map.values_mut() {
+            scancodes.truncate(1);
+        }
+
+        let mut keymap = from.default.clone();
+        keymap.map.insert(
+            "K01".to_string(),
+            ["A", "NOT_A_SCANCODE", "B", "C"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+        );
+        let translation = keymap.translate(&from, &to, "system76/launch_1");
+        assert_eq!(translation.keymap.map["K01"], vec!["A".to_string()]);
+        // Layers after the one without a default binding are reported too
+        let unplaced = translation
+            .unplaced
+            .iter()
+            .filter(|x| x.key == "K01")
+            .map(|x| (x.layer, x.scancode.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(unplaced, vec![(1, "NOT_A_SCANCODE"), (2, "B"), (3, "C")]);
+    }
+
+    #[test]
+    fn translate_launch_1_heavy_1() {
+        let (launch_1, heavy_1) = (launch("launch_1"), launch("launch_heavy_1"));
+
+        // Launch Heavy has every key of the Launch, plus a numpad
+        let translation =
+            launch_1
+                .default
+                .translate(&launch_1, &heavy_1, "system76/launch_heavy_1");
+        assert_eq!(translation.unplaced, Vec::new());
+        assert_eq!(translation.keymap.validate(&heavy_1), Ok(()));
+
+        let translation = heavy_1
+            .default
+            .translate(&heavy_1, &launch_1, "system76/launch_1");
+        assert_eq!(translation.keymap.model, "system76/launch_1");
+        assert_eq!(translation.keymap.validate(&launch_1), Ok(()));
+        assert!(translation
+            .unplaced
+            .iter()
+            .any(|x| x.scancode == "NUM_LOCK" && x.physical_name == "Num Lock"));
+    }
+}
@@ -3,7 +3,7 @@ use std::sync::{
     Mutex,
 };
 
-use crate::{Board, Daemon, Hs, Mode, Rgb, WeakBoard};
+use crate::{Board, Daemon, Edit, Error, HistoryGroup, Hs, Mode, Rgb, WeakBoard};
 
 #[derive(Debug)]
 pub struct Layer {
@@ -12,7 +12,9 @@ pub struct Layer {
     board: WeakBoard,
     pub(crate) mode: Mutex<Option<(u8, u8)>>,
     brightness: AtomicI32,
-    color: Mutex<Hs>,
+    /// Cached color, and the value last read from or written to the device,
+    /// to compare with since converting between them can be lossy
+    color: Mutex<(Hs, (u8, u8, u8))>,
 }
 
 impl Layer {
@@ -41,16 +43,11 @@ impl Layer {
             });
         let color = daemon
             .color(board.board(), index)
-            .map(|color| {
-                if index == 0xff {
-                    Rgb::new(color.0, color.1, color.2).to_hs_lossy()
-                } else {
-                    Hs::from_ints(color.0, color.1)
-                }
-            })
+            .map(|color| (Self::color_from_device(index, color), color))
             .unwrap_or_else(|err| {
                 error!("error getting layer color: {}", err);
-                Hs::new(0., 0.)
+                let hs = Hs::new(0., 0.);
+                (hs, Self::color_to_device(index, hs))
             });
         Self {
             layer,
@@ -62,6 +59,57 @@ impl Layer {
         }
     }
 
+    /// Re-read mode, brightness and color from the device, returning whether
+    /// any differed from the cached values
+    pub(crate) fn resync(&self, daemon: &dyn Daemon, board: &Board) -> Result<bool, Error> {
+        let mut changed = false;
+
+        if board.layout().meta.has_mode {
+            let mode = daemon.mode(board.board(), self.layer)?;
+            let mut cached = self.mode.lock().unwrap();
+            if *cached != Some(mode) {
+                *cached = Some(mode);
+                changed = true;
+            }
+        }
+
+        if board.layout().meta.has_brightness {
+            let brightness = daemon.brightness(board.board(), self.index)?;
+            if self.brightness.swap(brightness, Ordering::SeqCst) != brightness {
+                changed = true;
+            }
+        }
+
+        if board.layout().meta.has_color {
+            let color = daemon.color(board.board(), self.index)?;
+            let mut cached = self.color.lock().unwrap();
+            if cached.1 != color {
+                *cached = (Self::color_from_device(self.index, color), color);
+                changed = true;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    fn color_from_device(index: u8, color: (u8, u8, u8)) -> Hs {
+        if index == 0xff {
+            Rgb::new(color.0, color.1, color.2).to_hs_lossy()
+        } else {
+            Hs::from_ints(color.0, color.1)
+        }
+    }
+
+    fn color_to_device(index: u8, hs: Hs) -> (u8, u8, u8) {
+        if index == 0xff {
+            let Rgb { r, g, b } = hs.to_rgb();
+            (r, g, b)
+        } else {
+            let (h, s) = hs.to_ints();
+            (h, s, 0)
+        }
+    }
+
     fn board(&self) -> Board {
         self.board.upgrade().unwrap()
     }
@@ -72,13 +120,50 @@ impl Layer {
         Some((Mode::from_index(index)?, speed))
     }
 
-    pub async fn set_mode(&self, mode: &Mode, speed: u8) -> Result<(), String> {
+    pub async fn set_mode(&self, mode: &Mode, speed: u8) -> Result<(), Error> {
+        self.set_mode_grouped(mode, speed, None).await
+    }
+
+    /// Like `set_mode`, recording the edit in `group`
+    pub async fn set_mode_in(
+        &self,
+        group: &HistoryGroup,
+        mode: &Mode,
+        speed: u8,
+    ) -> Result<(), Error> {
+        self.set_mode_grouped(mode, speed, Some(group)).await
+    }
+
+    async fn set_mode_grouped(
+        &self,
+        mode: &Mode,
+        speed: u8,
+        group: Option<&HistoryGroup>,
+    ) -> Result<(), Error> {
+        let old = *self.mode.lock().unwrap();
+        self.write_mode(mode.index, speed).await?;
+        // Nothing to undo to if the mode couldn't be read
+        if let Some(old) = old {
+            self.board().record_edit(
+                Edit::LayerMode {
+                    layer: self.layer as usize,
+                    old,
+                    new: (mode.index, speed),
+                },
+                group,
+            );
+        }
+        Ok(())
+    }
+
+    /// Set mode, without recording it in the undo history
+    pub(crate) async fn write_mode(&self, mode: u8, speed: u8) -> Result<(), Error> {
         let board = self.board();
         board
             .thread_client()
-            .set_mode(board.board(), self.layer, mode.index, speed)
+            .set_mode(board.board(), self.layer, mode, speed)
             .await?;
-        *self.mode.lock().unwrap() = Some((mode.index, speed));
+        *self.mode.lock().unwrap() = Some((mode, speed));
         board.set_leds_changed();
         Ok(())
     }
@@ -88,7 +173,39 @@ impl Layer {
         self.brightness.load(Ordering::SeqCst)
     }
 
-    pub async fn set_brightness(&self, brightness: i32) -> Result<(), String> {
+    pub async fn set_brightness(&self, brightness: i32) -> Result<(), Error> {
+        self.set_brightness_grouped(brightness, None).await
+    }
+
+    /// Like `set_brightness`, recording the edit in `group`
+    pub async fn set_brightness_in(
+        &self,
+        group: &HistoryGroup,
+        brightness: i32,
+    ) -> Result<(), Error> {
+        self.set_brightness_grouped(brightness, Some(group)).await
+    }
+
+    async fn set_brightness_grouped(
+        &self,
+        brightness: i32,
+        group: Option<&HistoryGroup>,
+    ) -> Result<(), Error> {
+        let old = self.brightness();
+        self.write_brightness(brightness).await?;
+        self.board().record_edit(
+            Edit::LayerBrightness {
+                layer: self.layer as usize,
+                old,
+                new: brightness,
+            },
+            group,
+        );
+        Ok(())
+    }
+
+    /// Set brightness, without recording it in the undo history
+    pub(crate) async fn write_brightness(&self, brightness: i32) -> Result<(), Error> {
         let board = self.board();
         board
             .thread_client()
@@ -101,23 +218,41 @@ impl Layer {
 
     /// Get the current color
     pub fn color(&self) -> Hs {
-        *self.color.lock().unwrap()
+        self.color.lock().unwrap().0
+    }
+
+    pub async fn set_color(&self, hs: Hs) -> Result<(), Error> {
+        self.set_color_grouped(hs, None).await
+    }
+
+    /// Like `set_color`, recording the edit in `group`
+    pub async fn set_color_in(&self, group: &HistoryGroup, hs: Hs) -> Result<(), Error> {
+        self.set_color_grouped(hs, Some(group)).await
+    }
+
+    async fn set_color_grouped(&self, hs: Hs, group: Option<&HistoryGroup>) -> Result<(), Error> {
+        let old = self.color();
+        self.write_color(hs).await?;
+        self.board().record_edit(
+            Edit::LayerColor {
+                layer: self.layer as usize,
+                old,
+                new: hs,
+            },
+            group,
+        );
+        Ok(())
     }
 
-    pub async fn set_color(&self, hs: Hs) -> Result<(), String> {
+    /// Set color, without recording it in the undo history
+    pub(crate) async fn write_color(&self, hs: Hs) -> Result<(), Error> {
         let board = self.board();
-        let color = if self.index == 0xff {
-            let Rgb { r, g, b } = hs.to_rgb();
-            (r, g, b)
-        } else {
-            let (h, s) = hs.to_ints();
-            (h, s, 0)
-        };
+        let color = Self::color_to_device(self.index, hs);
         board
             .thread_client()
             .set_color(board.board(), self.index, color)
             .await?;
-        *self.color.lock().unwrap() = hs;
+        *self.color.lock().unwrap() = (hs, color);
         board.set_leds_changed();
         Ok(())
     }
@@ -0,0 +1,425 @@
+//! Consistency checks for layout files, used when loading user layouts and by
+//! the `layout-lint` tool
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::{Layout, Meta, PhysicalLayout};
+use crate::{KeyMap, KeyMapError};
+
+/// Problem found in a layout file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LintError {
+    pub path: PathBuf,
+    /// Logical key name, for problems with a single key
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl LintError {
+    fn new<P: Into<PathBuf>>(path: P, key: Option<&str>, message: String) -> Self {
+        Self {
+            path: path.into(),
+            key: key.map(str::to_string),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for LintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{}: {}: {}", self.path.display(), key, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl Layout {
+    /// Check the default keymap against `keymap_file`, and the keys of
+    /// `layout.json`, `leds.json` and `physical.json`. Paths of the returned
+    /// errors are file names.
+    pub(crate) fn lint(&self, keymap_file: &str) -> Vec<LintError> {
+        let mut errors = Vec::new();
+
+        if let Err(errs) = self.default.validate(self) {
+            for err in errs {
+                errors.push(match err {
+                    KeyMapError::UnknownScancode {
+                        key,
+                        layer,
+                        scancode,
+                    } => LintError::new(
+                        "default.json",
+                        Some(&key),
+                        format!(
+                            "scancode '{}' on layer {} not in {}",
+                            scancode, layer, keymap_file
+                        ),
+                    ),
+                    KeyMapError::UnknownKey(key) => LintError::new(
+                        "default.json",
+                        Some(&key),
+                        "key not in layout.json".to_string(),
+                    ),
+                    err => LintError::new("default.json", None, err.to_string()),
+                });
+            }
+        }
+
+        let mut keys_by_position = BTreeMap::<_, Vec<_>>::new();
+        for (key, position) in &self.layout {
+            keys_by_position.entry(position).or_default().push(key);
+        }
+        for ((row, col), mut keys) in keys_by_position {
+            keys.sort();
+            for key in keys.iter().skip(1) {
+                errors.push(LintError::new(
+                    "layout.json",
+                    Some(key),
+                    format!(
+                        "electrical position ({}, {}) also used by {}",
+                        row, col, keys[0]
+                    ),
+                ));
+            }
+        }
+
+        // Not every LED driven by the firmware belongs to a key, so indices
+        // can only be checked against the count given in `meta.json`
+        let mut led_keys = HashMap::new();
+        for (key, leds) in sorted(&self.leds) {
+            for led in leds {
+                let out_of_range = |num_leds: &u16| u16::from(*led) >= *num_leds;
+                if let Some(num_leds) = self.meta.num_leds.filter(out_of_range) {
+                    errors.push(LintError::new(
+                        "leds.json",
+                        Some(key),
+                        format!(
+                            "LED index {} out of range, keyboard has {} LEDs",
+                            led, num_leds
+                        ),
+                    ));
+                } else if let Some(other) = led_keys.insert(*led, key) {
+                    errors.push(LintError::new(
+                        "leds.json",
+                        Some(key),
+                        format!("LED index {} also used by {}", led, other),
+                    ));
+                }
+            }
+        }
+
+        let physical = self
+            .physical
+            .keys
+            .iter()
+            .map(|x| x.logical_name())
+            .collect::<HashSet<_>>();
+        let mut files: Vec<(&str, HashSet<String>)> =
+            vec![("layout.json", self.layout.keys().cloned().collect())];
+        // Keyboards without per-key LEDs have an empty `leds.json`
+        if self.leds.values().any(|leds| !leds.is_empty()) {
+            files.push(("leds.json", self.leds.keys().cloned().collect()));
+        }
+        for (file, keys) in files {
+            for key in sorted_set(physical.difference(&keys)) {
+                errors.push(LintError::new(
+                    file,
+                    Some(key),
+                    "key in physical.json is missing".to_string(),
+                ));
+            }
+            for key in sorted_set(keys.difference(&physical)) {
+                errors.push(LintError::new(
+                    file,
+                    Some(key),
+                    "key not in physical.json".to_string(),
+                ));
+            }
+        }
+
+        errors
+    }
+}
+
+fn sorted<V>(map: &HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut items = map.iter().collect::<Vec<_>>();
+    items.sort_by_key(|(k, _)| *k);
+    items
+}
+
+fn sorted_set<'a, I: Iterator<Item = &'a String>>(iter: I) -> Vec<&'a String> {
+    let mut items = iter.collect::<Vec<_>>();
+    items.sort();
+    items
+}
+
+/// Check every board layout under `dir`, which has the structure of the
+/// `layouts` directory of this repository
+pub fn lint_layouts(dir: &Path) -> Vec<LintError> {
+    let mut errors = Vec::new();
+
+    let vendors = match subdirs(dir) {
+        Ok(vendors) => vendors,
+        Err(err) => {
+            errors.push(LintError::new(dir, None, err.to_string()));
+            return errors;
+        }
+    };
+    for vendor in vendors {
+        let name = vendor.file_name().unwrap().to_string_lossy().to_string();
+        if name == "keyboards" || name == "keymap" {
+            continue;
+        }
+        match subdirs(&vendor) {
+            Ok(boards) => {
+                for board_dir in boards {
+                    let board = format!(
+                        "{}/{}",
+                        name,
+                        board_dir.file_name().unwrap().to_string_lossy()
+                    );
+                    errors.extend(lint_board(dir, &board, &board_dir));
+                }
+            }
+            Err(err) => errors.push(LintError::new(vendor, None, err.to_string())),
+        }
+    }
+
+    errors
+}
+
+fn lint_board(dir: &Path, board: &str, board_dir: &Path) -> Vec<LintError> {
+    let mut errors = Vec::new();
+
+    let meta = read(board_dir.join("meta.json"), &mut errors);
+    let default = read(board_dir.join("default.json"), &mut errors);
+    let (meta_path, meta_json) = match meta {
+        Some(meta) => meta,
+        None => return errors,
+    };
+    let keyboard = match serde_json::from_str::<serde_json::Value>(&meta_json) {
+        Ok(value) => value
+            .get("keyboard")
+            .and_then(|x| x.as_str())
+            .map(str::to_string),
+        Err(_) => None,
+    };
+    let keyboard_dir = match keyboard {
+        Some(keyboard) => dir.join("keyboards").join(keyboard),
+        None => {
+            errors.push(LintError::new(
+                meta_path,
+                None,
+                "missing 'keyboard'".to_string(),
+            ));
+            return errors;
+        }
+    };
+    let layout = read(keyboard_dir.join("layout.json"), &mut errors);
+    let leds = read(keyboard_dir.join("leds.json"), &mut errors);
+    let physical = read(keyboard_dir.join("physical.json"), &mut errors);
+    let (default, layout, leds, physical) = match (default, layout, leds, physical) {
+        (Some(a), Some(b), Some(c), Some(d)) => (a, b, c, d),
+        _ => return errors,
+    };
+
+    // Parse each file separately first, to report which one is invalid
+    let mut parse_errors = Vec::new();
+    let meta = match serde_json::from_str::<Meta>(&meta_json) {
+        Ok(meta) => Some(meta),
+        Err(err) => {
+            parse_errors.push(LintError::new(&meta_path, None, err.to_string()));
+            None
+        }
+    };
+    if let Err(err) = KeyMap::try_from(default.1.as_str()) {
+        parse_errors.push(LintError::new(&default.0, None, err.to_string()));
+    }
+    if let Err(err) = serde_json::from_str::<HashMap<String, (u8, u8)>>(&layout.1) {
+        parse_errors.push(LintError::new(&layout.0, None, err.to_string()));
+    }
+    if let Err(err) = serde_json::from_str::<HashMap<String, Vec<u8>>>(&leds.1) {
+        parse_errors.push(LintError::new(&leds.0, None, err.to_string()));
+    }
+    if let Err(err) = PhysicalLayout::from_str(&physical.1) {
+        parse_errors.push(LintError::new(&physical.0, None, err.to_string()));
+    }
+    let meta = match meta {
+        Some(meta) if parse_errors.is_empty() => meta,
+        _ => {
+            errors.extend(parse_errors);
+            return errors;
+        }
+    };
+
+    let keymap_files: &[_] = if meta.is_qmk {
+        &[("qmk.json", false), ("qmk_legacy.json", true)]
+    } else {
+        &[("ec.json", false)]
+    };
+    for (keymap_file, use_legacy_scancodes) in keymap_files {
+        let keymap_path = dir.join("keymap").join(keymap_file);
+        let keymap_json = match fs::read_to_string(&keymap_path) {
+            Ok(json) => json,
+            Err(err) => {
+                errors.push(LintError::new(keymap_path, None, err.to_string()));
+                continue;
+            }
+        };
+        let res = Layout::from_data(
+            board,
+            &meta_json,
+            &default.1,
+            &keymap_json,
+            &layout.1,
+            &leds.1,
+            &physical.1,
+            "dummy",
+            *use_legacy_scancodes,
+        );
+        let layout = match res {
+            Ok(layout) => layout,
+            Err(err) => {
+                errors.push(LintError::new(keymap_path, None, err.to_string()));
+                continue;
+            }
+        };
+        for mut err in layout.lint(&format!("keymap/{}", keymap_file)) {
+            err.path = match err.path.to_str() {
+                Some("default.json") => board_dir.join(&err.path),
+                _ => keyboard_dir.join(&err.path),
+            };
+            // Only errors about scancodes differ between keymaps
+            if !errors.contains(&err) {
+                errors.push(err);
+            }
+        }
+    }
+
+    errors
+}
+
+fn read(path: PathBuf, errors: &mut Vec<LintError>) -> Option<(PathBuf, String)> {
+    match fs::read_to_string(&path) {
+        Ok(json) => Some((path, json)),
+        Err(err) => {
+            errors.push(LintError::new(path, None, err.to_string()));
+            None
+        }
+    }
+}
+
+/// Subdirectories of `dir`, sorted by name
+fn subdirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn lint_repo_layouts() {
+        let errors = lint_layouts(Path::new("../layouts"));
+        assert_eq!(errors, Vec::new());
+    }
+
+    #[test]
+    fn lint_errors() {
+        let dir = env::temp_dir().join(format!("s76-lint-{}", uuid::Uuid::new_v4()));
+        let board_dir = dir.join("test/board");
+        let keyboard_dir = dir.join("keyboards/test/board");
+        fs::create_dir_all(&board_dir).unwrap();
+        fs::create_dir_all(&keyboard_dir).unwrap();
+        fs::create_dir_all(dir.join("keymap")).unwrap();
+        fs::create_dir_all(dir.join("test/broken")).unwrap();
+
+        fs::write(dir.join("keymap/ec.json"), r#"{"NONE": 0, "A": 4}"#).unwrap();
+        fs::write(
+            board_dir.join("meta.json"),
+            r##"{
+                "display_name": "Test",
+                "has_brightness": false,
+                "has_color": false,
+                "keyboard": "test/board",
+                "num_leds": 3,
+                "pressed_color": "#000000"
+            }"##,
+        )
+        .unwrap();
+        fs::write(
+            board_dir.join("default.json"),
+            r#"{
+                "model": "test/board",
+                "version": 1,
+                "map": {"K00": ["A", "B"], "K01": ["NONE", "NONE"], "K02": ["A", "A"]},
+                "key_leds": {},
+                "layers": []
+            }"#,
+        )
+        .unwrap();
+        fs::write(
+            keyboard_dir.join("layout.json"),
+            r#"{"K00": [0, 0], "K01": [0, 1], "K02": [0, 1]}"#,
+        )
+        .unwrap();
+        fs::write(
+            keyboard_dir.join("leds.json"),
+            r#"{"K00": [0], "K01": [0], "K02": [5]}"#,
+        )
+        .unwrap();
+        fs::write(
+            keyboard_dir.join("physical.json"),
+            r#"[{"name": "Test", "author": "Test"}, ["A", "B", "C"]]"#,
+        )
+        .unwrap();
+
+        let errors = lint_layouts(&dir)
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let board_dir = board_dir.display();
+        let keyboard_dir = keyboard_dir.display();
+        assert_eq!(
+            &errors[..4],
+            &[
+                format!(
+                    "{}/default.json: K00: scancode 'B' on layer 1 not in keymap/ec.json",
+                    board_dir
+                ),
+                format!(
+                    "{}/layout.json: K02: electrical position (0, 1) also used by K01",
+                    keyboard_dir
+                ),
+                format!(
+                    "{}/leds.json: K01: LED index 0 also used by K00",
+                    keyboard_dir
+                ),
+                format!(
+                    "{}/leds.json: K02: LED index 5 out of range, keyboard has 3 LEDs",
+                    keyboard_dir
+                ),
+            ]
+        );
+        for (err, file) in errors[4..].iter().zip(["meta.json", "default.json"]) {
+            let path = format!("{}: ", dir.join("test/broken").join(file).display());
+            assert!(err.starts_with(&path), "{}", err);
+        }
+        assert_eq!(errors.len(), 6, "{:?}", errors);
+    }
+}
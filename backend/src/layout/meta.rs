@@ -29,6 +29,10 @@ pub struct Meta {
     /// Number or layers; e.g. 2 where layer 2 is used when `Fn` is held
     #[serde(default = "num_layers_default")]
     pub num_layers: u8,
+    /// Number of LEDs driven by the firmware, if known. LED indices in
+    /// `leds.json` are checked against it.
+    #[serde(default)]
+    pub num_leds: Option<u16>,
     pub pressed_color: Rgb,
     #[serde(default)]
     pub is_qmk: bool,
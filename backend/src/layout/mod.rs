@@ -1,25 +1,69 @@
 use cascade::cascade;
-use regex::Regex;
-use std::{collections::HashMap, convert::TryFrom, fs, path::Path, str::FromStr};
+use std::{collections::HashMap, convert::TryFrom, path::Path};
 
+mod lint;
 mod meta;
 use once_cell::sync::Lazy;
 mod physical_layout;
+mod user;
+pub use self::lint::{lint_layouts, LintError};
 pub use self::meta::Meta;
 pub(crate) use physical_layout::{PhysicalLayout, PhysicalLayoutKey};
+pub use user::{load_layouts_from_dir, load_user_layouts, user_layouts_dir};
+use user::{user_layout, user_layout_names, LayoutFiles};
+
+use crate::{Error, FirmwareVersion, KeyMap};
+
+const QK_MODS: u16 = 0x0100;
+const QK_MODS_MAX: u16 = 0x1FFF;
+const QK_LAYER_TAP: u16 = 0x4000;
+const QK_LAYER_TAP_MAX: u16 = 0x4FFF;
+
+/// Quantum keycode ranges that moved between QMK versions
+struct QmkRanges {
+    mod_tap: u16,
+    mod_tap_max: u16,
+    /// Functions taking a layer, with their first scancode and maximum layer
+    layer_fns: [(&'static str, u16, u16); 4],
+    one_shot_mod: u16,
+}
 
-use crate::KeyMap;
-
-// Merge date of https://github.com/system76/ec/pull/229
-// Before this, `PAUSE` will not work.
-const EC_PAUSE_DATE: (u16, u16, u16) = (2022, 5, 23);
-// https://github.com/system76/ec/pull/263
-const EC_FNLOCK_DATE: (u16, u16, u16) = (2023, 8, 1);
-
-const QK_MOD_TAP_LEGACY: u16 = 0x6000;
-const QK_MOD_TAP_MAX_LEGACY: u16 = 0x7FFF;
-const QK_MOD_TAP: u16 = 0x2000;
-const QK_MOD_TAP_MAX: u16 = 0x3FFF;
+const QMK_RANGES: QmkRanges = QmkRanges {
+    mod_tap: 0x2000,
+    mod_tap_max: 0x3FFF,
+    layer_fns: [
+        ("TO", 0x5200, 0x1F),
+        ("MO", 0x5220, 0x1F),
+        ("TG", 0x5260, 0x1F),
+        ("OSL", 0x5280, 0x1F),
+    ],
+    one_shot_mod: 0x52A0,
+};
+
+// Legacy `TO` includes the `ON_PRESS` flag, 0x10
+const QMK_RANGES_LEGACY: QmkRanges = QmkRanges {
+    mod_tap: 0x6000,
+    mod_tap_max: 0x7FFF,
+    layer_fns: [
+        ("TO", 0x5010, 0x0F),
+        ("MO", 0x5100, 0xFF),
+        ("TG", 0x5300, 0xFF),
+        ("OSL", 0x5400, 0xFF),
+    ],
+    one_shot_mod: 0x5500,
+};
+
+/// Functions for modified keycodes, like `LCTL(A)`
+const MODIFIER_FNS: [(&str, u16); 8] = [
+    ("LCTL", 0x01),
+    ("LSFT", 0x02),
+    ("LALT", 0x04),
+    ("LGUI", 0x08),
+    ("RCTL", 0x11),
+    ("RSFT", 0x12),
+    ("RALT", 0x14),
+    ("RGUI", 0x18),
+];
 
 pub static MOD_TAP_MODS: Lazy<HashMap<&str, u16>> = Lazy::new(|| {
     cascade! {
@@ -41,8 +85,8 @@ pub struct Layout {
     pub meta: Meta,
     /// Default keymap for this keyboard
     pub default: KeyMap,
-    keymap: HashMap<String, u16>,
-    scancode_names: HashMap<u16, String>,
+    keymap: ScancodesByName,
+    scancode_names: NamesByScancode,
     pub(crate) physical: PhysicalLayout,
     pub(crate) layout: HashMap<String, (u8, u8)>,
     pub(crate) leds: HashMap<String, Vec<u8>>,
@@ -51,7 +95,7 @@ pub struct Layout {
 
 macro_rules! keyboards {
     ($( ($board:expr, $keyboard:expr, $is_qmk:expr) ),* $(,)?) => {
-        fn layout_data(board: &str, use_legacy_scancodes: bool) -> Option<(&'static str, &'static str, &'static str, &'static str, &'static str, &'static str)> {
+        fn layout_data(board: &str) -> Option<(&'static str, &'static str, &'static str, &'static str, &'static str, bool)> {
             match board {
                 $(
                 $board => {
@@ -59,27 +103,21 @@ macro_rules! keyboards {
                         include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../layouts/", $board, "/meta.json"));
                     let default_json =
                         include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../layouts/", $board, "/default.json"));
-                    let keymap_json = if use_legacy_scancodes && $is_qmk {
-                        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../layouts/keymap/qmk_legacy.json"))
-                    } else if $is_qmk {
-                        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../layouts/keymap/qmk.json"))
-                    } else {
-                        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../layouts/keymap/ec.json"))
-                    };
                     let layout_json =
                         include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../layouts/keyboards/", $keyboard, "/layout.json"));
                     let leds_json =
                         include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../layouts/keyboards/", $keyboard, "/leds.json"));
                     let physical_json =
                         include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/../layouts/keyboards/", $keyboard, "/physical.json"));
-                    Some((meta_json, default_json, keymap_json, layout_json, leds_json, physical_json))
+                    Some((meta_json, default_json, layout_json, leds_json, physical_json, $is_qmk))
                 }
                 )*
                 _ => None
             }
         }
 
-        /// Names of board layouts that can be opened with `Layout::from_board`
+        /// Names of board layouts compiled into the binary. See `all_layouts`
+        /// for those loaded at runtime too.
         pub fn layouts() -> &'static [&'static str] {
             &[$( $board ),*]
         }
@@ -89,6 +127,41 @@ macro_rules! keyboards {
 // Calls the `keyboards!` macro
 include!(concat!(env!("OUT_DIR"), "/keyboards.rs"));
 
+fn keymap_json(is_qmk: bool, use_legacy_scancodes: bool) -> &'static str {
+    if use_legacy_scancodes && is_qmk {
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../layouts/keymap/qmk_legacy.json"
+        ))
+    } else if is_qmk {
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../layouts/keymap/qmk.json"
+        ))
+    } else {
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../layouts/keymap/ec.json"
+        ))
+    }
+}
+
+fn use_legacy_scancodes(version: &str) -> bool {
+    FirmwareVersion::parse(version).map_or(false, |version| version.legacy_mod_tap())
+}
+
+/// Names of board layouts that can be opened with `Layout::from_board`,
+/// including any loaded by `load_user_layouts`
+pub fn all_layouts() -> Vec<String> {
+    let mut layouts = layouts().iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    for i in user_layout_names() {
+        if !layouts.contains(&i) {
+            layouts.push(i);
+        }
+    }
+    layouts
+}
+
 impl Layout {
     #[allow(clippy::too_many_arguments)]
     pub fn from_data(
@@ -101,24 +174,22 @@ impl Layout {
         physical_json: &str,
         version: &str,
         use_legacy_scancodes: bool,
-    ) -> Self {
-        let meta: Meta = serde_json::from_str(meta_json).unwrap();
-        let mut default = KeyMap::try_from(default_json).unwrap();
-
-        let has_pause_scancode = if meta.is_qmk {
-            true
-        } else {
-            parse_ec_date(version).map_or(true, |date| date >= EC_PAUSE_DATE)
-        };
+    ) -> Result<Self, Error> {
+        let meta: Meta = serde_json::from_str(meta_json)
+            .map_err(|err| Error::from(err).context("failed to parse meta.json"))?;
+        let mut default = KeyMap::try_from(default_json)
+            .map_err(|err| Error::from(err).context("failed to parse default.json"))?
+            .migrate()
+            .map_err(|err| {
+                Error::InvalidArgument(format!("failed to load default.json: {}", err))
+            })?;
+
+        let version = FirmwareVersion::parse(version);
+        let has_pause_scancode = FirmwareVersion::has_pause(version.as_ref(), meta.is_qmk);
         if !has_pause_scancode {
             keymap_remove_pause(&mut default);
         }
-
-        let has_fnlock_scancode = if meta.is_qmk {
-            false
-        } else {
-            parse_ec_date(version).map_or(true, |date| date >= EC_FNLOCK_DATE)
-        };
+        let has_fnlock_scancode = FirmwareVersion::has_fnlock(version.as_ref(), meta.is_qmk);
         if !has_fnlock_scancode {
             keymap_remove_fnlock(&mut default);
         }
@@ -129,11 +200,13 @@ impl Layout {
             &meta,
             has_pause_scancode,
             has_fnlock_scancode,
-        );
-        let layout = serde_json::from_str(layout_json).unwrap();
-        let leds = serde_json::from_str(leds_json).unwrap();
-        let physical = PhysicalLayout::from_str(physical_json);
-        Self {
+        )?;
+        let layout = serde_json::from_str(layout_json)
+            .map_err(|err| Error::from(err).context("failed to parse layout.json"))?;
+        let leds = serde_json::from_str(leds_json)
+            .map_err(|err| Error::from(err).context("failed to parse leds.json"))?;
+        let physical = PhysicalLayout::from_str(physical_json)?;
+        Ok(Self {
             meta,
             default,
             keymap,
@@ -142,96 +215,175 @@ impl Layout {
             layout,
             leds,
             use_legacy_scancodes,
-        }
+        })
     }
 
-    #[allow(dead_code)]
-    pub fn from_dir<P: AsRef<Path>>(board: &str, dir: P) -> Self {
-        let dir = dir.as_ref();
-
-        let meta_json =
-            fs::read_to_string(dir.join("meta.json")).expect("Failed to load meta.json");
-        let default_json =
-            fs::read_to_string(dir.join("default.json")).expect("Failed to load default.json");
-        let keymap_json =
-            fs::read_to_string(dir.join("keymap.json")).expect("Failed to load keymap.json");
-        let layout_json =
-            fs::read_to_string(dir.join("layout.json")).expect("Failed to load layout.json");
-        let leds_json =
-            fs::read_to_string(dir.join("leds.json")).expect("Failed to load leds.json");
-        let physical_json =
-            fs::read_to_string(dir.join("physical.json")).expect("Failed to load physical.json");
+    /// Load layout from a directory containing `meta.json`, `default.json`,
+    /// `layout.json`, `leds.json`, `physical.json`, and optionally
+    /// `keymap.json`
+    pub fn from_dir<P: AsRef<Path>>(board: &str, dir: P) -> Result<Self, Error> {
+        Self::from_files(board, &LayoutFiles::from_dir(dir.as_ref())?, "dummy")
+    }
 
+    fn from_files(board: &str, files: &LayoutFiles, version: &str) -> Result<Self, Error> {
+        let use_legacy_scancodes = use_legacy_scancodes(version);
+        let keymap_json = match &files.keymap_json {
+            Some(keymap_json) => keymap_json.as_str(),
+            None => {
+                let meta: Meta = serde_json::from_str(&files.meta_json)
+                    .map_err(|err| Error::from(err).context("failed to parse meta.json"))?;
+                keymap_json(meta.is_qmk, use_legacy_scancodes)
+            }
+        };
         Self::from_data(
             board,
-            &meta_json,
-            &default_json,
-            &keymap_json,
-            &layout_json,
-            &leds_json,
-            &physical_json,
-            "dummy",
-            false,
+            &files.meta_json,
+            &files.default_json,
+            keymap_json,
+            &files.layout_json,
+            &files.leds_json,
+            &files.physical_json,
+            version,
+            use_legacy_scancodes,
         )
     }
 
     pub fn from_board(board: &str, version: &str) -> Option<Self> {
-        let use_legacy_scancodes = version.contains("0.7.103")
-            || version.contains("0.7.104")
-            || version.contains("0.12.20");
-        layout_data(board, use_legacy_scancodes).map(
-            |(meta_json, default_json, keymap_json, layout_json, leds_json, physical_json)| {
+        if let Some(files) = user_layout(board) {
+            return match Self::from_files(board, &files, version) {
+                Ok(layout) => Some(layout),
+                Err(err) => {
+                    error!("Failed to load layout '{}': {}", board, err);
+                    None
+                }
+            };
+        }
+
+        let use_legacy_scancodes = use_legacy_scancodes(version);
+        layout_data(board).map(
+            |(meta_json, default_json, layout_json, leds_json, physical_json, is_qmk)| {
                 Self::from_data(
                     board,
                     meta_json,
                     default_json,
-                    keymap_json,
+                    keymap_json(is_qmk, use_legacy_scancodes),
                     layout_json,
                     leds_json,
                     physical_json,
                     version,
                     use_legacy_scancodes,
                 )
+                .expect("invalid built-in layout")
             },
         )
     }
 
-    /// Get the scancode number corresponding to a name
+    /// Get the name corresponding to a scancode number
     pub fn scancode_to_name(&self, scancode: u16) -> Option<String> {
+        if let Some(name) = self.scancode_names.get(&scancode) {
+            return Some(name.clone());
+        }
         if self.meta.is_qmk {
-            let (qk_mod_tap, qk_mod_tap_max) = if self.use_legacy_scancodes {
-                (QK_MOD_TAP_LEGACY, QK_MOD_TAP_MAX_LEGACY)
-            } else {
-                (QK_MOD_TAP, QK_MOD_TAP_MAX)
-            };
-            if scancode >= qk_mod_tap && scancode < qk_mod_tap_max {
-                let mod_ = (scancode >> 8) & 0x1f;
-                let kc = scancode & 0xff;
-                let mod_name = MOD_TAP_MODS.iter().find(|(_, v)| **v == mod_)?.0;
-                let kc_name = self.scancode_names.get(&kc)?;
-                return Some(format!("MT({}, {})", mod_name, kc_name));
-            }
+            self.qmk_scancode_to_name(scancode)
+        } else {
+            None
         }
-        self.scancode_names.get(&scancode).cloned()
     }
 
-    /// Get the name corresponding to a scancode number
+    /// Get the scancode number corresponding to a name
     pub fn scancode_from_name(&self, name: &str) -> Option<u16> {
+        if let Some(scancode) = self.keymap.get(name) {
+            return Some(*scancode);
+        }
         if self.meta.is_qmk {
-            // Check if mod-tap
-            let mt_re = Regex::new("MT\\(([^()]+), ([^()]+)\\)").unwrap();
-            if let Some(captures) = mt_re.captures(name) {
-                let qk_mod_tap = if self.use_legacy_scancodes {
-                    QK_MOD_TAP_LEGACY
-                } else {
-                    QK_MOD_TAP
-                };
-                let mod_ = *MOD_TAP_MODS.get(&captures.get(1).unwrap().as_str())?;
-                let kc = *self.keymap.get(captures.get(2).unwrap().as_str())?;
-                return Some(qk_mod_tap | ((mod_ & 0x1f) << 8) | (kc & 0xff));
+            self.qmk_scancode_from_name(name)
+        } else {
+            None
+        }
+    }
+
+    fn qmk_ranges(&self) -> &'static QmkRanges {
+        if self.use_legacy_scancodes {
+            &QMK_RANGES_LEGACY
+        } else {
+            &QMK_RANGES
+        }
+    }
+
+    fn basic_scancode_name(&self, scancode: u16) -> Option<&str> {
+        self.scancode_names
+            .get(&(scancode & 0xFF))
+            .map(String::as_str)
+    }
+
+    fn basic_scancode_from_name(&self, name: &str) -> Option<u16> {
+        self.keymap.get(name.trim()).copied().filter(|x| *x <= 0xFF)
+    }
+
+    /// Name QMK quantum keycodes, like `MT(LEFT_CTRL, A)` or `MO(4)`
+    fn qmk_scancode_to_name(&self, scancode: u16) -> Option<String> {
+        let ranges = self.qmk_ranges();
+        let mods = (scancode >> 8) & 0x1F;
+
+        if (QK_MODS..=QK_MODS_MAX).contains(&scancode) {
+            return modified_name(mods, self.basic_scancode_name(scancode)?);
+        }
+
+        if (ranges.mod_tap..=ranges.mod_tap_max).contains(&scancode) {
+            let kc_name = self.basic_scancode_name(scancode)?;
+            return Some(mod_tap_name(&mods_to_name(mods)?, kc_name));
+        }
+
+        if (QK_LAYER_TAP..=QK_LAYER_TAP_MAX).contains(&scancode) {
+            let kc_name = self.basic_scancode_name(scancode)?;
+            return Some(format!("LT({}, {})", (scancode >> 8) & 0xF, kc_name));
+        }
+
+        for (function, first, max_layer) in &ranges.layer_fns {
+            if (*first..=first + max_layer).contains(&scancode) {
+                return Some(format!("{}({})", function, scancode - first));
+            }
+        }
+
+        if (ranges.one_shot_mod..=ranges.one_shot_mod + 0x1F).contains(&scancode) {
+            return Some(format!(
+                "OSM({})",
+                mods_to_name(scancode - ranges.one_shot_mod)?
+            ));
+        }
+
+        None
+    }
+
+    /// Parse names generated by `qmk_scancode_to_name`
+    fn qmk_scancode_from_name(&self, name: &str) -> Option<u16> {
+        let ranges = self.qmk_ranges();
+        if let Some((mods, kc)) = parse_mod_tap(name) {
+            let kc = self.basic_scancode_from_name(kc)?;
+            return Some(ranges.mod_tap | (mods_from_name(mods)? << 8) | kc);
+        }
+
+        let (function, args) = name.strip_suffix(')')?.split_once('(')?;
+        match function {
+            "LT" => {
+                let (layer, kc) = args.split_once(',')?;
+                let layer = parse_layer(layer, 0xF)?;
+                let kc = self.basic_scancode_from_name(kc)?;
+                Some(QK_LAYER_TAP | (layer << 8) | kc)
+            }
+            "OSM" => Some(ranges.one_shot_mod | mods_from_name(args)?),
+            _ => {
+                if let Some((_, first, max_layer)) =
+                    ranges.layer_fns.iter().find(|(x, _, _)| *x == function)
+                {
+                    return Some(first + parse_layer(args, *max_layer)?);
+                }
+
+                let (mods, kc) = parse_modified(name)?;
+                let kc = self.basic_scancode_from_name(kc)?;
+                Some((mods << 8) | kc)
             }
         }
-        self.keymap.get(name).copied()
     }
 
     pub fn f_keys(&self) -> impl Iterator<Item = &str> {
@@ -248,16 +400,29 @@ impl Layout {
     pub fn layout(&self) -> &HashMap<String, (u8, u8)> {
         &self.layout
     }
+
+    /// Number of layers with their own LED settings
+    pub(crate) fn num_led_layers(&self) -> u8 {
+        if self.meta.has_per_layer {
+            self.meta.num_layers
+        } else {
+            1
+        }
+    }
 }
 
+type ScancodesByName = HashMap<String, u16>;
+type NamesByScancode = HashMap<u16, String>;
+
 fn parse_keymap_json(
     keymap_json: &str,
     board: &str,
     meta: &Meta,
     has_pause_scancode: bool,
     has_fnlock_scancode: bool,
-) -> (HashMap<String, u16>, HashMap<u16, String>) {
-    let mut keymap: HashMap<String, u16> = serde_json::from_str(keymap_json).unwrap();
+) -> Result<(ScancodesByName, NamesByScancode), Error> {
+    let mut keymap: ScancodesByName = serde_json::from_str(keymap_json)
+        .map_err(|err| Error::from(err).context("failed to parse keymap.json"))?;
 
     // Filter out keycodes that aren't relevant to this particular model
     // TODO: Support bonw backlight over USB?
@@ -278,28 +443,108 @@ fn parse_keymap_json(
         keymap.remove("FNLOCK");
     }
 
-    // Generate reverse mapping, from scancode to names
+    // Generate reverse mapping, from scancode to names. Skip QMK range
+    // markers like `MODS` and `MODS_MAX`, which aren't real keycodes.
+    let is_range_marker =
+        |name: &str| name.ends_with("_MAX") || keymap.contains_key(&format!("{}_MAX", name));
     let mut scancode_names = HashMap::new();
     for (scancode_name, scancode) in &keymap {
-        scancode_names.insert(*scancode, scancode_name.clone());
+        if !is_range_marker(scancode_name) {
+            scancode_names.insert(*scancode, scancode_name.clone());
+        }
     }
 
-    (keymap, scancode_names)
+    Ok((keymap, scancode_names))
+}
+
+/// Name of the mod-tap key sending `kc` when tapped and `mods` when held
+pub fn mod_tap_name(mods: &str, kc: &str) -> String {
+    format!("MT({}, {})", mods, kc)
 }
 
-fn parse_ec_date(version: &str) -> Option<(u16, u16, u16)> {
-    let groups = Regex::new(r"^(\d+)-(\d+)-(\d+)_")
-        .unwrap()
-        .captures(version)?;
-    let mut groups = groups
+/// Split a mod-tap name, like `MT(LEFT_CTRL, A)`, into modifier and tap
+/// keycode names
+pub fn parse_mod_tap(name: &str) -> Option<(&str, &str)> {
+    let (mods, kc) = name
+        .strip_prefix("MT(")?
+        .strip_suffix(')')?
+        .split_once(',')?;
+    Some((mods.trim(), kc.trim()))
+}
+
+/// Name of the keycode `kc` with the modifier bits in `mods` held, nested like
+/// `LCTL(LSFT(A))`
+pub fn modified_name(mods: u16, kc: &str) -> Option<String> {
+    if mods & 0xF == 0 {
+        return None;
+    }
+    let mut name = kc.to_string();
+    for (function, mod_) in MODIFIER_FNS.iter().rev() {
+        if mods & 0x10 == mod_ & 0x10 && mods & mod_ & 0xF != 0 {
+            name = format!("{}({})", function, name);
+        }
+    }
+    Some(name)
+}
+
+/// Split a modified keycode name, like `LCTL(LSFT(A))`, into modifier bits and
+/// keycode name
+pub fn parse_modified(name: &str) -> Option<(u16, &str)> {
+    let mut mods = 0;
+    let mut name = name.trim();
+    while let Some((function, args)) = name.strip_suffix(')').and_then(|x| x.split_once('(')) {
+        let mod_ = MODIFIER_FNS.iter().find(|(x, _)| *x == function)?.1;
+        // Left and right modifiers can't be combined, or repeated
+        if mods != 0 && (mods & 0x10 != mod_ & 0x10 || mods & mod_ & 0xF != 0) {
+            return None;
+        }
+        mods |= mod_;
+        name = args.trim();
+    }
+    if mods == 0 {
+        None
+    } else {
+        Some((mods, name))
+    }
+}
+
+/// Format QMK modifier bits, like `LEFT_CTRL | LEFT_SHIFT`, from the values
+/// in `MOD_TAP_MODS`
+pub fn mods_to_name(mods: u16) -> Option<String> {
+    let right = mods & 0x10;
+    let names = [0x01, 0x02, 0x04, 0x08]
         .iter()
-        .skip(1)
-        .map(|g| u16::from_str(g.unwrap().as_str()).unwrap());
-    Some((
-        groups.next().unwrap(),
-        groups.next().unwrap(),
-        groups.next().unwrap(),
-    ))
+        .filter(|bit| mods & *bit != 0)
+        .map(|bit| {
+            MOD_TAP_MODS
+                .iter()
+                .find(|(_, v)| **v == right | bit)
+                .map(|(k, _)| *k)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if names.is_empty() {
+        None
+    } else {
+        Some(names.join(" | "))
+    }
+}
+
+/// Parse names formatted by `mods_to_name`
+pub fn mods_from_name(name: &str) -> Option<u16> {
+    let mut mods = 0;
+    for i in name.split('|') {
+        let mod_ = *MOD_TAP_MODS.get(i.trim())?;
+        // Left and right modifiers can't be combined
+        if mods != 0 && mods & 0x10 != mod_ & 0x10 {
+            return None;
+        }
+        mods |= mod_;
+    }
+    Some(mods)
+}
+
+fn parse_layer(name: &str, max_layer: u16) -> Option<u16> {
+    name.trim().parse().ok().filter(|x| *x <= max_layer)
 }
 
 fn keymap_remove_pause(keymap: &mut KeyMap) {
@@ -352,6 +597,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn default_keymap_valid() {
+        for i in layouts() {
+            for version in VERSIONS {
+                let layout = Layout::from_board(i, version).unwrap();
+                assert_eq!(layout.default.validate(&layout), Ok(()), "{}", i);
+            }
+        }
+    }
+
     #[test]
     fn qmk_has_ec_keycodes() {
         for version in VERSIONS {
@@ -458,6 +713,119 @@ mod tests {
         }
     }
 
+    #[test]
+    fn scancode_round_trip() {
+        for i in layouts() {
+            for version in VERSIONS {
+                let layout = Layout::from_board(i, version).unwrap();
+                for scancode in 0..=u16::MAX {
+                    if let Some(name) = layout.scancode_to_name(scancode) {
+                        assert_eq!(
+                            layout.scancode_from_name(&name),
+                            Some(scancode),
+                            "{} {} {}",
+                            i,
+                            version,
+                            name
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ec_features() {
+        for (version, has_pause, has_fnlock) in [
+            ("2022-05-20_5b2b5b1", false, false),
+            ("2022-05-23_7e1a2b1", true, false),
+            ("2023-08-01_8c5f7d6", true, true),
+            ("1970-01-01-deadbee", true, true),
+        ] {
+            let layout = Layout::from_board("system76/darp6", version).unwrap();
+            assert_eq!(
+                layout.scancode_from_name("PAUSE").is_some(),
+                has_pause,
+                "{}",
+                version
+            );
+            assert_eq!(
+                layout.scancode_from_name("FNLOCK").is_some(),
+                has_fnlock,
+                "{}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn qmk_functions() {
+        let current = Layout::from_board("system76/launch_1", "0.19.12").unwrap();
+        let legacy = Layout::from_board("system76/launch_1", "0.7.104").unwrap();
+        for (name, scancode, legacy_scancode) in [
+            ("MT(LEFT_CTRL, A)", 0x2104, 0x6104),
+            ("MT(RIGHT_CTRL | RIGHT_ALT, ESC)", 0x3529, 0x7529),
+            ("LT(1, SPACE)", 0x412C, 0x412C),
+            ("MO(5)", 0x5225, 0x5105),
+            ("TG(5)", 0x5265, 0x5305),
+            ("TO(5)", 0x5205, 0x5015),
+            ("OSL(2)", 0x5282, 0x5402),
+            ("OSM(LEFT_SHIFT)", 0x52A2, 0x5502),
+            ("OSM(RIGHT_ALT | RIGHT_SUPER)", 0x52BC, 0x551C),
+            ("LCTL(A)", 0x0104, 0x0104),
+            ("LCTL(LSFT(A))", 0x0304, 0x0304),
+            ("RALT(RGUI(TAB))", 0x1C2B, 0x1C2B),
+        ] {
+            assert_eq!(current.scancode_from_name(name), Some(scancode), "{}", name);
+            assert_eq!(current.scancode_to_name(scancode).as_deref(), Some(name));
+            assert_eq!(
+                legacy.scancode_from_name(name),
+                Some(legacy_scancode),
+                "{}",
+                name
+            );
+            assert_eq!(
+                legacy.scancode_to_name(legacy_scancode).as_deref(),
+                Some(name)
+            );
+        }
+
+        // Versions from `git describe`, as reported by the firmware
+        let describe = Layout::from_board("system76/launch_1", "0.7.103-4011-g708ed5").unwrap();
+        assert_eq!(describe.scancode_from_name("MO(5)"), Some(0x5105));
+        let dirty = Layout::from_board("system76/launch_1", "0.7.103-4011-g708ed5-dirty").unwrap();
+        assert_eq!(dirty.scancode_from_name("MO(5)"), Some(0x5105));
+
+        // Named layer keys take precedence
+        assert_eq!(
+            current.scancode_from_name("MO(1)"),
+            current.scancode_from_name("FN")
+        );
+        assert_eq!(current.scancode_to_name(0x5221).as_deref(), Some("FN"));
+        assert_eq!(
+            legacy.scancode_from_name("TO(0)"),
+            legacy.scancode_from_name("LAYER_SWITCH_1")
+        );
+
+        for name in [
+            "MT(A, B)",
+            "MT(LEFT_CTRL | RIGHT_ALT, A)",
+            "MT(LEFT_CTRL, FN)",
+            "LT(16, A)",
+            "MO(32)",
+            "LCTL(LCTL(A))",
+            "LCTL(RSFT(A))",
+            "LCTL(FN)",
+            "XYZ(1)",
+        ] {
+            assert_eq!(current.scancode_from_name(name), None, "{}", name);
+        }
+
+        // Only QMK supports these
+        let ec = Layout::from_board("system76/darp6", "0.19.12").unwrap();
+        assert_eq!(ec.scancode_from_name("LCTL(A)"), None);
+    }
+
     #[test]
     fn layout_has_f_keys() {
         for i in layouts() {
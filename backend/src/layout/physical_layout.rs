@@ -3,7 +3,7 @@
 use serde::Deserialize;
 use std::char;
 
-use crate::{Rect, Rgb};
+use crate::{Error, Rect, Rgb};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -13,8 +13,9 @@ pub(crate) struct PhysicalLayout {
 }
 
 impl PhysicalLayout {
-    pub fn from_str(physical_json: &str) -> Self {
-        let json = serde_json::from_str::<PhysicalLayoutJson>(physical_json).unwrap();
+    pub fn from_str(physical_json: &str) -> Result<Self, Error> {
+        let json = serde_json::from_str::<PhysicalLayoutJson>(physical_json)
+            .map_err(|err| Error::from(err).context("failed to parse physical.json"))?;
 
         let mut keys = Vec::new();
 
@@ -67,9 +68,10 @@ impl PhysicalLayout {
             }
         }
 
-        let meta = meta.expect("No layout meta");
+        let meta = meta
+            .ok_or_else(|| Error::InvalidArgument("no layout meta in physical.json".to_string()))?;
 
-        Self { keys, meta }
+        Ok(Self { keys, meta })
     }
 }
 
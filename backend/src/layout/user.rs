@@ -0,0 +1,211 @@
+//! Layouts loaded at runtime, for custom and prototype boards that aren't
+//! compiled in
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use super::Layout;
+use crate::profile::{data_dir, APP_DIR};
+use crate::Error;
+
+static USER_LAYOUTS: Lazy<RwLock<BTreeMap<String, Arc<LayoutFiles>>>> = Lazy::new(Default::default);
+
+/// Contents of a layout directory
+#[derive(Debug)]
+pub(super) struct LayoutFiles {
+    pub meta_json: String,
+    pub default_json: String,
+    /// If not set, the built-in QMK or EC keymap is used
+    pub keymap_json: Option<String>,
+    pub layout_json: String,
+    pub leds_json: String,
+    pub physical_json: String,
+}
+
+impl LayoutFiles {
+    pub fn from_dir(dir: &Path) -> Result<Self, Error> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            fs::read_to_string(&path).map_err(|err| {
+                Error::from(err).context(&format!("failed to read {}", path.display()))
+            })
+        };
+        let keymap_json = if dir.join("keymap.json").exists() {
+            Some(read("keymap.json")?)
+        } else {
+            None
+        };
+        Ok(Self {
+            meta_json: read("meta.json")?,
+            default_json: read("default.json")?,
+            keymap_json,
+            layout_json: read("layout.json")?,
+            leds_json: read("leds.json")?,
+            physical_json: read("physical.json")?,
+        })
+    }
+}
+
+pub(super) fn user_layout(board: &str) -> Option<Arc<LayoutFiles>> {
+    USER_LAYOUTS.read().unwrap().get(board).cloned()
+}
+
+pub(super) fn user_layout_names() -> Vec<String> {
+    USER_LAYOUTS.read().unwrap().keys().cloned().collect()
+}
+
+/// Directory searched by `load_user_layouts`
+pub fn user_layouts_dir() -> Option<PathBuf> {
+    Some(data_dir()?.join(APP_DIR).join("layouts"))
+}
+
+/// Load layouts from `user_layouts_dir`, as with `load_layouts_from_dir`
+pub fn load_user_layouts() -> Vec<String> {
+    match user_layouts_dir() {
+        Some(dir) => load_layouts_from_dir(&dir),
+        None => Vec::new(),
+    }
+}
+
+/// Load each `<dir>/<vendor>/<name>` directory as the layout of board
+/// `<vendor>/<name>`, so it is listed by `all_layouts` and opened by
+/// `Layout::from_board`. A user layout replaces a built-in layout of the
+/// same name.
+///
+/// Layouts that fail to load or validate are skipped, returning an error
+/// message for each.
+pub fn load_layouts_from_dir(dir: &Path) -> Vec<String> {
+    let (layouts, errors) = read_layouts_dir(dir);
+    let mut user_layouts = USER_LAYOUTS.write().unwrap();
+    for (board, files) in layouts {
+        user_layouts.insert(board, Arc::new(files));
+    }
+    errors
+}
+
+/// Layouts in `dir` by board name, as loaded by `load_layouts_from_dir`,
+/// and an error message for each that failed
+fn read_layouts_dir(dir: &Path) -> (BTreeMap<String, LayoutFiles>, Vec<String>) {
+    let mut layouts = BTreeMap::new();
+    let mut errors = Vec::new();
+
+    let vendors = match subdirs(dir) {
+        Ok(vendors) => vendors,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return (layouts, errors),
+        Err(err) => {
+            errors.push(format!("failed to read {}: {}", dir.display(), err));
+            return (layouts, errors);
+        }
+    };
+
+    for (vendor, vendor_dir) in vendors {
+        let boards = match subdirs(&vendor_dir) {
+            Ok(boards) => boards,
+            Err(err) => {
+                errors.push(format!("failed to read {}: {}", vendor_dir.display(), err));
+                continue;
+            }
+        };
+        for (name, board_dir) in boards {
+            let board = format!("{}/{}", vendor, name);
+            match load_layout(&board, &board_dir) {
+                Ok(files) => {
+                    info!("Loaded layout '{}' from {}", board, board_dir.display());
+                    layouts.insert(board, files);
+                }
+                Err(err) => errors.push(format!(
+                    "invalid layout '{}' in {}:\n{}",
+                    board,
+                    board_dir.display(),
+                    err
+                )),
+            }
+        }
+    }
+
+    (layouts, errors)
+}
+
+fn load_layout(board: &str, dir: &Path) -> Result<LayoutFiles, Error> {
+    let files = LayoutFiles::from_dir(dir)?;
+    let keymap_file = if files.keymap_json.is_some() {
+        "keymap.json"
+    } else {
+        "built-in keymap"
+    };
+    let errors = Layout::from_files(board, &files, "dummy")?.lint(keymap_file);
+    if !errors.is_empty() {
+        let errors = errors.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        return Err(Error::InvalidArgument(errors.join("\n")));
+    }
+    Ok(files)
+}
+
+/// Subdirectories of `dir` with UTF-8 names, sorted by name
+fn subdirs(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            if let Ok(name) = entry.file_name().into_string() {
+                dirs.push((name, entry.path()));
+            }
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn copy_layout(board: &str, keyboard: &str, dir: &Path) {
+        fs::create_dir_all(dir).unwrap();
+        let layouts = Path::new("../layouts");
+        for i in ["meta.json", "default.json"] {
+            fs::copy(layouts.join(board).join(i), dir.join(i)).unwrap();
+        }
+        for i in ["layout.json", "leds.json", "physical.json"] {
+            let src = layouts.join("keyboards").join(keyboard).join(i);
+            fs::copy(src, dir.join(i)).unwrap();
+        }
+    }
+
+    #[test]
+    fn user_layouts() {
+        let dir = env::temp_dir().join(format!("s76-layouts-{}", uuid::Uuid::new_v4()));
+        copy_layout(
+            "system76/launch_1",
+            "system76/launch_1",
+            &dir.join("test/custom"),
+        );
+        copy_layout(
+            "system76/launch_1",
+            "system76/launch_1",
+            &dir.join("test/broken"),
+        );
+        fs::write(dir.join("test/broken/leds.json"), r#"{"NOT_A_KEY": [0]}"#).unwrap();
+        fs::create_dir_all(dir.join("test/missing")).unwrap();
+
+        let (layouts, errors) = read_layouts_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].contains("test/broken") && errors[0].contains("leds.json"));
+        assert!(errors[1].contains("test/missing") && errors[1].contains("meta.json"));
+
+        assert_eq!(layouts.keys().collect::<Vec<_>>(), ["test/custom"]);
+        let files = &layouts["test/custom"];
+        let layout = Layout::from_files("test/custom", files, "0.19.12").unwrap();
+        assert!(layout.meta.is_qmk);
+        assert_eq!(layout.scancode_from_name("FN"), Some(0x5221));
+        let layout = Layout::from_files("test/custom", files, "0.7.104").unwrap();
+        assert_eq!(layout.scancode_from_name("FN"), Some(0x5101));
+    }
+}
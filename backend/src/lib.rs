@@ -15,7 +15,7 @@
 //!     }
 //! });
 //! backend.refresh();
-//! # Ok::<(), String>(())
+//! # Ok::<(), system76_keyboard_configurator_backend::Error>(())
 //! ```
 
 #[macro_use]
@@ -27,6 +27,10 @@ mod board;
 mod color;
 mod daemon;
 mod deref_cell;
+mod error;
+mod firmware;
+mod flash;
+mod history;
 mod key;
 mod keymap;
 mod layer;
@@ -35,11 +39,14 @@ mod localize;
 mod matrix;
 mod mode;
 mod nelson;
+mod profile;
 mod rect;
+mod usb;
 
 pub use crate::daemon::BoardId;
 use crate::daemon::*;
 pub use crate::{
-    backend::*, benchmark::*, board::*, color::*, deref_cell::*, key::*, keymap::*, layer::*,
-    layout::*, localize::*, matrix::*, mode::*, nelson::*, rect::*,
+    backend::*, benchmark::*, board::*, color::*, deref_cell::*, error::*, firmware::*, flash::*,
+    history::*, key::*, keymap::*, layer::*, layout::*, localize::*, matrix::*, mode::*, nelson::*,
+    profile::*, rect::*, usb::*,
 };
@@ -0,0 +1,250 @@
+use std::{
+    env,
+    fs::{self, File},
+    io,
+    path::PathBuf,
+};
+
+use crate::{Error, KeyMap};
+
+pub(crate) const APP_DIR: &str = "system76-keyboard-configurator";
+
+/// Named `KeyMap` snapshots stored on disk, grouped by board model
+///
+/// Each profile is a keymap file at `<dir>/<model>/<name>.json`.
+#[derive(Clone, Debug)]
+pub struct ProfileStore {
+    dir: PathBuf,
+}
+
+impl ProfileStore {
+    /// Store in the user's data directory (`$XDG_DATA_HOME` on Linux)
+    pub fn new() -> Result<Self, Error> {
+        let dir = data_dir()
+            .ok_or_else(|| Error::NotFound("failed to find data directory".to_string()))?;
+        Ok(Self::with_dir(dir.join(APP_DIR).join("profiles")))
+    }
+
+    pub fn with_dir(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    fn model_dir(&self, model: &str) -> PathBuf {
+        // Models are of the form `system76/launch_1`
+        model
+            .split('/')
+            .fold(self.dir.clone(), |dir, x| dir.join(x))
+    }
+
+    fn path(&self, model: &str, name: &str) -> Result<PathBuf, Error> {
+        check_name(name)?;
+        Ok(self.model_dir(model).join(format!("{}.json", name)))
+    }
+
+    /// Names of the profiles saved for `model`, sorted
+    pub fn list(&self, model: &str) -> Result<Vec<String>, Error> {
+        let context = |err: io::Error| Error::from(err).context("failed to read profile directory");
+        let entries = match fs::read_dir(self.model_dir(model)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(context(err)),
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(context)?.path();
+            if path.extension().map_or(false, |x| x == "json") {
+                if let Some(name) = path.file_stem().and_then(|x| x.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn exists(&self, model: &str, name: &str) -> bool {
+        self.path(model, name).map_or(false, |path| path.is_file())
+    }
+
+    /// Save `keymap` as profile `name` for its model, replacing any existing profile
+    pub fn save(&self, name: &str, keymap: &KeyMap) -> Result<(), Error> {
+        let path = self.path(&keymap.model, name)?;
+        fs::create_dir_all(self.model_dir(&keymap.model))
+            .map_err(|err| Error::from(err).context("failed to create profile directory"))?;
+
+        // Write to a temporary file first, so a failed save doesn't lose the old profile
+        let tmp_path = path.with_extension("json.tmp");
+        let res = File::create(&tmp_path)
+            .map_err(Error::from)
+            .and_then(|file| keymap.to_writer_pretty(file).map_err(Error::from))
+            .and_then(|()| fs::rename(&tmp_path, &path).map_err(Error::from));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        res.map_err(|err| err.context(&format!("failed to write profile '{}'", name)))
+    }
+
+    /// Load profile `name` for `model`, migrated to the current keymap version
+    pub fn load(&self, model: &str, name: &str) -> Result<KeyMap, Error> {
+        let path = self.path(model, name)?;
+        let file = File::open(path).map_err(|err| {
+            Error::from(err).context(&format!("failed to open profile '{}'", name))
+        })?;
+        let keymap = KeyMap::from_reader(file)
+            .map_err(|err| {
+                Error::from(err).context(&format!("failed to parse profile '{}'", name))
+            })?
+            .migrate()
+            .map_err(|err| {
+                Error::InvalidArgument(format!("failed to load profile '{}': {}", name, err))
+            })?;
+        if keymap.model != model {
+            return Err(Error::InvalidArgument(format!(
+                "profile '{}' is for board '{}'",
+                name, keymap.model
+            )));
+        }
+        Ok(keymap)
+    }
+
+    pub fn delete(&self, model: &str, name: &str) -> Result<(), Error> {
+        let path = self.path(model, name)?;
+        fs::remove_file(path).map_err(|err| {
+            Error::from(err).context(&format!("failed to delete profile '{}'", name))
+        })
+    }
+
+    /// Rename profile `old` to `new`. Fails if `new` already exists.
+    pub fn rename(&self, model: &str, old: &str, new: &str) -> Result<(), Error> {
+        let old_path = self.path(model, old)?;
+        let new_path = self.path(model, new)?;
+        if !old_path.is_file() {
+            return Err(Error::NotFound(format!("profile '{}' does not exist", old)));
+        }
+        if new_path.exists() {
+            return Err(Error::InvalidArgument(format!(
+                "profile '{}' already exists",
+                new
+            )));
+        }
+        fs::rename(old_path, new_path)
+            .map_err(|err| Error::from(err).context(&format!("failed to rename profile '{}'", old)))
+    }
+}
+
+/// Profile names are used as file names, so path separators and hidden files
+/// are not allowed.
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() {
+        Err(Error::InvalidArgument("profile name is empty".to_string()))
+    } else if name.starts_with('.') || name.contains(['/', '\\', '\0']) {
+        Err(Error::InvalidArgument(format!(
+            "invalid profile name '{}'",
+            name
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) fn data_dir() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(PathBuf::from)
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn data_dir() -> Option<PathBuf> {
+    Some(PathBuf::from(env::var_os("HOME")?).join("Library/Application Support"))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub(crate) fn data_dir() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".local/share")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Layout;
+
+    struct TempStore(ProfileStore);
+
+    impl TempStore {
+        fn new() -> Self {
+            let dir = env::temp_dir().join(format!("s76-profiles-{}", uuid::Uuid::new_v4()));
+            Self(ProfileStore::with_dir(dir))
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.dir());
+        }
+    }
+
+    #[test]
+    fn profile_store() {
+        let store = TempStore::new();
+        let store = &store.0;
+        let model = "system76/launch_1";
+        let mut keymap = Layout::from_board(model, "0.19.12").unwrap().default;
+
+        assert_eq!(store.list(model), Ok(Vec::new()));
+
+        store.save("gaming", &keymap).unwrap();
+        keymap.layers[0].brightness = 0;
+        store.save("coding", &keymap).unwrap();
+        assert_eq!(
+            store.list(model),
+            Ok(vec!["coding".to_string(), "gaming".to_string()])
+        );
+        assert_eq!(store.list("system76/launch_2"), Ok(Vec::new()));
+        assert_eq!(store.load(model, "coding").unwrap().layers[0].brightness, 0);
+        assert!(store.load("system76/launch_2", "coding").is_err());
+
+        assert!(store.rename(model, "coding", "gaming").is_err());
+        store.rename(model, "coding", "work").unwrap();
+        assert!(!store.exists(model, "coding"));
+        assert_eq!(store.load(model, "work").unwrap().layers[0].brightness, 0);
+
+        store.delete(model, "gaming").unwrap();
+        assert_eq!(store.list(model), Ok(vec!["work".to_string()]));
+        assert!(store.delete(model, "gaming").is_err());
+    }
+
+    #[test]
+    fn save_failed() {
+        let store = TempStore::new();
+        let model = "system76/launch_1";
+        let keymap = Layout::from_board(model, "0.19.12").unwrap().default;
+        // A directory in the way of the profile
+        let path = store.0.model_dir(model).join("broken.json");
+        fs::create_dir_all(path.join("file")).unwrap();
+
+        assert!(matches!(store.0.save("broken", &keymap), Err(Error::Io(_))));
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn profile_names() {
+        let store = TempStore::new();
+        let keymap = Layout::from_board("system76/launch_1", "0.19.12")
+            .unwrap()
+            .default;
+        for name in ["", " ", ".hidden", "../escape", "a/b", "a\\b"] {
+            assert!(
+                matches!(store.0.save(name, &keymap), Err(Error::InvalidArgument(_))),
+                "{:?}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! USB devices listed by Linux in `/sys/bus/usb/devices`
+
+use std::{fs, io, path::Path};
+
+use crate::{Bootloaded, Error};
+
+const SYSTEM76_VID: u16 = 0x3384;
+const ATMEL_VID: u16 = 0x03eb;
+const ATMEGA32U4_PID: u16 = 0x2ff4;
+const AT90USB646_PID: u16 = 0x2ff9;
+
+/// Board in bootloader mode, waiting to be flashed
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BootloadedDevice {
+    pub kind: Bootloaded,
+    /// Port of the device, such as `1-2.1`, which stays the same until it is
+    /// plugged into another port
+    pub bus_path: String,
+    pub serial: Option<String>,
+}
+
+/// Boards attached in bootloader mode, sorted by bus path
+pub fn bootloaded_devices() -> Result<Vec<BootloadedDevice>, Error> {
+    bootloaded_devices_in(Path::new("/sys"))
+}
+
+/// Like `bootloaded_devices`, with sysfs mounted at `sysfs`
+pub(crate) fn bootloaded_devices_in(sysfs: &Path) -> Result<Vec<BootloadedDevice>, Error> {
+    let devices =
+        usb_devices(sysfs).map_err(|err| Error::from(err).context("Failed to list USB devices"))?;
+
+    let mut bootloaded = Vec::new();
+    for device in &devices {
+        let kind = match (device.vid, device.pid) {
+            (ATMEL_VID, AT90USB646_PID) => {
+                // Unlike the Launch Lite, other boards have a USB hub in front
+                // of the microcontroller
+                if device.parents().any(|parent| {
+                    devices
+                        .iter()
+                        .any(|x| x.bus_path == parent && x.is_system76_hub())
+                }) {
+                    Bootloaded::At90usb646
+                } else {
+                    Bootloaded::At90usb646Lite
+                }
+            }
+            (ATMEL_VID, ATMEGA32U4_PID) => Bootloaded::AtMega32u4,
+            _ => continue,
+        };
+        bootloaded.push(BootloadedDevice {
+            kind,
+            bus_path: device.bus_path.clone(),
+            serial: device.serial.clone(),
+        });
+    }
+    Ok(bootloaded)
+}
+
+/// Bus path of the USB device that the hidraw device `hidraw`, such as
+/// `/dev/hidraw3`, belongs to, with sysfs mounted at `sysfs`
+pub(crate) fn hidraw_bus_path(sysfs: &Path, hidraw: &Path) -> Option<String> {
+    let device = sysfs
+        .join("class/hidraw")
+        .join(hidraw.file_name()?)
+        .join("device");
+    let device = fs::canonicalize(device).ok()?;
+    // Only look within sysfs, not the directories it is mounted in
+    bus_path_of(device.strip_prefix(fs::canonicalize(sysfs).ok()?).ok()?)
+}
+
+/// Last USB device in the sysfs path `device`, such as `1-2` in
+/// `devices/.../usb1/1-2/1-2:1.1/0003:3384:0001.0005`. Interfaces contain a
+/// `:`.
+fn bus_path_of(device: &Path) -> Option<String> {
+    device
+        .iter()
+        .filter_map(|x| x.to_str())
+        .filter(|x| x.contains('-') && !x.contains(':'))
+        .last()
+        .map(str::to_string)
+}
+
+/// Whether `vid` and `pid` are those of a board in bootloader mode
+pub(crate) fn is_bootloader(vid: u16, pid: u16) -> bool {
+    vid == ATMEL_VID && matches!(pid, ATMEGA32U4_PID | AT90USB646_PID)
+}
+
+#[derive(Debug)]
+struct UsbDevice {
+    bus_path: String,
+    vid: u16,
+    pid: u16,
+    manufacturer: String,
+    product: String,
+    serial: Option<String>,
+}
+
+impl UsbDevice {
+    fn from_dir(bus_path: String, dir: &Path) -> Option<Self> {
+        let read = |name| {
+            fs::read_to_string(dir.join(name))
+                .ok()
+                .map(|x| x.trim().to_string())
+        };
+        let id = |name| u16::from_str_radix(&read(name)?, 16).ok();
+        Some(Self {
+            // Interfaces, such as `1-2:1.0`, have no IDs
+            vid: id("idVendor")?,
+            pid: id("idProduct")?,
+            manufacturer: read("manufacturer").unwrap_or_default(),
+            product: read("product").unwrap_or_default(),
+            serial: read("serial"),
+            bus_path,
+        })
+    }
+
+    /// Bus paths of the hubs the device is attached through, such as `1-2`
+    /// for `1-2.1`
+    fn parents(&self) -> impl Iterator<Item = &str> {
+        let mut bus_path = self.bus_path.as_str();
+        std::iter::from_fn(move || {
+            let (parent, _) = bus_path.rsplit_once('.')?;
+            bus_path = parent;
+            Some(parent)
+        })
+    }
+
+    /// Like the name matched in `lsusb` output, the manufacturer followed by
+    /// the product
+    fn is_system76_hub(&self) -> bool {
+        let name = format!("{} {}", self.manufacturer, self.product);
+        self.vid == SYSTEM76_VID && self.pid <= 0x000f && name.contains("System76 USB")
+    }
+}
+
+/// Devices in `<sysfs>/bus/usb/devices`, sorted by bus path
+fn usb_devices(sysfs: &Path) -> io::Result<Vec<UsbDevice>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(sysfs.join("bus/usb/devices"))? {
+        let entry = entry?;
+        if let Ok(bus_path) = entry.file_name().into_string() {
+            devices.extend(UsbDevice::from_dir(bus_path, &entry.path()));
+        }
+    }
+    devices.sort_by(|a, b| a.bus_path.cmp(&b.bus_path));
+    Ok(devices)
+}
+
+/// Sysfs tree with a few USB devices, created in a temporary directory since
+/// its paths aren't valid on every platform, and removed when dropped
+#[cfg(test)]
+pub(crate) struct TestSysfs(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestSysfs {
+    pub fn new() -> Self {
+        let sysfs = Self(std::env::temp_dir().join(format!("s76-sysfs-{}", uuid::Uuid::new_v4())));
+        let devices = sysfs.0.join("bus/usb/devices");
+        for (bus_path, vid, pid, manufacturer, product, serial) in [
+            (
+                "usb1",
+                "1d6b",
+                "0002",
+                "Linux 6.5.0 xhci-hcd",
+                "xHCI Host Controller",
+                None,
+            ),
+            (
+                "usb3",
+                "1d6b",
+                "0002",
+                "Linux 6.5.0 ehci_hcd",
+                "EHCI Host Controller",
+                None,
+            ),
+            ("1-2", "3384", "0003", "System76", "USB Hub", None),
+            (
+                "1-2.1",
+                "03eb",
+                "2ff9",
+                "ATMEL",
+                "AT90USB646 DFU",
+                Some("0123456789AB"),
+            ),
+            ("1-3", "046d", "c52b", "Logitech", "USB Receiver", None),
+            ("1-4", "03eb", "2ff9", "ATMEL", "AT90USB646 DFU", None),
+            ("3-1", "03eb", "2ff4", "ATMEL", "ATm32U4DFU", None),
+        ] {
+            let dir = devices.join(bus_path);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("idVendor"), format!("{}\n", vid)).unwrap();
+            fs::write(dir.join("idProduct"), format!("{}\n", pid)).unwrap();
+            fs::write(dir.join("manufacturer"), format!("{}\n", manufacturer)).unwrap();
+            fs::write(dir.join("product"), format!("{}\n", product)).unwrap();
+            if let Some(serial) = serial {
+                fs::write(dir.join("serial"), format!("{}\n", serial)).unwrap();
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            // Interface of the hub, and a hidraw device of a board behind it,
+            // named with `:` that Windows paths reject
+            let interface = devices.join("1-2:1.0");
+            fs::create_dir_all(&interface).unwrap();
+            fs::write(interface.join("bInterfaceClass"), "09\n").unwrap();
+
+            let device =
+                "devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2.2/1-2.2:1.1/0003:3384:0001.0001";
+            fs::create_dir_all(sysfs.0.join(device)).unwrap();
+            let hidraw = sysfs.0.join("class/hidraw/hidraw0");
+            fs::create_dir_all(&hidraw).unwrap();
+            std::os::unix::fs::symlink(Path::new("../../..").join(device), hidraw.join("device"))
+                .unwrap();
+        }
+
+        sysfs
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestSysfs {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixture() {
+        let sysfs = TestSysfs::new();
+        let devices = bootloaded_devices_in(sysfs.path()).unwrap();
+        assert_eq!(
+            devices,
+            vec![
+                BootloadedDevice {
+                    kind: Bootloaded::At90usb646,
+                    bus_path: "1-2.1".to_string(),
+                    serial: Some("0123456789AB".to_string()),
+                },
+                BootloadedDevice {
+                    kind: Bootloaded::At90usb646Lite,
+                    bus_path: "1-4".to_string(),
+                    serial: None,
+                },
+                BootloadedDevice {
+                    kind: Bootloaded::AtMega32u4,
+                    bus_path: "3-1".to_string(),
+                    serial: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn missing() {
+        assert!(matches!(
+            bootloaded_devices_in(Path::new("tests/no-sysfs")),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn parents() {
+        let device = UsbDevice {
+            bus_path: "1-2.3.1".to_string(),
+            vid: 0,
+            pid: 0,
+            manufacturer: String::new(),
+            product: String::new(),
+            serial: None,
+        };
+        assert_eq!(device.parents().collect::<Vec<_>>(), vec!["1-2.3", "1-2"]);
+    }
+
+    #[test]
+    fn hidraw() {
+        #[cfg(unix)]
+        {
+            let sysfs = TestSysfs::new();
+            assert_eq!(
+                hidraw_bus_path(sysfs.path(), Path::new("/dev/hidraw0")),
+                Some("1-2.2".to_string())
+            );
+            assert_eq!(
+                hidraw_bus_path(sysfs.path(), Path::new("/dev/hidraw1")),
+                None
+            );
+        }
+        assert_eq!(
+            bus_path_of(Path::new(
+                "devices/pci0000:00/0000:00:14.0/usb3/3-1/3-1:1.0/0003:3384:0001.0002"
+            )),
+            Some("3-1".to_string())
+        );
+    }
+}
@@ -0,0 +1,443 @@
+use backend::{Backend, Board, Event, Events, KeyMap, Mode, Rgb};
+use futures::{executor::block_on, prelude::*};
+use std::{
+    env,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    process,
+};
+
+const USAGE: &str = "Usage: system76-keyboard-configurator-cli [OPTIONS] <COMMAND> [ARGS]
+
+Options:
+  -k, --fake-keyboard <BOARDS>  Use fake keyboards instead of hardware
+                                (comma separated board names, or 'all')
+  -r, --replay <FILE>           Use a recording of daemon commands instead of
+                                hardware, made by setting
+                                KEYBOARD_CONFIGURATOR_RECORD=<FILE>
+  -b, --board <INDEX>           Board to operate on, as numbered by 'list' [default: 0]
+  -h, --help                    Print this help
+
+Commands:
+  list                              List connected boards
+  keys                              List keys of the board and their scancodes
+  dump [FILE]                       Write keymap as json to FILE, or to stdout
+  apply <FILE>                      Apply keymap from json FILE
+  get-key <KEY> [LAYER]             Print scancode of KEY, on LAYER or on every layer
+  set-key <KEY> <LAYER> <SCANCODE>  Set scancode of KEY on LAYER
+  set-layer <LAYER> [--mode <MODE>] [--speed <SPEED>] [--brightness <BRIGHTNESS>] [--color <#RRGGBB>]
+                                    Set LED settings of LAYER
+  led-save                          Save LED settings to the keyboard
+
+KEY is a logical key name, as printed by 'keys'. Layers are numbered from 0.
+
+Unless run as root, the CLI uses the daemon shared by the GUI and other tools
+if one was started with 'pkexec system76-keyboard-configurator --daemon-socket',
+and otherwise starts its own with pkexec.";
+
+enum Command {
+    List,
+    Keys,
+    Dump(Option<String>),
+    Apply(String),
+    GetKey(String, Option<usize>),
+    SetKey(String, usize, String),
+    SetLayer(usize, LayerSettings),
+    LedSave,
+}
+
+#[derive(Default)]
+struct LayerSettings {
+    mode: Option<&'static Mode>,
+    speed: Option<u8>,
+    brightness: Option<i32>,
+    color: Option<Rgb>,
+}
+
+struct Args {
+    fake_keyboard: Option<Vec<String>>,
+    replay: Option<String>,
+    board: usize,
+    command: Command,
+}
+
+fn parse_num<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} '{}'", name, value))
+}
+
+fn parse_layer_settings<I: Iterator<Item = String>>(args: &mut I) -> Result<LayerSettings, String> {
+    let mut settings = LayerSettings::default();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for '{}'", arg))?;
+        match arg.as_str() {
+            "--mode" => {
+                let mode = Mode::from_id(&value.to_uppercase()).ok_or_else(|| {
+                    let ids = Mode::all().iter().map(|m| m.id).collect::<Vec<_>>();
+                    format!(
+                        "Invalid mode '{}', expected one of: {}",
+                        value,
+                        ids.join(", ")
+                    )
+                })?;
+                settings.mode = Some(mode);
+            }
+            "--speed" => settings.speed = Some(parse_num("speed", &value)?),
+            "--brightness" => settings.brightness = Some(parse_num("brightness", &value)?),
+            "--color" => {
+                let color = Rgb::parse(&value)
+                    .ok_or_else(|| format!("Invalid color '{}', expected #RRGGBB", value))?;
+                settings.color = Some(color);
+            }
+            _ => return Err(format!("Unrecognized option '{}'", arg)),
+        }
+    }
+    Ok(settings)
+}
+
+fn required<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing argument <{}>", name))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut fake_keyboard = None;
+    let mut replay = None;
+    let mut board = 0;
+
+    let command = loop {
+        let arg = args.next().ok_or("No command given")?;
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "-k" | "--fake-keyboard" => {
+                let value = args.next().ok_or("Missing value for '--fake-keyboard'")?;
+                fake_keyboard = Some(match value.as_str() {
+                    "all" => backend::all_layouts(),
+                    _ => value.split(',').map(str::to_string).collect(),
+                });
+            }
+            "-r" | "--replay" => {
+                replay = Some(args.next().ok_or("Missing value for '--replay'")?);
+            }
+            "-b" | "--board" => {
+                let value = args.next().ok_or("Missing value for '--board'")?;
+                board = parse_num("board index", &value)?;
+            }
+            _ => break arg,
+        }
+    };
+
+    let command = match command.as_str() {
+        "list" => Command::List,
+        "keys" => Command::Keys,
+        "dump" => Command::Dump(args.next()),
+        "apply" => Command::Apply(required(&mut args, "FILE")?),
+        "get-key" => {
+            let key = required(&mut args, "KEY")?;
+            let layer = match args.next() {
+                Some(layer) => Some(parse_num("layer", &layer)?),
+                None => None,
+            };
+            Command::GetKey(key, layer)
+        }
+        "set-key" => {
+            let key = required(&mut args, "KEY")?;
+            let layer = parse_num("layer", &required(&mut args, "LAYER")?)?;
+            let scancode = required(&mut args, "SCANCODE")?;
+            Command::SetKey(key, layer, scancode)
+        }
+        "set-layer" => {
+            let layer = parse_num("layer", &required(&mut args, "LAYER")?)?;
+            Command::SetLayer(layer, parse_layer_settings(&mut args)?)
+        }
+        "led-save" => Command::LedSave,
+        _ => return Err(format!("Unrecognized command '{}'", command)),
+    };
+
+    if let Some(arg) = args.next() {
+        return Err(format!("Unexpected argument '{}'", arg));
+    }
+
+    Ok(Args {
+        fake_keyboard,
+        replay,
+        board,
+        command,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn backend_new() -> Result<(Backend, Events), backend::Error> {
+    if unsafe { libc::geteuid() == 0 } {
+        Backend::new()
+    } else {
+        Backend::new_socket().or_else(|_| Backend::new_pkexec())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn backend_new() -> Result<(Backend, Events), backend::Error> {
+    Backend::new()
+}
+
+/// Refresh, and collect all boards that were added
+fn boards(backend: &Backend, events: &mut Events) -> Result<Vec<Board>, String> {
+    block_on(backend.refresh_wait())?;
+
+    let mut boards = Vec::new();
+    while let Some(Some(event)) = events.next().now_or_never() {
+        if let Event::BoardAdded(board) = event {
+            boards.push(board);
+        }
+    }
+    Ok(boards)
+}
+
+fn key_index(board: &Board, name: &str) -> Result<usize, String> {
+    board
+        .keys()
+        .iter()
+        .position(|k| k.logical_name == name)
+        .ok_or_else(|| format!("No key named '{}' on {}", name, board.model()))
+}
+
+fn check_layer(board: &Board, layer: usize) -> Result<(), String> {
+    let num_layers = board.layout().meta.num_layers as usize;
+    if layer < num_layers {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid layer {}, {} has {} layers",
+            layer,
+            board.model(),
+            num_layers
+        ))
+    }
+}
+
+async fn apply_keymap(board: &Board, keymap: KeyMap) -> Result<(), String> {
+    let keymap = keymap.migrate().map_err(|err| err.to_string())?;
+
+    let report = board
+        .apply_keymap(&keymap)
+        .await
+        .map_err(|err| err.to_string())?;
+    for change in &report.changed {
+        println!(
+            "{} layer {}: {} -> {}",
+            change.key, change.layer, change.old, change.new
+        );
+    }
+
+    let mut errors = Vec::new();
+
+    for (name, hs) in &keymap.key_leds {
+        let key = &board.keys()[key_index(board, name)?];
+        if let Err(err) = key.set_color(*hs).await {
+            errors.push(format!("{} LED: {}", name, err));
+        }
+    }
+
+    for (i, (layer, keymap_layer)) in board.layers().iter().zip(&keymap.layers).enumerate() {
+        if let Some((mode, speed)) = keymap_layer.mode {
+            if let Err(err) = layer.set_mode(Mode::from_index(mode).unwrap(), speed).await {
+                errors.push(format!("Layer {} mode: {}", i, err));
+            }
+        }
+        if let Err(err) = layer.set_brightness(keymap_layer.brightness).await {
+            errors.push(format!("Layer {} brightness: {}", i, err));
+        }
+        if let Err(err) = layer.set_color(keymap_layer.color).await {
+            errors.push(format!("Layer {} color: {}", i, err));
+        }
+    }
+
+    board.led_save().await?;
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+async fn set_layer(board: &Board, index: usize, settings: LayerSettings) -> Result<(), String> {
+    let layer = board.layers().get(index).ok_or_else(|| {
+        format!(
+            "Invalid layer {}, {} has {} LED layers",
+            index,
+            board.model(),
+            board.layers().len()
+        )
+    })?;
+
+    if settings.mode.is_some() || settings.speed.is_some() {
+        if !board.layout().meta.has_mode {
+            return Err(format!("{} does not support LED modes", board.model()));
+        }
+        let (current_mode, current_speed) = layer.mode().unwrap_or_else(|| (&Mode::all()[0], 0));
+        let mode = settings.mode.unwrap_or(current_mode);
+        let speed = settings.speed.unwrap_or(current_speed);
+        layer.set_mode(mode, speed).await?;
+    }
+    if let Some(brightness) = settings.brightness {
+        if !(0..=board.max_brightness()).contains(&brightness) {
+            return Err(format!(
+                "Invalid brightness {}, maximum is {}",
+                brightness,
+                board.max_brightness()
+            ));
+        }
+        layer.set_brightness(brightness).await?;
+    }
+    if let Some(color) = settings.color {
+        if !board.layout().meta.has_color {
+            return Err(format!("{} does not support LED color", board.model()));
+        }
+        layer.set_color(color.to_hs_lossy()).await?;
+    }
+
+    Ok(board.led_save().await?)
+}
+
+fn run(boards: &[Board], index: usize, command: Command) -> Result<(), String> {
+    match (command, boards.get(index)) {
+        (Command::List, _) => {
+            for (i, board) in boards.iter().enumerate() {
+                println!("{}\t{}\t{}", i, board.model(), board.version());
+            }
+        }
+        (_, None) => return Err(format!("No board with index {}", index)),
+        (Command::Keys, Some(board)) => {
+            let num_layers = board.layout().meta.num_layers as usize;
+            for key in board.keys() {
+                let scancodes = (0..num_layers)
+                    .map(|layer| key.get_scancode(layer).unwrap().1)
+                    .collect::<Vec<_>>();
+                println!(
+                    "{}\t{}\t{}",
+                    key.logical_name,
+                    key.physical_name,
+                    scancodes.join("\t")
+                );
+            }
+        }
+        (Command::Dump(path), Some(board)) => {
+            let keymap = board.export_keymap();
+            match path {
+                Some(path) => {
+                    let file = File::create(&path)
+                        .map_err(|err| format!("Failed to create '{}': {}", path, err))?;
+                    keymap
+                        .to_writer_pretty(file)
+                        .map_err(|err| err.to_string())?;
+                }
+                None => {
+                    let mut stdout = io::stdout();
+                    keymap
+                        .to_writer_pretty(&mut stdout)
+                        .map_err(|err| err.to_string())?;
+                    writeln!(stdout).map_err(|err| err.to_string())?;
+                }
+            }
+        }
+        (Command::Apply(path), Some(board)) => {
+            let file =
+                File::open(&path).map_err(|err| format!("Failed to open '{}': {}", path, err))?;
+            let keymap = KeyMap::from_reader(file)
+                .map_err(|err| format!("Failed to parse '{}': {}", path, err))?;
+            block_on(apply_keymap(board, keymap))?;
+        }
+        (Command::GetKey(name, layer), Some(board)) => {
+            let key = &board.keys()[key_index(board, &name)?];
+            match layer {
+                Some(layer) => {
+                    check_layer(board, layer)?;
+                    println!("{}", key.get_scancode(layer).unwrap().1);
+                }
+                None => {
+                    for layer in 0..board.layout().meta.num_layers as usize {
+                        println!("{}\t{}", layer, key.get_scancode(layer).unwrap().1);
+                    }
+                }
+            }
+        }
+        (Command::SetKey(name, layer, scancode), Some(board)) => {
+            check_layer(board, layer)?;
+            let key = &board.keys()[key_index(board, &name)?];
+            block_on(key.set_scancode(layer, &scancode))?;
+        }
+        (Command::SetLayer(layer, settings), Some(board)) => {
+            block_on(set_layer(board, layer, settings))?;
+        }
+        (Command::LedSave, Some(board)) => {
+            if !board.has_led_save() {
+                return Err(format!("{} does not support saving LEDs", board.model()));
+            }
+            board.set_leds_changed();
+            block_on(board.led_save())?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "warn"),
+    )
+    .format_timestamp(None)
+    .format_module_path(false)
+    .init();
+
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("--daemon") {
+        backend::run_daemon();
+    }
+    #[cfg(target_os = "linux")]
+    if args.peek().map(String::as_str) == Some("--daemon-socket") {
+        backend::run_daemon_socket();
+    }
+
+    for err in backend::load_user_layouts() {
+        eprintln!("Failed to load layout: {}", err);
+    }
+
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let res = match (args.fake_keyboard, &args.replay) {
+        (Some(board_names), _) => Backend::new_dummy(board_names),
+        (None, Some(path)) => Backend::new_replay(Path::new(path)),
+        (None, None) => backend_new(),
+    };
+    let (backend, mut events) = match res {
+        Ok(ok) => ok,
+        Err(err) => {
+            eprintln!("Failed to start backend: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let res =
+        boards(&backend, &mut events).and_then(|boards| run(&boards, args.board, args.command));
+
+    // Make sure the daemon is told to exit before we do
+    drop(backend);
+
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
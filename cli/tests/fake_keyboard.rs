@@ -0,0 +1,55 @@
+use std::process::{Command, Output};
+
+fn cli(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_system76-keyboard-configurator-cli"))
+        .arg("--fake-keyboard")
+        .arg("system76/launch_1,system76/darp6")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn list() {
+    let output = cli(&["list"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let models = stdout
+        .lines()
+        .map(|line| line.split('\t').nth(1).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(models, ["system76/launch_1", "system76/darp6"]);
+}
+
+#[test]
+fn dump_apply() {
+    let output = cli(&["--board", "1", "dump"]);
+    assert!(output.status.success());
+    let keymap = backend::KeyMap::from_reader(&output.stdout[..]).unwrap();
+    assert_eq!(keymap.model, "system76/darp6");
+
+    let path =
+        std::env::temp_dir().join(format!("s76-cli-dump-apply-{}.json", uuid::Uuid::new_v4()));
+    std::fs::write(&path, &output.stdout).unwrap();
+    assert!(cli(&["--board", "1", "apply", path.to_str().unwrap()])
+        .status
+        .success());
+    // Keymap is for a different model
+    let res = cli(&["apply", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    assert!(!res.status.success());
+}
+
+#[test]
+fn invalid_arguments() {
+    assert!(cli(&["set-key", "K00", "0", "ESC"]).status.success());
+    assert!(!cli(&["set-key", "K00", "0", "NOT_A_SCANCODE"])
+        .status
+        .success());
+    assert!(!cli(&["set-key", "NOT_A_KEY", "0", "ESC"]).status.success());
+    assert!(!cli(&["set-key", "K00", "9", "ESC"]).status.success());
+    assert!(!cli(&["set-layer", "0", "--mode", "NOT_A_MODE"])
+        .status
+        .success());
+    assert!(!cli(&["--board", "5", "keys"]).status.success());
+}
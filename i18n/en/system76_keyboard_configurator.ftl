@@ -13,11 +13,18 @@ button-test = Test
 button-start = Start
 button-stop = Stop
 
+edit-redo = Redo
+edit-undo = Undo
+
 error-disable-key = Failed to disable key
 error-export-keymap = Failed to export keymap
 error-import-keymap = Failed to import keymap
 error-key-led = Failed to key LED
+error-load-recording = Failed to load recording
 error-open-file = Failed to open file
+error-profile = Profile error
+error-redo = Failed to redo
+error-resync = Failed to read settings from keyboard
 error-save-leds = Failed to save LEDs
 error-set-keyboard-brightness = Error setting brightness
 error-set-keyboard-mode = Error setting keyboard mode
@@ -25,6 +32,7 @@ error-set-keymap = Failed to set keymap
 error-set-layer-brightness = Failed to set layer brightness
 error-set-layer-color = Failed to set layer color
 error-set-layer-mode = Failed to set layer mode
+error-undo = Failed to undo
 error-unsupported-keymap = Unsupported keymap file
 error-unsupported-keymap-desc = Keymap file appears to be from newer Configurator version.
 
@@ -36,6 +44,10 @@ keyboard-color = Color:
 key-color = Key Color:
 
 keymap-for-board = Keymap is for board '{$model}'
+keymap-translate = Import Translated
+keymap-translate-desc = Keys will be matched by keycap and position. Bindings without a matching key on this keyboard will be skipped.
+keymap-unplaced = Some bindings could not be imported
+keymap-unplaced-binding = {$key} (layer {$layer}): {$scancode}
 
 layer-all-brightness = Brightness (all layers):
 layer-animation-speed = Layer Animation Speed:
@@ -48,10 +60,15 @@ layout-import = Import Layout
 layout-reset = Reset Layout
 layout-invert-f-keys = Invert F Keys
 
+flash-port = USB port {$port}
 flash-to-launch-heavy = Flash to Launch Heavy 1
 flash-to-launch-2 = Flash to Launch 2
 flash-to-launch-1 = Flash to Launch 1
 flash-to-launch-lite-1 = Flash to Launch Lite 1
+flash-erasing = Erasing keyboard firmware...
+flash-writing = Writing keyboard firmware: {$percent}%
+flash-verifying = Verifying keyboard firmware: {$percent}%
+flash-failed = Failed to flash keyboard firmware
 
 loading = Keyboard(s) detected. Loading...
 loading-keyboard = Loading keymap and LEDs for {$keyboard}
@@ -66,6 +83,39 @@ page-layer4 = Layer 4
 page-leds = LEDs
 page-logical = Logical
 
+picker-function-key = Key
+picker-function-key-desc = Send the picked key
+picker-mod-tap = Mod-tap
+picker-mod-tap-desc = Send the picked key when tapped, or the modifiers when held
+picker-function-modified = Modified key
+picker-function-modified-desc = Send the picked key with the modifiers held
+picker-function-layer-tap = Layer-tap
+picker-function-layer-tap-desc = Send the picked key when tapped, or switch to the layer while held
+picker-function-mo = Momentary layer
+picker-function-mo-desc = Switch to the layer while held
+picker-function-tg = Toggle layer
+picker-function-tg-desc = Turn the layer on or off
+picker-function-to = Switch to layer
+picker-function-to-desc = Switch to the layer
+picker-function-osl = One-shot layer
+picker-function-osl-desc = Switch to the layer for the next key press
+picker-layer = Layer:
+picker-mods = Modifiers:
+picker-mod-ctrl = Ctrl
+picker-mod-shift = Shift
+picker-mod-alt = Alt
+picker-mod-super = Super
+picker-mod-right = Right
+picker-mod-right-desc = Hold the right-hand modifiers instead of the left-hand ones
+
+profiles = Profiles
+profile-delete = Delete Profile
+profile-load = Load Profile
+profile-name = Profile name
+profile-none = No saved profiles
+profile-rename = Rename Profile
+profile-save = Save
+
 no-boards = No keyboard detected
 no-boards-msg = Make sure your built-in keyboard has up to date
  System76 Open Firmware.
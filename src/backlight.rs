@@ -353,8 +353,9 @@ impl Backlight {
         let value = self.inner().brightness_scale.value() as i32;
         let board = self.board().clone();
         glib::MainContext::default().spawn_local(async move {
+            let group = board.history_group();
             for layer in board.layers() {
-                if let Err(err) = layer.set_brightness(value).await {
+                if let Err(err) = layer.set_brightness_in(&group, value).await {
                     error!("{}: {}", fl!("error-set-keyboard-brightness"), err);
                 }
             }
@@ -409,9 +410,10 @@ impl Backlight {
         let self_ = self.clone();
         let selected = self.inner().selected.borrow().clone();
         glib::MainContext::default().spawn_local(async move {
+            let group = self_.board().history_group();
             let futures = FuturesUnordered::new();
             for i in selected.iter() {
-                futures.push(self_.board().keys()[*i].set_color(None));
+                futures.push(self_.board().keys()[*i].set_color_in(&group, None));
             }
             if let Err(err) = futures.try_collect::<()>().await {
                 error!("{}: {}", fl!("error-disable-key"), err);
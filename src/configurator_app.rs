@@ -1,7 +1,9 @@
 use cascade::cascade;
 use gtk::{gdk, gio, glib, prelude::*, subclass::prelude::*};
-use std::cell::Cell;
-use std::process::Command;
+use std::{
+    cell::Cell,
+    path::{Path, PathBuf},
+};
 
 use crate::{about_dialog, fl, MainWindow, Page};
 use backend::DerefCell;
@@ -9,6 +11,7 @@ use backend::DerefCell;
 #[derive(Default)]
 pub struct ConfiguratorAppInner {
     phony_board_names: DerefCell<Vec<String>>,
+    replay: DerefCell<Option<PathBuf>>,
     debug_layers: Cell<bool>,
     launch_test: Cell<bool>,
 }
@@ -36,6 +39,14 @@ impl ObjectImpl for ConfiguratorAppInner {
             "",
             None,
         );
+        app.add_main_option(
+            "replay",
+            glib::Char::from(b'r'),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::String,
+            "",
+            None,
+        );
         app.add_main_option(
             "debug-layers",
             glib::Char::from(b'\0'),
@@ -62,12 +73,14 @@ impl ApplicationImpl for ConfiguratorAppInner {
         }
 
         let board_names = match lookup::<String>(opts, "fake-keyboard").as_deref() {
-            Some("all") => backend::layouts().iter().map(|s| s.to_string()).collect(),
+            Some("all") => backend::all_layouts(),
             Some(value) => value.split(',').map(str::to_string).collect(),
             None => vec![],
         };
 
         self.phony_board_names.set(board_names);
+        self.replay
+            .set(lookup::<String>(opts, "replay").map(PathBuf::from));
         self.debug_layers.set(opts.contains("debug-layers"));
         self.launch_test.set(opts.contains("launch-test"));
 
@@ -82,35 +95,16 @@ impl ApplicationImpl for ConfiguratorAppInner {
             ..connect_activate(|_, _| about_dialog::show_about_dialog());
         };
 
-        let flash_1 = cascade! {
-            gio::SimpleAction::new("flash-to-launch-1", None);
-            ..connect_activate(|_, _| {
-                let _ = Command::new("dfu-programmer").args(["atmega32u4", "erase"]).status();
-                let _ = Command::new("dfu-programmer").args(["atmega32u4", "flash", "/var/lib/system76-keyboard-configurator/system76_launch_1_default.hex", "--suppress-bootloader-mem"]).status();
-                let _ = Command::new("dfu-programmer").args(["atmega32u4", "start"]).status();
-            });
-        };
-        let flash_2 = cascade! {
-            gio::SimpleAction::new("flash-to-launch-2", None);
-            ..connect_activate(|_, _| {
-                let _ = Command::new("dfu-programmer").args(["at90usb646", "flash", "/var/lib/system76-keyboard-configurator/system76_launch_2_default.hex", "--suppress-bootloader-mem"]).status();
-                let _ = Command::new("dfu-programmer").args(["at90usb646", "reset"]).status();
-            });
-        };
-        let flash_lite_1 = cascade! {
-            gio::SimpleAction::new("flash-to-launch-lite-1", None);
-            ..connect_activate(|_, _| {
-                let _ = Command::new("dfu-programmer").args(["at90usb646", "flash", "/var/lib/system76-keyboard-configurator/system76_launch_lite_1_default.hex", "--suppress-bootloader-mem"]).status();
-                let _ = Command::new("dfu-programmer").args(["at90usb646", "reset"]).status();
-            });
-        };
-        let flash_heavy_1 = cascade! {
-            gio::SimpleAction::new("flash-to-launch-heavy-1", None);
-            ..connect_activate(|_, _| {
-                let _ = Command::new("dfu-programmer").args(["at90usb646", "flash", "/var/lib/system76-keyboard-configurator/system76_launch_heavy_1_default.hex", "--suppress-bootloader-mem"]).status();
-                let _ = Command::new("dfu-programmer").args(["at90usb646", "reset"]).status();
-            });
-        };
+        let flash_1 = flash_action("flash-to-launch-1", "system76_launch_1_default.hex");
+        let flash_2 = flash_action("flash-to-launch-2", "system76_launch_2_default.hex");
+        let flash_lite_1 = flash_action(
+            "flash-to-launch-lite-1",
+            "system76_launch_lite_1_default.hex",
+        );
+        let flash_heavy_1 = flash_action(
+            "flash-to-launch-heavy-1",
+            "system76_launch_heavy_1_default.hex",
+        );
 
         let app = self.obj();
         app.add_action(&about_action);
@@ -120,6 +114,8 @@ impl ApplicationImpl for ConfiguratorAppInner {
         app.add_action(&flash_lite_1);
         app.set_accels_for_action("kbd.import", &["<Primary>o"]);
         app.set_accels_for_action("kbd.export", &["<Primary>e"]);
+        app.set_accels_for_action("kbd.undo", &["<Primary>z"]);
+        app.set_accels_for_action("kbd.redo", &["<Primary><Shift>z"]);
         for (i, _) in Page::iter_all().enumerate() {
             app.set_accels_for_action(&format!("kbd.page{}", i), &[&format!("<Primary>{}", i + 1)]);
         }
@@ -137,6 +133,25 @@ impl ApplicationImpl for ConfiguratorAppInner {
     }
 }
 
+/// Action flashing `firmware`, installed with the configurator, to the board
+/// in bootloader mode with the bus path given as the parameter
+fn flash_action(name: &str, firmware: &str) -> gio::SimpleAction {
+    let path = format!("/var/lib/system76-keyboard-configurator/{}", firmware);
+    cascade! {
+        gio::SimpleAction::new(name, Some(glib::VariantTy::STRING));
+        ..connect_activate(move |_, bus_path| {
+            let window = gio::Application::default()
+                .and_then(|app| app.downcast::<gtk::Application>().ok())
+                .and_then(|app| app.active_window())
+                .and_then(|window| window.downcast::<MainWindow>().ok());
+            let bus_path = bus_path.and_then(|x| x.get::<String>());
+            if let (Some(window), Some(bus_path)) = (window, bus_path) {
+                window.flash(&bus_path, &path);
+            }
+        });
+    }
+}
+
 impl GtkApplicationImpl for ConfiguratorAppInner {}
 
 glib::wrapper! {
@@ -158,6 +173,11 @@ impl ConfiguratorApp {
         &self.inner().phony_board_names
     }
 
+    /// Recording of daemon commands to use instead of hardware
+    pub fn replay(&self) -> Option<&Path> {
+        self.inner().replay.as_deref()
+    }
+
     pub fn debug_layers(&self) -> bool {
         self.inner().debug_layers.get()
     }
@@ -16,13 +16,15 @@ use std::{
 };
 
 use crate::{show_error_dialog, Backlight, KeyboardLayer, MainWindow, Page, Picker, Testing};
-use backend::{Board, BoardEvent, DerefCell, KeyMap, Layout, Mode};
+use backend::{Board, BoardEvent, DerefCell, HistoryGroup, KeyMap, Layout, Mode};
 use widgets::SelectedKeys;
 
 #[derive(Default)]
 pub struct KeyboardInner {
     action_group: DerefCell<gio::SimpleActionGroup>,
     invert_f_action: DerefCell<gio::SimpleAction>,
+    undo_action: DerefCell<gio::SimpleAction>,
+    redo_action: DerefCell<gio::SimpleAction>,
     board: DerefCell<Board>,
     page: Cell<Page>,
     picker: RefCell<WeakRef<Picker>>,
@@ -104,6 +106,26 @@ impl ObjectImpl for KeyboardInner {
             ));
         };
 
+        let undo_action = cascade! {
+            gio::SimpleAction::new("undo", None);
+            ..set_enabled(false);
+            ..connect_activate(clone!(@weak keyboard => move |_, _|
+                glib::MainContext::default().spawn_local(async move {
+                    keyboard.undo().await;
+                });
+            ));
+        };
+
+        let redo_action = cascade! {
+            gio::SimpleAction::new("redo", None);
+            ..set_enabled(false);
+            ..connect_activate(clone!(@weak keyboard => move |_, _|
+                glib::MainContext::default().spawn_local(async move {
+                    keyboard.redo().await;
+                });
+            ));
+        };
+
         let action_group = cascade! {
             gio::SimpleActionGroup::new();
             ..add_action(&cascade! {
@@ -127,10 +149,14 @@ impl ObjectImpl for KeyboardInner {
                 ));
             });
             ..add_action(&invert_f_action);
+            ..add_action(&undo_action);
+            ..add_action(&redo_action);
         };
 
         self.action_group.set(action_group);
         self.invert_f_action.set(invert_f_action);
+        self.undo_action.set(undo_action);
+        self.redo_action.set(redo_action);
         self.layer_stack.set(layer_stack);
         self.stack.set(stack);
         self.picker_box.set(picker_box);
@@ -254,8 +280,14 @@ impl Keyboard {
 
     pub fn handle_backend_event(&self, event: BoardEvent) {
         match event {
-            BoardEvent::KeymapChanged => self.queue_draw(),
-            BoardEvent::LedsChanged => {}
+            BoardEvent::KeymapChanged => {
+                self.queue_draw();
+                self.update_history_actions();
+            }
+            BoardEvent::LedsChanged => {
+                self.queue_draw();
+                self.update_history_actions();
+            }
             BoardEvent::MatrixChanged => {
                 self.queue_draw();
                 if let Some(testing) = self.inner().testing.as_ref() {
@@ -265,6 +297,47 @@ impl Keyboard {
         }
     }
 
+    fn update_history_actions(&self) {
+        self.inner()
+            .undo_action
+            .set_enabled(self.board().can_undo());
+        self.inner()
+            .redo_action
+            .set_enabled(self.board().can_redo());
+    }
+
+    async fn undo(&self) {
+        if let Err(err) = self.board().undo().await {
+            error!("{}: {}", fl!("error-undo"), err);
+        }
+        self.refresh_settings();
+    }
+
+    async fn redo(&self) {
+        if let Err(err) = self.board().redo().await {
+            error!("{}: {}", fl!("error-redo"), err);
+        }
+        self.refresh_settings();
+    }
+
+    /// Re-read settings from the device, in case another program changed them
+    pub async fn resync(&self) {
+        if let Err(err) = self.board().resync().await {
+            error!("{}: {}", fl!("error-resync"), err);
+        }
+        self.refresh_settings();
+    }
+
+    /// Update widgets showing settings that may have changed by undo, redo or resync
+    fn refresh_settings(&self) {
+        self.update_history_actions();
+        if let Some(layer) = self.layer() {
+            self.inner().backlight.set_layer(layer);
+        }
+        self.set_selected(self.selected());
+        self.queue_draw();
+    }
+
     pub fn action_group(&self) -> &gio::ActionGroup {
         self.inner().action_group.upcast_ref()
     }
@@ -307,9 +380,15 @@ impl Keyboard {
         self.layout().scancode_from_name(scancode_name).is_some()
     }
 
-    pub async fn keymap_set(&self, key_index: usize, layer: usize, scancode_name: &str) {
+    pub async fn keymap_set(
+        &self,
+        group: &HistoryGroup,
+        key_index: usize,
+        layer: usize,
+        scancode_name: &str,
+    ) {
         if let Err(err) = self.board().keys()[key_index]
-            .set_scancode(layer, scancode_name)
+            .set_scancode_in(group, layer, scancode_name)
             .await
         {
             error!("{}: {:?}", fl!("error-set-keymap"), err);
@@ -323,14 +402,50 @@ impl Keyboard {
     }
 
     pub async fn import_keymap(&self, keymap: KeyMap) {
-        // TODO: Ideally don't want this function to be O(Keys^2)
-        // TODO: Make sure it doesn't panic with invalid json with invalid indexes?
+        let mut keymap = match keymap.migrate() {
+            Ok(keymap) => keymap,
+            Err(_) => {
+                show_error_dialog(
+                    &self.window().unwrap(),
+                    &fl!("error-unsupported-keymap"),
+                    fl!("error-unsupported-keymap-desc"),
+                );
+                return;
+            }
+        };
 
+        let mut unplaced = Vec::new();
         if keymap.model != self.board().model() {
+            let from = match Layout::from_board(&keymap.model, self.board().version()) {
+                Some(from) => from,
+                None => {
+                    show_error_dialog(
+                        &self.window().unwrap(),
+                        &fl!("error-import-keymap"),
+                        fl!("keymap-for-board", model = keymap.model),
+                    );
+                    return;
+                }
+            };
+
+            if !self.confirm_translate(&keymap.model).await {
+                return;
+            }
+
+            let translation = keymap.translate(&from, self.layout(), self.board().model());
+            keymap = translation.keymap;
+            unplaced = translation.unplaced;
+        }
+
+        if let Err(errors) = keymap.validate(self.layout()) {
+            let errors = errors
+                .iter()
+                .map(|err| glib::markup_escape_text(&err.to_string()).to_string())
+                .collect::<Vec<_>>();
             show_error_dialog(
                 &self.window().unwrap(),
                 &fl!("error-import-keymap"),
-                fl!("keymap-for-board", model = keymap.model),
+                errors.join("\n"),
             );
             return;
         }
@@ -342,61 +457,93 @@ impl Keyboard {
             )
         });
 
-        let key_indices = self
-            .board()
-            .keys()
-            .iter()
-            .enumerate()
-            .map(|(i, k)| (&k.logical_name, i))
-            .collect::<HashMap<_, _>>();
+        // Undo the whole import at once
+        let group = &self.board().history_group();
+
+        // Scancodes are applied atomically, so nothing else is changed if this fails
+        if let Err(err) = self.board().apply_keymap_in(group, &keymap).await {
+            error!("{}: {}", fl!("error-set-keymap"), err);
+            show_error_dialog(
+                &self.window().unwrap(),
+                &fl!("error-set-keymap"),
+                glib::markup_escape_text(&err.to_string()),
+            );
+            return;
+        }
 
         let futures = FuturesUnordered::<Pin<Box<dyn Future<Output = ()>>>>::new();
 
-        for (k, v) in &keymap.map {
-            for (layer, scancode_name) in v.iter().enumerate() {
-                let n = key_indices[&k];
-                futures.push(Box::pin(async move {
-                    if let Err(err) = self.board().keys()[n]
-                        .set_scancode(layer, scancode_name)
-                        .await
-                    {
-                        error!("{}: {:?}", fl!("error-set-keymap"), err);
-                    }
-                }));
+        let key_leds = &keymap.key_leds;
+        futures.push(Box::pin(async move {
+            if let Err(err) = self.board().set_key_colors_in(group, key_leds).await {
+                error!("{}: {}", fl!("error-key-led"), err);
             }
-        }
-
-        for (k, hs) in &keymap.key_leds {
-            let res = self.board().keys()[key_indices[&k]].set_color(*hs);
-            futures.push(Box::pin(async move {
-                if let Err(err) = res.await {
-                    error!("{}: {}", fl!("error-key-led"), err);
-                }
-            }));
-        }
+        }));
 
-        for (i, keymap_layer) in keymap.layers.iter().enumerate() {
-            let layer = &self.board().layers()[i];
+        for (layer, keymap_layer) in self.board().layers().iter().zip(&keymap.layers) {
             if let Some((mode, speed)) = keymap_layer.mode {
                 futures.push(Box::pin(async move {
-                    if let Err(err) = layer.set_mode(Mode::from_index(mode).unwrap(), speed).await {
+                    let mode = Mode::from_index(mode).unwrap();
+                    if let Err(err) = layer.set_mode_in(group, mode, speed).await {
                         error!("{}: {}", fl!("error-set-layer-mode"), err)
                     }
                 }));
             }
             futures.push(Box::pin(async move {
-                if let Err(err) = layer.set_brightness(keymap_layer.brightness).await {
+                if let Err(err) = layer
+                    .set_brightness_in(group, keymap_layer.brightness)
+                    .await
+                {
                     error!("{}: {}", fl!("error-set-layer-brightness"), err)
                 }
             }));
             futures.push(Box::pin(async move {
-                if let Err(err) = layer.set_color(keymap_layer.color).await {
+                if let Err(err) = layer.set_color_in(group, keymap_layer.color).await {
                     error!("{}: {}", fl!("error-set-layer-color"), err)
                 }
             }));
         }
 
         futures.collect::<()>().await;
+
+        if !unplaced.is_empty() {
+            let bindings = unplaced
+                .iter()
+                .map(|x| {
+                    let name = x.physical_name.replace('\n', " ");
+                    fl!(
+                        "keymap-unplaced-binding",
+                        key = glib::markup_escape_text(&name).to_string(),
+                        layer = x.layer + 1,
+                        scancode = glib::markup_escape_text(&x.scancode).to_string()
+                    )
+                })
+                .collect::<Vec<_>>();
+            show_error_dialog(
+                &self.window().unwrap(),
+                &fl!("keymap-unplaced"),
+                bindings.join("\n"),
+            );
+        }
+    }
+
+    /// Ask whether a keymap for another model should be imported anyway
+    async fn confirm_translate(&self, model: &str) -> bool {
+        let dialog = cascade! {
+            gtk::MessageDialog::new(
+                self.window().as_ref(),
+                gtk::DialogFlags::MODAL,
+                gtk::MessageType::Question,
+                gtk::ButtonsType::None,
+                &fl!("keymap-for-board", model = model),
+            );
+            ..set_secondary_text(Some(&fl!("keymap-translate-desc")));
+            ..add_button(&fl!("button-cancel"), gtk::ResponseType::Cancel);
+            ..add_button(&fl!("keymap-translate"), gtk::ResponseType::Accept);
+        };
+        let response = dialog.run_future().await;
+        dialog.close();
+        response == gtk::ResponseType::Accept
     }
 
     fn import(&self) {
@@ -448,14 +595,6 @@ impl Keyboard {
             let path = chooser.filename().unwrap();
             let keymap = self.export_keymap();
 
-            if keymap.version != 1 {
-                show_error_dialog(
-                    &self.window().unwrap(),
-                    &fl!("error-unsupported-keymap"),
-                    fl!("error-unsupported-keymap-desc"),
-                )
-            }
-
             match File::create(path) {
                 Ok(file) => match keymap.to_writer_pretty(file) {
                     Ok(()) => {}
@@ -475,6 +614,8 @@ impl Keyboard {
     }
 
     async fn invert_f_keys(&self) {
+        let group = &self.board().history_group();
+
         let key_indices = self
             .board()
             .keys()
@@ -495,12 +636,12 @@ impl Keyboard {
             }
 
             futures.push(Box::pin(async move {
-                if let Err(err) = k.set_scancode(0, &layer1_keycode).await {
+                if let Err(err) = k.set_scancode_in(group, 0, &layer1_keycode).await {
                     error!("{}: {:?}", fl!("error-set-keymap"), err);
                 }
             }));
             futures.push(Box::pin(async move {
-                if let Err(err) = k.set_scancode(1, &layer0_keycode).await {
+                if let Err(err) = k.set_scancode_in(group, 1, &layer0_keycode).await {
                     error!("{}: {:?}", fl!("error-set-keymap"), err);
                 }
             }));
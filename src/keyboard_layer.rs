@@ -134,6 +134,27 @@ impl WidgetImpl for KeyboardLayerInner {
                 cr.stroke().unwrap();
             }
 
+            cr.set_source_rgba(fg.0, fg.1, fg.2, text_alpha);
+
+            // Draw hold modifier of mod-tap key, in smaller text at the bottom
+            let mut label_h = h;
+            if let Some(text) = self.obj().page().get_hold_label(k) {
+                let layout = cascade! {
+                    self.obj().create_pango_layout(Some(&text));
+                    ..set_width((w * pango::SCALE as f64) as i32);
+                    ..set_alignment(pango::Alignment::Center);
+                    ..set_attributes(Some(&cascade! {
+                        pango::AttrList::new();
+                        ..insert(pango::AttrFloat::new_scale(pango::SCALE_SMALL));
+                    }));
+                };
+                let text_height = layout.pixel_size().1 as f64;
+                label_h -= text_height;
+                cr.new_path();
+                cr.move_to(x, y + label_h - MARGIN);
+                pangocairo::show_layout(cr, &layout);
+            }
+
             // Draw label
             let text = self.obj().page().get_label(k);
             let layout = cascade! {
@@ -143,8 +164,7 @@ impl WidgetImpl for KeyboardLayerInner {
             };
             let text_height = layout.pixel_size().1 as f64;
             cr.new_path();
-            cr.move_to(x, y + (h - text_height) / 2.);
-            cr.set_source_rgba(fg.0, fg.1, fg.2, text_alpha);
+            cr.move_to(x, y + (label_h - text_height) / 2.);
             pangocairo::show_layout(cr, &layout);
         }
 
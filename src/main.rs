@@ -16,6 +16,7 @@ mod localize;
 mod main_window;
 mod page;
 mod picker;
+mod profile_switcher;
 mod shortcuts_window;
 mod testing;
 
@@ -24,7 +25,7 @@ use gtk::glib;
 pub use self::configurator_app::run;
 use self::{
     backlight::*, configurator_app::*, error_dialog::*, keyboard::*, keyboard_layer::*,
-    main_window::*, page::*, picker::*, shortcuts_window::*, testing::*,
+    main_window::*, page::*, picker::*, profile_switcher::*, shortcuts_window::*, testing::*,
 };
 
 fn main() -> glib::ExitCode {
@@ -41,6 +42,14 @@ fn main() -> glib::ExitCode {
         if arg.as_str() == "--daemon" {
             backend::run_daemon();
         }
+        #[cfg(target_os = "linux")]
+        if arg.as_str() == "--daemon-socket" {
+            backend::run_daemon_socket();
+        }
+    }
+
+    for err in backend::load_user_layouts() {
+        error!("{}", err);
     }
 
     crate::run()
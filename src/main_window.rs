@@ -14,8 +14,13 @@ use std::{
     time::Duration,
 };
 
-use crate::{shortcuts_window, ConfiguratorApp, Keyboard, KeyboardLayer, Page, Picker};
-use backend::{Backend, Board, BoardId, Bootloaded, DerefCell};
+use crate::{
+    shortcuts_window, show_error_dialog, ConfiguratorApp, Keyboard, KeyboardLayer, Page, Picker,
+    ProfileSwitcher,
+};
+use backend::{
+    Backend, Board, BoardId, Bootloaded, BootloadedDevice, DerefCell, Firmware, FlashProgress,
+};
 
 pub struct Loader(MainWindow, gtk::Box);
 
@@ -44,9 +49,12 @@ pub struct MainWindowInner {
     load_box: DerefCell<gtk::Box>,
     load_revealer: DerefCell<gtk::Revealer>,
     picker: DerefCell<Picker>,
+    profile_switcher: DerefCell<ProfileSwitcher>,
     stack: DerefCell<gtk::Stack>,
     keyboards: RefCell<Vec<(Keyboard, gtk::Box)>>,
+    bootloaded: RefCell<Vec<BootloadedDevice>>,
     board_loading: RefCell<Option<Loader>>,
+    flash_loader: RefCell<Option<Loader>>,
     board_list_stack: DerefCell<gtk::Stack>,
     is_testing_mode: DerefCell<bool>,
 }
@@ -90,6 +98,8 @@ impl ObjectImpl for MainWindowInner {
                 });
         };
 
+        let profile_switcher = ProfileSwitcher::new();
+
         let menu = cascade! {
             gio::Menu::new();
             ..append_section(None, &cascade! {
@@ -119,6 +129,7 @@ impl ObjectImpl for MainWindowInner {
                 });
             });
             ..pack_end(&flash_button);
+            ..pack_end(&profile_switcher);
         };
 
         let no_boards_msg = format!(
@@ -201,6 +212,7 @@ impl ObjectImpl for MainWindowInner {
         };
         back_button.set_visible(false);
         flash_button.set_visible(false);
+        profile_switcher.set_visible(false);
 
         self.back_button.set(back_button);
         self.flash_button.set(flash_button);
@@ -211,6 +223,7 @@ impl ObjectImpl for MainWindowInner {
         self.load_box.set(load_box);
         self.load_revealer.set(load_revealer);
         self.picker.set(picker);
+        self.profile_switcher.set(profile_switcher);
         self.stack.set(stack);
         self.board_list_stack.set(board_list_stack);
     }
@@ -238,7 +251,17 @@ impl MainWindow {
         let is_testing_mode = app.launch_test();
         app.add_window(&window);
 
-        let (backend, receiver) = daemon();
+        let (backend, receiver) = match app.replay() {
+            Some(path) => Backend::new_replay(path).unwrap_or_else(|err| {
+                error!("Failed to load recording {}: {}", path.display(), err);
+                // Rather than the real keyboards, which weren't asked for
+                glib::idle_add_local_once(clone!(@weak window => move || {
+                    show_error_dialog(&window, &fl!("error-load-recording"), err);
+                }));
+                Backend::new_dummy(Vec::new()).expect("Failed to create dummy backend")
+            }),
+            None => daemon(),
+        };
         window.handle_backend_event_stream(receiver, false);
         backend.refresh();
 
@@ -257,6 +280,18 @@ impl MainWindow {
                 } else {
                     None
                 });
+
+            // Settings may have been changed by another program while unfocused
+            if window.is_active() {
+                for (keyboard, _) in &*window.inner().keyboards.borrow() {
+                    if keyboard.board().has_keymap() {
+                        let keyboard = keyboard.clone();
+                        glib::MainContext::default().spawn_local(async move {
+                            keyboard.resync().await;
+                        });
+                    }
+                }
+            }
         });
 
         let phony_board_names = app.phony_board_names().to_vec();
@@ -275,7 +310,8 @@ impl MainWindow {
         glib::timeout_add_seconds_local(
             1,
             clone!(@weak window => @default-return ControlFlow::Break, move || {
-                if !REFRESH_DISABLED.load(Ordering::Relaxed) {
+                // Without hotplug events, poll for added and removed boards
+                if !REFRESH_DISABLED.load(Ordering::Relaxed) && !window.inner().backend.has_hotplug() {
                   let inner = window.inner();
                   inner.backend.refresh();
                   if *inner.is_testing_mode && !inner.back_button.is_visible() {
@@ -340,12 +376,78 @@ impl MainWindow {
             backend::Event::BoardRemoved(id) => {
                 self.remove_keyboard(id);
             }
-            backend::Event::BootloadedAdded(board) => {
-                self.add_flash_menu(board);
+            backend::Event::BootloadedAdded(device) => {
+                self.inner().bootloaded.borrow_mut().push(device);
+                self.update_flash_menu();
+            }
+            backend::Event::BootloadedRemoved(device) => {
+                self.inner()
+                    .bootloaded
+                    .borrow_mut()
+                    .retain(|x| *x != device);
+                self.update_flash_menu();
+            }
+            backend::Event::Flash(_, progress) => {
+                self.handle_flash_progress(progress);
+            }
+            backend::Event::DaemonExited => {
+                // Reload boards from the relaunched daemon
+                info!("daemon exited");
+                let ids = self
+                    .inner()
+                    .keyboards
+                    .borrow()
+                    .iter()
+                    .map(|(keyboard, _)| keyboard.board().board())
+                    .collect::<Vec<_>>();
+                for id in ids {
+                    self.remove_keyboard(id);
+                }
+            }
+        }
+    }
+
+    fn handle_flash_progress(&self, progress: FlashProgress) {
+        let text = match progress {
+            FlashProgress::Erasing => fl!("flash-erasing"),
+            FlashProgress::Writing { done, total } => {
+                fl!("flash-writing", percent = done * 100 / total)
+            }
+            FlashProgress::Verifying { done, total } => {
+                fl!("flash-verifying", percent = done * 100 / total)
+            }
+            FlashProgress::Done => {
+                self.inner().flash_loader.borrow_mut().take();
+                return;
             }
-            backend::Event::BootloadedRemoved => {
-                self.remove_flash_menu();
+            FlashProgress::Failed(err) => {
+                self.inner().flash_loader.borrow_mut().take();
+                show_error_dialog(self, &fl!("flash-failed"), err);
+                return;
             }
+        };
+        let mut flash_loader = self.inner().flash_loader.borrow_mut();
+        flash_loader.take();
+        *flash_loader = Some(self.display_loader(&text));
+    }
+
+    /// Flash the firmware in `path` to the board in bootloader mode at
+    /// `bus_path`
+    pub fn flash(&self, bus_path: &str, path: &str) {
+        let device = self
+            .inner()
+            .bootloaded
+            .borrow()
+            .iter()
+            .find(|x| x.bus_path == bus_path)
+            .cloned();
+        let device = match device {
+            Some(device) => device,
+            None => return,
+        };
+        match Firmware::from_file(path) {
+            Ok(firmware) => self.inner().backend.flash(device, firmware),
+            Err(err) => show_error_dialog(self, &fl!("flash-failed"), err),
         }
     }
 
@@ -359,8 +461,10 @@ impl MainWindow {
         inner.layer_switcher.set_stack(None::<&gtk::Stack>);
         self.insert_action_group("kbd", None::<&gio::ActionGroup>);
         inner.back_button.set_visible(false);
+        inner.profile_switcher.set_visible(false);
 
         inner.picker.set_keyboard(None);
+        inner.profile_switcher.set_keyboard(None);
     }
 
     fn show_keyboard(&self, keyboard: &Keyboard) {
@@ -376,8 +480,10 @@ impl MainWindow {
         inner.layer_switcher.set_stack(Some(keyboard.layer_stack()));
         self.insert_action_group("kbd", Some(keyboard.action_group()));
         inner.back_button.set_visible(true);
+        inner.profile_switcher.set_visible(true);
 
         inner.picker.set_keyboard(Some(keyboard.clone()));
+        inner.profile_switcher.set_keyboard(Some(keyboard.clone()));
     }
 
     fn add_keyboard(&self, board: Board) {
@@ -468,42 +574,28 @@ impl MainWindow {
         }
     }
 
-    fn add_flash_menu(&self, board: Bootloaded) {
+    /// Offer flashing each board in bootloader mode, in a section labeled
+    /// with its USB port
+    fn update_flash_menu(&self) {
         let menu = &self.inner().flash_menu;
         menu.remove_all();
 
-        match board {
-            Bootloaded::At90usb646 => {
-                menu.append(
-                    Some(&fl!("flash-to-launch-2")),
-                    Some("app.flash-to-launch-2"),
-                );
-                menu.append(
-                    Some(&fl!("flash-to-launch-heavy")),
-                    Some("app.flash-to-launch-heavy-1"),
-                );
-            }
-            Bootloaded::At90usb646Lite => {
-                menu.append(
-                    Some(&fl!("flash-to-launch-lite-1")),
-                    Some("app.flash-to-launch-lite-1"),
-                );
-            }
-            Bootloaded::AtMega32u4 => {
-                menu.append(
-                    Some(&fl!("flash-to-launch-1")),
-                    Some("app.flash-to-launch-1"),
-                );
-            }
+        // Flashing is only offered for production testing
+        let bootloaded = self.inner().bootloaded.borrow();
+        if !*self.inner().is_testing_mode || bootloaded.is_empty() {
+            self.inner().flash_button.set_visible(false);
+            return;
         }
 
+        for device in bootloaded.iter() {
+            menu.append_section(
+                Some(&fl!("flash-port", port = device.bus_path.as_str())),
+                &flash_menu_section(device),
+            );
+        }
         self.inner().flash_button.set_visible(true);
     }
 
-    fn remove_flash_menu(&self) {
-        self.inner().flash_button.set_visible(false);
-    }
-
     fn num_keyboards(&self) -> usize {
         let mut count = 0;
         self.inner().keyboard_box.foreach(|_| count += 1);
@@ -529,12 +621,42 @@ impl MainWindow {
     }
 }
 
+/// Menu of the firmware that can be flashed to `device`, by actions given its
+/// bus path, since several boards of a kind may be in bootloader mode
+fn flash_menu_section(device: &BootloadedDevice) -> gio::Menu {
+    let items = match device.kind {
+        Bootloaded::At90usb646 => vec![
+            (fl!("flash-to-launch-2"), "app.flash-to-launch-2"),
+            (fl!("flash-to-launch-heavy"), "app.flash-to-launch-heavy-1"),
+        ],
+        Bootloaded::At90usb646Lite => {
+            vec![(fl!("flash-to-launch-lite-1"), "app.flash-to-launch-lite-1")]
+        }
+        Bootloaded::AtMega32u4 => vec![(fl!("flash-to-launch-1"), "app.flash-to-launch-1")],
+    };
+    let menu = gio::Menu::new();
+    for (label, action) in items {
+        menu.append_item(&cascade! {
+            gio::MenuItem::new(Some(label.as_str()), None);
+            ..set_action_and_target_value(Some(action), Some(&device.bus_path.to_variant()));
+        });
+    }
+    menu
+}
+
 #[cfg(target_os = "linux")]
 fn daemon() -> (Backend, backend::Events) {
     if unsafe { libc::geteuid() == 0 } {
         info!("Already running as root");
         Backend::new()
     } else {
+        match Backend::new_socket() {
+            Ok(backend) => {
+                info!("Connected to shared daemon");
+                return backend;
+            }
+            Err(err) => info!("No shared daemon: {}", err),
+        }
         info!("Not running as root, spawning daemon with pkexec");
         Backend::new_pkexec()
     }
@@ -1,6 +1,6 @@
 use crate::fl;
 use crate::picker::SCANCODE_LABELS;
-use backend::Key;
+use backend::{parse_mod_tap, Key};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Page {
@@ -63,10 +63,9 @@ impl Page {
         match self {
             Page::Layer1 | Page::Layer2 | Page::Layer3 | Page::Layer4 => {
                 let scancode_name = key.get_scancode(self.layer().unwrap()).unwrap().1;
-                SCANCODE_LABELS
-                    .get(&scancode_name)
-                    .unwrap_or(&scancode_name)
-                    .into()
+                // Mod-tap keys are labeled with their tap keycode
+                let name = parse_mod_tap(&scancode_name).map_or(scancode_name.as_str(), |x| x.1);
+                scancode_label(name)
             }
             Page::Keycaps => key.physical_name.clone(),
             Page::Logical => key.logical_name.clone(),
@@ -74,6 +73,25 @@ impl Page {
             Page::Leds => key.led_name.clone(),
         }
     }
+
+    /// Modifier sent when a mod-tap key is held, drawn below its label
+    pub fn get_hold_label(&self, key: &Key) -> Option<String> {
+        let scancode_name = key.get_scancode(self.layer()?)?.1;
+        let (mods, _) = parse_mod_tap(&scancode_name)?;
+        Some(
+            mods.split('|')
+                .map(|x| scancode_label(x.trim()))
+                .collect::<Vec<_>>()
+                .join(" + "),
+        )
+    }
+}
+
+fn scancode_label(name: &str) -> String {
+    SCANCODE_LABELS
+        .get(name)
+        .map_or(name, String::as_str)
+        .to_string()
 }
 
 impl Default for Page {
@@ -0,0 +1,169 @@
+use backend::{
+    mod_tap_name, modified_name, mods_from_name, mods_to_name, parse_mod_tap, parse_modified,
+};
+
+/// QMK layer functions that don't take a keycode
+pub const LAYER_FNS: [&str; 4] = ["MO", "TG", "TO", "OSL"];
+
+/// Function that picked keycodes are combined with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyFunction {
+    /// Keycode on its own
+    Basic,
+    /// `MT(mods, kc)`, sending the keycode when tapped or the modifier bits
+    /// when held
+    ModTap(u16),
+    /// Keycode with the modifier bits held, like `LCTL(LSFT(kc))`
+    Modified(u16),
+    /// `LT(layer, kc)`, sending the keycode when tapped or switching to the
+    /// layer while held
+    LayerTap(u16),
+    /// One of `LAYER_FNS`, with a layer and no keycode
+    Layer(&'static str, u16),
+}
+
+impl KeyFunction {
+    /// Split a keycode name into its function and the keycode it combines,
+    /// which is `None` for layer functions
+    pub fn parse(name: &str) -> (Self, Option<&str>) {
+        if let Some((mods, kc)) = parse_mod_tap(name) {
+            if let Some(mods) = mods_from_name(mods) {
+                return (Self::ModTap(mods), Some(kc));
+            }
+        }
+        if let Some((mods, kc)) = parse_modified(name) {
+            return (Self::Modified(mods), Some(kc));
+        }
+        if let Some((function, args)) = name.strip_suffix(')').and_then(|x| x.split_once('(')) {
+            if function == "LT" {
+                if let Some((layer, kc)) = args.split_once(',') {
+                    if let Ok(layer) = layer.trim().parse() {
+                        return (Self::LayerTap(layer), Some(kc.trim()));
+                    }
+                }
+            } else if let Some(function) = LAYER_FNS.iter().find(|x| **x == function) {
+                if let Ok(layer) = args.trim().parse() {
+                    return (Self::Layer(function, layer), None);
+                }
+            }
+        }
+        (Self::Basic, Some(name))
+    }
+
+    /// Name of the key combining `kc` with this function. Layer functions
+    /// ignore `kc`, and other functions need one.
+    pub fn name(&self, kc: Option<&str>) -> Option<String> {
+        match *self {
+            Self::Basic => kc.map(str::to_string),
+            Self::ModTap(mods) => Some(mod_tap_name(&mods_to_name(mods)?, kc?)),
+            Self::Modified(mods) => modified_name(mods, kc?),
+            Self::LayerTap(layer) => Some(format!("LT({}, {})", layer, kc?)),
+            Self::Layer(function, layer) => Some(format!("{}({})", function, layer)),
+        }
+    }
+
+    /// Whether the function combines a picked keycode
+    pub fn has_keycode(&self) -> bool {
+        !matches!(self, Self::Layer(..))
+    }
+
+    /// Modifier bits of the function, if it takes any
+    pub fn mods(&self) -> Option<u16> {
+        match *self {
+            Self::ModTap(mods) | Self::Modified(mods) => Some(mods),
+            _ => None,
+        }
+    }
+
+    /// Layer of the function, if it takes one
+    pub fn layer(&self) -> Option<u16> {
+        match *self {
+            Self::LayerTap(layer) | Self::Layer(_, layer) => Some(layer),
+            _ => None,
+        }
+    }
+
+    /// Name identifying the function, without its modifiers or layer
+    pub fn id(&self) -> &'static str {
+        match *self {
+            Self::Basic => "KC",
+            Self::ModTap(_) => "MT",
+            Self::Modified(_) => "MODS",
+            Self::LayerTap(_) => "LT",
+            Self::Layer(function, _) => function,
+        }
+    }
+
+    /// Function with the name `id` from `id()`, and modifiers and layer
+    pub fn from_id(id: &str, mods: u16, layer: u16) -> Option<Self> {
+        match id {
+            "KC" => Some(Self::Basic),
+            "MT" => Some(Self::ModTap(mods)),
+            "MODS" => Some(Self::Modified(mods)),
+            "LT" => Some(Self::LayerTap(layer)),
+            _ => LAYER_FNS
+                .iter()
+                .find(|x| **x == id)
+                .map(|function| Self::Layer(function, layer)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_function_round_trip() {
+        for (name, function, kc) in [
+            ("A", KeyFunction::Basic, Some("A")),
+            (
+                "MT(LEFT_CTRL | LEFT_SHIFT, A)",
+                KeyFunction::ModTap(0x03),
+                Some("A"),
+            ),
+            (
+                "MT(RIGHT_ALT | RIGHT_SUPER, ESC)",
+                KeyFunction::ModTap(0x1C),
+                Some("ESC"),
+            ),
+            ("LCTL(LSFT(A))", KeyFunction::Modified(0x03), Some("A")),
+            ("RALT(ESC)", KeyFunction::Modified(0x14), Some("ESC")),
+            ("LT(2, SPACE)", KeyFunction::LayerTap(2), Some("SPACE")),
+            ("MO(1)", KeyFunction::Layer("MO", 1), None),
+            ("TG(3)", KeyFunction::Layer("TG", 3), None),
+            ("TO(0)", KeyFunction::Layer("TO", 0), None),
+            ("OSL(1)", KeyFunction::Layer("OSL", 1), None),
+        ] {
+            assert_eq!(KeyFunction::parse(name), (function, kc));
+            assert_eq!(function.name(kc).as_deref(), Some(name));
+            assert_eq!(
+                KeyFunction::from_id(
+                    function.id(),
+                    function.mods().unwrap_or(0),
+                    function.layer().unwrap_or(0)
+                ),
+                Some(function)
+            );
+        }
+    }
+
+    #[test]
+    fn key_function_change() {
+        // Change the function of a key, keeping its keycode
+        let (_, kc) = KeyFunction::parse("MT(LEFT_CTRL, A)");
+        assert_eq!(
+            KeyFunction::LayerTap(1).name(kc).as_deref(),
+            Some("LT(1, A)")
+        );
+        assert_eq!(
+            KeyFunction::Layer("MO", 1).name(kc).as_deref(),
+            Some("MO(1)")
+        );
+        // Layer functions have no keycode to keep
+        let (_, kc) = KeyFunction::parse("MO(1)");
+        assert_eq!(KeyFunction::Basic.name(kc), None);
+        // Functions with modifiers need some
+        assert_eq!(KeyFunction::Modified(0x10).name(Some("A")), None);
+    }
+}
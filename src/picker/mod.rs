@@ -6,16 +6,21 @@ use gtk::{
     subclass::prelude::*,
 };
 use once_cell::sync::Lazy;
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
 
-use crate::Keyboard;
+use crate::{fl, Keyboard};
 use backend::DerefCell;
 
+mod key_function;
 mod picker_group;
 mod picker_group_box;
 mod picker_json;
 mod picker_key;
 
+use key_function::KeyFunction;
 use picker_group_box::PickerGroupBox;
 use picker_json::picker_json;
 use picker_key::PickerKey;
@@ -30,10 +35,67 @@ pub static SCANCODE_LABELS: Lazy<HashMap<String, String>> = Lazy::new(|| {
     labels
 });
 
+/// Modifier bit of right-hand modifiers, combined with the bits in `MOD_BITS`
+const MOD_RIGHT: u16 = 0x10;
+const MOD_BITS: [u16; 4] = [0x01, 0x02, 0x04, 0x08];
+
+/// Function of each entry of the function combo box, with an example to check
+/// that the keyboard supports it
+const FUNCTIONS: [KeyFunction; 8] = [
+    KeyFunction::Basic,
+    KeyFunction::ModTap(0x01),
+    KeyFunction::Modified(0x01),
+    KeyFunction::LayerTap(0),
+    KeyFunction::Layer("MO", 0),
+    KeyFunction::Layer("TG", 0),
+    KeyFunction::Layer("TO", 0),
+    KeyFunction::Layer("OSL", 0),
+];
+
+/// Highest layer `LT` can switch to
+const LAYER_TAP_MAX: u8 = 0xF;
+
+fn function_label(function: KeyFunction) -> String {
+    match function.id() {
+        "MT" => fl!("picker-mod-tap"),
+        "MODS" => fl!("picker-function-modified"),
+        "LT" => fl!("picker-function-layer-tap"),
+        "MO" => fl!("picker-function-mo"),
+        "TG" => fl!("picker-function-tg"),
+        "TO" => fl!("picker-function-to"),
+        "OSL" => fl!("picker-function-osl"),
+        _ => fl!("picker-function-key"),
+    }
+}
+
+fn function_desc(function: KeyFunction) -> String {
+    match function.id() {
+        "MT" => fl!("picker-mod-tap-desc"),
+        "MODS" => fl!("picker-function-modified-desc"),
+        "LT" => fl!("picker-function-layer-tap-desc"),
+        "MO" => fl!("picker-function-mo-desc"),
+        "TG" => fl!("picker-function-tg-desc"),
+        "TO" => fl!("picker-function-to-desc"),
+        "OSL" => fl!("picker-function-osl-desc"),
+        _ => fl!("picker-function-key-desc"),
+    }
+}
+
 #[derive(Default)]
 pub struct PickerInner {
     group_box: DerefCell<PickerGroupBox>,
+    function_box: DerefCell<gtk::Box>,
+    function_combo: DerefCell<gtk::ComboBoxText>,
+    mods_box: DerefCell<gtk::Box>,
+    /// Check button for each bit of `MOD_BITS`
+    mod_checks: DerefCell<Vec<gtk::CheckButton>>,
+    mod_right_check: DerefCell<gtk::CheckButton>,
+    layer_box: DerefCell<gtk::Box>,
+    /// Layer of layer functions, counted from 1 like the layer labels
+    layer_spin: DerefCell<gtk::SpinButton>,
     keyboard: RefCell<Option<glib::WeakRef<Keyboard>>>,
+    /// Set while function widgets are updated to match the selected keys
+    updating: Cell<bool>,
 }
 
 #[glib::object_subclass]
@@ -56,13 +118,87 @@ impl ObjectImpl for PickerInner {
             }));
         };
 
+        let function_combo = cascade! {
+            gtk::ComboBoxText::new();
+            ..append(Some(KeyFunction::Basic.id()), &function_label(KeyFunction::Basic));
+            ..set_active_id(Some(KeyFunction::Basic.id()));
+        };
+
+        let mod_checks = [
+            fl!("picker-mod-ctrl"),
+            fl!("picker-mod-shift"),
+            fl!("picker-mod-alt"),
+            fl!("picker-mod-super"),
+        ]
+        .iter()
+        .map(|label| gtk::CheckButton::with_label(label))
+        .collect::<Vec<_>>();
+        mod_checks[0].set_active(true);
+
+        let mod_right_check = cascade! {
+            gtk::CheckButton::with_label(&fl!("picker-mod-right"));
+            ..set_tooltip_text(Some(&fl!("picker-mod-right-desc")));
+        };
+
+        let mods_box = cascade! {
+            gtk::Box::new(gtk::Orientation::Horizontal, 8);
+            ..add(&gtk::Label::new(Some(&fl!("picker-mods"))));
+        };
+        for check in mod_checks.iter().chain(Some(&mod_right_check)) {
+            mods_box.add(check);
+        }
+
+        let layer_spin = gtk::SpinButton::with_range(1., 1., 1.);
+
+        let layer_box = cascade! {
+            gtk::Box::new(gtk::Orientation::Horizontal, 8);
+            ..add(&gtk::Label::new(Some(&fl!("picker-layer"))));
+            ..add(&layer_spin);
+        };
+
+        function_combo.connect_changed(clone!(@weak picker => move |_| {
+            picker.function_changed();
+        }));
+        for check in mod_checks.iter().chain(Some(&mod_right_check)) {
+            check.connect_toggled(clone!(@weak picker => move |_| {
+                picker.function_changed();
+            }));
+        }
+        layer_spin.connect_value_changed(clone!(@weak picker => move |_| {
+            picker.function_changed();
+        }));
+
+        let function_box = cascade! {
+            gtk::Box::new(gtk::Orientation::Horizontal, 16);
+            ..set_halign(gtk::Align::Center);
+            ..set_margin_bottom(16);
+            ..set_no_show_all(true);
+            ..add(&function_combo);
+            ..add(&mods_box);
+            ..add(&layer_box);
+        };
+        function_combo.show();
+        mods_box.set_no_show_all(true);
+        mods_box.foreach(|x| x.show());
+        layer_box.set_no_show_all(true);
+        layer_box.foreach(|x| x.show());
+
         cascade! {
             picker;
+            ..set_orientation(gtk::Orientation::Vertical);
+            ..add(&function_box);
             ..add(&group_box);
             ..show_all();
         };
 
         self.group_box.set(group_box);
+        self.function_box.set(function_box);
+        self.function_combo.set(function_combo);
+        self.mods_box.set(mods_box);
+        self.mod_checks.set(mod_checks);
+        self.mod_right_check.set(mod_right_check);
+        self.layer_box.set(layer_box);
+        self.layer_spin.set(layer_spin);
     }
 }
 
@@ -95,6 +231,8 @@ impl Picker {
     }
 
     pub(crate) fn set_keyboard(&self, keyboard: Option<Keyboard>) {
+        let inner = self.inner();
+
         if let Some(old_kb) = self.keyboard() {
             old_kb.set_picker(None);
         }
@@ -104,18 +242,131 @@ impl Picker {
         }
 
         if let Some(kb) = &keyboard {
-            // Check that scancode is available for the keyboard
-            self.inner()
-                .group_box
-                .set_key_visibility(|name| kb.has_scancode(name));
+            // List the functions the keyboard supports
+            let meta = &kb.layout().meta;
+            inner.updating.set(true);
+            inner.function_combo.remove_all();
+            for function in FUNCTIONS {
+                let supported = match function {
+                    KeyFunction::Basic => true,
+                    KeyFunction::ModTap(_) if !meta.has_mod_tap => false,
+                    _ => function
+                        .name(Some("A"))
+                        .map_or(false, |x| kb.has_scancode(&x)),
+                };
+                if supported {
+                    inner
+                        .function_combo
+                        .append(Some(function.id()), &function_label(function));
+                }
+            }
+            inner
+                .function_combo
+                .set_active_id(Some(KeyFunction::Basic.id()));
+            let max_layer = meta.num_layers.min(LAYER_TAP_MAX + 1).max(1);
+            inner.layer_spin.set_range(1., f64::from(max_layer));
+            inner.updating.set(false);
+
+            let num_functions = inner
+                .function_combo
+                .model()
+                .map_or(0, |x| x.iter_n_children(None));
+            inner.function_box.set_visible(num_functions > 1);
             kb.set_picker(Some(self));
         }
 
-        *self.inner().keyboard.borrow_mut() = keyboard.map(|x| x.downgrade());
+        *inner.keyboard.borrow_mut() = keyboard.map(|x| x.downgrade());
+        self.update_key_visibility();
     }
 
     pub(crate) fn set_selected(&self, scancode_names: Vec<String>) {
-        self.inner().group_box.set_selected(scancode_names);
+        let inner = self.inner();
+
+        // Show the function of selected keys, and select the keycodes they
+        // combine, or the keys themselves for layer functions
+        let function = scancode_names
+            .iter()
+            .map(|x| KeyFunction::parse(x).0)
+            .find(|x| *x != KeyFunction::Basic)
+            .unwrap_or(KeyFunction::Basic);
+        inner.updating.set(true);
+        inner.function_combo.set_active_id(Some(function.id()));
+        if let Some(mods) = function.mods() {
+            self.set_mods(mods);
+        }
+        if let Some(layer) = function.layer() {
+            inner.layer_spin.set_value(f64::from(layer) + 1.);
+        }
+        inner.updating.set(false);
+
+        let names = scancode_names
+            .iter()
+            .map(|x| KeyFunction::parse(x).1.unwrap_or(x.as_str()).to_string())
+            .collect();
+        inner.group_box.set_selected(names);
+    }
+
+    /// Modifier bits of the checked modifiers
+    fn mods(&self) -> u16 {
+        let inner = self.inner();
+        let mut mods = MOD_BITS
+            .iter()
+            .zip(inner.mod_checks.iter())
+            .filter(|(_, check)| check.is_active())
+            .fold(0, |mods, (bit, _)| mods | bit);
+        if inner.mod_right_check.is_active() {
+            mods |= MOD_RIGHT;
+        }
+        mods
+    }
+
+    fn set_mods(&self, mods: u16) {
+        let inner = self.inner();
+        for (bit, check) in MOD_BITS.iter().zip(inner.mod_checks.iter()) {
+            check.set_active(mods & bit != 0);
+        }
+        inner.mod_right_check.set_active(mods & MOD_RIGHT != 0);
+    }
+
+    /// Function to combine picked keys with
+    fn function(&self) -> KeyFunction {
+        let inner = self.inner();
+        let layer = (inner.layer_spin.value_as_int() - 1).max(0) as u16;
+        inner
+            .function_combo
+            .active_id()
+            .and_then(|id| KeyFunction::from_id(&id, self.mods(), layer))
+            .unwrap_or(KeyFunction::Basic)
+    }
+
+    /// Show the modifiers or layer the function takes
+    fn update_function_widgets(&self) {
+        let inner = self.inner();
+        let function = self.function();
+        inner.mods_box.set_visible(function.mods().is_some());
+        inner.layer_box.set_visible(function.layer().is_some());
+        inner
+            .function_combo
+            .set_tooltip_text(Some(&function_desc(function)));
+        // Layer functions don't take a keycode
+        inner.group_box.set_sensitive(function.has_keycode());
+    }
+
+    fn update_key_visibility(&self) {
+        let kb = match self.keyboard() {
+            Some(kb) => kb,
+            None => {
+                return;
+            }
+        };
+
+        // Check that scancode is available for the keyboard
+        let function = self.function();
+        self.inner().group_box.set_key_visibility(|name| {
+            function
+                .name(Some(name))
+                .map_or(false, |x| kb.has_scancode(&x))
+        });
     }
 
     fn key_pressed(&self, name: String) {
@@ -125,17 +376,69 @@ impl Picker {
                 return;
             }
         };
-        let layer = kb.layer();
 
-        if let Some(layer) = layer {
+        let name = match self.function().name(Some(&name)) {
+            Some(name) => name,
+            None => {
+                return;
+            }
+        };
+        let changes = kb.selected().iter().map(|i| (*i, name.clone())).collect();
+        self.set_scancodes(&kb, changes);
+    }
+
+    /// Edit the function of the selected keys in place, keeping the keycodes
+    /// they combine
+    fn function_changed(&self) {
+        self.update_function_widgets();
+        self.update_key_visibility();
+
+        if self.inner().updating.get() {
+            return;
+        }
+
+        let kb = match self.keyboard() {
+            Some(kb) => kb,
+            None => {
+                return;
+            }
+        };
+        let layer = match kb.layer() {
+            Some(layer) => layer,
+            None => {
+                return;
+            }
+        };
+
+        let function = self.function();
+        let keys = kb.board().keys();
+        let mut changes = Vec::new();
+        for i in kb.selected().iter() {
+            let name = match keys[*i].get_scancode(layer) {
+                Some((_, name)) => name,
+                None => continue,
+            };
+            let new_name = match function.name(KeyFunction::parse(&name).1) {
+                Some(new_name) => new_name,
+                None => continue,
+            };
+            if new_name != name && kb.has_scancode(&new_name) {
+                changes.push((*i, new_name));
+            }
+        }
+        self.set_scancodes(&kb, changes);
+    }
+
+    fn set_scancodes(&self, kb: &Keyboard, changes: Vec<(usize, String)>) {
+        if let Some(layer) = kb.layer() {
+            let group = kb.board().history_group();
             let futures = FuturesUnordered::new();
-            for i in kb.selected().iter() {
-                let i = *i;
-                futures.push(clone!(@strong kb, @strong name => async move {
-                    kb.keymap_set(i, layer, &name).await;
+            for (i, name) in changes {
+                futures.push(clone!(@strong kb, @strong group => async move {
+                    kb.keymap_set(&group, i, layer, &name).await;
                 }));
             }
-            glib::MainContext::default().spawn_local(async { futures.collect::<()>().await });
+            glib::MainContext::default().spawn_local(futures.collect::<()>());
         }
     }
 }
@@ -0,0 +1,289 @@
+use crate::fl;
+use cascade::cascade;
+use gtk::{
+    glib::{self, clone},
+    prelude::*,
+    subclass::prelude::*,
+};
+use std::cell::RefCell;
+
+use crate::{show_error_dialog, Keyboard};
+use backend::{DerefCell, Error, ProfileStore};
+
+#[derive(Default)]
+pub struct ProfileSwitcherInner {
+    store: RefCell<Option<ProfileStore>>,
+    keyboard: RefCell<Option<glib::WeakRef<Keyboard>>>,
+    list_box: DerefCell<gtk::ListBox>,
+    name_entry: DerefCell<gtk::Entry>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for ProfileSwitcherInner {
+    const NAME: &'static str = "S76ProfileSwitcher";
+    type ParentType = gtk::Box;
+    type Type = ProfileSwitcher;
+}
+
+impl ObjectImpl for ProfileSwitcherInner {
+    fn constructed(&self) {
+        self.parent_constructed();
+
+        let switcher = self.obj();
+
+        let list_box = cascade! {
+            gtk::ListBox::new();
+            ..set_selection_mode(gtk::SelectionMode::None);
+        };
+
+        let name_entry = cascade! {
+            gtk::Entry::new();
+            ..set_placeholder_text(Some(&fl!("profile-name")));
+            ..connect_activate(clone!(@weak switcher => move |_|
+                switcher.save();
+            ));
+        };
+
+        let popover = cascade! {
+            gtk::Popover::new(None::<&gtk::Widget>);
+            ..add(&cascade! {
+                gtk::Box::new(gtk::Orientation::Vertical, 6);
+                ..set_margin(6);
+                ..add(&list_box);
+                ..add(&gtk::Separator::new(gtk::Orientation::Horizontal));
+                ..add(&cascade! {
+                    gtk::Box::new(gtk::Orientation::Horizontal, 6);
+                    ..add(&name_entry);
+                    ..add(&cascade! {
+                        gtk::Button::with_label(&fl!("profile-save"));
+                        ..connect_clicked(clone!(@weak switcher => move |_|
+                            switcher.save();
+                        ));
+                    });
+                });
+                ..show_all();
+            });
+            ..connect_show(clone!(@weak switcher => move |_|
+                switcher.update_list();
+            ));
+        };
+
+        cascade! {
+            &*switcher;
+            ..add(&cascade! {
+                gtk::MenuButton::new();
+                ..set_tooltip_text(Some(&fl!("profiles")));
+                ..set_popover(Some(&popover));
+                ..add(&gtk::Image::from_icon_name(Some("view-list-symbolic"), gtk::IconSize::Button));
+            });
+            ..show_all();
+        };
+
+        match ProfileStore::new() {
+            Ok(store) => *self.store.borrow_mut() = Some(store),
+            Err(err) => {
+                error!("{}", err);
+                switcher.set_sensitive(false);
+            }
+        }
+
+        self.list_box.set(list_box);
+        self.name_entry.set(name_entry);
+    }
+}
+
+impl WidgetImpl for ProfileSwitcherInner {}
+impl ContainerImpl for ProfileSwitcherInner {}
+impl BoxImpl for ProfileSwitcherInner {}
+
+glib::wrapper! {
+    pub struct ProfileSwitcher(ObjectSubclass<ProfileSwitcherInner>)
+        @extends gtk::Box, gtk::Container, gtk::Widget, @implements gtk::Orientable;
+}
+
+impl ProfileSwitcher {
+    pub fn new() -> Self {
+        glib::Object::new()
+    }
+
+    fn inner(&self) -> &ProfileSwitcherInner {
+        ProfileSwitcherInner::from_obj(self)
+    }
+
+    fn keyboard(&self) -> Option<Keyboard> {
+        self.inner()
+            .keyboard
+            .borrow()
+            .as_ref()
+            .and_then(|x| x.upgrade())
+    }
+
+    pub(crate) fn set_keyboard(&self, keyboard: Option<Keyboard>) {
+        *self.inner().keyboard.borrow_mut() = keyboard.map(|x| x.downgrade());
+    }
+
+    fn store(&self) -> Option<ProfileStore> {
+        self.inner().store.borrow().clone()
+    }
+
+    fn window(&self) -> Option<gtk::Window> {
+        self.toplevel()?.downcast().ok()
+    }
+
+    fn show_error(&self, err: Error) {
+        error!("{}", err);
+        if let Some(window) = self.window() {
+            show_error_dialog(
+                &window,
+                &fl!("error-profile"),
+                glib::markup_escape_text(&err.to_string()),
+            );
+        }
+    }
+
+    fn update_list(&self) {
+        let list_box = &*self.inner().list_box;
+        list_box.foreach(|row| list_box.remove(row));
+
+        let (store, keyboard) = match (self.store(), self.keyboard()) {
+            (Some(store), Some(keyboard)) => (store, keyboard),
+            _ => return,
+        };
+        let names = match store.list(keyboard.board().model()) {
+            Ok(names) => names,
+            Err(err) => {
+                self.show_error(err);
+                return;
+            }
+        };
+
+        if names.is_empty() {
+            list_box.add(&cascade! {
+                gtk::Label::new(Some(&fl!("profile-none")));
+                ..set_margin(6);
+                ..set_sensitive(false);
+            });
+        }
+
+        let switcher = self;
+        for name in names {
+            list_box.add(&cascade! {
+                gtk::Box::new(gtk::Orientation::Horizontal, 0);
+                ..add(&cascade! {
+                    gtk::Button::with_label(&name);
+                    ..set_relief(gtk::ReliefStyle::None);
+                    ..set_hexpand(true);
+                    ..set_tooltip_text(Some(&fl!("profile-load")));
+                    ..connect_clicked(clone!(@weak switcher, @strong name => move |_|
+                        switcher.load(&name);
+                    ));
+                });
+                ..add(&cascade! {
+                    gtk::Button::from_icon_name(Some("document-edit-symbolic"), gtk::IconSize::Button);
+                    ..set_relief(gtk::ReliefStyle::None);
+                    ..set_tooltip_text(Some(&fl!("profile-rename")));
+                    ..connect_clicked(clone!(@weak switcher, @strong name => move |_| {
+                        let name = name.clone();
+                        glib::MainContext::default().spawn_local(async move {
+                            switcher.rename(&name).await;
+                        });
+                    }));
+                });
+                ..add(&cascade! {
+                    gtk::Button::from_icon_name(Some("user-trash-symbolic"), gtk::IconSize::Button);
+                    ..set_relief(gtk::ReliefStyle::None);
+                    ..set_tooltip_text(Some(&fl!("profile-delete")));
+                    ..connect_clicked(clone!(@weak switcher, @strong name => move |_|
+                        switcher.delete(&name);
+                    ));
+                });
+            });
+        }
+
+        list_box.show_all();
+    }
+
+    fn load(&self, name: &str) {
+        let (store, keyboard) = match (self.store(), self.keyboard()) {
+            (Some(store), Some(keyboard)) => (store, keyboard),
+            _ => return,
+        };
+        match store.load(keyboard.board().model(), name) {
+            Ok(keymap) => {
+                glib::MainContext::default().spawn_local(async move {
+                    keyboard.import_keymap(keymap).await;
+                });
+            }
+            Err(err) => self.show_error(err),
+        }
+    }
+
+    fn save(&self) {
+        let (store, keyboard) = match (self.store(), self.keyboard()) {
+            (Some(store), Some(keyboard)) => (store, keyboard),
+            _ => return,
+        };
+        let name = self.inner().name_entry.text();
+        match store.save(name.trim(), &keyboard.export_keymap()) {
+            Ok(()) => {
+                self.inner().name_entry.set_text("");
+                self.update_list();
+            }
+            Err(err) => self.show_error(err),
+        }
+    }
+
+    fn delete(&self, name: &str) {
+        let (store, keyboard) = match (self.store(), self.keyboard()) {
+            (Some(store), Some(keyboard)) => (store, keyboard),
+            _ => return,
+        };
+        if let Err(err) = store.delete(keyboard.board().model(), name) {
+            self.show_error(err);
+        }
+        self.update_list();
+    }
+
+    async fn rename(&self, name: &str) {
+        let (store, keyboard) = match (self.store(), self.keyboard()) {
+            (Some(store), Some(keyboard)) => (store, keyboard),
+            _ => return,
+        };
+
+        let entry = cascade! {
+            gtk::Entry::new();
+            ..set_text(name);
+            ..set_activates_default(true);
+        };
+        let (cancel, rename) = (fl!("button-cancel"), fl!("profile-rename"));
+        let dialog = cascade! {
+            gtk::Dialog::with_buttons(
+                Some(&rename),
+                self.window().as_ref(),
+                gtk::DialogFlags::MODAL | gtk::DialogFlags::USE_HEADER_BAR,
+                &[
+                    (cancel.as_str(), gtk::ResponseType::Cancel),
+                    (rename.as_str(), gtk::ResponseType::Accept),
+                ],
+            );
+            ..set_default_response(gtk::ResponseType::Accept);
+        };
+        cascade! {
+            dialog.content_area();
+            ..add(&entry);
+            ..set_margin(24);
+            ..show_all();
+        };
+
+        let response = dialog.run_future().await;
+        let new_name = entry.text();
+        dialog.close();
+
+        if response == gtk::ResponseType::Accept && new_name.trim() != name {
+            if let Err(err) = store.rename(keyboard.board().model(), name, new_name.trim()) {
+                self.show_error(err);
+            }
+            self.update_list();
+        }
+    }
+}
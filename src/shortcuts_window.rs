@@ -14,5 +14,11 @@ pub fn shortcuts_window() -> gtk::ShortcutsWindow {
     let export: gtk::ShortcutsShortcut = builder.object("export-layout").unwrap();
     export.set_title(Some(&fl!("layout-export")));
 
+    let undo: gtk::ShortcutsShortcut = builder.object("undo").unwrap();
+    undo.set_title(Some(&fl!("edit-undo")));
+
+    let redo: gtk::ShortcutsShortcut = builder.object("redo").unwrap();
+    redo.set_title(Some(&fl!("edit-redo")));
+
     builder.object("shortcuts-window").unwrap()
 }
@@ -0,0 +1,15 @@
+use std::{env, path::PathBuf, process};
+
+fn main() {
+    let dir = env::args_os()
+        .nth(1)
+        .map_or_else(|| PathBuf::from("layouts"), PathBuf::from);
+
+    let errors = backend::lint_layouts(&dir);
+    for err in &errors {
+        eprintln!("{}", err);
+    }
+    if !errors.is_empty() {
+        process::exit(1);
+    }
+}
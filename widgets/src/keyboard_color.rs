@@ -25,6 +25,7 @@ impl KeyboardColorIndex {
     pub async fn set_color(&self, board: &Board, hs: Hs) -> Result<(), String> {
         match self {
             KeyboardColorIndex::Keys(keys) => {
+                let _group = board.history_group();
                 let futures = FuturesUnordered::new();
                 for i in keys.iter() {
                     futures.push(board.keys()[*i].set_color(Some(hs)));
@@ -69,6 +70,7 @@ impl KeyboardColorIndex {
     ) -> Result<(), String> {
         match self {
             KeyboardColorIndex::Keys(keys) => {
+                let _group = board.history_group();
                 let futures = FuturesUnordered::new();
                 for i in keys.iter() {
                     futures.push(board.keys()[*i].set_color(colors.get(i).copied()));